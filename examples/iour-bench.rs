@@ -0,0 +1,365 @@
+/*
+ * Kornilios Kourtis <kkourt@kkourt.io>
+ *
+ * vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+ */
+
+// A (tiny) fio-like benchmark
+//
+// Keeps qd reads or writes of bs bytes in flight against a file or block device for the given
+// runtime, and reports IOPS, bandwidth, and completion latency percentiles. Latency is measured
+// from queueing the request until its cqe is reaped, so it includes the submission batching of the
+// event loop.
+//
+// Example:
+//  iour-bench --rw=randread --bs=4k --qd=32 --direct --iopoll /dev/nvme0n1
+
+use iouring::io_uring::{self, SetupFlags, ShutdownPolicy};
+
+use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+// NB: enough for O_DIRECT on any device we care about
+const BUF_ALIGN: usize = 4096;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Rw {
+    Read,
+    Write,
+    RandRead,
+    RandWrite,
+}
+
+impl Rw {
+    fn is_read(&self) -> bool {
+        matches!(self, Rw::Read | Rw::RandRead)
+    }
+
+    fn is_rand(&self) -> bool {
+        matches!(self, Rw::RandRead | Rw::RandWrite)
+    }
+}
+
+struct Opts {
+    path: String,
+    rw: Rw,
+    bs: usize,
+    qd: u32,
+    size: Option<u64>,
+    runtime: Duration,
+    direct: bool,
+    iopoll: bool,
+}
+
+const USAGE: &str = "[options] <file>
+options:
+  --rw=<read|write|randread|randwrite>  I/O pattern (default: randread)
+  --bs=<bytes>                          block size, k/m/g suffixes allowed (default: 4k)
+  --qd=<n>                              queue depth (default: 32)
+  --size=<bytes>                        size of the region to do I/O on (default: file size)
+  --runtime=<secs>                      duration of the run (default: 10)
+  --direct                              open the file with O_DIRECT
+  --iopoll                              use a polled (IORING_SETUP_IOPOLL) ring, needs --direct";
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (num, mult) = match s.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&s[..s.len() - 1], 1 << 10),
+        Some('m') => (&s[..s.len() - 1], 1 << 20),
+        Some('g') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|x| x.checked_mul(mult))
+        .ok_or_else(|| format!("invalid size: {}", s))
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Opts, String> {
+    let mut opts = Opts {
+        path: String::new(),
+        rw: Rw::RandRead,
+        bs: 4096,
+        qd: 32,
+        size: None,
+        runtime: Duration::from_secs(10),
+        direct: false,
+        iopoll: false,
+    };
+
+    let mut path = None;
+    for arg in args {
+        let (key, val) = match arg.split_once('=') {
+            Some((k, v)) => (k, Some(v)),
+            None => (arg.as_str(), None),
+        };
+        match (key, val) {
+            ("--rw", Some(v)) => {
+                opts.rw = match v {
+                    "read" => Rw::Read,
+                    "write" => Rw::Write,
+                    "randread" => Rw::RandRead,
+                    "randwrite" => Rw::RandWrite,
+                    _ => return Err(format!("invalid --rw value: {}", v)),
+                }
+            }
+            ("--bs", Some(v)) => opts.bs = parse_size(v)? as usize,
+            ("--qd", Some(v)) => opts.qd = v.parse().map_err(|_| format!("invalid qd: {}", v))?,
+            ("--size", Some(v)) => opts.size = Some(parse_size(v)?),
+            ("--runtime", Some(v)) => {
+                let secs = v.parse().map_err(|_| format!("invalid runtime: {}", v))?;
+                opts.runtime = Duration::from_secs(secs);
+            }
+            ("--direct", None) => opts.direct = true,
+            ("--iopoll", None) => opts.iopoll = true,
+            (k, _) if k.starts_with("--") => return Err(format!("invalid option: {}", arg)),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("unexpected argument: {}", arg)),
+        }
+    }
+
+    opts.path = path.ok_or("no file given")?;
+    if opts.bs == 0 || opts.qd == 0 {
+        return Err("block size and queue depth need to be positive".to_string());
+    }
+    if opts.direct && !opts.bs.is_multiple_of(512) {
+        return Err("--direct needs a block size that is a multiple of 512".to_string());
+    }
+    if opts.iopoll && !opts.direct {
+        return Err("--iopoll needs --direct".to_string());
+    }
+    Ok(opts)
+}
+
+/// A heap buffer, aligned for O_DIRECT
+struct AlignedBuf {
+    ptr: *mut u8,
+    layout: std::alloc::Layout,
+}
+
+impl AlignedBuf {
+    fn new(size: usize) -> AlignedBuf {
+        let layout = std::alloc::Layout::from_size_align(size, BUF_ALIGN).unwrap();
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        AlignedBuf { ptr, layout }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+/// An I/O slot: there is one per queue depth, and each has at most one request in flight
+struct Slot {
+    // NB: the iovec points to buf, so it needs to be kept alive while the request is in flight
+    _buf: AlignedBuf,
+    iov: libc::iovec,
+    start: Instant,
+}
+
+struct Bench {
+    ior: io_uring::IoUring,
+    fd: RawFd,
+    rw: Rw,
+    bs: u64,
+    // number of blocks in the region
+    nblocks: u64,
+    next_block: u64,
+    rng: u64,
+    slots: Vec<Slot>,
+    // completion latencies in ns
+    lats: Vec<u64>,
+}
+
+impl Bench {
+
+    // xorshift64*
+    fn rand(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    fn next_off(&mut self) -> u64 {
+        let block = if self.rw.is_rand() {
+            self.rand() % self.nblocks
+        } else {
+            let b = self.next_block;
+            self.next_block = (b + 1) % self.nblocks;
+            b
+        };
+        block * self.bs
+    }
+
+    fn queue(&mut self, idx: usize) {
+        let off = self.next_off();
+        let slot = &mut self.slots[idx];
+        slot.start = Instant::now();
+        // NB: there is one slot per sq entry, so there is always space
+        let mut sqe = self.ior.get_sqe().expect("submission queue is full");
+        if self.rw.is_read() {
+            sqe.prep_readv(self.fd, &slot.iov, 1, off);
+        } else {
+            sqe.prep_writev(self.fd, &slot.iov, 1, off);
+        }
+        sqe.set_data(idx as u64);
+    }
+
+    fn run(&mut self, runtime: Duration) -> std::io::Result<Duration> {
+        let start = Instant::now();
+        let mut inflight = self.slots.len();
+        for idx in 0..inflight {
+            self.queue(idx);
+        }
+
+        while inflight > 0 {
+            // NB: this also submits the requests queued in the previous iteration. For IOPOLL
+            // rings, this is what polls for completions.
+            self.ior.submit_and_wait(1)?;
+            let running = start.elapsed() < runtime;
+            while let Some(cqe) = self.ior.pop_cqe() {
+                let idx = cqe.user_data() as usize;
+                self.lats.push(self.slots[idx].start.elapsed().as_nanos() as u64);
+                let res = cqe.res();
+                if res < 0 {
+                    return Err(std::io::Error::from_raw_os_error(-res));
+                } else if res as u64 != self.bs {
+                    let msg = format!("short I/O: {} out of {} bytes", res, self.bs);
+                    return Err(std::io::Error::other(msg));
+                }
+
+                if running {
+                    self.queue(idx);
+                } else {
+                    inflight -= 1;
+                }
+            }
+        }
+
+        Ok(start.elapsed())
+    }
+}
+
+fn report(opts: &Opts, lats: &mut [u64], elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    let ios = lats.len();
+    let mib = (ios as f64) * (opts.bs as f64) / (1024.0 * 1024.0);
+    let name = if opts.rw.is_read() { "read" } else { "write" };
+    println!(
+        "{}: IOPS={:.0}, BW={:.1}MiB/s, ios={}, runtime={:.2}s",
+        name, ios as f64 / secs, mib / secs, ios, secs
+    );
+    if ios == 0 {
+        return;
+    }
+
+    lats.sort_unstable();
+    let us = |ns: u64| ns as f64 / 1000.0;
+    let avg = lats.iter().map(|x| *x as f64).sum::<f64>() / ios as f64;
+    println!(
+        "  lat (usec): min={:.1}, avg={:.1}, max={:.1}",
+        us(lats[0]), avg / 1000.0, us(lats[ios - 1])
+    );
+    let pcts = [50.0, 90.0, 99.0, 99.9, 99.99];
+    let vals: Vec<String> = pcts.iter()
+        .map(|p| {
+            let idx = ((p / 100.0) * ios as f64).ceil() as usize;
+            format!("{:.2}th=[{:.1}]", p, us(lats[idx.clamp(1, ios) - 1]))
+        })
+        .collect();
+    println!("  lat percentiles (usec): {}", vals.join(", "));
+}
+
+pub fn main() {
+    let mut args = std::env::args();
+    let arg0 = args.next().unwrap();
+    let opts = match parse_args(args) {
+        Ok(x) => x,
+        Err(e) => {
+            let pname = std::path::Path::new(&arg0).file_name().unwrap().to_str()
+                .unwrap_or("iour-bench");
+            eprintln!("{}\nUsage: {} {}", e, pname, USAGE);
+            std::process::exit(-1);
+        }
+    };
+
+    let mut oo = std::fs::OpenOptions::new();
+    oo.read(true).write(!opts.rw.is_read());
+    if opts.direct {
+        oo.custom_flags(libc::O_DIRECT);
+    }
+    let mut f = match oo.open(&opts.path) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", opts.path, e);
+            std::process::exit(-1);
+        }
+    };
+
+    // NB: seeking to the end also works for block devices
+    let size = match opts.size {
+        Some(x) => x,
+        None => match f.seek(SeekFrom::End(0)) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Failed to get size of {}: {}", opts.path, e);
+                std::process::exit(-1);
+            }
+        },
+    };
+    let nblocks = size / opts.bs as u64;
+    if nblocks == 0 {
+        eprintln!("{} is smaller than the block size, try --size", opts.path);
+        std::process::exit(-1);
+    }
+
+    let flags = if opts.iopoll { SetupFlags::IOPOLL } else { SetupFlags::empty() };
+    let mut ior = match io_uring::IoUring::init_with_flags(opts.qd, flags) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to initialize io_uring: {}", e);
+            std::process::exit(-1);
+        }
+    };
+    // NB: the ring is dropped before the slots, so make sure no requests use their buffers
+    ior.set_drop_policy(ShutdownPolicy::CancelAndWait);
+
+    let slots = (0..opts.qd)
+        .map(|_| {
+            let buf = AlignedBuf::new(opts.bs);
+            let iov = libc::iovec { iov_base: buf.ptr as *mut libc::c_void, iov_len: opts.bs };
+            Slot { _buf: buf, iov, start: Instant::now() }
+        })
+        .collect();
+
+    let mut bench = Bench {
+        ior,
+        fd: f.as_raw_fd(),
+        rw: opts.rw,
+        bs: opts.bs as u64,
+        nblocks,
+        next_block: 0,
+        rng: 0x9E3779B97F4A7C15,
+        slots,
+        lats: vec![],
+    };
+
+    match bench.run(opts.runtime) {
+        Ok(elapsed) => report(&opts, &mut bench.lats, elapsed),
+        Err(e) => {
+            eprintln!("Benchmark failed: {}", e);
+            if opts.iopoll && e.raw_os_error() == Some(libc::EOPNOTSUPP) {
+                // NB: e.g., NVMe devices need poll queues (nvme.poll_queues module parameter)
+                eprintln!("(does the device support polled I/O?)");
+            }
+            std::process::exit(-1);
+        }
+    }
+}
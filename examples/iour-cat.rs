@@ -0,0 +1,98 @@
+/*
+ * Kornilios Kourtis <kkourt@kkourt.io>
+ *
+ * vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+ */
+
+// cat using io_uring, following liburing/examples/io_uring-cat.c
+//
+// Files are read in chunks of up to MAX_BLOCKS blocks, using a single readv request per chunk
+// with one BLOCK_SZ buffer per iovec.
+
+use iouring::io_uring;
+
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+
+const BLOCK_SZ: usize = 1024;
+const MAX_BLOCKS: usize = 64; // NB: must not exceed UIO_MAXIOV (1024)
+
+/// Read up to nblocks blocks at offset off, and print them. Returns the number of bytes read.
+fn cat_chunk(ior: &mut io_uring::IoUring, f: &std::fs::File, off: u64, nblocks: usize)
+-> std::io::Result<usize> {
+    let mut blocks: Vec<Vec<u8>> = (0..nblocks).map(|_| vec![0u8; BLOCK_SZ]).collect();
+    let iovecs: Vec<std::io::IoSliceMut> = blocks.iter_mut()
+        .map(|b| std::io::IoSliceMut::new(b))
+        .collect();
+
+    // submit
+    {
+        let mut sqe = ior.get_sqe().expect("submission queue is full");
+        sqe.prep_read_slice(f.as_raw_fd(), &iovecs, off)?;
+        sqe.set_data(off);
+    }
+    ior.submit()?;
+
+    // wait
+    let cqe = loop {
+        if let Some(cqe) = ior.pop_cqe() {
+            break cqe;
+        }
+        ior.submit_and_wait(1)?;
+    };
+    if cqe.res() < 0 {
+        return Err(std::io::Error::from_raw_os_error(-cqe.res()));
+    }
+
+    // print
+    drop(iovecs);
+    let nbytes = cqe.res() as usize;
+    let mut left = nbytes;
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    for b in blocks.iter() {
+        let len = std::cmp::min(left, b.len());
+        out.write_all(&b[..len])?;
+        left -= len;
+    }
+    Ok(nbytes)
+}
+
+fn cat_file(ior: &mut io_uring::IoUring, path: &str) -> std::io::Result<()> {
+    let f = std::fs::File::open(path)?;
+    let file_sz = f.metadata()?.len();
+    let mut off = 0;
+    while off < file_sz {
+        let nblocks = std::cmp::min((file_sz - off).div_ceil(BLOCK_SZ as u64), MAX_BLOCKS as u64);
+        match cat_chunk(ior, &f, off, nblocks as usize)? {
+            0 => break, // the file was truncated since we got its size
+            n => off += n as u64,
+        }
+    }
+    Ok(())
+}
+
+pub fn main() {
+    let mut args = std::env::args();
+    let arg0 = args.next().unwrap();
+    if args.len() < 1 {
+        let pname = std::path::Path::new(&arg0).file_name().unwrap().to_str().unwrap_or("iour-cat");
+        eprintln!("Usage: {} <filename1> [<filename2> ...]", pname);
+        std::process::exit(-1);
+    }
+
+    let mut ior = match io_uring::IoUring::init(1) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to initialize io_uring: {}", e);
+            std::process::exit(-1);
+        }
+    };
+
+    for path in args {
+        if let Err(e) = cat_file(&mut ior, &path) {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(-1);
+        }
+    }
+}
@@ -6,14 +6,12 @@
 
 // cp using io_uring, following liburing/examples/io_uring-cp.c
 
-use libc;
-use iouring::io_uring;
+use iouring::{io_uring, util};
 
-// use std::ops::Deref;
-use std::convert::TryInto;
+use std::os::unix::io::{AsRawFd, RawFd};
 
-const QD : usize = 64;
-const BS : usize = (32*1024);
+const QD : u32 = 64;
+const BS : u64 = 32*1024;
 
 // rust uses IoSlice for write_vectored and IoSliceMut for read_vectored.
 // _Both_ are guaranteed to be ABI compatible with iovec.
@@ -29,115 +27,227 @@ const BS : usize = (32*1024);
 // |     vec: iovec,
 // |     _p: PhantomData<&'a mut [u8]>,
 // | }
+//
+// Here, however, the buffer needs to outlive the function that queues the request, so we keep
+// the buffer and the iovec together in a heap-allocated IoData, and pass its pointer as the
+// user data of the request.
 
-/// Buffer for performing IO
-struct IoBuff {
-    off: usize,
-    size: usize,
-    buff: Vec<u8>,
+/// Data for an in-flight IO operation
+struct IoData {
+    read: bool,
+    first_off: u64,
+    first_len: usize,
+    // current offset and iovec, updated on short reads/writes
+    off: u64,
     iov: libc::iovec,
+    buff: Vec<u8>,
 }
 
-impl IoBuff {
-
-    pub fn new(size: usize, off: usize) -> IoBuff {
-        IoBuff {
-            off: off,
-            size: size,
-            buff: Vec::with_capacity(size),
-            iov: libc::iovec {
-                iov_base: buff.as_mut_ptr() as *mut libc::c_void,
-                iov_len: size,
-            },
-        }
+impl IoData {
+
+    pub fn new(size: usize, off: u64) -> Box<IoData> {
+        let mut ret = Box::new(IoData {
+            read: true,
+            first_off: off,
+            first_len: size,
+            off,
+            iov: libc::iovec { iov_base: std::ptr::null_mut(), iov_len: 0 },
+            buff: vec![0; size],
+        });
+        ret.reset_iov();
+        ret
     }
-}
 
-/// get the size of the file, properly handling block devices
-///
-/// (fs::metdata -> len(), does not work for block devices)
-fn get_file_size(f: &std::fs::File) -> std::io::Result<usize>  {
+    /// Point the iovec to the whole buffer
+    fn reset_iov(&mut self) {
+        self.off = self.first_off;
+        self.iov = libc::iovec {
+            iov_base: self.buff.as_mut_ptr() as *mut libc::c_void,
+            iov_len: self.first_len,
+        };
+    }
 
-    pub const IOC_BLKGETSIZE64: libc::c_ulong = 0x80081272;
+    /// Adjust the iovec after a short read/write of len bytes
+    fn advance(&mut self, len: usize) {
+        self.iov.iov_base = unsafe { (self.iov.iov_base as *mut u8).add(len) as *mut libc::c_void };
+        self.iov.iov_len -= len;
+        self.off += len as u64;
+    }
+}
 
-    let s_isreg = |m: u32| -> bool {
-        (m & libc::S_IFMT) == libc::S_IFREG
-    };
+struct Copy {
+    ior: io_uring::IoUring,
+    infd: RawFd,
+    outfd: RawFd,
+}
 
-    let s_isblk = |m: u32| -> bool {
-        (m & libc::S_IFMT) == libc::S_IFBLK
-    };
+impl Copy {
 
-    use std::os::unix::io::AsRawFd;
-    let fd = f.as_raw_fd();
+    /// Queue a read request. Returns false if the submission queue is full.
+    fn queue_read(&mut self, size: usize, off: u64) -> bool {
+        // allocate entry in the submission queue
+        let mut sqe = match self.ior.get_sqe() {
+            Some(x) => x,
+            None => return false,
+        };
 
-    let st: libc::stat  = unsafe {
-        let mut ret: libc::stat = std::mem::zeroed();
-        let err = libc::fstat(fd, &mut ret);
-        if err != 0 {
-            return Err(std::io::Error::from_raw_os_error(err));
-        }
-        ret
-    };
+        let iodata = IoData::new(size, off);
+        sqe.prep_readv(self.infd, &iodata.iov, 1, off);
+        sqe.set_data(Box::into_raw(iodata) as usize as u64);
+        true
+    }
 
-    if s_isreg(st.st_mode) {
-       return Ok(st.st_size as usize)
-    } else if s_isblk(st.st_mode) {
-        let mut bytes: libc::c_ulonglong = 0;
-        let err = unsafe { libc::ioctl(fd, IOC_BLKGETSIZE64, &mut bytes) };
-        if err == 0 {
-            Ok(bytes as usize)
+    /// (Re)queue a request using its current iovec and offset
+    fn queue_prepped(&mut self, iodata: Box<IoData>) {
+        // NB: the number of requests in flight never exceeds QD, so there is always space
+        let mut sqe = self.ior.get_sqe().expect("submission queue is full");
+        if iodata.read {
+            sqe.prep_readv(self.infd, &iodata.iov, 1, iodata.off);
         } else {
-            Err(std::io::Error::last_os_error())
+            sqe.prep_writev(self.outfd, &iodata.iov, 1, iodata.off);
         }
-    } else {
-        Err(std::io::Error::new(std::io::ErrorKind::Other, "Cannot determine file size"))
+        sqe.set_data(Box::into_raw(iodata) as usize as u64);
     }
-}
 
-fn queue_read(ior: &mut io_uring::IoUring, fd: RawFd, size: usize, off: usize) -> Option<()> {
+    /// Turn a completed read into a write of the same data
+    fn queue_write(&mut self, mut iodata: Box<IoData>) -> std::io::Result<()> {
+        iodata.read = false;
+        iodata.reset_iov();
+        self.queue_prepped(iodata);
+        self.ior.submit()?;
+        Ok(())
+    }
 
-    // allocate entry in the submission queue
-    let mut sqe = match ior.get_sqe() {
-        Some(x) => x,
-        None => return None,
-    };
+    /// Wait for a completion. Returns the cqe and the IoData of the request.
+    fn wait_cqe(&mut self) -> std::io::Result<(io_uring::io_uring_cqe, Box<IoData>)> {
+        loop {
+            if let Some(x) = self.peek_cqe() {
+                return Ok(x);
+            }
+            self.ior.submit_and_wait(1)?;
+        }
+    }
 
-    let iodata = Box::new(IoBuff::new(size, off));
-    sqe.prep_readv(fd, &iodata.iov, 1, off.try_into().unwrap());
-    let iodata_ptr = Box::into_raw(iodata) as u64;
-    sqe.set_data(iodata_ptr);
-    Some(())
-}
+    /// Get a completion, if one is available
+    fn peek_cqe(&mut self) -> Option<(io_uring::io_uring_cqe, Box<IoData>)> {
+        let cqe = self.ior.pop_cqe()?;
+        let iodata = unsafe { Box::from_raw(cqe.user_data() as usize as *mut IoData) };
+        Some((cqe, iodata))
+    }
+
+    fn copy_file(&mut self, mut insize: u64) -> std::io::Result<()> {
+        let mut reads: u32 = 0;
+        let mut writes: u32 = 0;
+        let mut offset: u64 = 0;
+        let mut write_left = insize;
 
-fn copy_file(ior: &io_uring::IoUring, infd: RawFd, insize: usize, outfd: RawFd) -> std::io::Result<()> {
-    let mut rd_issued: usize = 0;
-    let mut rd_done: usize = 0;
-    let mut wr_issued: usize = 0;
-    let mut wr_done: usize = 0;
-
-    while wr_done < insize {
-
-        // queue as many read requests as possible
-        let mut rd_queued = 0;
-        while rd_issued < insize {
-            let rd_size = std::cmp::min(insize - rd_issued, BS);
-            let rd_off = rd_issued;
-            match queue_read(ior, infd, rd_size, rd_off) {
-                None => break,
-                Some(()) => {
-                    rd_issued += rd_size;
-                    rd_queued++;
-                },
+        while insize > 0 || write_left > 0 {
+
+            // queue as many read requests as possible
+            let had_reads = reads;
+            while insize > 0 && reads + writes < QD {
+                let this_size = std::cmp::min(insize, BS);
+                if !self.queue_read(this_size as usize, offset) {
+                    break;
+                }
+                insize -= this_size;
+                offset += this_size;
+                reads += 1;
+            }
+
+            // submit the read requests enqueued (if any)
+            if had_reads != reads {
+                self.ior.submit()?;
+            }
+
+            // Queue is full at this point. Let's find at least one completion.
+            let mut got_comp = false;
+            while write_left > 0 {
+                let (cqe, mut iodata) = if !got_comp {
+                    got_comp = true;
+                    self.wait_cqe()?
+                } else {
+                    match self.peek_cqe() {
+                        Some(x) => x,
+                        None => break,
+                    }
+                };
+
+                let res = cqe.res();
+                if res < 0 {
+                    if res == -libc::EAGAIN {
+                        self.queue_prepped(iodata);
+                        continue;
+                    }
+                    return Err(std::io::Error::from_raw_os_error(-res));
+                } else if res == 0 && iodata.iov.iov_len > 0 {
+                    // file was truncated under our feet
+                    let msg = format!("unexpected EOF at offset {}", iodata.off);
+                    return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, msg));
+                } else if res as usize != iodata.iov.iov_len {
+                    // short read/write: adjust and requeue
+                    iodata.advance(res as usize);
+                    self.queue_prepped(iodata);
+                    continue;
+                }
+
+                // All done. If write, nothing else to do. If read, queue up corresponding write.
+                if iodata.read {
+                    write_left -= iodata.first_len as u64;
+                    self.queue_write(iodata)?;
+                    reads -= 1;
+                    writes += 1;
+                } else {
+                    writes -= 1;
+                }
             }
         }
 
-        // submit the read requests enqueued (if any)
-        if rd_queued > 0 {
-            ior.submit()?
+        // wait out pending writes
+        while writes > 0 {
+            let (cqe, iodata) = self.wait_cqe()?;
+            let res = cqe.res();
+            if res < 0 {
+                return Err(std::io::Error::from_raw_os_error(-res));
+            } else if res as usize != iodata.iov.iov_len {
+                // short write: adjust and requeue
+                let mut iodata = iodata;
+                iodata.advance(res as usize);
+                self.queue_prepped(iodata);
+                self.ior.submit()?;
+                continue;
+            }
+            writes -= 1;
         }
+
+        Ok(())
     }
+}
+
+/// Check that the two files have the same contents
+fn verify(inpath: &str, outpath: &str) -> std::io::Result<bool> {
+    use std::io::Read;
 
+    let mut fin = std::fs::File::open(inpath)?;
+    let mut fout = std::fs::File::open(outpath)?;
+    let mut bin = vec![0u8; BS as usize];
+    let mut bout = vec![0u8; BS as usize];
+    loop {
+        let n = fin.read(&mut bin)?;
+        if n == 0 {
+            // output should be at EOF as well
+            return Ok(fout.read(&mut bout)? == 0);
+        }
+        if let Err(e) = fout.read_exact(&mut bout[..n]) {
+            return match e.kind() {
+                std::io::ErrorKind::UnexpectedEof => Ok(false),
+                _ => Err(e),
+            }
+        }
+        if bin[..n] != bout[..n] {
+            return Ok(false);
+        }
+    }
 }
 
 pub fn main() {
@@ -151,29 +261,24 @@ pub fn main() {
         std::process::exit(-1);
     }
 
-    let fin = {
-        let arg1 = &args.next().unwrap();
-        match std::fs::File::open(arg1) {
-            Ok(x) => x,
-            Err(e) => {
-                eprintln!("Failed to open {}: {}", arg1, e);
-                std::process::exit(-1);
-            }
+    let inpath = args.next().unwrap();
+    let fin = match std::fs::File::open(&inpath) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", inpath, e);
+            std::process::exit(-1);
         }
     };
 
-    let fout = {
-        let arg2 = &args.next().unwrap();
-        match std::fs::File::create(arg2) {
-            Ok(x) => x,
-            Err(e) => {
-                eprintln!("Failed to create {}: {}", arg2, e);
-                std::process::exit(-1);
-            }
+    let outpath = args.next().unwrap();
+    let fout = match std::fs::File::create(&outpath) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to create {}: {}", outpath, e);
+            std::process::exit(-1);
         }
     };
 
-
     let iour = match io_uring::IoUring::init(QD) {
         Ok(x) => x,
         Err(e) => {
@@ -182,7 +287,7 @@ pub fn main() {
         }
     };
 
-    let insize = match get_file_size(&fin) {
+    let insize = match util::file_size(&fin) {
         Ok(x) => x,
         Err(e) => {
             eprintln!("Failed to get size of input file: {}", e);
@@ -190,6 +295,26 @@ pub fn main() {
         }
     };
 
-    println!("insize={}", insize);
-    println!("iodata::capacity={}", IoData::MAX_SIZE);
+    let mut cp = Copy {
+        ior: iour,
+        infd: fin.as_raw_fd(),
+        outfd: fout.as_raw_fd(),
+    };
+
+    if let Err(e) = cp.copy_file(insize) {
+        eprintln!("Copy failed: {}", e);
+        std::process::exit(-1);
+    }
+
+    match verify(&inpath, &outpath) {
+        Ok(true) => (),
+        Ok(false) => {
+            eprintln!("Verification failed: {} and {} differ", inpath, outpath);
+            std::process::exit(-1);
+        },
+        Err(e) => {
+            eprintln!("Verification failed: {}", e);
+            std::process::exit(-1);
+        }
+    }
 }
@@ -0,0 +1,382 @@
+/*
+ * Kornilios Kourtis <kkourt@kkourt.io>
+ *
+ * vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+ */
+
+// A toy DNS responder, answering A queries from a fixed set of records
+//
+// Everything runs in a single thread, driven by the completions of a single io_uring:
+//  - queries are received by a single multishot recvmsg request (Linux 6.0), into provided buffers
+//    picked by the kernel from a buffer ring. Each buffer holds the source address of the query,
+//    along with its payload. The request is re-armed if the kernel terminates it (e.g., because
+//    it ran out of buffers).
+//  - each query is answered as soon as it is received, and its buffer is handed back to the
+//    kernel. Replies are sent with sendmsg, to the source address of the query. The replies to all
+//    the queries of a batch of completions are submitted together, with a single system call.
+//
+// Only single-question queries are supported, and names are matched exactly (case insensitive).
+// Names that are not in the records get an NXDOMAIN response.
+//
+// Try it with: dig @127.0.0.1 -p 5353 localhost
+
+use iouring::buf_ring::BufRing;
+use iouring::io_uring::{self, RecvMsgOut, SqeFlags};
+
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::unix::io::AsRawFd;
+
+const QD: u32 = 256;
+// provided buffers
+const BGID: u16 = 0;
+const NBUFS: u16 = 64;
+const BUF_SZ: usize = 2048;
+// TTL of answers, in seconds
+const TTL: u32 = 60;
+
+// The user data of a reply is (reply_id << 8) | op. The recvmsg request uses 0.
+const OP_RECV: u64 = 0;
+const OP_SEND: u64 = 1;
+
+// DNS constants (RFC 1035)
+const HDR_SZ: usize = 12;
+const FLAG_QR: u16 = 1 << 15;
+const FLAG_AA: u16 = 1 << 10;
+const FLAG_RD: u16 = 1 << 8;
+const OPCODE_MASK: u16 = 0xf << 11;
+const RCODE_FORMERR: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_NOTIMP: u16 = 4;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+// A reply in flight. NB: the kernel accesses msg (and what it points to) until the sendmsg
+// completes, so replies are boxed, and kept around until then.
+struct Reply {
+    addr: libc::sockaddr_storage,
+    buf: Vec<u8>,
+    iov: libc::iovec,
+    msg: libc::msghdr,
+}
+
+impl Reply {
+    fn new(name: &[u8], buf: Vec<u8>) -> Box<Reply> {
+        let mut ret = Box::new(Reply {
+            addr: unsafe { std::mem::zeroed() },
+            buf,
+            iov: libc::iovec { iov_base: std::ptr::null_mut(), iov_len: 0 },
+            msg: unsafe { std::mem::zeroed() },
+        });
+        let addr_len = std::cmp::min(name.len(), std::mem::size_of::<libc::sockaddr_storage>());
+        unsafe {
+            let addr_p = &mut ret.addr as *mut libc::sockaddr_storage as *mut u8;
+            std::ptr::copy_nonoverlapping(name.as_ptr(), addr_p, addr_len);
+        }
+        ret.iov.iov_base = ret.buf.as_mut_ptr() as *mut libc::c_void;
+        ret.iov.iov_len = ret.buf.len();
+        ret.msg.msg_name = &mut ret.addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+        ret.msg.msg_namelen = addr_len as libc::socklen_t;
+        ret.msg.msg_iov = &mut ret.iov;
+        ret.msg.msg_iovlen = 1;
+        ret
+    }
+}
+
+struct Server {
+    // NB: fields are dropped in declaration order. The ring goes first, so that the kernel no
+    // longer accesses the buffers and replies below when they are freed.
+    ior: io_uring::IoUring,
+    bufring: BufRing,
+    bufs: Vec<u8>,
+    sock: std::net::UdpSocket,
+    // the msghdr of the recvmsg request: the space for the source address in each buffer
+    recv_msg: Box<libc::msghdr>,
+    records: HashMap<String, Ipv4Addr>,
+    replies: HashMap<u64, Box<Reply>>,
+    next_id: u64,
+    nqueries: u64,
+}
+
+fn get_u16(buf: &[u8], off: usize) -> u16 {
+    u16::from_be_bytes([buf[off], buf[off + 1]])
+}
+
+fn set_u16(buf: &mut [u8], off: usize, val: u16) {
+    buf[off..off + 2].copy_from_slice(&val.to_be_bytes());
+}
+
+// Parse the (uncompressed) name at the start of buf, and return it with its length
+fn parse_name(buf: &[u8]) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    let mut off = 0;
+    loop {
+        let len = *buf.get(off)? as usize;
+        off += 1;
+        if len == 0 {
+            break;
+        } else if len > 63 {
+            // compression pointers make no sense in a question
+            return None;
+        }
+        let label = std::str::from_utf8(buf.get(off..off + len)?).ok()?;
+        labels.push(label.to_ascii_lowercase());
+        off += len;
+    }
+    Some((labels.join("."), off))
+}
+
+impl Server {
+
+    /// Prepare a request via f, submitting queued requests if the submission queue is full
+    fn queue<F: FnOnce(&mut io_uring::SQEntry)>(&mut self, f: F) -> io::Result<()> {
+        loop {
+            if let Some(mut sqe) = self.ior.get_sqe() {
+                f(&mut sqe);
+                return Ok(());
+            }
+            self.ior.submit()?;
+        }
+    }
+
+    fn queue_recv(&mut self) -> io::Result<()> {
+        let fd = self.sock.as_raw_fd();
+        let msg = &mut *self.recv_msg as *mut libc::msghdr;
+        self.queue(|sqe| {
+            sqe.prep_recvmsg_multishot(fd, msg, 0);
+            sqe.set_flags(SqeFlags::BUFFER_SELECT);
+            sqe.set_buf_group(BGID);
+            sqe.set_data(OP_RECV);
+        })
+    }
+
+    fn queue_reply(&mut self, reply: Box<Reply>) -> io::Result<()> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let fd = self.sock.as_raw_fd();
+        let msg = &reply.msg as *const libc::msghdr;
+        self.replies.insert(id, reply);
+        self.queue(|sqe| {
+            sqe.prep_sendmsg(fd, msg, 0);
+            sqe.set_data((id << 8) | OP_SEND);
+        })
+    }
+
+    /// Hand a provided buffer back to the kernel
+    fn recycle_buf(&mut self, bid: u16) {
+        let addr = unsafe { self.bufs.as_mut_ptr().add(bid as usize * BUF_SZ) };
+        unsafe { self.bufring.add(addr, BUF_SZ as u32, bid) };
+        self.bufring.commit();
+    }
+
+    /// Build the response to query, or None if it should be dropped
+    fn handle_query(&self, query: &[u8]) -> Option<Vec<u8>> {
+        if query.len() < HDR_SZ || get_u16(query, 2) & FLAG_QR != 0 {
+            // not a query
+            return None;
+        }
+
+        let flags = get_u16(query, 2);
+        let mut resp = query[..HDR_SZ].to_vec();
+        let mut rcode = 0;
+        let mut answer = None;
+        let question = parse_name(&query[HDR_SZ..]).and_then(|(name, len)| {
+            let off = HDR_SZ + len;
+            if query.len() < off + 4 {
+                return None;
+            }
+            Some((name, off + 4))
+        });
+        if flags & OPCODE_MASK != 0 {
+            rcode = RCODE_NOTIMP;
+        } else if get_u16(query, 4) != 1 {
+            rcode = RCODE_FORMERR;
+        } else if let Some((name, end)) = question {
+            let (qtype, qclass) = (get_u16(query, end - 4), get_u16(query, end - 2));
+            resp.extend_from_slice(&query[HDR_SZ..end]);
+            match self.records.get(&name) {
+                None => rcode = RCODE_NXDOMAIN,
+                // NB: other types of known names get an empty answer
+                Some(addr) if qtype == TYPE_A && qclass == CLASS_IN => answer = Some(*addr),
+                Some(_) => (),
+            }
+        } else {
+            rcode = RCODE_FORMERR;
+        }
+
+        let qdcount = if resp.len() > HDR_SZ { 1 } else { 0 };
+        set_u16(&mut resp, 2, FLAG_QR | FLAG_AA | (flags & (OPCODE_MASK | FLAG_RD)) | rcode);
+        set_u16(&mut resp, 4, qdcount);
+        set_u16(&mut resp, 6, answer.is_some() as u16);
+        set_u16(&mut resp, 8, 0);
+        set_u16(&mut resp, 10, 0);
+        if let Some(addr) = answer {
+            // the name is a pointer to the name of the question
+            resp.extend_from_slice(&(0xc000 | HDR_SZ as u16).to_be_bytes());
+            resp.extend_from_slice(&TYPE_A.to_be_bytes());
+            resp.extend_from_slice(&CLASS_IN.to_be_bytes());
+            resp.extend_from_slice(&TTL.to_be_bytes());
+            resp.extend_from_slice(&4u16.to_be_bytes());
+            resp.extend_from_slice(&addr.octets());
+        }
+        Some(resp)
+    }
+
+    fn handle_recv(&mut self, cqe: io_uring::io_uring_cqe) -> io::Result<()> {
+        if !cqe.has_more() {
+            // the kernel terminated the multishot request (e.g., it ran out of buffers), so
+            // re-arm it
+            self.queue_recv()?;
+        }
+
+        let res = cqe.res();
+        if res < 0 {
+            if res != -libc::ENOBUFS {
+                let err = io::Error::from_raw_os_error(-res);
+                eprintln!("recvmsg failed: {}", err);
+            }
+            return Ok(());
+        }
+
+        let bid = cqe.buffer_id().expect("recvmsg completed without a buffer");
+        let off = bid as usize * BUF_SZ;
+        let reply = match RecvMsgOut::parse(&self.bufs[off..off + res as usize], &self.recv_msg) {
+            Err(e) => {
+                eprintln!("invalid recvmsg buffer: {}", e);
+                None
+            }
+            Ok(out) if out.flags() & libc::MSG_TRUNC != 0 => {
+                eprintln!("dropping truncated query ({} bytes)", out.payload_len());
+                None
+            }
+            Ok(out) => {
+                self.nqueries += 1;
+                self.handle_query(out.payload()).map(|resp| Reply::new(out.name(), resp))
+            }
+        };
+        self.recycle_buf(bid);
+
+        match reply {
+            Some(reply) => self.queue_reply(reply),
+            None => Ok(()),
+        }
+    }
+
+    fn handle_send(&mut self, id: u64, cqe: io_uring::io_uring_cqe) -> io::Result<()> {
+        let reply = self.replies.remove(&id).unwrap();
+        let res = cqe.res();
+        if res < 0 {
+            let err = io::Error::from_raw_os_error(-res);
+            eprintln!("sendmsg failed: {}", err);
+        } else if res as usize != reply.buf.len() {
+            eprintln!("short sendmsg: {} of {} bytes", res, reply.buf.len());
+        }
+        Ok(())
+    }
+
+    fn handle_cqe(&mut self, cqe: io_uring::io_uring_cqe) -> io::Result<()> {
+        let (id, op) = (cqe.user_data() >> 8, cqe.user_data() & 0xff);
+        match op {
+            OP_RECV => self.handle_recv(cqe),
+            OP_SEND => self.handle_send(id, cqe),
+            _ => panic!("unexpected user data: {:#x}", cqe.user_data()),
+        }
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        for bid in 0..NBUFS {
+            self.recycle_buf(bid);
+        }
+        self.queue_recv()?;
+        loop {
+            // NB: this submits the replies to all the queries of the previous batch
+            self.ior.submit_and_wait(1)?;
+            while let Some(cqe) = self.ior.pop_cqe() {
+                self.handle_cqe(cqe)?;
+            }
+        }
+    }
+}
+
+fn usage(arg0: &str) -> ! {
+    let pname = std::path::Path::new(arg0).file_name().unwrap().to_str().unwrap_or("iour-dns");
+    eprintln!("Usage: {} [<port> [<name>=<ipv4 address> ...]]", pname);
+    std::process::exit(-1);
+}
+
+pub fn main() {
+    let mut args = std::env::args();
+    let arg0 = args.next().unwrap();
+
+    let port: u16 = match args.next().map(|x| x.parse()) {
+        None => 5353,
+        Some(Ok(x)) => x,
+        Some(Err(e)) => {
+            eprintln!("Invalid port: {}", e);
+            usage(&arg0)
+        }
+    };
+
+    let mut records = HashMap::new();
+    records.insert("localhost".to_string(), Ipv4Addr::LOCALHOST);
+    for arg in args {
+        let (name, addr) = match arg.split_once('=') {
+            Some(x) => x,
+            None => usage(&arg0),
+        };
+        let addr: Ipv4Addr = match addr.parse() {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Invalid address {}: {}", addr, e);
+                usage(&arg0)
+            }
+        };
+        records.insert(name.trim_end_matches('.').to_ascii_lowercase(), addr);
+    }
+
+    let sock = match std::net::UdpSocket::bind(("0.0.0.0", port)) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to bind to port {}: {}", port, e);
+            std::process::exit(-1);
+        }
+    };
+
+    let ior = match io_uring::IoUring::init(QD) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to initialize io_uring: {}", e);
+            std::process::exit(-1);
+        }
+    };
+
+    let bufring = match BufRing::register(&ior, BGID, NBUFS) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to register buffer ring: {}", e);
+            std::process::exit(-1);
+        }
+    };
+
+    let mut recv_msg: Box<libc::msghdr> = Box::new(unsafe { std::mem::zeroed() });
+    recv_msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+    let mut server = Server {
+        ior,
+        bufring,
+        bufs: vec![0u8; NBUFS as usize * BUF_SZ],
+        sock,
+        recv_msg,
+        records,
+        replies: HashMap::new(),
+        next_id: 1,
+        nqueries: 0,
+    };
+
+    eprintln!("Serving {} records on port {}", server.records.len(), port);
+    if let Err(e) = server.run() {
+        eprintln!("Server failed after {} queries: {}", server.nqueries, e);
+        std::process::exit(-1);
+    }
+}
@@ -0,0 +1,216 @@
+/*
+ * Kornilios Kourtis <kkourt@kkourt.io>
+ *
+ * vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+ */
+
+// cp using registered buffers and files
+//
+// This is the registered fast path of iour-cp:
+//  1. the input and output files are registered, and requests refer to them by their index in the
+//     file table, with IOSQE_FIXED_FILE set. This saves looking up (and reference counting) the
+//     files on every request.
+//  2. QD buffers of BS bytes are registered, and requests use READ_FIXED/WRITE_FIXED with the
+//     index of their buffer. This saves pinning and unpinning the buffer pages on every request.
+//
+// Each buffer is used by one block at a time: it is read into, and then written out.
+//
+// With --compare, the copy is first done with plain readv/writev requests, and the times of the
+// two variants are reported. Note that the first copy also warms up the page cache for the second.
+
+use iouring::io_uring::{self, SqeFlags, ShutdownPolicy};
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Instant;
+
+const QD: u32 = 64;
+const BS: u64 = 32 * 1024;
+
+// indices in the registered file table
+const FILE_IN: RawFd = 0;
+const FILE_OUT: RawFd = 1;
+
+/// A block being copied, using the buffer with the same index
+#[derive(Clone, Copy)]
+struct Slot {
+    off: u64,
+    len: usize,
+    // bytes read (or written) so far
+    done: usize,
+    read: bool,
+    // used for plain requests
+    iov: libc::iovec,
+}
+
+struct Copy {
+    ior: io_uring::IoUring,
+    fixed: bool,
+    infd: RawFd,
+    outfd: RawFd,
+    bufs: Vec<u8>,
+    slots: Vec<Option<Slot>>,
+}
+
+impl Copy {
+
+    fn queue(&mut self, idx: usize) {
+        let slot = self.slots[idx].as_mut().unwrap();
+        let buf = unsafe { self.bufs.as_mut_ptr().add(idx * BS as usize + slot.done) };
+        let len = slot.len - slot.done;
+        let off = slot.off + slot.done as u64;
+
+        // NB: there is one slot per sq entry, so there is always space
+        let mut sqe = self.ior.get_sqe().expect("submission queue is full");
+        if self.fixed {
+            let (len, bidx) = (len as u32, idx as u16);
+            if slot.read {
+                sqe.prep_read_fixed(FILE_IN, buf, len, off, bidx);
+            } else {
+                sqe.prep_write_fixed(FILE_OUT, buf, len, off, bidx);
+            }
+            sqe.set_flags(SqeFlags::FIXED_FILE);
+        } else {
+            slot.iov = libc::iovec { iov_base: buf as *mut libc::c_void, iov_len: len };
+            if slot.read {
+                sqe.prep_readv(self.infd, &slot.iov, 1, off);
+            } else {
+                sqe.prep_writev(self.outfd, &slot.iov, 1, off);
+            }
+        }
+        sqe.set_data(idx as u64);
+    }
+
+    fn handle_cqe(&mut self, cqe: io_uring::io_uring_cqe) -> std::io::Result<()> {
+        let idx = cqe.user_data() as usize;
+        let slot = self.slots[idx].as_mut().unwrap();
+        let res = cqe.res();
+        if res == -libc::EAGAIN {
+            self.queue(idx);
+            return Ok(());
+        } else if res < 0 {
+            return Err(std::io::Error::from_raw_os_error(-res));
+        } else if res == 0 {
+            // file was truncated under our feet
+            let msg = format!("unexpected EOF at offset {}", slot.off + slot.done as u64);
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, msg));
+        }
+
+        slot.done += res as usize;
+        if slot.done < slot.len {
+            // short read/write
+            self.queue(idx);
+        } else if slot.read {
+            slot.read = false;
+            slot.done = 0;
+            self.queue(idx);
+        } else {
+            self.slots[idx] = None;
+        }
+        Ok(())
+    }
+
+    fn copy_file(&mut self, insize: u64) -> std::io::Result<()> {
+        let mut off = 0;
+        let mut inflight = 0;
+        while off < insize || inflight > 0 {
+            // assign blocks to free slots
+            for idx in 0..self.slots.len() {
+                if off == insize {
+                    break;
+                }
+                if self.slots[idx].is_some() {
+                    continue;
+                }
+                let len = std::cmp::min(insize - off, BS) as usize;
+                let iov = libc::iovec { iov_base: std::ptr::null_mut(), iov_len: 0 };
+                self.slots[idx] = Some(Slot { off, len, done: 0, read: true, iov });
+                self.queue(idx);
+                off += len as u64;
+                inflight += 1;
+            }
+
+            self.ior.submit_and_wait(1)?;
+            while let Some(cqe) = self.ior.pop_cqe() {
+                self.handle_cqe(cqe)?;
+                if self.slots[cqe.user_data() as usize].is_none() {
+                    inflight -= 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn copy(fin: &std::fs::File, fout: &std::fs::File, fixed: bool) -> std::io::Result<f64> {
+    let insize = fin.metadata()?.len();
+    let mut ior = io_uring::IoUring::init(QD)?;
+    // NB: the ring is dropped before the buffers, so make sure no requests use them
+    ior.set_drop_policy(ShutdownPolicy::CancelAndWait);
+    let mut cp = Copy {
+        ior,
+        fixed,
+        infd: fin.as_raw_fd(),
+        outfd: fout.as_raw_fd(),
+        bufs: vec![0u8; (QD as u64 * BS) as usize],
+        slots: vec![None; QD as usize],
+    };
+
+    if fixed {
+        cp.ior.register_files(&[cp.infd, cp.outfd])?;
+        let iovecs: Vec<std::io::IoSliceMut> = cp.bufs.chunks_mut(BS as usize)
+            .map(std::io::IoSliceMut::new)
+            .collect();
+        // NB: the buffers (cp.bufs) outlive the ring
+        cp.ior.register_buffers(&iovecs)?;
+    }
+
+    let start = Instant::now();
+    cp.copy_file(insize)?;
+    fout.sync_all()?;
+    Ok(start.elapsed().as_secs_f64())
+}
+
+pub fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let compare = args.iter().skip(1).any(|x| x == "--compare");
+    let paths: Vec<&String> = args.iter().skip(1).filter(|x| *x != "--compare").collect();
+    if paths.len() != 2 {
+        let pname = std::path::Path::new(&args[0]).file_name().unwrap().to_str()
+            .unwrap_or("iour-fixed-cp");
+        eprintln!("Usage: {} [--compare] <infile> <outfile>", pname);
+        std::process::exit(-1);
+    }
+
+    let (inpath, outpath) = (paths[0], paths[1]);
+    let fin = match std::fs::File::open(inpath) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", inpath, e);
+            std::process::exit(-1);
+        }
+    };
+
+    let fout = match std::fs::File::create(outpath) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to create {}: {}", outpath, e);
+            std::process::exit(-1);
+        }
+    };
+
+    let variants: &[bool] = if compare { &[false, true] } else { &[true] };
+    for fixed in variants {
+        let name = if *fixed { "fixed" } else { "plain" };
+        match copy(&fin, &fout, *fixed) {
+            Ok(secs) => {
+                if compare {
+                    println!("{}: {:.3}s", name, secs);
+                }
+            }
+            Err(e) => {
+                eprintln!("Copy ({}) failed: {}", name, e);
+                std::process::exit(-1);
+            }
+        }
+    }
+}
@@ -0,0 +1,372 @@
+/*
+ * Kornilios Kourtis <kkourt@kkourt.io>
+ *
+ * vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+ */
+
+// A (very) minimal HTTP/1.0 static file server
+//
+// Everything runs in a single thread, driven by the completions of a single io_uring:
+//  - connections are accepted by a single multishot accept request (Linux 5.19), which is re-armed
+//    if the kernel terminates it.
+//  - requests are received into provided buffers, picked by the kernel from a buffer ring
+//    (Linux 5.19), so that idle connections do not hold any buffers. Data are copied out of the
+//    provided buffer as soon as the recv completes, and the buffer is handed back to the kernel.
+//  - responses are sent with zero-copy send (Linux 6.0). A zero-copy send posts two cqes: the
+//    result of the send, and a notification once the kernel is done with the buffer. The response
+//    (and the connection) are kept around until all notifications have arrived.
+//
+// Each connection serves a single GET request, and is closed after the response is sent. Files are
+// read synchronously via std::fs.
+//
+// Try it with: curl -v http://localhost:8080/Cargo.toml
+
+use iouring::buf_ring::BufRing;
+use iouring::io_uring::{self, SqeFlags};
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+const QD: u32 = 256;
+// provided buffers
+const BGID: u16 = 0;
+const NBUFS: u16 = 64;
+const BUF_SZ: usize = 4096;
+// maximum size of a request header
+const MAX_REQ_SZ: usize = 8192;
+
+// The user data of a request is (conn_id << 8) | op. Connection ids start from 1, and the accept
+// request uses 0.
+const OP_ACCEPT: u64 = 0;
+const OP_RECV: u64 = 1;
+const OP_SEND: u64 = 2;
+
+struct Conn {
+    // NB: the socket is closed when the connection is dropped
+    sock: std::net::TcpStream,
+    req: Vec<u8>,
+    resp: Vec<u8>,
+    // bytes of resp sent so far
+    sent: usize,
+    // cqes we still expect for this connection (including send notifications)
+    pending: u32,
+    // no more requests will be queued for this connection
+    done: bool,
+}
+
+struct Server {
+    // NB: fields are dropped in declaration order. The ring goes first, so that the kernel no
+    // longer accesses the buffers below when they are freed.
+    ior: io_uring::IoUring,
+    bufring: BufRing,
+    bufs: Vec<u8>,
+    listener: std::net::TcpListener,
+    root: std::path::PathBuf,
+    conns: HashMap<u64, Conn>,
+    next_id: u64,
+}
+
+fn content_type(path: &std::path::Path) -> &'static str {
+    match path.extension().and_then(|x| x.to_str()) {
+        Some("html") | Some("htm") => "text/html",
+        Some("css") => "text/css",
+        Some("js") => "application/javascript",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("txt") | Some("rs") | Some("toml") | Some("md") => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+fn response(status: &str, ctype: &str, body: &[u8]) -> Vec<u8> {
+    let hdr = format!(
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, ctype, body.len()
+    );
+    let mut ret = Vec::with_capacity(hdr.len() + body.len());
+    ret.extend_from_slice(hdr.as_bytes());
+    ret.extend_from_slice(body);
+    ret
+}
+
+fn error_response(status: &str) -> Vec<u8> {
+    response(status, "text/plain", format!("{}\n", status).as_bytes())
+}
+
+impl Server {
+
+    /// Prepare a request via f, submitting queued requests if the submission queue is full
+    fn queue<F: FnOnce(&mut io_uring::SQEntry)>(&mut self, f: F) -> io::Result<()> {
+        loop {
+            if let Some(mut sqe) = self.ior.get_sqe() {
+                f(&mut sqe);
+                return Ok(());
+            }
+            self.ior.submit()?;
+        }
+    }
+
+    fn queue_accept(&mut self) -> io::Result<()> {
+        let fd = self.listener.as_raw_fd();
+        self.queue(|sqe| {
+            let flags = libc::SOCK_CLOEXEC;
+            sqe.prep_multishot_accept(fd, std::ptr::null_mut(), std::ptr::null_mut(), flags);
+            sqe.set_data(OP_ACCEPT);
+        })
+    }
+
+    fn queue_recv(&mut self, id: u64) -> io::Result<()> {
+        let conn = self.conns.get_mut(&id).unwrap();
+        let fd = conn.sock.as_raw_fd();
+        conn.pending += 1;
+        self.queue(|sqe| {
+            sqe.prep_recv(fd, std::ptr::null_mut(), 0, 0);
+            sqe.set_flags(SqeFlags::BUFFER_SELECT);
+            sqe.set_buf_group(BGID);
+            sqe.set_data((id << 8) | OP_RECV);
+        })
+    }
+
+    fn queue_send(&mut self, id: u64) -> io::Result<()> {
+        let conn = self.conns.get_mut(&id).unwrap();
+        let fd = conn.sock.as_raw_fd();
+        let rem = &conn.resp[conn.sent..];
+        let buf = rem.as_ptr() as *const libc::c_void;
+        let len = std::cmp::min(rem.len(), u32::MAX as usize) as u32;
+        conn.pending += 1;
+        self.queue(|sqe| {
+            sqe.prep_send_zc(fd, buf, len, libc::MSG_NOSIGNAL, 0);
+            sqe.set_data((id << 8) | OP_SEND);
+        })
+    }
+
+    /// Hand a provided buffer back to the kernel
+    fn recycle_buf(&mut self, bid: u16) {
+        let addr = unsafe { self.bufs.as_mut_ptr().add(bid as usize * BUF_SZ) };
+        unsafe { self.bufring.add(addr, BUF_SZ as u32, bid) };
+        self.bufring.commit();
+    }
+
+    /// Mark the connection as done, and close it if there is nothing pending
+    fn finish(&mut self, id: u64) {
+        let conn = self.conns.get_mut(&id).unwrap();
+        conn.done = true;
+        if conn.pending == 0 {
+            self.conns.remove(&id);
+        }
+    }
+
+    /// Build the response for the request
+    fn handle_request(&self, req: &[u8]) -> Vec<u8> {
+        let line_end = req.iter().position(|c| *c == b'\r' || *c == b'\n').unwrap_or(req.len());
+        let line = match std::str::from_utf8(&req[..line_end]) {
+            Ok(x) => x,
+            Err(_) => return error_response("400 Bad Request"),
+        };
+        let mut parts = line.split_whitespace();
+        let (method, target) = match (parts.next(), parts.next()) {
+            (Some(m), Some(t)) => (m, t),
+            _ => return error_response("400 Bad Request"),
+        };
+        if method != "GET" {
+            return error_response("405 Method Not Allowed");
+        }
+
+        let path = target.split('?').next().unwrap();
+        if !path.starts_with('/') || path.split('/').any(|c| c == "..") {
+            return error_response("400 Bad Request");
+        }
+        let path = match path.trim_start_matches('/') {
+            "" => "index.html",
+            x => x,
+        };
+        let path = self.root.join(path);
+        match std::fs::read(&path) {
+            Ok(body) => response("200 OK", content_type(&path), &body),
+            Err(e) => match e.kind() {
+                io::ErrorKind::NotFound => error_response("404 Not Found"),
+                io::ErrorKind::PermissionDenied => error_response("403 Forbidden"),
+                _ => error_response("500 Internal Server Error"),
+            }
+        }
+    }
+
+    fn handle_accept(&mut self, cqe: io_uring::io_uring_cqe) -> io::Result<()> {
+        if !cqe.has_more() {
+            // the kernel terminated the multishot request, so re-arm it
+            self.queue_accept()?;
+        }
+
+        let res = cqe.res();
+        if res < 0 {
+            let err = io::Error::from_raw_os_error(-res);
+            eprintln!("accept failed: {}", err);
+            return Ok(());
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let conn = Conn {
+            sock: unsafe { std::net::TcpStream::from_raw_fd(res) },
+            req: vec![],
+            resp: vec![],
+            sent: 0,
+            pending: 0,
+            done: false,
+        };
+        self.conns.insert(id, conn);
+        self.queue_recv(id)
+    }
+
+    fn handle_recv(&mut self, id: u64, cqe: io_uring::io_uring_cqe) -> io::Result<()> {
+        self.conns.get_mut(&id).unwrap().pending -= 1;
+
+        let res = cqe.res();
+        if res == -libc::ENOBUFS {
+            // all provided buffers are in use: try again
+            return self.queue_recv(id);
+        } else if res <= 0 {
+            // EOF or error: nothing to respond to
+            self.finish(id);
+            return Ok(());
+        }
+
+        let bid = cqe.buffer_id().expect("recv completed without a buffer");
+        let off = bid as usize * BUF_SZ;
+        let conn = self.conns.get_mut(&id).unwrap();
+        conn.req.extend_from_slice(&self.bufs[off..off + res as usize]);
+        self.recycle_buf(bid);
+
+        let req = &self.conns[&id].req;
+        let resp = if req.windows(4).any(|w| w == b"\r\n\r\n") {
+            self.handle_request(req)
+        } else if req.len() > MAX_REQ_SZ {
+            error_response("431 Request Header Fields Too Large")
+        } else {
+            return self.queue_recv(id);
+        };
+
+        self.conns.get_mut(&id).unwrap().resp = resp;
+        self.queue_send(id)
+    }
+
+    fn handle_send(&mut self, id: u64, cqe: io_uring::io_uring_cqe) -> io::Result<()> {
+        let conn = self.conns.get_mut(&id).unwrap();
+        conn.pending -= 1;
+
+        if cqe.is_notif() {
+            // the kernel is done with the buffer
+            if conn.done && conn.pending == 0 {
+                self.conns.remove(&id);
+            }
+            return Ok(());
+        }
+
+        if cqe.has_more() {
+            // a notification will follow
+            conn.pending += 1;
+        }
+
+        let res = cqe.res();
+        if res < 0 {
+            let err = io::Error::from_raw_os_error(-res);
+            eprintln!("send failed: {}", err);
+            self.finish(id);
+            return Ok(());
+        }
+
+        conn.sent += res as usize;
+        if conn.sent < conn.resp.len() {
+            // short send
+            self.queue_send(id)
+        } else {
+            self.finish(id);
+            Ok(())
+        }
+    }
+
+    fn handle_cqe(&mut self, cqe: io_uring::io_uring_cqe) -> io::Result<()> {
+        let (id, op) = (cqe.user_data() >> 8, cqe.user_data() & 0xff);
+        match op {
+            OP_ACCEPT => self.handle_accept(cqe),
+            OP_RECV => self.handle_recv(id, cqe),
+            OP_SEND => self.handle_send(id, cqe),
+            _ => panic!("unexpected user data: {:#x}", cqe.user_data()),
+        }
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        for bid in 0..NBUFS {
+            self.recycle_buf(bid);
+        }
+        self.queue_accept()?;
+        loop {
+            self.ior.submit_and_wait(1)?;
+            while let Some(cqe) = self.ior.pop_cqe() {
+                self.handle_cqe(cqe)?;
+            }
+        }
+    }
+}
+
+pub fn main() {
+    let mut args = std::env::args();
+    let arg0 = args.next().unwrap();
+    if args.len() < 1 {
+        let pname = std::path::Path::new(&arg0).file_name().unwrap().to_str()
+            .unwrap_or("iour-http-server");
+        eprintln!("Usage: {} <root> [<port>]", pname);
+        std::process::exit(-1);
+    }
+
+    let root = std::path::PathBuf::from(args.next().unwrap());
+    let port: u16 = match args.next().map(|x| x.parse()) {
+        None => 8080,
+        Some(Ok(x)) => x,
+        Some(Err(e)) => {
+            eprintln!("Invalid port: {}", e);
+            std::process::exit(-1);
+        }
+    };
+
+    let listener = match std::net::TcpListener::bind(("0.0.0.0", port)) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to bind to port {}: {}", port, e);
+            std::process::exit(-1);
+        }
+    };
+
+    let ior = match io_uring::IoUring::init(QD) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to initialize io_uring: {}", e);
+            std::process::exit(-1);
+        }
+    };
+
+    let bufring = match BufRing::register(&ior, BGID, NBUFS) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to register buffer ring: {}", e);
+            std::process::exit(-1);
+        }
+    };
+
+    let mut server = Server {
+        ior,
+        bufring,
+        bufs: vec![0u8; NBUFS as usize * BUF_SZ],
+        listener,
+        root,
+        conns: HashMap::new(),
+        next_id: 1,
+    };
+
+    eprintln!("Serving {} on port {}", server.root.display(), port);
+    if let Err(e) = server.run() {
+        eprintln!("Server failed: {}", e);
+        std::process::exit(-1);
+    }
+}
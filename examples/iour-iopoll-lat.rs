@@ -0,0 +1,290 @@
+/*
+ * Kornilios Kourtis <kkourt@kkourt.io>
+ *
+ * vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+ */
+
+// Per-I/O latency of polled (IORING_SETUP_IOPOLL) O_DIRECT reads
+//
+// This is the reference configuration for polled I/O:
+//  - the file (or block device) is opened with O_DIRECT: IOPOLL rings only support direct I/O
+//  - the ring is created with IORING_SETUP_IOPOLL: instead of waiting for an interrupt, the kernel
+//    polls the device for completions when we wait for cqes (io_uring_enter with GETEVENTS). Note
+//    that completions are *only* reaped this way, so the ring must be entered to make progress.
+//  - the buffer is aligned (to 4k, which is enough for any logical block size), and registered,
+//    so that requests use READ_FIXED and the pages are not pinned on every request.
+//
+// The example issues one random read at a time (queue depth 1, where polling matters most), and
+// reports a histogram of the latencies. Use --no-poll to compare against a regular
+// (interrupt-driven) ring.
+//
+// For NVMe devices, polling needs dedicated poll queues (nvme.poll_queues module parameter);
+// otherwise requests fail with EOPNOTSUPP.
+//
+// Example:
+//  iour-iopoll-lat --bs=4k --nr=100000 /dev/nvme0n1
+
+use iouring::io_uring::{self, SetupFlags, ShutdownPolicy};
+
+use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+const BUF_ALIGN: usize = 4096;
+
+struct Opts {
+    path: String,
+    bs: usize,
+    nr: u64,
+    poll: bool,
+}
+
+const USAGE: &str = "[options] <file>
+options:
+  --bs=<bytes>  read size, a multiple of 512, k suffix allowed (default: 4k)
+  --nr=<n>      number of reads (default: 10000)
+  --no-poll     use a regular ring instead of an IOPOLL one";
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Opts, String> {
+    let mut opts = Opts {
+        path: String::new(),
+        bs: 4096,
+        nr: 10000,
+        poll: true,
+    };
+
+    let mut paths = vec![];
+    for arg in args {
+        let (key, val) = match arg.split_once('=') {
+            Some((k, v)) => (k, Some(v)),
+            None => (arg.as_str(), None),
+        };
+        match (key, val) {
+            ("--bs", Some(v)) => {
+                let (num, mult) = match v.strip_suffix(['k', 'K']) {
+                    Some(x) => (x, 1024),
+                    None => (v, 1),
+                };
+                opts.bs = num.parse::<usize>().ok()
+                    .and_then(|x| x.checked_mul(mult))
+                    .filter(|x| *x > 0 && x.is_multiple_of(512))
+                    .ok_or(format!("invalid block size: {}", v))?;
+            }
+            ("--nr", Some(v)) => {
+                opts.nr = v.parse().ok().filter(|x| *x > 0).ok_or(format!("invalid nr: {}", v))?
+            }
+            ("--no-poll", None) => opts.poll = false,
+            _ if !arg.starts_with("--") => paths.push(arg),
+            _ => return Err(format!("invalid argument: {}", arg)),
+        }
+    }
+
+    if paths.len() != 1 {
+        return Err("expecting a single file".to_string());
+    }
+    opts.path = paths.pop().unwrap();
+    Ok(opts)
+}
+
+/// A heap buffer, aligned for O_DIRECT
+struct AlignedBuf {
+    ptr: *mut u8,
+    layout: std::alloc::Layout,
+}
+
+impl AlignedBuf {
+    fn new(size: usize) -> AlignedBuf {
+        let layout = std::alloc::Layout::from_size_align(size, BUF_ALIGN).unwrap();
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        AlignedBuf { ptr, layout }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+/// Latency histogram, with power-of-two buckets in ns
+struct Hist {
+    buckets: [u64; 64],
+    nr: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Hist {
+    fn new() -> Hist {
+        Hist { buckets: [0; 64], nr: 0, sum: 0, min: u64::MAX, max: 0 }
+    }
+
+    fn add(&mut self, ns: u64) {
+        self.buckets[(64 - ns.leading_zeros()) as usize % 64] += 1;
+        self.nr += 1;
+        self.sum += ns;
+        self.min = std::cmp::min(self.min, ns);
+        self.max = std::cmp::max(self.max, ns);
+    }
+
+    fn print(&self) {
+        if self.nr == 0 {
+            return;
+        }
+        let us = |ns: u64| ns as f64 / 1000.0;
+        println!(
+            "lat (usec): min={:.1}, avg={:.1}, max={:.1}",
+            us(self.min), us(self.sum) / self.nr as f64, us(self.max)
+        );
+        let top = *self.buckets.iter().max().unwrap();
+        for (i, n) in self.buckets.iter().enumerate() {
+            if *n == 0 {
+                continue;
+            }
+            // bucket i holds latencies in [2^(i-1), 2^i)
+            let (lo, hi) = (if i == 0 { 0 } else { 1u64 << (i - 1) }, 1u64 << i);
+            let bar = "#".repeat((n * 50 / top) as usize);
+            println!(
+                "  [{:>9.1}, {:>9.1}) {:>6.2}% {}",
+                us(lo), us(hi), *n as f64 * 100.0 / self.nr as f64, bar
+            );
+        }
+    }
+}
+
+struct LatTest {
+    ior: io_uring::IoUring,
+    fd: RawFd,
+    buf: AlignedBuf,
+    nblocks: u64,
+    rng: u64,
+}
+
+impl LatTest {
+
+    // xorshift64*
+    fn rand(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    fn run(&mut self, nr: u64) -> std::io::Result<(Hist, Duration)> {
+        let bs = self.buf.layout.size();
+        let mut hist = Hist::new();
+        let start = Instant::now();
+        for _ in 0..nr {
+            let off = (self.rand() % self.nblocks) * bs as u64;
+            {
+                let mut sqe = self.ior.get_sqe().expect("submission queue is full");
+                sqe.prep_read_fixed(self.fd, self.buf.ptr, bs as u32, off, 0);
+                sqe.set_data(off);
+            }
+
+            let t = Instant::now();
+            // NB: for IOPOLL rings, this polls the device for completions. The kernel might return
+            // before the read completes, so keep entering the ring until it does.
+            self.ior.submit_and_wait(1)?;
+            let cqe = loop {
+                match self.ior.pop_cqe() {
+                    Some(x) => break x,
+                    None => self.ior.submit_and_wait(1)?,
+                };
+            };
+            hist.add(t.elapsed().as_nanos() as u64);
+
+            let res = cqe.res();
+            if res < 0 {
+                return Err(std::io::Error::from_raw_os_error(-res));
+            } else if res as usize != bs {
+                let msg = format!("short read at {}: {} out of {} bytes", cqe.user_data(), res, bs);
+                return Err(std::io::Error::other(msg));
+            }
+        }
+        Ok((hist, start.elapsed()))
+    }
+}
+
+pub fn main() {
+    let mut args = std::env::args();
+    let arg0 = args.next().unwrap();
+    let opts = match parse_args(args) {
+        Ok(x) => x,
+        Err(e) => {
+            let pname = std::path::Path::new(&arg0).file_name().unwrap().to_str()
+                .unwrap_or("iour-iopoll-lat");
+            eprintln!("{}\nUsage: {} {}", e, pname, USAGE);
+            std::process::exit(-1);
+        }
+    };
+
+    let f = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(&opts.path);
+    let mut f = match f {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to open {} with O_DIRECT: {}", opts.path, e);
+            std::process::exit(-1);
+        }
+    };
+
+    // NB: seeking to the end also works for block devices
+    let nblocks = match f.seek(SeekFrom::End(0)) {
+        Ok(x) => x / opts.bs as u64,
+        Err(e) => {
+            eprintln!("Failed to get size of {}: {}", opts.path, e);
+            std::process::exit(-1);
+        }
+    };
+    if nblocks == 0 {
+        eprintln!("{} is smaller than the block size", opts.path);
+        std::process::exit(-1);
+    }
+
+    let flags = if opts.poll { SetupFlags::IOPOLL } else { SetupFlags::empty() };
+    let mut ior = match io_uring::IoUring::init_with_flags(1, flags) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to initialize io_uring: {}", e);
+            std::process::exit(-1);
+        }
+    };
+    // NB: the ring is dropped before the buffer, so make sure no requests use it
+    ior.set_drop_policy(ShutdownPolicy::CancelAndWait);
+
+    let buf = AlignedBuf::new(opts.bs);
+    let iov = unsafe { std::slice::from_raw_parts_mut(buf.ptr, opts.bs) };
+    if let Err(e) = ior.register_buffers(&[std::io::IoSliceMut::new(iov)]) {
+        eprintln!("Failed to register buffer: {}", e);
+        std::process::exit(-1);
+    }
+
+    let mut test = LatTest { ior, fd: f.as_raw_fd(), buf, nblocks, rng: 0x9E3779B97F4A7C15 };
+    match test.run(opts.nr) {
+        Ok((hist, elapsed)) => {
+            let mode = if opts.poll { "iopoll" } else { "irq" };
+            let secs = elapsed.as_secs_f64();
+            println!(
+                "{}: {} reads of {} bytes in {:.2}s ({:.0} IOPS)",
+                mode, hist.nr, opts.bs, secs, hist.nr as f64 / secs
+            );
+            hist.print();
+        }
+        Err(e) => {
+            eprintln!("Reads failed: {}", e);
+            if opts.poll && e.raw_os_error() == Some(libc::EOPNOTSUPP) {
+                // NB: e.g., NVMe devices need poll queues (nvme.poll_queues module parameter)
+                eprintln!("(does the device support polled I/O? try --no-poll)");
+            }
+            std::process::exit(-1);
+        }
+    }
+}
@@ -0,0 +1,201 @@
+/*
+ * Kornilios Kourtis <kkourt@kkourt.io>
+ *
+ * vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+ */
+
+// cp using linked read->write pairs, following liburing/examples/link-cp.c
+//
+// Each block is copied by a chain of two requests: a read with IOSQE_IO_LINK set, followed by a
+// write of the same buffer. The kernel starts the write only after the read completes
+// successfully. If the read fails or is short, the link is broken and the write completes with
+// -ECANCELED, in which case we queue the whole pair again.
+//
+// Both requests of a chain carry the same user data: a pointer to the chain's state, which is
+// freed after both of its cqes are reaped.
+
+use iouring::io_uring::{self, SqeFlags};
+
+use std::os::unix::io::{AsRawFd, RawFd};
+
+const QD : u32 = 64;
+const BS : u64 = 32*1024;
+
+/// State of a read->write chain
+struct Chain {
+    off: u64,
+    // number of cqes reaped for this chain
+    ncqes: u32,
+    iov: libc::iovec,
+    buff: Vec<u8>,
+}
+
+struct Copy {
+    ior: io_uring::IoUring,
+    infd: RawFd,
+    outfd: RawFd,
+    // number of requests in flight
+    inflight: u32,
+    // number of chains that were broken and requeued
+    requeued: u64,
+}
+
+impl Copy {
+
+    fn queue_rw_pair(&mut self, size: usize, off: u64) {
+        let mut chain = Box::new(Chain {
+            off,
+            ncqes: 0,
+            iov: libc::iovec { iov_base: std::ptr::null_mut(), iov_len: size },
+            buff: vec![0; size],
+        });
+        chain.iov.iov_base = chain.buff.as_mut_ptr() as *mut libc::c_void;
+        let iov: *const libc::iovec = &chain.iov;
+        let udata = Box::into_raw(chain) as usize as u64;
+
+        // NB: the number of requests in flight never exceeds the queue size, so there is always
+        // space.
+        {
+            let mut sqe = self.ior.get_sqe().expect("submission queue is full");
+            sqe.prep_readv(self.infd, iov, 1, off);
+            sqe.set_flags(SqeFlags::IO_LINK);
+            sqe.set_data(udata);
+        }
+        {
+            let mut sqe = self.ior.get_sqe().expect("submission queue is full");
+            sqe.prep_writev(self.outfd, iov, 1, off);
+            sqe.set_data(udata);
+        }
+        self.inflight += 2;
+    }
+
+    fn handle_cqe(&mut self, cqe: io_uring::io_uring_cqe) -> std::io::Result<()> {
+        let chain_p = cqe.user_data() as usize as *mut Chain;
+        let chain = unsafe { &mut *chain_p };
+        chain.ncqes += 1;
+        self.inflight -= 1;
+
+        // NB: the read always completes before the write of the same chain, so the first cqe is
+        // for the read.
+        let is_read = chain.ncqes == 1;
+        let res = cqe.res();
+        let ret = if is_read && res == 0 && chain.iov.iov_len > 0 {
+            // the file was truncated under our feet, requeuing would loop forever
+            let msg = format!("unexpected EOF at offset {}", chain.off);
+            Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, msg))
+        } else if res == -libc::ECANCELED {
+            // broken link: the read failed or was short, so redo the whole chain
+            self.requeued += 1;
+            let (size, off) = (chain.iov.iov_len, chain.off);
+            self.queue_rw_pair(size, off);
+            Ok(())
+        } else if res < 0 {
+            Err(std::io::Error::from_raw_os_error(-res))
+        } else {
+            Ok(())
+        };
+
+        if chain.ncqes == 2 {
+            drop(unsafe { Box::from_raw(chain_p) });
+        }
+        ret
+    }
+
+    fn wait_cqe(&mut self) -> std::io::Result<io_uring::io_uring_cqe> {
+        loop {
+            if let Some(cqe) = self.ior.pop_cqe() {
+                return Ok(cqe);
+            }
+            // NB: this also submits any requeued chains
+            self.ior.submit_and_wait(1)?;
+        }
+    }
+
+    fn copy_file(&mut self, mut insize: u64) -> std::io::Result<()> {
+        let mut off = 0;
+        while insize > 0 || self.inflight > 0 {
+            let had_inflight = self.inflight;
+            while insize > 0 && self.inflight < QD {
+                let this_size = std::cmp::min(insize, BS);
+                self.queue_rw_pair(this_size as usize, off);
+                off += this_size;
+                insize -= this_size;
+            }
+
+            if had_inflight != self.inflight {
+                self.ior.submit()?;
+            }
+
+            // if there is more to copy, wait until there is space for a new pair
+            let depth = if insize > 0 { QD - 1 } else { 1 };
+            while self.inflight >= depth {
+                let cqe = self.wait_cqe()?;
+                self.handle_cqe(cqe)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn main() {
+    let mut args = std::env::args();
+
+    let arg0 = &args.next().unwrap();
+    if args.len() < 2 {
+        let pname = std::path::Path::new(arg0).file_name().unwrap().to_str()
+            .unwrap_or("iour-link-cp");
+        eprintln!("Usage: {} <infile> <outfile>", pname);
+        std::process::exit(-1);
+    }
+
+    let inpath = args.next().unwrap();
+    let fin = match std::fs::File::open(&inpath) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", inpath, e);
+            std::process::exit(-1);
+        }
+    };
+
+    let outpath = args.next().unwrap();
+    let fout = match std::fs::File::create(&outpath) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to create {}: {}", outpath, e);
+            std::process::exit(-1);
+        }
+    };
+
+    let insize = match fin.metadata() {
+        Ok(x) => x.len(),
+        Err(e) => {
+            eprintln!("Failed to get size of input file: {}", e);
+            std::process::exit(-1);
+        }
+    };
+
+    let iour = match io_uring::IoUring::init(QD) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to initialize io_uring: {}", e);
+            std::process::exit(-1);
+        }
+    };
+
+    let mut cp = Copy {
+        ior: iour,
+        infd: fin.as_raw_fd(),
+        outfd: fout.as_raw_fd(),
+        inflight: 0,
+        requeued: 0,
+    };
+
+    if let Err(e) = cp.copy_file(insize) {
+        eprintln!("Copy failed: {}", e);
+        std::process::exit(-1);
+    }
+
+    if cp.requeued > 0 {
+        eprintln!("{} broken chain(s) requeued", cp.requeued);
+    }
+}
@@ -0,0 +1,329 @@
+/*
+ * Kornilios Kourtis <kkourt@kkourt.io>
+ *
+ * vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+ */
+
+// A multi-threaded echo server, where an acceptor ring hands connections to worker rings
+//
+// Each thread has its own ring. The acceptor thread accepts connections directly into a slot of
+// its registered files (Linux 5.15), and passes each connection to the next worker (round-robin)
+// via a MSG_RING request (Linux 6.0). The kernel installs the file in a free slot of the worker's
+// registered files, and posts a cqe to the worker's ring, so the worker learns about the new
+// connection without any synchronization in userspace. Connections never get a regular fd.
+//
+// For every connection, the acceptor issues a chain of three requests:
+//   MSG_RING (hard link) -> CLOSE (link) -> ACCEPT
+// The hard link means that the registered file is closed in the acceptor ring even if passing it
+// fails (e.g., if the worker has no free slots), so that the connection is only referenced by the
+// worker. The accept of the next connection starts after the close.
+//
+// Workers echo back whatever they receive, using their registered files (IOSQE_FIXED_FILE).
+//
+// Try it with: nc localhost 8080
+
+use iouring::io_uring::{self, SqeFlags, IORING_FILE_INDEX_ALLOC};
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+// registered file slots of each worker
+const MAX_CONNS: u32 = 1024;
+const BUF_SZ: usize = 4096;
+
+// acceptor user data
+const OP_ACCEPT: u64 = 0;
+const OP_MSG: u64 = 1;
+const OP_CLOSE: u64 = 2;
+// the slot the acceptor accepts into
+const ACCEPT_SLOT: u32 = 0;
+
+// The user data of worker requests is (slot << 8) | op. The user data of the MSG_RING cqe that
+// announces a new connection is OP_NEW.
+const OP_NEW: u64 = 0;
+const OP_RECV: u64 = 1;
+const OP_SEND: u64 = 2;
+const OP_CONN_CLOSE: u64 = 3;
+
+struct Conn {
+    buf: Vec<u8>,
+    // bytes received in buf, and bytes of them sent back so far
+    len: usize,
+    sent: usize,
+    // total bytes echoed
+    total: u64,
+}
+
+struct Worker {
+    id: usize,
+    ior: io_uring::IoUring,
+    // indexed by slot
+    conns: Vec<Option<Conn>>,
+}
+
+impl Worker {
+
+    /// Prepare a request via f, submitting queued requests if the submission queue is full
+    fn queue<F: FnOnce(&mut io_uring::SQEntry)>(&mut self, f: F) -> io::Result<()> {
+        loop {
+            if let Some(mut sqe) = self.ior.get_sqe() {
+                f(&mut sqe);
+                return Ok(());
+            }
+            self.ior.submit()?;
+        }
+    }
+
+    fn queue_recv(&mut self, slot: u32) -> io::Result<()> {
+        let conn = self.conns[slot as usize].as_mut().unwrap();
+        let buf = conn.buf.as_mut_ptr() as *mut libc::c_void;
+        let len = conn.buf.len() as u32;
+        self.queue(|sqe| {
+            // NB: for registered files, the fd is the slot
+            sqe.prep_recv(slot as RawFd, buf, len, 0);
+            sqe.set_flags(SqeFlags::FIXED_FILE);
+            sqe.set_data(((slot as u64) << 8) | OP_RECV);
+        })
+    }
+
+    fn queue_send(&mut self, slot: u32) -> io::Result<()> {
+        let conn = self.conns[slot as usize].as_mut().unwrap();
+        let rem = &conn.buf[conn.sent..conn.len];
+        let (buf, len) = (rem.as_ptr() as *const libc::c_void, rem.len() as u32);
+        self.queue(|sqe| {
+            sqe.prep_send(slot as RawFd, buf, len, libc::MSG_NOSIGNAL);
+            sqe.set_flags(SqeFlags::FIXED_FILE);
+            sqe.set_data(((slot as u64) << 8) | OP_SEND);
+        })
+    }
+
+    fn queue_close(&mut self, slot: u32) -> io::Result<()> {
+        self.queue(|sqe| {
+            sqe.prep_close_fixed(slot);
+            sqe.set_data(((slot as u64) << 8) | OP_CONN_CLOSE);
+        })
+    }
+
+    fn handle_cqe(&mut self, cqe: io_uring::io_uring_cqe) -> io::Result<()> {
+        let (slot, op) = ((cqe.user_data() >> 8) as u32, cqe.user_data() & 0xff);
+        let res = cqe.res();
+        match op {
+            OP_NEW => {
+                // NB: res is the slot the kernel installed the connection at
+                let slot = res as u32;
+                eprintln!("worker {}: new connection at slot {}", self.id, slot);
+                let conn = Conn { buf: vec![0u8; BUF_SZ], len: 0, sent: 0, total: 0 };
+                self.conns[slot as usize] = Some(conn);
+                self.queue_recv(slot)?;
+            }
+
+            OP_RECV => {
+                if res <= 0 {
+                    if res < 0 {
+                        let err = io::Error::from_raw_os_error(-res);
+                        eprintln!("worker {}: slot {}: recv failed: {}", self.id, slot, err);
+                    }
+                    return self.queue_close(slot);
+                }
+                let conn = self.conns[slot as usize].as_mut().unwrap();
+                conn.len = res as usize;
+                conn.sent = 0;
+                self.queue_send(slot)?;
+            }
+
+            OP_SEND => {
+                if res < 0 {
+                    let err = io::Error::from_raw_os_error(-res);
+                    eprintln!("worker {}: slot {}: send failed: {}", self.id, slot, err);
+                    return self.queue_close(slot);
+                }
+                let conn = self.conns[slot as usize].as_mut().unwrap();
+                conn.sent += res as usize;
+                conn.total += res as u64;
+                if conn.sent < conn.len {
+                    // short send
+                    self.queue_send(slot)?;
+                } else {
+                    self.queue_recv(slot)?;
+                }
+            }
+
+            OP_CONN_CLOSE => {
+                let conn = self.conns[slot as usize].take().unwrap();
+                eprintln!(
+                    "worker {}: closed connection at slot {} ({} bytes echoed)",
+                    self.id, slot, conn.total
+                );
+            }
+
+            _ => panic!("unexpected user data: {:#x}", cqe.user_data()),
+        }
+        Ok(())
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        loop {
+            self.ior.submit_and_wait(1)?;
+            while let Some(cqe) = self.ior.pop_cqe() {
+                self.handle_cqe(cqe)?;
+            }
+        }
+    }
+}
+
+/// Start a worker thread. Returns the fd of its ring.
+fn spawn_worker(id: usize) -> io::Result<RawFd> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let init = || -> io::Result<io_uring::IoUring> {
+            let mut ior = io_uring::IoUring::init(256)?;
+            // a sparse table: MSG_RING installs the connections in free slots
+            ior.register_files(&vec![-1; MAX_CONNS as usize])?;
+            Ok(ior)
+        };
+        let ior = match init() {
+            Ok(x) => x,
+            Err(e) => {
+                tx.send(Err(e)).unwrap();
+                return;
+            }
+        };
+        tx.send(Ok(ior.as_raw_fd())).unwrap();
+
+        let mut worker = Worker { id, ior, conns: (0..MAX_CONNS).map(|_| None).collect() };
+        if let Err(e) = worker.run() {
+            eprintln!("worker {} failed: {}", id, e);
+            std::process::exit(-1);
+        }
+    });
+    rx.recv().unwrap()
+}
+
+struct Acceptor {
+    ior: io_uring::IoUring,
+    listener: std::net::TcpListener,
+    workers: Vec<RawFd>,
+    next_worker: usize,
+}
+
+impl Acceptor {
+
+    fn queue_accept(&mut self) {
+        let fd = self.listener.as_raw_fd();
+        // NB: there are at most three requests in flight
+        let mut sqe = self.ior.get_sqe().expect("submission queue is full");
+        // NB: SOCK_CLOEXEC is invalid here, since there is no fd
+        sqe.prep_accept_direct(fd, std::ptr::null_mut(), std::ptr::null_mut(), 0, ACCEPT_SLOT);
+        sqe.set_data(OP_ACCEPT);
+    }
+
+    /// Pass the accepted connection to the next worker, and accept the next one
+    fn queue_pass(&mut self) {
+        let ring_fd = self.workers[self.next_worker];
+        self.next_worker = (self.next_worker + 1) % self.workers.len();
+        {
+            let mut sqe = self.ior.get_sqe().expect("submission queue is full");
+            sqe.prep_msg_ring_fd(ring_fd, ACCEPT_SLOT, IORING_FILE_INDEX_ALLOC, OP_NEW);
+            sqe.set_flags(SqeFlags::IO_HARDLINK);
+            sqe.set_data(OP_MSG);
+        }
+        {
+            let mut sqe = self.ior.get_sqe().expect("submission queue is full");
+            sqe.prep_close_fixed(ACCEPT_SLOT);
+            sqe.set_flags(SqeFlags::IO_LINK);
+            sqe.set_data(OP_CLOSE);
+        }
+        self.queue_accept();
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        self.queue_accept();
+        loop {
+            self.ior.submit_and_wait(1)?;
+            while let Some(cqe) = self.ior.pop_cqe() {
+                let res = cqe.res();
+                match cqe.user_data() {
+                    OP_ACCEPT if res == -libc::ECANCELED => {
+                        // the close before it failed
+                        self.queue_accept();
+                    }
+                    OP_ACCEPT if res < 0 => return Err(io::Error::from_raw_os_error(-res)),
+                    OP_ACCEPT => self.queue_pass(),
+                    OP_MSG if res < 0 => {
+                        let err = io::Error::from_raw_os_error(-res);
+                        eprintln!("Failed to pass connection to a worker: {}", err);
+                    }
+                    OP_CLOSE if res < 0 => {
+                        let err = io::Error::from_raw_os_error(-res);
+                        eprintln!("Failed to close connection: {}", err);
+                    }
+                    OP_MSG | OP_CLOSE => (),
+                    x => panic!("unexpected user data: {}", x),
+                }
+            }
+        }
+    }
+}
+
+pub fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 3 {
+        let pname = std::path::Path::new(&args[0]).file_name().unwrap().to_str()
+            .unwrap_or("iour-msg-ring-server");
+        eprintln!("Usage: {} [<port> [<workers>]]", pname);
+        std::process::exit(-1);
+    }
+
+    let port: u16 = match args.get(1).map(|x| x.parse()) {
+        None => 8080,
+        Some(Ok(x)) => x,
+        Some(Err(e)) => {
+            eprintln!("Invalid port: {}", e);
+            std::process::exit(-1);
+        }
+    };
+    let nworkers: usize = match args.get(2).map(|x| x.parse()) {
+        None => 4,
+        Some(Ok(x)) if x > 0 => x,
+        Some(_) => {
+            eprintln!("Invalid number of workers: {}", args[2]);
+            std::process::exit(-1);
+        }
+    };
+
+    let listener = match std::net::TcpListener::bind(("0.0.0.0", port)) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to bind to port {}: {}", port, e);
+            std::process::exit(-1);
+        }
+    };
+
+    let workers = match (0..nworkers).map(spawn_worker).collect::<io::Result<Vec<_>>>() {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to start workers: {}", e);
+            std::process::exit(-1);
+        }
+    };
+
+    let init = || -> io::Result<io_uring::IoUring> {
+        let mut ior = io_uring::IoUring::init(4)?;
+        ior.register_files(&[-1])?;
+        Ok(ior)
+    };
+    let ior = match init() {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to initialize io_uring: {}", e);
+            std::process::exit(-1);
+        }
+    };
+
+    let mut acceptor = Acceptor { ior, listener, workers, next_worker: 0 };
+    eprintln!("Listening on port {} with {} workers", port, nworkers);
+    if let Err(e) = acceptor.run() {
+        eprintln!("Acceptor failed: {}", e);
+        std::process::exit(-1);
+    }
+}
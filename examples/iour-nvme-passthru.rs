@@ -0,0 +1,247 @@
+/*
+ * Kornilios Kourtis <kkourt@kkourt.io>
+ *
+ * vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+ */
+
+// Issue NVMe commands via io_uring passthrough (URING_CMD)
+//
+// NVMe generic character devices (/dev/ngXnY, Linux 5.19) accept NVMe commands directly via
+// URING_CMD requests, bypassing the block layer. The command (struct nvme_uring_cmd) is 72 bytes,
+// so it does not fit in a regular sqe: the ring needs 128-byte sqes (IORING_SETUP_SQE128). The
+// driver also requires 32-byte cqes (IORING_SETUP_CQE32), where the second half carries the
+// command-specific result.
+//
+// The example identifies the controller and the namespace (admin commands), and then reads a block
+// from the namespace (I/O command) and dumps its first bytes. The cqe result is 0 on success, the
+// NVMe status if the device failed the command, or a negative errno.
+//
+// Needs read access to the device (usually root).
+//
+// Example:
+//  iour-nvme-passthru /dev/ng0n1 0
+
+use iouring::io_uring::{self, SetupFlags, ShutdownPolicy};
+
+use std::convert::TryInto;
+use std::os::unix::io::AsRawFd;
+
+// from include/uapi/linux/nvme_ioctl.h
+const NVME_IOCTL_ID: libc::c_ulong = 0x4e40; // _IO('N', 0x40)
+const NVME_URING_CMD_IO: u32 = 0xc0484e80; // _IOWR('N', 0x80, struct nvme_uring_cmd)
+const NVME_URING_CMD_ADMIN: u32 = 0xc0484e82; // _IOWR('N', 0x82, struct nvme_uring_cmd)
+
+// opcodes
+const NVME_ADMIN_IDENTIFY: u8 = 0x06;
+const NVME_CMD_READ: u8 = 0x02;
+
+// identify CNS values
+const NVME_ID_CNS_NS: u32 = 0x00;
+const NVME_ID_CNS_CTRL: u32 = 0x01;
+
+const ID_SZ: usize = 4096;
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+#[allow(non_camel_case_types)]
+struct nvme_uring_cmd {
+    opcode: u8,
+    flags: u8,
+    rsvd1: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    metadata: u64,
+    addr: u64,
+    metadata_len: u32,
+    data_len: u32,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+    timeout_ms: u32,
+    rsvd2: u32,
+}
+
+const _: () = assert!(std::mem::size_of::<nvme_uring_cmd>() == 72);
+
+// NB: the struct is all integers, without padding
+unsafe impl io_uring::CmdPayload for nvme_uring_cmd {}
+
+/// A page-aligned buffer for the device to DMA into
+struct AlignedBuf {
+    ptr: *mut u8,
+    layout: std::alloc::Layout,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> AlignedBuf {
+        let layout = std::alloc::Layout::from_size_align(len, 4096).unwrap();
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        assert!(!ptr.is_null());
+        AlignedBuf { ptr, layout }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.ptr, self.layout) };
+    }
+}
+
+struct Dev {
+    ior: io_uring::IoUring,
+    file: std::fs::File,
+    nsid: u32,
+}
+
+impl Dev {
+
+    /// Issue a command and wait for it to complete
+    fn exec(&mut self, cmd_op: u32, cmd: &nvme_uring_cmd) -> std::io::Result<()> {
+        let fd = self.file.as_raw_fd();
+        {
+            let mut sqe = self.ior.get_sqe().expect("submission queue is full");
+            sqe.prep_uring_cmd_typed(fd, cmd_op, cmd)?;
+            sqe.set_data(cmd.opcode as u64);
+        }
+        self.ior.submit_and_wait(1)?;
+        let cqe = self.ior.pop_cqe32().expect("no completion");
+        let res = cqe.res();
+        if res < 0 {
+            return Err(std::io::Error::from_raw_os_error(-res));
+        } else if res > 0 {
+            // NB: the first extra word of the cqe is the NVMe command result (dword 0)
+            let msg = format!("NVMe command {:#x} failed: status {:#x} result {:#x}", cmd.opcode,
+                res, cqe.extra1());
+            return Err(std::io::Error::other(msg));
+        }
+        Ok(())
+    }
+
+    fn identify(&mut self, nsid: u32, cns: u32, buf: &AlignedBuf) -> std::io::Result<()> {
+        let cmd = nvme_uring_cmd {
+            opcode: NVME_ADMIN_IDENTIFY,
+            nsid,
+            addr: buf.ptr as u64,
+            data_len: ID_SZ as u32,
+            cdw10: cns,
+            ..Default::default()
+        };
+        self.exec(NVME_URING_CMD_ADMIN, &cmd)
+    }
+
+    fn read(&mut self, slba: u64, nblocks: u32, buf: &AlignedBuf) -> std::io::Result<()> {
+        let cmd = nvme_uring_cmd {
+            opcode: NVME_CMD_READ,
+            nsid: self.nsid,
+            addr: buf.ptr as u64,
+            data_len: buf.layout.size() as u32,
+            cdw10: slba as u32,
+            cdw11: (slba >> 32) as u32,
+            // NB: 0-based
+            cdw12: nblocks - 1,
+            ..Default::default()
+        };
+        self.exec(NVME_URING_CMD_IO, &cmd)
+    }
+}
+
+/// An ASCII field of an identify structure
+fn id_str(b: &[u8]) -> String {
+    String::from_utf8_lossy(b).trim().to_string()
+}
+
+fn hexdump(b: &[u8]) {
+    for (i, line) in b.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|x| format!("{:02x}", x)).collect();
+        println!("  {:04x}: {}", i * 16, hex.join(" "));
+    }
+}
+
+fn run(dev: &mut Dev, slba: u64) -> std::io::Result<()> {
+    let buf = AlignedBuf::new(ID_SZ);
+
+    dev.identify(0, NVME_ID_CNS_CTRL, &buf)?;
+    let id = buf.as_slice();
+    println!("serial:   {}", id_str(&id[4..24]));
+    println!("model:    {}", id_str(&id[24..64]));
+    println!("firmware: {}", id_str(&id[64..72]));
+
+    dev.identify(dev.nsid, NVME_ID_CNS_NS, &buf)?;
+    let id = buf.as_slice();
+    let nsze = u64::from_le_bytes(id[0..8].try_into().unwrap());
+    // the low 4 bits of flbas are the index of the LBA format in use
+    let lbaf_off = 128 + 4 * (id[26] & 0xf) as usize;
+    let lbaf = u32::from_le_bytes(id[lbaf_off..lbaf_off + 4].try_into().unwrap());
+    let lba_sz = 1usize << ((lbaf >> 16) & 0xff);
+    println!("namespace {}: {} blocks of {} bytes", dev.nsid, nsze, lba_sz);
+
+    if slba >= nsze {
+        let msg = format!("lba {} is past the end of the namespace", slba);
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+    }
+    let buf = AlignedBuf::new(lba_sz);
+    dev.read(slba, 1, &buf)?;
+    println!("lba {}:", slba);
+    hexdump(&buf.as_slice()[..std::cmp::min(64, lba_sz)]);
+    Ok(())
+}
+
+pub fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        let pname = std::path::Path::new(&args[0]).file_name().unwrap().to_str()
+            .unwrap_or("iour-nvme-passthru");
+        eprintln!("Usage: {} <device (e.g., /dev/ng0n1)> [<lba>]", pname);
+        std::process::exit(-1);
+    }
+
+    let path = &args[1];
+    let slba = match args.get(2).map(|x| x.parse::<u64>()) {
+        None => 0,
+        Some(Ok(x)) => x,
+        Some(Err(e)) => {
+            eprintln!("Invalid lba {}: {}", args[2], e);
+            std::process::exit(-1);
+        }
+    };
+
+    let file = match std::fs::File::open(path) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", path, e);
+            std::process::exit(-1);
+        }
+    };
+
+    let nsid = unsafe { libc::ioctl(file.as_raw_fd(), NVME_IOCTL_ID) };
+    if nsid <= 0 {
+        let err = std::io::Error::last_os_error();
+        eprintln!("Failed to get the namespace id of {}: {}", path, err);
+        std::process::exit(-1);
+    }
+
+    let flags = SetupFlags::SQE128 | SetupFlags::CQE32;
+    let mut ior = match io_uring::IoUring::init_with_flags(4, flags) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to initialize io_uring (SQE128|CQE32 need Linux 5.19): {}", e);
+            std::process::exit(-1);
+        }
+    };
+    // NB: the buffers are dropped before the ring, so make sure no commands use them
+    ior.set_drop_policy(ShutdownPolicy::CancelAndWait);
+
+    let mut dev = Dev { ior, file, nsid: nsid as u32 };
+    if let Err(e) = run(&mut dev, slba) {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(-1);
+    }
+}
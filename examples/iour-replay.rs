@@ -0,0 +1,89 @@
+/*
+ * Kornilios Kourtis <kkourt@kkourt.io>
+ *
+ * vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+ */
+
+// Replay a log recorded via iouring::record::Recorder, and print the cqes of the replayed requests
+//
+// The addresses in the log (e.g., of buffers and iovecs) are from the recording process, so they
+// are meaningless here. By default, sqes with addresses (addr, or addr3) are skipped, which leaves
+// requests such as fsync, close, poll, and cancel. With --unsafe-addrs, they are replayed as they
+// are, which is only sensible for requests whose addresses the kernel ignores, or does not write
+// to.
+//
+// After replaying, we wait for the cqes of the replayed requests for up to WAIT, so that requests
+// that never complete (e.g., polls on fds that do not exist here) do not hang the tool.
+
+use iouring::io_uring::{IoUring, KernelTimespec, TimeoutFlags};
+use iouring::record;
+
+const WAIT: std::time::Duration = std::time::Duration::from_secs(1);
+const UDATA_TIMEOUT: u64 = u64::MAX;
+
+pub fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    let arg0 = args.remove(0);
+    let unsafe_addrs = match args.iter().position(|a| a == "--unsafe-addrs") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+    if args.len() != 1 {
+        let pname = std::path::Path::new(&arg0).file_name().unwrap().to_str()
+            .unwrap_or("iour-replay");
+        eprintln!("Usage: {} [--unsafe-addrs] <log>", pname);
+        std::process::exit(-1);
+    }
+    let log = match std::fs::File::open(&args[0]) {
+        Ok(x) => std::io::BufReader::new(x),
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", args[0], e);
+            std::process::exit(-1);
+        }
+    };
+
+    let mut ior = IoUring::init(256).expect("failed to initialize io_uring");
+    let mut skipped = 0;
+    // NB: the sqes are checked and queued by replay(), and the kernel only sees the ones with no
+    // addresses, unless unsafe_addrs is set.
+    let replayed = unsafe {
+        record::replay(&mut ior, log, |sqe| {
+            if !unsafe_addrs && (sqe.addr != 0 || sqe.addr3 != 0) {
+                skipped += 1;
+                return false;
+            }
+            true
+        })
+    };
+    let replayed = match replayed {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to replay {}: {}", args[0], e);
+            std::process::exit(-1);
+        }
+    };
+    eprintln!("replayed {} sqes, skipped {}", replayed, skipped);
+
+    // a timeout that completes after the cqes of the replayed requests, or after WAIT
+    let ts = KernelTimespec::from(WAIT);
+    {
+        let mut sqe = ior.get_sqe().expect("submission queue is full");
+        sqe.prep_timeout(&ts, replayed as u32, TimeoutFlags::empty());
+        sqe.set_data(UDATA_TIMEOUT);
+    }
+    let mut done = false;
+    while !done {
+        ior.submit_and_wait(1).expect("submit failed");
+        while let Some(cqe) = ior.pop_cqe() {
+            if cqe.user_data() == UDATA_TIMEOUT {
+                done = true;
+                continue;
+            }
+            println!("cqe user_data={:#x} res={} flags={:#x}", cqe.user_data(), cqe.res(),
+                cqe.flags());
+        }
+    }
+}
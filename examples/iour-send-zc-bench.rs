@@ -0,0 +1,290 @@
+/*
+ * Kornilios Kourtis <kkourt@kkourt.io>
+ *
+ * vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+ */
+
+// Compare send and zero-copy send throughput, following liburing/examples/send-zerocopy.c
+//
+// Keeps nr sends of the same buffer in flight on a TCP connection for the given time, and reports
+// the throughput and the CPU time of the sending thread. Without a destination, a receiver thread
+// drains a loopback connection.
+//
+// A zero-copy send posts two cqes: the result of the send (with IORING_CQE_F_MORE set), and a
+// notification once the kernel no longer references the buffer. All sends use the same buffer
+// here, which is never modified, so the notifications are only counted. Note that over loopback
+// the data are copied by the receiver anyway, so zero-copy sends are not expected to be faster
+// there (use --report-usage to see how many notifications report a copy).
+//
+// Example:
+//  iour-send-zc-bench --mode=both --size=64k --nr=8 --time=5
+
+use iouring::io_uring::{self, ShutdownPolicy};
+use iouring::io_uring::{IORING_NOTIF_USAGE_ZC_COPIED, IORING_SEND_ZC_REPORT_USAGE};
+
+use std::io::Read;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Send,
+    SendZc,
+}
+
+struct Opts {
+    modes: Vec<Mode>,
+    dst: Option<String>,
+    size: usize,
+    nr: u32,
+    time: Duration,
+    report_usage: bool,
+}
+
+const USAGE: &str = "[options]
+options:
+  --mode=<send|zc|both>  send variant(s) to run (default: both)
+  --dst=<host:port>      send to the given receiver (default: a loopback receiver thread)
+  --size=<bytes>         payload size of each send, k/m suffixes allowed (default: 64k)
+  --nr=<n>               number of sends in flight (default: 8)
+  --time=<secs>          duration of each run (default: 5)
+  --report-usage         report whether zero-copy sends were copied (Linux 6.2)";
+
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (num, mult) = match s.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&s[..s.len() - 1], 1 << 10),
+        Some('m') => (&s[..s.len() - 1], 1 << 20),
+        _ => (s, 1),
+    };
+    num.parse::<usize>()
+        .ok()
+        .and_then(|x| x.checked_mul(mult))
+        .filter(|x| *x > 0 && *x <= u32::MAX as usize)
+        .ok_or_else(|| format!("invalid size: {}", s))
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Opts, String> {
+    let mut opts = Opts {
+        modes: vec![Mode::Send, Mode::SendZc],
+        dst: None,
+        size: 64 * 1024,
+        nr: 8,
+        time: Duration::from_secs(5),
+        report_usage: false,
+    };
+
+    for arg in args {
+        let (key, val) = match arg.split_once('=') {
+            Some((k, v)) => (k, Some(v)),
+            None => (arg.as_str(), None),
+        };
+        match (key, val) {
+            ("--mode", Some(v)) => {
+                opts.modes = match v {
+                    "send" => vec![Mode::Send],
+                    "zc" => vec![Mode::SendZc],
+                    "both" => vec![Mode::Send, Mode::SendZc],
+                    _ => return Err(format!("invalid --mode value: {}", v)),
+                }
+            }
+            ("--dst", Some(v)) => opts.dst = Some(v.to_string()),
+            ("--size", Some(v)) => opts.size = parse_size(v)?,
+            ("--nr", Some(v)) => {
+                opts.nr = v.parse().ok().filter(|x| *x > 0).ok_or(format!("invalid nr: {}", v))?
+            }
+            ("--time", Some(v)) => {
+                let secs = v.parse().map_err(|_| format!("invalid time: {}", v))?;
+                opts.time = Duration::from_secs(secs);
+            }
+            ("--report-usage", None) => opts.report_usage = true,
+            _ => return Err(format!("invalid argument: {}", arg)),
+        }
+    }
+    Ok(opts)
+}
+
+/// Start a receiver thread that drains a loopback connection. Returns the address to connect to.
+fn spawn_receiver() -> std::io::Result<std::net::SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || {
+        let mut buf = vec![0u8; 1 << 20];
+        for conn in listener.incoming() {
+            let mut conn = match conn {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            while let Ok(n) = conn.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+            }
+        }
+    });
+    Ok(addr)
+}
+
+/// CPU time (user, system) of the calling thread
+fn thread_cpu_time() -> (Duration, Duration) {
+    let tv = |tv: libc::timeval| {
+        Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+    };
+    let mut ru: libc::rusage = unsafe { std::mem::zeroed() };
+    let err = unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut ru) };
+    assert_eq!(err, 0);
+    (tv(ru.ru_utime), tv(ru.ru_stime))
+}
+
+#[derive(Default)]
+struct Stats {
+    bytes: u64,
+    sends: u64,
+    notifs: u64,
+    // notifications reporting that the data were copied
+    copied: u64,
+}
+
+struct Sender {
+    ior: io_uring::IoUring,
+    sock: TcpStream,
+    buf: Vec<u8>,
+    mode: Mode,
+    zc_flags: u16,
+    stats: Stats,
+}
+
+impl Sender {
+
+    fn queue_send(&mut self) {
+        let fd = self.sock.as_raw_fd();
+        let (buf, len) = (self.buf.as_ptr() as *const libc::c_void, self.buf.len() as u32);
+        // NB: the number of requests in flight never exceeds the ring size
+        let mut sqe = self.ior.get_sqe().expect("submission queue is full");
+        match self.mode {
+            Mode::Send => sqe.prep_send(fd, buf, len, libc::MSG_NOSIGNAL),
+            Mode::SendZc => sqe.prep_send_zc(fd, buf, len, libc::MSG_NOSIGNAL, self.zc_flags),
+        }
+    }
+
+    fn run(&mut self, nr: u32, time: Duration) -> std::io::Result<()> {
+        let start = Instant::now();
+        // sends in flight, and notifications we are waiting for
+        let (mut sends, mut notifs) = (0, 0);
+        for _ in 0..nr {
+            self.queue_send();
+            sends += 1;
+        }
+
+        while sends > 0 || notifs > 0 {
+            self.ior.submit_and_wait(1)?;
+            let running = start.elapsed() < time;
+            while let Some(cqe) = self.ior.pop_cqe() {
+                if cqe.is_notif() {
+                    notifs -= 1;
+                    self.stats.notifs += 1;
+                    if cqe.res() & IORING_NOTIF_USAGE_ZC_COPIED != 0 {
+                        self.stats.copied += 1;
+                    }
+                    continue;
+                }
+
+                sends -= 1;
+                if cqe.has_more() {
+                    notifs += 1;
+                }
+                let res = cqe.res();
+                if res < 0 {
+                    return Err(std::io::Error::from_raw_os_error(-res));
+                }
+                self.stats.bytes += res as u64;
+                self.stats.sends += 1;
+                if running {
+                    self.queue_send();
+                    sends += 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn bench(opts: &Opts, mode: Mode, dst: &str) -> std::io::Result<()> {
+    let sock = TcpStream::connect(dst)?;
+    let mut ior = io_uring::IoUring::init(opts.nr)?;
+    // NB: the ring is dropped before the buffer, so make sure no sends use it
+    ior.set_drop_policy(ShutdownPolicy::CancelAndWait);
+    let zc_flags = if opts.report_usage { IORING_SEND_ZC_REPORT_USAGE } else { 0 };
+    let mut sender = Sender {
+        ior,
+        sock,
+        buf: vec![0xab; opts.size],
+        mode,
+        zc_flags,
+        stats: Stats::default(),
+    };
+
+    let (utime0, stime0) = thread_cpu_time();
+    let start = Instant::now();
+    sender.run(opts.nr, opts.time)?;
+    let elapsed = start.elapsed().as_secs_f64();
+    let (utime1, stime1) = thread_cpu_time();
+
+    let stats = &sender.stats;
+    let usr = (utime1 - utime0).as_secs_f64() / elapsed * 100.0;
+    let sys = (stime1 - stime0).as_secs_f64() / elapsed * 100.0;
+    let name = match mode {
+        Mode::Send => "send",
+        Mode::SendZc => "send_zc",
+    };
+    println!(
+        "{:>8}: {:.1} MB/s, {:.0} sends/s, cpu {:.1}% (usr {:.1}%, sys {:.1}%)",
+        name,
+        stats.bytes as f64 / elapsed / 1e6,
+        stats.sends as f64 / elapsed,
+        usr + sys,
+        usr,
+        sys,
+    );
+    if mode == Mode::SendZc {
+        print!("          notifications: {}", stats.notifs);
+        if opts.report_usage {
+            print!(", copied: {}", stats.copied);
+        }
+        println!();
+    }
+    Ok(())
+}
+
+pub fn main() {
+    let mut args = std::env::args();
+    let arg0 = args.next().unwrap();
+    let opts = match parse_args(args) {
+        Ok(x) => x,
+        Err(e) => {
+            let pname = std::path::Path::new(&arg0).file_name().unwrap().to_str()
+                .unwrap_or("iour-send-zc-bench");
+            eprintln!("{}\nUsage: {} {}", e, pname, USAGE);
+            std::process::exit(-1);
+        }
+    };
+
+    let dst = match opts.dst {
+        Some(ref x) => x.clone(),
+        None => match spawn_receiver() {
+            Ok(addr) => addr.to_string(),
+            Err(e) => {
+                eprintln!("Failed to start receiver: {}", e);
+                std::process::exit(-1);
+            }
+        },
+    };
+
+    for mode in opts.modes.iter() {
+        if let Err(e) = bench(&opts, *mode, &dst) {
+            eprintln!("Benchmark failed: {}", e);
+            std::process::exit(-1);
+        }
+    }
+}
@@ -0,0 +1,331 @@
+/*
+ * Kornilios Kourtis <kkourt@kkourt.io>
+ *
+ * vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+ */
+
+// A TCP proxy that moves data between sockets via splice, without copying them to userspace
+//
+// Connections are served one at a time. For each direction of a connection, data are spliced from
+// the source socket into a pipe, and from the pipe into the destination socket. If a capture file
+// is given, the client->upstream data are also duplicated into a second pipe via tee, and spliced
+// from there into the file.
+//
+// The sockets, pipes, and capture file of each connection are registered with the ring, and
+// requests refer to them by their index in the file table.
+//
+// Try it with:
+//  iour-splice-proxy 8081 example.com:80 /tmp/capture &
+//  curl -H 'Host: example.com' http://localhost:8081/
+
+use iouring::io_uring::{self, SqeFlags, SPLICE_F_FD_IN_FIXED};
+
+use std::io;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+const QD: u32 = 16;
+// NB: this is the default pipe capacity
+const SPLICE_SZ: u32 = 64 * 1024;
+
+// indices in the registered file table
+const FILE_CLIENT: u32 = 0;
+const FILE_UPSTREAM: u32 = 1;
+const FILE_CAP_R: u32 = 6;
+const FILE_CAP_W: u32 = 7;
+const FILE_CAP: u32 = 8;
+
+// The user data of a request is (dir << 8) | op
+const OP_IN: u64 = 0; // socket -> pipe
+const OP_TEE: u64 = 1; // pipe -> capture pipe
+const OP_CAP: u64 = 2; // capture pipe -> capture file
+const OP_OUT: u64 = 3; // pipe -> socket
+
+/// One direction of a connection
+struct Dir {
+    src: u32,
+    dst: u32,
+    pipe_r: u32,
+    pipe_w: u32,
+    capture: bool,
+    // bytes in the pipe, not yet spliced to dst
+    in_pipe: u32,
+    // bytes in the capture pipe, not yet spliced to the capture file
+    in_cap: u32,
+    // a request is in flight
+    busy: bool,
+    // src reached EOF, or the connection failed
+    done: bool,
+}
+
+impl Dir {
+    fn new(src: u32, dst: u32, pipe_r: u32, capture: bool) -> Dir {
+        Dir {
+            src,
+            dst,
+            pipe_r,
+            pipe_w: pipe_r + 1,
+            capture,
+            in_pipe: 0,
+            in_cap: 0,
+            busy: false,
+            done: false,
+        }
+    }
+}
+
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds: [RawFd; 2] = [-1, -1];
+    let err = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+    if err != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+struct Proxy {
+    ior: io_uring::IoUring,
+    capture: Option<std::fs::File>,
+}
+
+struct Conn<'a> {
+    ior: &'a mut io_uring::IoUring,
+    client: TcpStream,
+    upstream: TcpStream,
+    dirs: [Dir; 2],
+    error: Option<io::Error>,
+}
+
+impl Conn<'_> {
+
+    /// Queue a splice (or a tee) between two registered files
+    fn queue(&mut self, d: usize, op: u64, fd_in: u32, fd_out: u32, nbytes: u32) {
+        // NB: each direction has at most one request in flight, so there is always space
+        let mut sqe = self.ior.get_sqe().expect("submission queue is full");
+        let (fd_in, fd_out) = (fd_in as libc::c_int, fd_out as libc::c_int);
+        let flags = libc::SPLICE_F_MOVE | SPLICE_F_FD_IN_FIXED;
+        if op == OP_TEE {
+            sqe.prep_tee(fd_in, fd_out, nbytes, flags);
+        } else {
+            sqe.prep_splice(fd_in, -1, fd_out, -1, nbytes, flags);
+        }
+        sqe.set_flags(SqeFlags::FIXED_FILE);
+        sqe.set_data(((d as u64) << 8) | op);
+        self.dirs[d].busy = true;
+    }
+
+    fn queue_in(&mut self, d: usize) {
+        let (src, pipe_w) = (self.dirs[d].src, self.dirs[d].pipe_w);
+        self.queue(d, OP_IN, src, pipe_w, SPLICE_SZ);
+    }
+
+    fn queue_tee(&mut self, d: usize) {
+        let (pipe_r, n) = (self.dirs[d].pipe_r, self.dirs[d].in_pipe);
+        self.queue(d, OP_TEE, pipe_r, FILE_CAP_W, n);
+    }
+
+    fn queue_cap(&mut self, d: usize) {
+        let n = self.dirs[d].in_cap;
+        self.queue(d, OP_CAP, FILE_CAP_R, FILE_CAP, n);
+    }
+
+    fn queue_out(&mut self, d: usize) {
+        let (pipe_r, dst, n) = (self.dirs[d].pipe_r, self.dirs[d].dst, self.dirs[d].in_pipe);
+        self.queue(d, OP_OUT, pipe_r, dst, n);
+    }
+
+    /// Stop proxying in both directions. Requests blocked on the sockets fail once the sockets are
+    /// shut down.
+    fn fail(&mut self, err: io::Error) {
+        if self.error.is_none() {
+            self.error = Some(err);
+        }
+        for d in self.dirs.iter_mut() {
+            d.done = true;
+        }
+        let _ = self.client.shutdown(Shutdown::Both);
+        let _ = self.upstream.shutdown(Shutdown::Both);
+    }
+
+    fn handle_cqe(&mut self, cqe: io_uring::io_uring_cqe) {
+        let (d, op) = ((cqe.user_data() >> 8) as usize, cqe.user_data() & 0xff);
+        self.dirs[d].busy = false;
+        if self.dirs[d].done {
+            // the connection failed: just wait for the pending requests
+            return;
+        }
+
+        let res = cqe.res();
+        if res < 0 {
+            self.fail(io::Error::from_raw_os_error(-res));
+            return;
+        }
+
+        let res = res as u32;
+        match op {
+            OP_IN if res == 0 => {
+                // EOF: propagate it to the destination
+                self.dirs[d].done = true;
+                let dst = match self.dirs[d].dst {
+                    FILE_CLIENT => &self.client,
+                    _ => &self.upstream,
+                };
+                let _ = dst.shutdown(Shutdown::Write);
+            }
+            OP_IN => {
+                self.dirs[d].in_pipe = res;
+                if self.dirs[d].capture {
+                    self.queue_tee(d);
+                } else {
+                    self.queue_out(d);
+                }
+            }
+            OP_TEE => {
+                // NB: tee does not consume the data, so a short tee cannot be continued. The
+                // capture pipe is empty at this point, and has the same capacity as the pipe, so
+                // this should not happen.
+                if res != self.dirs[d].in_pipe {
+                    let msg = format!("short tee: {}/{}", res, self.dirs[d].in_pipe);
+                    self.fail(io::Error::other(msg));
+                    return;
+                }
+                self.dirs[d].in_cap = res;
+                self.queue_cap(d);
+            }
+            OP_CAP | OP_OUT if res == 0 => {
+                self.fail(io::Error::new(io::ErrorKind::WriteZero, "splice wrote 0 bytes"));
+            }
+            OP_CAP => {
+                self.dirs[d].in_cap -= res;
+                if self.dirs[d].in_cap > 0 {
+                    self.queue_cap(d);
+                } else {
+                    self.queue_out(d);
+                }
+            }
+            OP_OUT => {
+                self.dirs[d].in_pipe -= res;
+                if self.dirs[d].in_pipe > 0 {
+                    self.queue_out(d);
+                } else {
+                    self.queue_in(d);
+                }
+            }
+            _ => panic!("unexpected user data: {:#x}", cqe.user_data()),
+        }
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        self.queue_in(0);
+        self.queue_in(1);
+        while self.dirs.iter().any(|d| d.busy) {
+            self.ior.submit_and_wait(1)?;
+            while let Some(cqe) = self.ior.pop_cqe() {
+                self.handle_cqe(cqe);
+            }
+        }
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Proxy {
+
+    fn serve(&mut self, client: TcpStream, upstream_addr: &str) -> io::Result<()> {
+        let upstream = TcpStream::connect(upstream_addr)?;
+        let (up_r, up_w) = pipe()?;
+        let (down_r, down_w) = pipe()?;
+        let (cap_r, cap_w) = pipe()?;
+
+        // NB: the order needs to match the FILE_* constants
+        let mut files = vec![
+            client.as_raw_fd(),
+            upstream.as_raw_fd(),
+            up_r.as_raw_fd(),
+            up_w.as_raw_fd(),
+            down_r.as_raw_fd(),
+            down_w.as_raw_fd(),
+            cap_r.as_raw_fd(),
+            cap_w.as_raw_fd(),
+        ];
+        if let Some(ref f) = self.capture {
+            files.push(f.as_raw_fd());
+        }
+        self.ior.register_files(&files)?;
+
+        let capture = self.capture.is_some();
+        let mut conn = Conn {
+            ior: &mut self.ior,
+            client,
+            upstream,
+            dirs: [
+                Dir::new(FILE_CLIENT, FILE_UPSTREAM, 2, capture),
+                Dir::new(FILE_UPSTREAM, FILE_CLIENT, 4, false),
+            ],
+            error: None,
+        };
+        let ret = conn.run();
+        // NB: nothing is in flight at this point
+        self.ior.unregister_files()?;
+        ret
+    }
+}
+
+pub fn main() {
+    let mut args = std::env::args();
+    let arg0 = args.next().unwrap();
+    if args.len() < 2 {
+        let pname = std::path::Path::new(&arg0).file_name().unwrap().to_str()
+            .unwrap_or("iour-splice-proxy");
+        eprintln!("Usage: {} <port> <upstream host:port> [<capture file>]", pname);
+        std::process::exit(-1);
+    }
+
+    let port: u16 = match args.next().unwrap().parse() {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Invalid port: {}", e);
+            std::process::exit(-1);
+        }
+    };
+    let upstream = args.next().unwrap();
+    let capture = args.next().map(|path| match std::fs::File::create(&path) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to create {}: {}", path, e);
+            std::process::exit(-1);
+        }
+    });
+
+    let listener = match TcpListener::bind(("0.0.0.0", port)) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to bind to port {}: {}", port, e);
+            std::process::exit(-1);
+        }
+    };
+
+    let ior = match io_uring::IoUring::init(QD) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to initialize io_uring: {}", e);
+            std::process::exit(-1);
+        }
+    };
+
+    let mut proxy = Proxy { ior, capture };
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("accept failed: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = proxy.serve(client, &upstream) {
+            eprintln!("connection failed: {}", e);
+        }
+    }
+}
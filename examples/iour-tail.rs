@@ -0,0 +1,243 @@
+/*
+ * Kornilios Kourtis <kkourt@kkourt.io>
+ *
+ * vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+ */
+
+// tail -f using (multishot) poll requests
+//
+// A single multishot POLL_ADD request notifies us whenever there is new data, and we then read
+// them via the ring until there is nothing left. For pipes, FIFOs, and other pollable files, we
+// poll the file itself, and exit when the writer goes away.
+//
+// Regular files are always "ready" (poll returns POLLIN immediately, even at EOF), so for them we
+// poll an inotify instance that watches the file for modifications instead. Like tail -n0 -f, we
+// start from the end of the file, and start over if the file is truncated.
+//
+// Reads use an offset of -1, i.e., the file position, which the kernel updates (Linux 5.6).
+
+use iouring::io_uring;
+
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+const BUF_SZ: usize = 64 * 1024;
+
+// user data
+const UDATA_POLL: u64 = 1;
+const UDATA_READ: u64 = 2;
+
+struct Tail {
+    ior: io_uring::IoUring,
+    file: std::fs::File,
+    // inotify instance, for regular files
+    inotify: Option<OwnedFd>,
+    buf: Vec<u8>,
+    // NB: the kernel reads the iovec when the request is submitted, so it needs to outlive
+    // queue_read()
+    iov: libc::iovec,
+    reading: bool,
+    // there was a poll event while reading
+    poll_pending: bool,
+}
+
+impl Tail {
+
+    fn poll_fd(&self) -> RawFd {
+        match self.inotify {
+            Some(ref x) => x.as_raw_fd(),
+            None => self.file.as_raw_fd(),
+        }
+    }
+
+    fn queue_poll(&mut self) {
+        let fd = self.poll_fd();
+        // NB: there are at most two requests in flight, and the ring has space for more
+        let mut sqe = self.ior.get_sqe().expect("submission queue is full");
+        sqe.prep_poll_multishot(fd, libc::POLLIN as u32);
+        sqe.set_data(UDATA_POLL);
+    }
+
+    fn queue_read(&mut self) {
+        let fd = self.file.as_raw_fd();
+        self.iov = libc::iovec {
+            iov_base: self.buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: self.buf.len(),
+        };
+        let mut sqe = self.ior.get_sqe().expect("submission queue is full");
+        sqe.prep_readv(fd, &self.iov, 1, u64::MAX);
+        sqe.set_data(UDATA_READ);
+        self.reading = true;
+    }
+
+    /// Drain the inotify events, and handle file truncation
+    fn handle_inotify(&mut self) -> std::io::Result<()> {
+        let fd = self.inotify.as_ref().unwrap().as_raw_fd();
+        let mut evbuf = [0u8; 4096];
+        loop {
+            let ret = unsafe {
+                libc::read(fd, evbuf.as_mut_ptr() as *mut libc::c_void, evbuf.len())
+            };
+            if ret < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.raw_os_error() == Some(libc::EAGAIN) {
+                    break;
+                }
+                return Err(err);
+            }
+        }
+
+        let pos = self.file.stream_position()?;
+        if self.file.metadata()?.len() < pos {
+            eprintln!("tail: file truncated");
+            self.file.seek(SeekFrom::Start(0))?;
+        }
+        Ok(())
+    }
+
+    /// Returns false when done
+    fn handle_cqe(&mut self, cqe: io_uring::io_uring_cqe) -> std::io::Result<bool> {
+        let res = cqe.res();
+        match cqe.user_data() {
+            UDATA_POLL => {
+                if res < 0 {
+                    return Err(std::io::Error::from_raw_os_error(-res));
+                }
+                if !cqe.has_more() {
+                    // the kernel terminated the multishot request, so re-arm it
+                    self.queue_poll();
+                }
+                if self.inotify.is_some() {
+                    self.handle_inotify()?;
+                }
+                if self.reading {
+                    self.poll_pending = true;
+                } else {
+                    self.queue_read();
+                }
+            }
+
+            UDATA_READ => {
+                self.reading = false;
+                if res > 0 {
+                    let stdout = std::io::stdout();
+                    let mut out = stdout.lock();
+                    out.write_all(&self.buf[..res as usize])?;
+                    out.flush()?;
+                    // there might be more
+                    self.queue_read();
+                } else if res == 0 && self.inotify.is_none() {
+                    // the writer went away
+                    return Ok(false);
+                } else if res == 0 || res == -libc::EAGAIN {
+                    // nothing more for now
+                    if self.poll_pending {
+                        self.poll_pending = false;
+                        self.queue_read();
+                    }
+                } else {
+                    return Err(std::io::Error::from_raw_os_error(-res));
+                }
+            }
+
+            x => panic!("unexpected user data: {}", x),
+        }
+        Ok(true)
+    }
+
+    fn run(&mut self) -> std::io::Result<()> {
+        // NB: for pipes, the poll fires right away if there are data already
+        self.queue_poll();
+        loop {
+            self.ior.submit_and_wait(1)?;
+            while let Some(cqe) = self.ior.pop_cqe() {
+                if !self.handle_cqe(cqe)? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn inotify_watch(path: &str) -> std::io::Result<OwnedFd> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let cpath = std::ffi::CString::new(path)?;
+    let wd = unsafe { libc::inotify_add_watch(fd.as_raw_fd(), cpath.as_ptr(), libc::IN_MODIFY) };
+    if wd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+pub fn main() {
+    let mut args = std::env::args();
+    let arg0 = args.next().unwrap();
+    if args.len() != 1 {
+        let pname = std::path::Path::new(&arg0).file_name().unwrap().to_str()
+            .unwrap_or("iour-tail");
+        eprintln!("Usage: {} <file>", pname);
+        std::process::exit(-1);
+    }
+    let path = args.next().unwrap();
+
+    // NB: O_NONBLOCK, so that opening a FIFO does not wait for a writer, and reads return EAGAIN
+    // instead of blocking.
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(&path);
+    let mut file = match file {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", path, e);
+            std::process::exit(-1);
+        }
+    };
+
+    let is_file = match file.metadata() {
+        Ok(x) => x.is_file(),
+        Err(e) => {
+            eprintln!("Failed to stat {}: {}", path, e);
+            std::process::exit(-1);
+        }
+    };
+    let inotify = if is_file {
+        let ret = inotify_watch(&path).and_then(|x| file.seek(SeekFrom::End(0)).map(|_| x));
+        match ret {
+            Ok(x) => Some(x),
+            Err(e) => {
+                eprintln!("Failed to watch {}: {}", path, e);
+                std::process::exit(-1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let ior = match io_uring::IoUring::init(4) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to initialize io_uring: {}", e);
+            std::process::exit(-1);
+        }
+    };
+
+    let mut tail = Tail {
+        ior,
+        file,
+        inotify,
+        buf: vec![0u8; BUF_SZ],
+        iov: libc::iovec { iov_base: std::ptr::null_mut(), iov_len: 0 },
+        reading: false,
+        poll_pending: false,
+    };
+    if let Err(e) = tail.run() {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(-1);
+    }
+}
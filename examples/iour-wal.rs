@@ -0,0 +1,346 @@
+/*
+ * Kornilios Kourtis <kkourt@kkourt.io>
+ *
+ * vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+ */
+
+// A write-ahead log with group commit
+//
+// Like a database WAL, records are appended to a log file, and a transaction (a group of records)
+// commits once all its records are durable. There are two ways to make a transaction durable:
+//  - dsync: every write has RWF_DSYNC set, so each write completes once its data are durable, and
+//    the transaction commits when all its writes have completed.
+//  - fsync: the writes of the transaction and an fdatasync are linked (IOSQE_IO_LINK) in a chain,
+//    so the fsync starts after the last write completes, and the transaction commits when the
+//    fsync completes. If a write fails or is short, the rest of the chain is cancelled.
+//
+// Up to --inflight transactions are queued at any time, but transactions are still committed in
+// order: the first request of each transaction has IOSQE_IO_DRAIN set, so it does not start before
+// all previously submitted requests (i.e., the previous transactions) have completed. This is a
+// barrier between transactions, which still allows queueing them without waiting for a round trip
+// through userspace.
+//
+// The commit latency of a transaction is measured from queueing its requests until its last cqe is
+// reaped.
+//
+// Example:
+//  iour-wal --mode=fsync --txns=1000 --recs=8 --rec-size=512 /tmp/wal.log
+
+use iouring::io_uring::{self, SqeFlags, ShutdownPolicy, IORING_FSYNC_DATASYNC};
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+// user data: (slot << 8) | op
+const OP_WRITE: u64 = 1;
+const OP_FSYNC: u64 = 2;
+
+// record header: txn id (u64), record index (u32), payload length (u32)
+const HDR_SZ: usize = 16;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Dsync,
+    Fsync,
+}
+
+struct Opts {
+    path: String,
+    mode: Mode,
+    txns: u64,
+    recs: u32,
+    rec_size: usize,
+    inflight: u32,
+}
+
+const USAGE: &str = "[options] <log file>
+options:
+  --mode=<dsync|fsync>  make writes durable with RWF_DSYNC, or a linked fdatasync (default: fsync)
+  --txns=<n>            number of transactions (default: 1000)
+  --recs=<n>            records per transaction (default: 8)
+  --rec-size=<bytes>    size of each record, including its 16-byte header (default: 512)
+  --inflight=<n>        transactions queued at any time (default: 4)";
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Opts, String> {
+    let mut opts = Opts {
+        path: String::new(),
+        mode: Mode::Fsync,
+        txns: 1000,
+        recs: 8,
+        rec_size: 512,
+        inflight: 4,
+    };
+
+    fn parse_num<T: std::str::FromStr + PartialOrd + Default>(v: &str) -> Result<T, String> {
+        v.parse::<T>().ok().filter(|x| *x > T::default()).ok_or(format!("invalid number: {}", v))
+    }
+
+    let mut paths = vec![];
+    for arg in args {
+        let (key, val) = match arg.split_once('=') {
+            Some((k, v)) => (k, Some(v)),
+            None => (arg.as_str(), None),
+        };
+        match (key, val) {
+            ("--mode", Some(v)) => {
+                opts.mode = match v {
+                    "dsync" => Mode::Dsync,
+                    "fsync" => Mode::Fsync,
+                    _ => return Err(format!("invalid --mode value: {}", v)),
+                }
+            }
+            ("--txns", Some(v)) => opts.txns = parse_num(v)?,
+            ("--recs", Some(v)) => opts.recs = parse_num(v)?,
+            ("--rec-size", Some(v)) => opts.rec_size = parse_num(v)?,
+            ("--inflight", Some(v)) => opts.inflight = parse_num(v)?,
+            _ if !arg.starts_with("--") => paths.push(arg),
+            _ => return Err(format!("invalid argument: {}", arg)),
+        }
+    }
+
+    if paths.len() != 1 {
+        return Err("expecting a single log file".to_string());
+    }
+    if opts.rec_size < HDR_SZ {
+        return Err(format!("record size needs to be at least {} bytes", HDR_SZ));
+    }
+    opts.path = paths.pop().unwrap();
+    Ok(opts)
+}
+
+/// A transaction in flight
+struct Txn {
+    id: u64,
+    // the records of the transaction, back to back
+    buf: Vec<u8>,
+    // NB: the kernel reads the iovecs when the requests are issued, which (because of linking and
+    // draining) can be well after they are submitted
+    iovs: Vec<libc::iovec>,
+    // cqes we are still waiting for
+    pending: u32,
+    start: Instant,
+}
+
+struct Wal {
+    ior: io_uring::IoUring,
+    fd: RawFd,
+    mode: Mode,
+    recs: u32,
+    rec_size: usize,
+    // offset of the next record
+    off: u64,
+    slots: Vec<Option<Txn>>,
+    // commit latencies in ns
+    lats: Vec<u64>,
+}
+
+impl Wal {
+
+    fn fill_records(&self, txn: &mut Txn) {
+        let payload = (self.rec_size - HDR_SZ) as u32;
+        for (i, rec) in txn.buf.chunks_mut(self.rec_size).enumerate() {
+            rec[0..8].copy_from_slice(&txn.id.to_le_bytes());
+            rec[8..12].copy_from_slice(&(i as u32).to_le_bytes());
+            rec[12..16].copy_from_slice(&payload.to_le_bytes());
+            rec[HDR_SZ..].fill(txn.id as u8);
+        }
+    }
+
+    /// Queue the requests of transaction id in the given slot
+    fn queue_txn(&mut self, slot: usize, id: u64) {
+        let txn_sz = self.recs as usize * self.rec_size;
+        let mut txn = match self.slots[slot].take() {
+            Some(x) => x,
+            None => Txn {
+                id,
+                buf: vec![0u8; txn_sz],
+                iovs: vec![],
+                pending: 0,
+                start: Instant::now(),
+            },
+        };
+        txn.id = id;
+        self.fill_records(&mut txn);
+        txn.iovs = txn.buf.chunks_mut(self.rec_size)
+            .map(|x| {
+                let iov_base = x.as_mut_ptr() as *mut libc::c_void;
+                libc::iovec { iov_base, iov_len: x.len() }
+            })
+            .collect();
+        txn.pending = 0;
+        txn.start = Instant::now();
+
+        let udata = |op| ((slot as u64) << 8) | op;
+        // NB: the ring has space for all requests of all transactions in flight
+        for (i, iov) in txn.iovs.iter().enumerate() {
+            let mut sqe = self.ior.get_sqe().expect("submission queue is full");
+            sqe.prep_writev(self.fd, iov, 1, self.off);
+            sqe.set_data(udata(OP_WRITE));
+            let mut flags = SqeFlags::empty();
+            if i == 0 {
+                // barrier: start after the previous transactions are done
+                flags |= SqeFlags::IO_DRAIN;
+            }
+            match self.mode {
+                Mode::Dsync => sqe.set_rw_flags(libc::RWF_DSYNC),
+                Mode::Fsync => flags |= SqeFlags::IO_LINK,
+            }
+            sqe.set_flags(flags);
+            self.off += iov.iov_len as u64;
+            txn.pending += 1;
+        }
+        if self.mode == Mode::Fsync {
+            let mut sqe = self.ior.get_sqe().expect("submission queue is full");
+            sqe.prep_fsync(self.fd, IORING_FSYNC_DATASYNC);
+            sqe.set_data(udata(OP_FSYNC));
+            txn.pending += 1;
+        }
+
+        self.slots[slot] = Some(txn);
+    }
+
+    /// Returns true if the transaction of the cqe committed
+    fn handle_cqe(&mut self, cqe: io_uring::io_uring_cqe) -> std::io::Result<bool> {
+        let (slot, op) = ((cqe.user_data() >> 8) as usize, cqe.user_data() & 0xff);
+        let txn = self.slots[slot].as_mut().unwrap();
+        let res = cqe.res();
+        let name = if op == OP_WRITE { "write" } else { "fsync" };
+        if res < 0 {
+            // NB: for fsync chains, a failed (or short) write cancels the rest of the chain, so
+            // the first error reported is the interesting one.
+            let err = std::io::Error::from_raw_os_error(-res);
+            let msg = format!("transaction {}: {} failed: {}", txn.id, name, err);
+            return Err(std::io::Error::new(err.kind(), msg));
+        } else if op == OP_WRITE && res as usize != self.rec_size {
+            // NB: retrying is not an option if other transactions are in flight, since it would
+            // break the order of the log. A real WAL would have to abort and recover.
+            let msg = format!("transaction {}: short write ({} bytes)", txn.id, res);
+            return Err(std::io::Error::other(msg));
+        }
+
+        txn.pending -= 1;
+        if txn.pending > 0 {
+            return Ok(false);
+        }
+        self.lats.push(txn.start.elapsed().as_nanos() as u64);
+        Ok(true)
+    }
+
+    fn run(&mut self, txns: u64) -> std::io::Result<Duration> {
+        let start = Instant::now();
+        let mut next_id = 0;
+        for slot in 0..self.slots.len() {
+            if next_id == txns {
+                break;
+            }
+            self.queue_txn(slot, next_id);
+            next_id += 1;
+        }
+        let mut inflight = next_id;
+
+        while inflight > 0 {
+            self.ior.submit_and_wait(1)?;
+            while let Some(cqe) = self.ior.pop_cqe() {
+                if !self.handle_cqe(cqe)? {
+                    continue;
+                }
+                // reuse the slot for the next transaction
+                if next_id < txns {
+                    self.queue_txn((cqe.user_data() >> 8) as usize, next_id);
+                    next_id += 1;
+                } else {
+                    inflight -= 1;
+                }
+            }
+        }
+
+        Ok(start.elapsed())
+    }
+}
+
+fn report(opts: &Opts, lats: &mut [u64], elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    let n = lats.len();
+    let bytes = n as f64 * opts.recs as f64 * opts.rec_size as f64;
+    let name = match opts.mode {
+        Mode::Dsync => "dsync",
+        Mode::Fsync => "fsync",
+    };
+    println!(
+        "{}: {} txns in {:.2}s: {:.0} txns/s, {:.0} records/s, {:.2} MiB/s",
+        name, n, secs, n as f64 / secs, n as f64 * opts.recs as f64 / secs,
+        bytes / secs / (1024.0 * 1024.0)
+    );
+    if n == 0 {
+        return;
+    }
+
+    lats.sort_unstable();
+    let us = |ns: u64| ns as f64 / 1000.0;
+    let avg = lats.iter().map(|x| *x as f64).sum::<f64>() / n as f64;
+    println!(
+        "  commit lat (usec): min={:.1}, avg={:.1}, max={:.1}",
+        us(lats[0]), avg / 1000.0, us(lats[n - 1])
+    );
+    let pcts = [50.0, 90.0, 99.0, 99.9];
+    let vals: Vec<String> = pcts.iter()
+        .map(|p| {
+            let idx = ((p / 100.0) * n as f64).ceil() as usize;
+            format!("{:.1}th=[{:.1}]", p, us(lats[idx.clamp(1, n) - 1]))
+        })
+        .collect();
+    println!("  commit lat percentiles (usec): {}", vals.join(", "));
+}
+
+pub fn main() {
+    let mut args = std::env::args();
+    let arg0 = args.next().unwrap();
+    let opts = match parse_args(args) {
+        Ok(x) => x,
+        Err(e) => {
+            let pname = std::path::Path::new(&arg0).file_name().unwrap().to_str()
+                .unwrap_or("iour-wal");
+            eprintln!("{}\nUsage: {} {}", e, pname, USAGE);
+            std::process::exit(-1);
+        }
+    };
+
+    let f = match std::fs::File::create(&opts.path) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to create {}: {}", opts.path, e);
+            std::process::exit(-1);
+        }
+    };
+
+    // requests per transaction
+    let txn_reqs = opts.recs + if opts.mode == Mode::Fsync { 1 } else { 0 };
+    let mut ior = match io_uring::IoUring::init(opts.inflight * txn_reqs) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to initialize io_uring: {}", e);
+            std::process::exit(-1);
+        }
+    };
+    // NB: the ring is dropped before the transaction buffers, so make sure no requests use them
+    ior.set_drop_policy(ShutdownPolicy::CancelAndWait);
+
+    let mut wal = Wal {
+        ior,
+        fd: f.as_raw_fd(),
+        mode: opts.mode,
+        recs: opts.recs,
+        rec_size: opts.rec_size,
+        off: 0,
+        slots: (0..opts.inflight).map(|_| None).collect(),
+        lats: vec![],
+    };
+
+    match wal.run(opts.txns) {
+        Ok(elapsed) => report(&opts, &mut wal.lats, elapsed),
+        Err(e) => {
+            eprintln!("{}: {}", opts.path, e);
+            std::process::exit(-1);
+        }
+    }
+}
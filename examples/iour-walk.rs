@@ -0,0 +1,201 @@
+/*
+ * Kornilios Kourtis <kkourt@kkourt.io>
+ *
+ * vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+ */
+
+// Walk a directory tree, and stat every entry via STATX requests
+//
+// Directories are listed synchronously (there is no getdents request), but up to QD statx calls
+// are in flight at any time. The path and the statx buffer of each request are kept in a slab,
+// and the user data of the request is its index in the slab. When a request completes, its index
+// gives us back the path it was for, and the slot is reused for the next request.
+//
+// Prints a summary like du -s (or every entry with -v).
+
+use iouring::io_uring;
+
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+const QD: u32 = 128;
+
+/// A statx request in flight
+struct Req {
+    path: PathBuf,
+    // NB: the kernel uses these until the request completes
+    cpath: CString,
+    stx: Box<libc::statx>,
+}
+
+#[derive(Default)]
+struct Stats {
+    files: u64,
+    dirs: u64,
+    others: u64,
+    bytes: u64,
+    // allocated bytes
+    alloc: u64,
+    errors: u64,
+}
+
+struct Walker {
+    ior: io_uring::IoUring,
+    verbose: bool,
+    // requests in flight, indexed by their user data
+    slab: Vec<Option<Req>>,
+    free: Vec<usize>,
+    // paths to stat, and directories to list
+    todo: VecDeque<PathBuf>,
+    dirs: VecDeque<PathBuf>,
+    stats: Stats,
+}
+
+impl Walker {
+
+    fn queue_statx(&mut self, path: PathBuf) {
+        let cpath = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(x) => x,
+            Err(_) => {
+                eprintln!("{}: path contains a nul byte", path.display());
+                self.stats.errors += 1;
+                return;
+            }
+        };
+        let idx = self.free.pop().expect("no free slots");
+        let mut req = Req { path, cpath, stx: Box::new(unsafe { std::mem::zeroed() }) };
+
+        // NB: there is one slot per sq entry, so there is always space
+        let mut sqe = self.ior.get_sqe().expect("submission queue is full");
+        let flags = libc::AT_SYMLINK_NOFOLLOW;
+        let mask = libc::STATX_TYPE | libc::STATX_SIZE | libc::STATX_BLOCKS;
+        sqe.prep_statx(libc::AT_FDCWD, req.cpath.as_ptr(), flags, mask, &mut *req.stx);
+        sqe.set_data(idx as u64);
+        self.slab[idx] = Some(req);
+    }
+
+    fn handle_cqe(&mut self, cqe: io_uring::io_uring_cqe) {
+        let idx = cqe.user_data() as usize;
+        let req = self.slab[idx].take().unwrap();
+        self.free.push(idx);
+
+        let res = cqe.res();
+        if res < 0 {
+            let err = std::io::Error::from_raw_os_error(-res);
+            eprintln!("{}: {}", req.path.display(), err);
+            self.stats.errors += 1;
+            return;
+        }
+
+        let stx = &req.stx;
+        let alloc = stx.stx_blocks * 512;
+        match stx.stx_mode as libc::mode_t & libc::S_IFMT {
+            libc::S_IFDIR => {
+                self.stats.dirs += 1;
+                self.dirs.push_back(req.path.clone());
+            }
+            libc::S_IFREG => self.stats.files += 1,
+            _ => self.stats.others += 1,
+        }
+        self.stats.bytes += stx.stx_size;
+        self.stats.alloc += alloc;
+        if self.verbose {
+            println!("{:>12} {}", stx.stx_size, req.path.display());
+        }
+    }
+
+    /// List the next directory, adding its entries to todo
+    fn list_dir(&mut self) {
+        let dir = self.dirs.pop_front().unwrap();
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("{}: {}", dir.display(), e);
+                self.stats.errors += 1;
+                return;
+            }
+        };
+        for entry in entries {
+            match entry {
+                Ok(e) => self.todo.push_back(e.path()),
+                Err(e) => {
+                    eprintln!("{}: {}", dir.display(), e);
+                    self.stats.errors += 1;
+                }
+            }
+        }
+    }
+
+    fn walk(&mut self, root: PathBuf) -> std::io::Result<()> {
+        self.todo.push_back(root);
+        loop {
+            // fill the queue
+            while !self.free.is_empty() {
+                if let Some(path) = self.todo.pop_front() {
+                    self.queue_statx(path);
+                } else if !self.dirs.is_empty() {
+                    self.list_dir();
+                } else {
+                    break;
+                }
+            }
+
+            if self.free.len() == self.slab.len() {
+                // nothing in flight, and nothing left to do
+                return Ok(());
+            }
+
+            self.ior.submit_and_wait(1)?;
+            while let Some(cqe) = self.ior.pop_cqe() {
+                self.handle_cqe(cqe);
+            }
+        }
+    }
+}
+
+pub fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let verbose = args.iter().skip(1).any(|x| x == "-v");
+    let paths: Vec<&String> = args.iter().skip(1).filter(|x| *x != "-v").collect();
+    if paths.len() != 1 {
+        let pname = std::path::Path::new(&args[0]).file_name().unwrap().to_str()
+            .unwrap_or("iour-walk");
+        eprintln!("Usage: {} [-v] <dir>", pname);
+        std::process::exit(-1);
+    }
+
+    let ior = match io_uring::IoUring::init(QD) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to initialize io_uring: {}", e);
+            std::process::exit(-1);
+        }
+    };
+
+    let mut walker = Walker {
+        ior,
+        verbose,
+        slab: (0..QD).map(|_| None).collect(),
+        free: (0..QD as usize).collect(),
+        todo: VecDeque::new(),
+        dirs: VecDeque::new(),
+        stats: Stats::default(),
+    };
+
+    let start = std::time::Instant::now();
+    if let Err(e) = walker.walk(PathBuf::from(paths[0])) {
+        eprintln!("Walk failed: {}", e);
+        std::process::exit(-1);
+    }
+    let secs = start.elapsed().as_secs_f64();
+
+    let s = &walker.stats;
+    let entries = s.files + s.dirs + s.others;
+    println!(
+        "{} entries ({} files, {} dirs, {} other), {} bytes ({} allocated), {} errors",
+        entries, s.files, s.dirs, s.others, s.bytes, s.alloc, s.errors
+    );
+    println!("{:.3}s, {:.0} entries/s", secs, entries as f64 / secs);
+}
@@ -0,0 +1,22 @@
+# Kornilios Kourtis <kkourt@kkourt.io>
+#
+# vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+#
+# Build (and, if possible, test) the crate for 32-bit targets using cross
+# (https://github.com/cross-rs/cross). The kernel ABI layout is checked at
+# compile time, so a successful build is already a meaningful check.
+
+set -e
+
+targets="i686-unknown-linux-gnu armv7-unknown-linux-gnueabihf"
+if [ -n "$1" ]; then
+    targets="$@"
+fi
+
+for t in $targets; do
+    echo "==> $t"
+    cross build --target $t
+    # NB: tests run under qemu-user for non-native targets, which may not
+    # support io_uring syscalls.
+    cross test --target $t --lib || echo "WARNING: tests failed for $t"
+done
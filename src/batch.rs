@@ -0,0 +1,258 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Running many requests with a bounded number in flight
+//
+// Issuing a large (or unbounded) sequence of requests at once overruns the ring: the submission
+// queue fills up, and cqes beyond the CQ ring size go to the overflow list (or are dropped, on
+// kernels without FEAT_NODROP). Batch keeps at most a window of requests in flight, and issues the
+// next request as each one completes. The window defaults to the number of SQ entries, so that a
+// window of requests fits the submission queue, and their cqes fit the CQ ring.
+//
+// The user data of the requests is the tag (top 8 bits) and the index of the request in the
+// sequence.
+
+use std::collections::VecDeque;
+use std::io;
+use std::iter::Enumerate;
+
+use crate::io_uring::{io_uring_cqe, IoUring, SQEntry};
+
+const TAG_SHIFT: u32 = 56;
+const IDX_MASK: u64 = (1 << TAG_SHIFT) - 1;
+
+/// Requests run with a bounded number in flight (see [`Self::for_each_op`])
+///
+/// ```no_run
+/// # use iouring::io_uring::IoUring;
+/// # use iouring::batch::Batch;
+/// # let fd = 0;
+/// # let mut bufs = vec![vec![0u8; 4096]; 1024];
+/// let iovs: Vec<_> = bufs.iter_mut()
+///     .map(|b| libc::iovec { iov_base: b.as_mut_ptr() as _, iov_len: b.len() })
+///     .collect();
+/// let mut ring = IoUring::init(32).unwrap();
+/// let batch = Batch::new(0xfa);
+/// // at most 32 reads are in flight at any time
+/// let ops = iovs.iter().enumerate().map(|(i, iov)| {
+///     move |sqe: &mut iouring::io_uring::SQEntry| sqe.prep_readv(fd, iov, 1, (i * 4096) as u64)
+/// });
+/// let cqes = batch.join_all_ops(&mut ring, ops, |_other| ()).unwrap();
+/// ```
+///
+/// The requests need to post a single cqe each (i.e., no multishot requests, and no
+/// IOSQE_CQE_SKIP_SUCCESS).
+pub struct Batch {
+    tag: u64,
+    limit: Option<usize>,
+}
+
+impl Batch {
+
+    /// Batch whose requests use the given tag in the top 8 bits of their user data
+    ///
+    /// No other requests on the ring should have user data with this tag.
+    pub fn new(tag: u8) -> Batch {
+        Batch {
+            tag: (tag as u64) << TAG_SHIFT,
+            limit: None,
+        }
+    }
+
+    /// Like [`Self::new`], but with at most limit requests in flight
+    pub fn with_limit(tag: u8, limit: usize) -> Batch {
+        Batch {
+            limit: Some(limit),
+            ..Batch::new(tag)
+        }
+    }
+
+    /// Whether the cqe is for a request of this batch
+    pub fn owns(&self, cqe: &io_uring_cqe) -> bool {
+        cqe.user_data() >> TAG_SHIFT == self.tag >> TAG_SHIFT
+    }
+
+    /// Maximum number of requests in flight on ring: the limit, if any, capped to the number of
+    /// SQ entries (and at least 1)
+    pub fn window(&self, ring: &IoUring) -> usize {
+        let sq_entries = ring.geometry().sq_entries as usize;
+        self.limit.unwrap_or(sq_entries).clamp(1, sq_entries)
+    }
+
+    /// Run the requests of ops, prepared by calling each of them on an sqe, with at most
+    /// [`Self::window`] of them in flight, and pass the cqe of each request, with its index in
+    /// ops, to f
+    ///
+    /// The ops do not need to set the user data: it is overwritten. Cqes that are not for this
+    /// batch are passed to other. Returns once all requests have completed. If submitting fails,
+    /// the error is returned, and requests may remain in flight.
+    pub fn for_each_op<I, P, F, O>(&self, ring: &mut IoUring, ops: I, mut f: F, other: O)
+    -> io::Result<()>
+    where
+        I: IntoIterator<Item = P>,
+        P: FnOnce(&mut SQEntry),
+        F: FnMut(usize, io_uring_cqe),
+        O: FnMut(io_uring_cqe),
+    {
+        for x in self.sink(ring, ops, other) {
+            let (i, cqe) = x?;
+            f(i, cqe);
+        }
+        Ok(())
+    }
+
+    /// Like [`Self::for_each_op`], but as an iterator over the cqes of the requests, with their
+    /// index in ops, in completion order
+    ///
+    /// Requests are issued as the iterator is advanced: ops is consumed lazily, so it can be
+    /// unbounded, or generate the requests as it goes. E.g., to fsync a list of files:
+    ///
+    /// ```no_run
+    /// # use iouring::io_uring::IoUring;
+    /// # use iouring::batch::Batch;
+    /// # let files: Vec<std::fs::File> = vec![];
+    /// # let mut ring = IoUring::init(32).unwrap();
+    /// use std::os::unix::io::AsRawFd;
+    /// let ops = files.iter().map(|f| {
+    ///     let fd = f.as_raw_fd();
+    ///     move |sqe: &mut iouring::io_uring::SQEntry| sqe.prep_fsync(fd, 0)
+    /// });
+    /// let failed = Batch::new(0xfa).sink(&mut ring, ops, drop)
+    ///     .filter(|x| x.as_ref().map_or(true, |(_, cqe)| cqe.res() < 0))
+    ///     .count();
+    /// ```
+    ///
+    /// If submitting fails, the iterator returns the error, and it can be advanced again to retry.
+    pub fn sink<'r, I, P, O>(&self, ring: &'r mut IoUring, ops: I, other: O)
+    -> Sink<'r, I::IntoIter, O>
+    where
+        I: IntoIterator<Item = P>,
+        P: FnOnce(&mut SQEntry),
+        O: FnMut(io_uring_cqe),
+    {
+        Sink {
+            tag: self.tag,
+            window: self.window(ring),
+            ring,
+            ops: ops.into_iter().enumerate(),
+            next_op: None,
+            done: false,
+            inflight: 0,
+            ready: VecDeque::new(),
+            other,
+        }
+    }
+
+    /// Like [`Self::for_each_op`], but return the cqes of the requests, in request order
+    pub fn join_all_ops<I, P, O>(&self, ring: &mut IoUring, ops: I, other: O)
+    -> io::Result<Vec<io_uring_cqe>>
+    where
+        I: IntoIterator<Item = P>,
+        P: FnOnce(&mut SQEntry),
+        O: FnMut(io_uring_cqe),
+    {
+        let mut cqes: Vec<Option<io_uring_cqe>> = vec![];
+        self.for_each_op(ring, ops, |i, cqe| {
+            if cqes.len() <= i {
+                cqes.resize(i + 1, None);
+            }
+            cqes[i] = Some(cqe);
+        }, other)?;
+        Ok(cqes.into_iter().map(|x| x.unwrap()).collect())
+    }
+}
+
+/// Iterator over the cqes of requests run by a [`Batch`] (see [`Batch::sink`])
+pub struct Sink<'r, I: Iterator, O> {
+    tag: u64,
+    window: usize,
+    ring: &'r mut IoUring,
+    ops: Enumerate<I>,
+    // an op that we could not queue, because submitting failed
+    next_op: Option<(usize, I::Item)>,
+    done: bool,
+    inflight: usize,
+    // cqes of the batch that we have reaped, but not returned yet
+    ready: VecDeque<(usize, io_uring_cqe)>,
+    other: O,
+}
+
+impl<I, P, O> Sink<'_, I, O>
+where
+    I: Iterator<Item = P>,
+    P: FnOnce(&mut SQEntry),
+    O: FnMut(io_uring_cqe),
+{
+    /// Number of requests in flight
+    pub fn inflight(&self) -> usize {
+        self.inflight
+    }
+
+    // Queue ops until the window is full, or there are no more ops
+    fn fill(&mut self) -> io::Result<()> {
+        while self.inflight < self.window {
+            let (i, prep) = match self.next_op.take() {
+                Some(x) => x,
+                None => match self.ops.next() {
+                    Some(x) => x,
+                    None => {
+                        self.done = true;
+                        return Ok(());
+                    }
+                },
+            };
+            match self.ring.get_sqe() {
+                Some(mut sqe) => {
+                    prep(&mut sqe);
+                    sqe.set_data(self.tag | (i as u64 & IDX_MASK));
+                }
+                None => {
+                    self.next_op = Some((i, prep));
+                    self.ring.submit()?;
+                    continue;
+                }
+            }
+            self.inflight += 1;
+        }
+        Ok(())
+    }
+}
+
+impl<I, P, O> Iterator for Sink<'_, I, O>
+where
+    I: Iterator<Item = P>,
+    P: FnOnce(&mut SQEntry),
+    O: FnMut(io_uring_cqe),
+{
+    type Item = io::Result<(usize, io_uring_cqe)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(x) = self.ready.pop_front() {
+                return Some(Ok(x));
+            }
+            if !self.done {
+                if let Err(e) = self.fill() {
+                    return Some(Err(e));
+                }
+            }
+            if self.inflight == 0 {
+                return None;
+            }
+            if let Err(e) = self.ring.submit_and_wait(1) {
+                return Some(Err(e));
+            }
+            while let Some(cqe) = self.ring.pop_cqe() {
+                if cqe.user_data() >> TAG_SHIFT == self.tag >> TAG_SHIFT {
+                    self.inflight -= 1;
+                    self.ready.push_back(((cqe.user_data() & IDX_MASK) as usize, cqe));
+                } else {
+                    (self.other)(cqe);
+                }
+            }
+        }
+    }
+}
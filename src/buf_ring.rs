@@ -0,0 +1,278 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Provided buffer rings (IORING_REGISTER_PBUF_RING, Linux 5.19)
+//
+// A buffer ring is a ring of buffer descriptors shared with the kernel. Requests submitted with
+// IOSQE_BUFFER_SELECT and a buffer group id pick a buffer from the ring of that group when they
+// are ready to transfer data, and report the id of the buffer they used in their cqe (see
+// io_uring_cqe::buffer_id()). The application hands buffers (back) to the kernel by adding them to
+// the ring.
+//
+// With incremental consumption (IOU_PBUF_RING_INC, Linux 6.12), a request uses only as much of a
+// buffer as it needs, and the rest of the buffer stays in the ring for the next ones. The cqe sets
+// IORING_CQE_F_BUF_MORE if the kernel kept (the rest of) the buffer. The data of a request starts
+// where the data of the previous request that used the buffer ended, so this allows a few large
+// buffers to serve many small receives. [`BufRing::consume`] keeps track of the offsets.
+//
+// The ring memory is either allocated by us and registered with the kernel, or allocated by the
+// kernel and mapped by us (IOU_PBUF_RING_MMAP, Linux 6.4).
+//
+// Reference: io_uring_setup_buf_ring(3), io_uring_buf_ring_add(3)
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+use crate::io_uring::{io_uring_cqe, io_uring_register, IoUring};
+use crate::io_uring::{IORING_REGISTER_PBUF_RING, IORING_UNREGISTER_PBUF_RING};
+
+#[repr(C)]
+struct io_uring_buf {
+    addr: u64,
+    len: u32,
+    bid: u16,
+    // NB: the resv field of the first entry is the ring tail
+    resv: u16,
+}
+
+#[repr(C)]
+struct io_uring_buf_reg {
+    ring_addr: u64,
+    ring_entries: u32,
+    bgid: u16,
+    flags: u16,
+    resv: [u64; 3],
+}
+
+// io_uring_buf_reg->flags
+const IOU_PBUF_RING_MMAP: u16 = 1;
+const IOU_PBUF_RING_INC: u16 = 2;
+
+// mmap offset of the ring of buffer group bgid, for IOU_PBUF_RING_MMAP:
+// IORING_OFF_PBUF_RING | (bgid << IORING_OFF_PBUF_SHIFT)
+const IORING_OFF_PBUF_RING: u64 = 0x80000000;
+const IORING_OFF_PBUF_SHIFT: u32 = 16;
+
+const _: () = assert!(std::mem::size_of::<io_uring_buf>() == 16);
+const _: () = assert!(std::mem::size_of::<io_uring_buf_reg>() == 40);
+
+/// A provided buffer ring registered with an io_uring
+pub struct BufRing {
+    bufs: *mut io_uring_buf,
+    mem_sz: libc::size_t,
+    entries: u16,
+    bgid: u16,
+    // tail as seen by the kernel
+    tail: u16,
+    // number of buffers added, but not yet made visible to the kernel
+    pending: u16,
+    // for incremental consumption, the offset of the unused part of each buffer, by buffer id
+    offsets: Option<Vec<u32>>,
+}
+
+impl BufRing {
+
+    /// Allocate a buffer ring of `entries` entries (a power of two, up to 32768) and register it
+    /// with the given io_uring as buffer group `bgid`.
+    pub fn register(ior: &IoUring, bgid: u16, entries: u16) -> io::Result<BufRing> {
+        BufRing::register_with_flags(ior, bgid, entries, 0)
+    }
+
+    /// Like [`Self::register`], but requests consume the buffers incrementally (Linux 6.12)
+    ///
+    /// Use [`Self::consume`] to find the data of each cqe in its buffer.
+    #[cfg(feature = "linux-6_13")]
+    pub fn register_incremental(ior: &IoUring, bgid: u16, entries: u16) -> io::Result<BufRing> {
+        BufRing::register_with_flags(ior, bgid, entries, IOU_PBUF_RING_INC)
+    }
+
+    /// Like [`Self::register`], but the kernel allocates the ring memory, and we map it
+    /// (IOU_PBUF_RING_MMAP, Linux 6.4)
+    ///
+    /// This spares the application from providing suitably aligned memory for the ring, and the
+    /// kernel from pinning application pages.
+    #[cfg(feature = "linux-6_4")]
+    pub fn register_mmap(ior: &IoUring, bgid: u16, entries: u16) -> io::Result<BufRing> {
+        BufRing::register_with_flags(ior, bgid, entries, IOU_PBUF_RING_MMAP)
+    }
+
+    fn register_with_flags(
+        ior: &IoUring,
+        bgid: u16,
+        entries: u16,
+        flags: u16,
+    ) -> io::Result<BufRing> {
+        if !entries.is_power_of_two() || entries > 32768 {
+            let msg = format!("invalid number of buffer ring entries: {}", entries);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+
+        let mem_sz = entries as libc::size_t * std::mem::size_of::<io_uring_buf>();
+        let kernel_mem = flags & IOU_PBUF_RING_MMAP != 0;
+        let mut ret = BufRing {
+            bufs: std::ptr::null_mut(),
+            mem_sz,
+            entries,
+            bgid,
+            tail: 0,
+            pending: 0,
+            offsets: if flags & IOU_PBUF_RING_INC != 0 { Some(Vec::new()) } else { None },
+        };
+        if !kernel_mem {
+            // NB: the kernel requires the ring to be page-aligned, which anonymous mappings are
+            let ptr = unsafe {
+                let prot = libc::PROT_READ | libc::PROT_WRITE;
+                let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+                libc::mmap(std::ptr::null_mut(), mem_sz, prot, flags, -1, 0)
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            ret.bufs = ptr as *mut io_uring_buf;
+        }
+
+        let mut reg = io_uring_buf_reg {
+            ring_addr: ret.bufs as usize as u64,
+            ring_entries: entries as u32,
+            bgid,
+            flags,
+            resv: [0; 3],
+        };
+        let err = unsafe {
+            let arg = &mut reg as *mut io_uring_buf_reg as *mut libc::c_void;
+            io_uring_register(ior.as_raw_fd(), IORING_REGISTER_PBUF_RING, arg, 1)
+        };
+        if err < 0 {
+            // NB: ret is dropped here, which unmaps the ring memory
+            return Err(io::Error::last_os_error());
+        }
+
+        if kernel_mem {
+            // NB: the offset does not fit in a 32-bit off_t, so this fails on 32-bit targets
+            let off = IORING_OFF_PBUF_RING | (bgid as u64) << IORING_OFF_PBUF_SHIFT;
+            let ptr = unsafe {
+                let prot = libc::PROT_READ | libc::PROT_WRITE;
+                let flags = libc::MAP_SHARED | libc::MAP_POPULATE;
+                libc::mmap(std::ptr::null_mut(), mem_sz, prot, flags, ior.as_raw_fd(),
+                    off as libc::off_t)
+            };
+            if ptr == libc::MAP_FAILED {
+                let error = io::Error::last_os_error();
+                if let Err(err) = ret.unregister(ior) {
+                    eprintln!("WARNING: unregistering buffer ring failed: {}", err);
+                }
+                return Err(error);
+            }
+            ret.bufs = ptr as *mut io_uring_buf;
+        }
+
+        Ok(ret)
+    }
+
+    /// Unregister the buffer ring from the io_uring
+    ///
+    /// After this returns, the kernel will no longer pick any buffers from the ring.
+    pub fn unregister(self, ior: &IoUring) -> io::Result<()> {
+        let mut reg: io_uring_buf_reg = unsafe { std::mem::zeroed() };
+        reg.bgid = self.bgid;
+        let err = unsafe {
+            let arg = &mut reg as *mut io_uring_buf_reg as *mut libc::c_void;
+            io_uring_register(ior.as_raw_fd(), IORING_UNREGISTER_PBUF_RING, arg, 1)
+        };
+        if err < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// The buffer group id of the ring
+    pub fn bgid(&self) -> u16 {
+        self.bgid
+    }
+
+    /// Number of entries of the ring
+    pub fn entries(&self) -> u16 {
+        self.entries
+    }
+
+    /// Add a buffer to the ring. The buffer becomes visible to the kernel after [`Self::commit`].
+    ///
+    /// # Safety
+    ///
+    /// The kernel may write up to `len` bytes into `addr` at any point until the buffer is
+    /// returned in a cqe, or the ring is unregistered (or the io_uring is closed).
+    pub unsafe fn add(&mut self, addr: *mut u8, len: u32, bid: u16) {
+        let mask = self.entries - 1;
+        let idx = self.tail.wrapping_add(self.pending) & mask;
+        let buf = &mut *self.bufs.add(idx as usize);
+        buf.addr = addr as usize as u64;
+        buf.len = len;
+        buf.bid = bid;
+        self.pending += 1;
+        if let Some(off) = self.offsets.as_mut().and_then(|x| x.get_mut(bid as usize)) {
+            *off = 0;
+        }
+    }
+
+    /// Whether requests consume the buffers incrementally (see register_incremental())
+    pub fn is_incremental(&self) -> bool {
+        self.offsets.is_some()
+    }
+
+    /// The id of the buffer that cqe used, and the range of its data in the buffer
+    ///
+    /// cqe needs to be of a request that selected a buffer from this ring, and each such cqe needs
+    /// to be passed here once, in order. For rings without incremental consumption, the data is
+    /// always at the start of the buffer. Returns None if the cqe has no buffer.
+    ///
+    /// The buffer is returned to the application if the cqe does not have IORING_CQE_F_BUF_MORE
+    /// set (see [`io_uring_cqe::buffer_more`]), and it can be added again after its data is used.
+    pub fn consume(&mut self, cqe: &io_uring_cqe) -> Option<(u16, std::ops::Range<usize>)> {
+        let bid = cqe.buffer_id()?;
+        let len = std::cmp::max(cqe.res(), 0) as u32;
+        let offsets = match self.offsets.as_mut() {
+            Some(x) => x,
+            None => return Some((bid, 0..len as usize)),
+        };
+        if offsets.len() <= bid as usize {
+            offsets.resize(bid as usize + 1, 0);
+        }
+        let off = offsets[bid as usize];
+        offsets[bid as usize] = if cqe.buffer_more() { off + len } else { 0 };
+        Some((bid, off as usize..(off + len) as usize))
+    }
+
+    /// Make the buffers added via [`Self::add`] visible to the kernel
+    pub fn commit(&mut self) {
+        if self.pending == 0 {
+            return;
+        }
+        self.tail = self.tail.wrapping_add(self.pending);
+        self.pending = 0;
+        // The release ensures that the kernel sees the buffer descriptors before the new tail.
+        unsafe {
+            let tail_p = std::ptr::addr_of_mut!((*self.bufs).resv) as *const AtomicU16;
+            (*tail_p).store(self.tail, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for BufRing {
+    fn drop(&mut self) {
+        if self.bufs.is_null() {
+            return;
+        }
+        // NB: the kernel pins the ring pages when registering it, so unmapping them while the ring
+        // is still registered is fine. Kernel-allocated rings are freed when they are both
+        // unregistered and unmapped.
+        let err = unsafe { libc::munmap(self.bufs as *mut libc::c_void, self.mem_sz) };
+        if err != 0 {
+            let error = io::Error::last_os_error();
+            eprintln!("WARNING: munmap() of buffer ring failed: {}", error);
+        }
+    }
+}
@@ -0,0 +1,348 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+//! liburing-style names for the ring API
+//!
+//! These are thin wrappers, named after their liburing counterparts, to help with porting C code
+//! mechanically. The translation is not one-to-one:
+//!  - errors are returned as `io::Result` instead of negative errno values.
+//!  - cqes are returned by value, and their slot is released to the kernel when they are returned
+//!    (see [`IoUring::pop_cqe`]), so [`io_uring_cqe_seen`] is a no-op.
+//!  - an sqe mutably borrows the ring, so it has to go out of scope before the ring can be
+//!    submitted.
+
+use crate::io_uring::{io_uring_cqe, IoUring, SQEntry, SetupFlags, SqeFlags};
+use crate::io_uring::{KernelTimespec, TimeoutFlags};
+
+use std::io;
+use std::os::unix::io::RawFd;
+
+/*
+ * setup and teardown
+ */
+
+/// liburing: io_uring_queue_init()
+pub fn io_uring_queue_init(entries: u32, flags: SetupFlags) -> io::Result<IoUring> {
+    IoUring::init_with_flags(entries, flags)
+}
+
+/// liburing: io_uring_queue_exit()
+pub fn io_uring_queue_exit(ring: IoUring) {
+    drop(ring)
+}
+
+/// liburing: io_uring_register_buffers()
+pub fn io_uring_register_buffers(ring: &mut IoUring, iovecs: &[io::IoSliceMut]) -> io::Result<()> {
+    ring.register_buffers(iovecs)
+}
+
+/// liburing: io_uring_unregister_buffers()
+pub fn io_uring_unregister_buffers(ring: &mut IoUring) -> io::Result<()> {
+    ring.unregister_buffers()
+}
+
+/// liburing: io_uring_register_files()
+pub fn io_uring_register_files(ring: &mut IoUring, files: &[RawFd]) -> io::Result<()> {
+    ring.register_files(files)
+}
+
+/// liburing: io_uring_unregister_files()
+pub fn io_uring_unregister_files(ring: &mut IoUring) -> io::Result<()> {
+    ring.unregister_files()
+}
+
+/*
+ * submission
+ */
+
+/// liburing: io_uring_get_sqe()
+pub fn io_uring_get_sqe(ring: &mut IoUring) -> Option<SQEntry<'_>> {
+    ring.get_sqe()
+}
+
+/// liburing: io_uring_sqe_set_data64()
+pub fn io_uring_sqe_set_data(sqe: &mut SQEntry, data: u64) {
+    sqe.set_data(data)
+}
+
+/// liburing: io_uring_sqe_set_flags()
+pub fn io_uring_sqe_set_flags(sqe: &mut SQEntry, flags: SqeFlags) {
+    sqe.set_flags(flags)
+}
+
+/// liburing: io_uring_submit()
+pub fn io_uring_submit(ring: &mut IoUring) -> io::Result<u32> {
+    ring.submit()
+}
+
+/// liburing: io_uring_submit_and_wait()
+pub fn io_uring_submit_and_wait(ring: &mut IoUring, wait_nr: u32) -> io::Result<u32> {
+    ring.submit_and_wait(wait_nr)
+}
+
+/// liburing: io_uring_sq_space_left()
+pub fn io_uring_sq_space_left(ring: &IoUring) -> u32 {
+    ring.sq_space_left()
+}
+
+/*
+ * prep functions
+ */
+
+/// liburing: io_uring_prep_readv()
+pub fn io_uring_prep_readv(
+    sqe: &mut SQEntry,
+    fd: RawFd,
+    iovecs: *const libc::iovec,
+    nr_vecs: u32,
+    offset: u64,
+) {
+    sqe.prep_readv(fd, iovecs, nr_vecs, offset)
+}
+
+/// liburing: io_uring_prep_writev()
+pub fn io_uring_prep_writev(
+    sqe: &mut SQEntry,
+    fd: RawFd,
+    iovecs: *const libc::iovec,
+    nr_vecs: u32,
+    offset: u64,
+) {
+    sqe.prep_writev(fd, iovecs, nr_vecs, offset)
+}
+
+/// liburing: io_uring_prep_read_fixed()
+pub fn io_uring_prep_read_fixed(
+    sqe: &mut SQEntry,
+    fd: RawFd,
+    buf: *mut u8,
+    nbytes: u32,
+    offset: u64,
+    buf_index: u16,
+) {
+    sqe.prep_read_fixed(fd, buf, nbytes, offset, buf_index)
+}
+
+/// liburing: io_uring_prep_write_fixed()
+pub fn io_uring_prep_write_fixed(
+    sqe: &mut SQEntry,
+    fd: RawFd,
+    buf: *const u8,
+    nbytes: u32,
+    offset: u64,
+    buf_index: u16,
+) {
+    sqe.prep_write_fixed(fd, buf, nbytes, offset, buf_index)
+}
+
+/// liburing: io_uring_prep_fsync()
+pub fn io_uring_prep_fsync(sqe: &mut SQEntry, fd: RawFd, fsync_flags: u32) {
+    sqe.prep_fsync(fd, fsync_flags)
+}
+
+/// liburing: io_uring_prep_poll_add()
+pub fn io_uring_prep_poll_add(sqe: &mut SQEntry, fd: RawFd, poll_mask: u32) {
+    sqe.prep_poll_add(fd, poll_mask)
+}
+
+/// liburing: io_uring_prep_poll_multishot()
+#[cfg(feature = "linux-5_15")]
+pub fn io_uring_prep_poll_multishot(sqe: &mut SQEntry, fd: RawFd, poll_mask: u32) {
+    sqe.prep_poll_multishot(fd, poll_mask)
+}
+
+/// liburing: io_uring_prep_timeout()
+pub fn io_uring_prep_timeout(
+    sqe: &mut SQEntry,
+    ts: *const KernelTimespec,
+    count: u32,
+    flags: TimeoutFlags,
+) {
+    sqe.prep_timeout(ts, count, flags)
+}
+
+/// liburing: io_uring_prep_timeout_remove()
+pub fn io_uring_prep_timeout_remove(sqe: &mut SQEntry, user_data: u64) {
+    sqe.prep_timeout_remove(user_data)
+}
+
+/// liburing: io_uring_prep_link_timeout()
+pub fn io_uring_prep_link_timeout(
+    sqe: &mut SQEntry,
+    ts: *const KernelTimespec,
+    flags: TimeoutFlags,
+) {
+    sqe.prep_link_timeout(ts, flags)
+}
+
+/// liburing: io_uring_prep_cancel64()
+pub fn io_uring_prep_cancel(sqe: &mut SQEntry, user_data: u64) {
+    sqe.prep_cancel(user_data)
+}
+
+/// liburing: io_uring_prep_accept()
+pub fn io_uring_prep_accept(
+    sqe: &mut SQEntry,
+    fd: RawFd,
+    addr: *mut libc::sockaddr,
+    addrlen: *mut libc::socklen_t,
+    flags: libc::c_int,
+) {
+    sqe.prep_accept(fd, addr, addrlen, flags)
+}
+
+/// liburing: io_uring_prep_multishot_accept()
+#[cfg(feature = "linux-5_19")]
+pub fn io_uring_prep_multishot_accept(
+    sqe: &mut SQEntry,
+    fd: RawFd,
+    addr: *mut libc::sockaddr,
+    addrlen: *mut libc::socklen_t,
+    flags: libc::c_int,
+) {
+    sqe.prep_multishot_accept(fd, addr, addrlen, flags)
+}
+
+/// liburing: io_uring_prep_send()
+#[cfg(feature = "linux-5_6")]
+pub fn io_uring_prep_send(
+    sqe: &mut SQEntry,
+    sockfd: RawFd,
+    buf: *const libc::c_void,
+    len: u32,
+    flags: libc::c_int,
+) {
+    sqe.prep_send(sockfd, buf, len, flags)
+}
+
+/// liburing: io_uring_prep_recv()
+#[cfg(feature = "linux-5_6")]
+pub fn io_uring_prep_recv(
+    sqe: &mut SQEntry,
+    sockfd: RawFd,
+    buf: *mut libc::c_void,
+    len: u32,
+    flags: libc::c_int,
+) {
+    sqe.prep_recv(sockfd, buf, len, flags)
+}
+
+/// liburing: io_uring_prep_splice()
+#[cfg(feature = "linux-5_15")]
+pub fn io_uring_prep_splice(
+    sqe: &mut SQEntry,
+    fd_in: RawFd,
+    off_in: i64,
+    fd_out: RawFd,
+    off_out: i64,
+    nbytes: u32,
+    splice_flags: u32,
+) {
+    sqe.prep_splice(fd_in, off_in, fd_out, off_out, nbytes, splice_flags)
+}
+
+/// liburing: io_uring_prep_tee()
+#[cfg(feature = "linux-5_15")]
+pub fn io_uring_prep_tee(
+    sqe: &mut SQEntry,
+    fd_in: RawFd,
+    fd_out: RawFd,
+    nbytes: u32,
+    splice_flags: u32,
+) {
+    sqe.prep_tee(fd_in, fd_out, nbytes, splice_flags)
+}
+
+/// liburing: io_uring_prep_statx()
+#[cfg(feature = "linux-5_6")]
+pub fn io_uring_prep_statx(
+    sqe: &mut SQEntry,
+    dfd: RawFd,
+    path: *const libc::c_char,
+    flags: libc::c_int,
+    mask: u32,
+    statxbuf: *mut libc::statx,
+) {
+    sqe.prep_statx(dfd, path, flags, mask, statxbuf)
+}
+
+/// liburing: io_uring_prep_close_direct()
+#[cfg(feature = "linux-5_15")]
+pub fn io_uring_prep_close_direct(sqe: &mut SQEntry, file_index: u32) {
+    sqe.prep_close_fixed(file_index)
+}
+
+/// liburing: io_uring_prep_msg_ring()
+#[cfg(feature = "linux-5_19")]
+pub fn io_uring_prep_msg_ring(sqe: &mut SQEntry, fd: RawFd, len: u32, data: u64) {
+    sqe.prep_msg_ring(fd, len, data)
+}
+
+/// liburing: io_uring_prep_msg_ring_cqe_flags()
+#[cfg(feature = "linux-6_4")]
+pub fn io_uring_prep_msg_ring_cqe_flags(
+    sqe: &mut SQEntry,
+    fd: RawFd,
+    len: u32,
+    data: u64,
+    cqe_flags: u32,
+) {
+    sqe.prep_msg_ring_cqe_flags(fd, len, data, cqe_flags)
+}
+
+/// liburing: io_uring_prep_waitid()
+#[cfg(feature = "linux-6_7")]
+pub fn io_uring_prep_waitid(
+    sqe: &mut SQEntry,
+    idtype: libc::idtype_t,
+    id: libc::id_t,
+    infop: *mut libc::siginfo_t,
+    options: libc::c_int,
+    flags: u32,
+) {
+    sqe.prep_waitid(idtype, id, infop, options, flags)
+}
+
+/*
+ * completion
+ */
+
+/// liburing: io_uring_peek_cqe()
+pub fn io_uring_peek_cqe(ring: &mut IoUring) -> Option<io_uring_cqe> {
+    ring.pop_cqe()
+}
+
+/// liburing: io_uring_wait_cqe()
+///
+/// Submits any pending sqes, like liburing does if it needs to enter the kernel.
+pub fn io_uring_wait_cqe(ring: &mut IoUring) -> io::Result<io_uring_cqe> {
+    loop {
+        if let Some(cqe) = ring.pop_cqe() {
+            return Ok(cqe);
+        }
+        ring.submit_and_wait(1)?;
+    }
+}
+
+/// liburing: io_uring_cq_ready()
+pub fn io_uring_cq_ready(ring: &IoUring) -> u32 {
+    ring.cq_ready()
+}
+
+/// liburing: io_uring_get_events()
+pub fn io_uring_get_events(ring: &mut IoUring) -> io::Result<()> {
+    ring.get_events(0).map(|_| ())
+}
+
+/// liburing: io_uring_cqe_seen()
+///
+/// NB: this is a no-op, since cqes are consumed when they are returned
+pub fn io_uring_cqe_seen(_ring: &mut IoUring, _cqe: &io_uring_cqe) {}
+
+/// liburing: io_uring_cqe_get_data64()
+pub fn io_uring_cqe_get_data(cqe: &io_uring_cqe) -> u64 {
+    cqe.user_data()
+}
@@ -0,0 +1,186 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Requests with deadlines, via linked timeouts
+//
+// Each request is linked (IOSQE_IO_LINK) to a LINK_TIMEOUT request, which cancels it if it does
+// not complete before the deadline. Both requests post a cqe, and only the two together tell
+// what happened: a request that missed its deadline completes with -ECANCELED (or -EINTR, for
+// requests that were interrupted) while its timeout completes with -ETIME. Deadlines collects
+// both cqes, so that late requests are reported separately from requests that failed.
+//
+// The user data of the requests is:
+//   tag (8 bits) | timeout (1 bit) | request id (32 bits)
+//
+// NB: the kernel ends link chains at the end of each submission, so a request and its timeout
+// need to be submitted together: if there is no room for both, queued requests are submitted
+// first.
+//
+// Reference: io_uring_prep_link_timeout(3)
+
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+use crate::io_uring::{io_uring_cqe, IoUring, KernelTimespec, SQEntry, SqeFlags, TimeoutFlags};
+
+const TAG_SHIFT: u32 = 56;
+const TIMEOUT_BIT: u64 = 1 << 55;
+
+struct Pending {
+    // the kernel reads the deadline when the timeout is submitted
+    _ts: Box<KernelTimespec>,
+    op: Option<io_uring_cqe>,
+    timeout_res: Option<i32>,
+}
+
+/// The result of a request with a deadline (see [`Deadlines::complete`])
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome {
+    /// The request completed (successfully or not) before its deadline
+    Done(io_uring_cqe),
+    /// The request did not complete before its deadline, and was cancelled
+    Late,
+}
+
+/// Requests that are cancelled if they do not complete before a deadline
+///
+/// ```no_run
+/// # use iouring::io_uring::IoUring;
+/// # use iouring::deadline::{Deadlines, Outcome};
+/// # use std::time::Duration;
+/// # let fd = 0;
+/// let mut ring = IoUring::init(32).unwrap();
+/// let mut deadlines = Deadlines::new(0xfc);
+/// deadlines.submit(&mut ring, Duration::from_millis(10), |sqe| {
+///     sqe.prep_poll_add(fd, libc::POLLIN as u32);
+/// }).unwrap();
+/// while !deadlines.is_empty() {
+///     ring.submit_and_wait(1).unwrap();
+///     while let Some(cqe) = ring.pop_cqe() {
+///         match deadlines.complete(&cqe) {
+///             Some((id, Outcome::Done(cqe))) => println!("{}: {}", id, cqe.res()),
+///             Some((id, Outcome::Late)) => println!("{}: missed its deadline", id),
+///             None => (),
+///         }
+///     }
+/// }
+/// ```
+///
+/// The requests need to post a single cqe each (i.e., no multishot requests, and no
+/// IOSQE_CQE_SKIP_SUCCESS).
+pub struct Deadlines {
+    tag: u64,
+    flags: TimeoutFlags,
+    pending: HashMap<u32, Pending>,
+    next_id: u32,
+}
+
+impl Deadlines {
+
+    /// Deadlines whose requests use the given tag in the top 8 bits of their user data
+    ///
+    /// No other requests on the ring should have user data with this tag.
+    pub fn new(tag: u8) -> Deadlines {
+        Deadlines::with_flags(tag, TimeoutFlags::empty())
+    }
+
+    /// Like [`Self::new`], but with flags for the timeouts, e.g., to select the clock
+    ///
+    /// NB: deadlines are always relative, so [`TimeoutFlags::ABS`] is ignored.
+    pub fn with_flags(tag: u8, flags: TimeoutFlags) -> Deadlines {
+        Deadlines {
+            tag: (tag as u64) << TAG_SHIFT,
+            flags: flags - TimeoutFlags::ABS,
+            pending: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Prepare a request via prep, link a timeout to it that expires after deadline, and return
+    /// the request id
+    ///
+    /// prep does not need to set the user data: it is overwritten. The requests are queued, and
+    /// submitted with the next submission.
+    pub fn submit<F>(&mut self, ring: &mut IoUring, deadline: Duration, prep: F)
+    -> io::Result<u32>
+    where
+        F: FnOnce(&mut SQEntry),
+    {
+        while ring.sq_space_left() < 2 {
+            ring.submit()?;
+        }
+        let mut id = self.next_id;
+        while self.pending.contains_key(&id) {
+            id = id.wrapping_add(1);
+        }
+        self.next_id = id.wrapping_add(1);
+
+        let ts = Box::new(KernelTimespec::from(deadline));
+        let ud = self.tag | id as u64;
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            prep(&mut sqe);
+            sqe.set_flags(sqe.flags() | SqeFlags::IO_LINK);
+            sqe.set_data(ud);
+        }
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_link_timeout(&*ts, self.flags);
+            sqe.set_data(ud | TIMEOUT_BIT);
+        }
+        self.pending.insert(id, Pending { _ts: ts, op: None, timeout_res: None });
+        Ok(id)
+    }
+
+    /// Whether the cqe is for a request of these deadlines
+    pub fn owns(&self, cqe: &io_uring_cqe) -> bool {
+        cqe.user_data() >> TAG_SHIFT == self.tag >> TAG_SHIFT
+    }
+
+    /// Handle a cqe, and return the id and the outcome of its request once both the request and
+    /// its timeout have completed
+    ///
+    /// Returns None if the cqe is not for these deadlines (see [`Self::owns`]), or if the other
+    /// cqe of the request has not arrived yet.
+    pub fn complete(&mut self, cqe: &io_uring_cqe) -> Option<(u32, Outcome)> {
+        if !self.owns(cqe) {
+            return None;
+        }
+        let ud = cqe.user_data();
+        let id = ud as u32;
+        let p = self.pending.get_mut(&id)?;
+        if ud & TIMEOUT_BIT != 0 {
+            p.timeout_res = Some(cqe.res());
+        } else {
+            p.op = Some(*cqe);
+        }
+        let (op, timeout_res) = match (p.op, p.timeout_res) {
+            (Some(op), Some(res)) => (op, res),
+            _ => return None,
+        };
+        self.pending.remove(&id);
+
+        // NB: if the timeout expires while the request is completing, the cancellation fails,
+        // and the request keeps its result
+        let cancelled = op.res() == -libc::ECANCELED || op.res() == -libc::EINTR;
+        if timeout_res == -libc::ETIME && cancelled {
+            Some((id, Outcome::Late))
+        } else {
+            Some((id, Outcome::Done(op)))
+        }
+    }
+
+    /// Number of requests that have not completed yet
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Whether all requests have completed
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
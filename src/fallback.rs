@@ -0,0 +1,317 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// A fallback for when io_uring is not available
+//
+// io_uring can be unavailable even on recent kernels: it can be disabled via the
+// kernel.io_uring_disabled sysctl, or blocked by a seccomp filter (e.g., in containers). Instead
+// of having a second I/O path for that case, applications can use FallbackRing, which has the
+// same submission and completion interface as IoUring (get_sqe(), prep_*() on SQEntry, submit(),
+// pop_cqe()), but executes requests with regular system calls (preadv2(), pwritev2(), fsync(),
+// ...) on a pool of threads. AutoRing picks IoUring when it is available, and FallbackRing
+// otherwise.
+//
+// Only a subset of the requests is supported: NOP, READV, WRITEV, READ, WRITE, FSYNC, and CLOSE.
+// Other requests (and requests on registered files, or with provided buffers) complete with
+// -EINVAL, as they would on a kernel that does not know them. Linked requests run in order, and a
+// request of a chain that fails (with a negative result) cancels the rest of the chain, unless it
+// is hard-linked. IOSQE_CQE_SKIP_SUCCESS is honored, and the other sqe flags are ignored.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+
+use crate::io_uring::{io_uring_cqe, io_uring_sqe, IoUring, OpCode, SQEntry};
+use crate::io_uring::IORING_FSYNC_DATASYNC;
+use crate::record::RecordedSqe;
+
+/// Default number of threads of a [`FallbackRing`]
+pub const DEFAULT_THREADS: usize = 4;
+
+// IOSQE_* flags that FallbackRing looks at
+const IOSQE_FIXED_FILE: u8 = 1 << 0;
+const IOSQE_IO_LINK: u8 = 1 << 2;
+const IOSQE_IO_HARDLINK: u8 = 1 << 3;
+const IOSQE_BUFFER_SELECT: u8 = 1 << 5;
+const IOSQE_CQE_SKIP_SUCCESS: u8 = 1 << 6;
+
+// Completed requests, shared with the threads
+struct Completions {
+    cqes: Mutex<VecDeque<io_uring_cqe>>,
+    cond: Condvar,
+}
+
+fn res(ret: isize) -> i32 {
+    if ret < 0 {
+        -io::Error::last_os_error().raw_os_error().unwrap_or(libc::EIO)
+    } else {
+        ret as i32
+    }
+}
+
+// Read or write iovecs, at off (or at the file position, for -1)
+unsafe fn rw(write: bool, fd: i32, iov: *const libc::iovec, cnt: i32, off: i64, flags: i32)
+-> i32 {
+    let ret = if write {
+        libc::pwritev2(fd, iov, cnt, off, flags)
+    } else {
+        libc::preadv2(fd, iov, cnt, off, flags)
+    };
+    let ret = res(ret as isize);
+    // NB: io_uring ignores the offset of files without a position (e.g., pipes, and sockets)
+    if ret == -libc::ESPIPE && off != -1 {
+        return rw(write, fd, iov, cnt, -1, flags);
+    }
+    ret
+}
+
+// Execute a request, and return its result
+fn execute(sqe: &RecordedSqe) -> i32 {
+    if sqe.flags & (IOSQE_FIXED_FILE | IOSQE_BUFFER_SELECT) != 0 {
+        return -libc::EINVAL;
+    }
+    let (fd, off, flags) = (sqe.fd, sqe.off as i64, sqe.op_flags as i32);
+    let iov = libc::iovec {
+        iov_base: sqe.addr as usize as *mut libc::c_void,
+        iov_len: sqe.len as usize,
+    };
+    let vecs = sqe.addr as usize as *const libc::iovec;
+    unsafe {
+        match OpCode::from_u8(sqe.opcode) {
+            Some(OpCode::Nop) => 0,
+            Some(OpCode::Readv) => rw(false, fd, vecs, sqe.len as i32, off, flags),
+            Some(OpCode::Writev) => rw(true, fd, vecs, sqe.len as i32, off, flags),
+            Some(OpCode::Read) => rw(false, fd, &iov, 1, off, flags),
+            Some(OpCode::Write) => rw(true, fd, &iov, 1, off, flags),
+            Some(OpCode::Fsync) if sqe.op_flags & IORING_FSYNC_DATASYNC != 0 => {
+                res(libc::fdatasync(fd) as isize)
+            }
+            Some(OpCode::Fsync) => res(libc::fsync(fd) as isize),
+            Some(OpCode::Close) => res(libc::close(fd) as isize),
+            _ => -libc::EINVAL,
+        }
+    }
+}
+
+// Execute a chain of linked requests, and post their cqes
+fn run_chain(chain: Vec<RecordedSqe>, completions: &Completions) {
+    let mut cqes = Vec::with_capacity(chain.len());
+    let mut cancel = false;
+    for sqe in chain.iter() {
+        let res = if cancel { -libc::ECANCELED } else { execute(sqe) };
+        if res < 0 && sqe.flags & IOSQE_IO_HARDLINK == 0 {
+            cancel = true;
+        }
+        if res >= 0 && sqe.flags & IOSQE_CQE_SKIP_SUCCESS != 0 {
+            continue;
+        }
+        cqes.push(io_uring_cqe::new(sqe.user_data, res, 0));
+    }
+    completions.cqes.lock().unwrap().extend(cqes);
+    completions.cond.notify_all();
+}
+
+/// A ring that executes requests on a pool of threads, for when io_uring is not available
+///
+/// ```no_run
+/// # use iouring::fallback::FallbackRing;
+/// let mut ring = FallbackRing::init(32).unwrap();
+/// {
+///     let mut sqe = ring.get_sqe().unwrap();
+///     sqe.prep_fsync(1, 0);
+///     sqe.set_data(42);
+/// }
+/// ring.submit_and_wait(1).unwrap();
+/// let cqe = ring.pop_cqe().unwrap();
+/// assert_eq!(cqe.user_data(), 42);
+/// ```
+///
+/// As with IoUring, the memory that requests point to needs to stay valid until they complete.
+/// Dropping the ring waits for the requests in flight.
+pub struct FallbackRing {
+    sqes: Vec<io_uring_sqe>,
+    nqueued: usize,
+    jobs: Option<mpsc::Sender<Vec<RecordedSqe>>>,
+    completions: Arc<Completions>,
+    threads: Vec<std::thread::JoinHandle<()>>,
+}
+
+impl FallbackRing {
+
+    /// A ring with room for nentries queued requests, and [`DEFAULT_THREADS`] threads
+    pub fn init(nentries: libc::c_uint) -> io::Result<FallbackRing> {
+        FallbackRing::with_threads(nentries, DEFAULT_THREADS)
+    }
+
+    /// A ring with room for nentries queued requests, executed by nthreads threads
+    pub fn with_threads(nentries: libc::c_uint, nthreads: usize) -> io::Result<FallbackRing> {
+        if nentries == 0 || nthreads == 0 {
+            let msg = format!("invalid fallback ring: {} entries, {} threads", nentries, nthreads);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let completions = Arc::new(Completions {
+            cqes: Mutex::new(VecDeque::new()),
+            cond: Condvar::new(),
+        });
+        let (tx, rx) = mpsc::channel::<Vec<RecordedSqe>>();
+        let rx = Arc::new(Mutex::new(rx));
+        let mut threads = Vec::with_capacity(nthreads);
+        for i in 0..nthreads {
+            let (rx, completions) = (rx.clone(), completions.clone());
+            let t = std::thread::Builder::new()
+                .name(format!("iouring-fallback-{}", i))
+                .spawn(move || loop {
+                    // NB: the lock is dropped before the chain runs
+                    let chain = match rx.lock().unwrap().recv() {
+                        Ok(x) => x,
+                        Err(_) => return,
+                    };
+                    run_chain(chain, &completions);
+                })?;
+            threads.push(t);
+        }
+        Ok(FallbackRing {
+            sqes: (0..nentries).map(|_| io_uring_sqe::zeroed()).collect(),
+            nqueued: 0,
+            jobs: Some(tx),
+            completions,
+            threads,
+        })
+    }
+
+    /// Number of threads that execute requests
+    pub fn nthreads(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// See [`IoUring::get_sqe`]
+    pub fn get_sqe(&mut self) -> Option<SQEntry<'_>> {
+        let sqe = self.sqes.get_mut(self.nqueued)?;
+        self.nqueued += 1;
+        Some(SQEntry::new(sqe))
+    }
+
+    /// Hand the queued requests to the threads, and return their number
+    pub fn submit(&mut self) -> io::Result<u32> {
+        let jobs = self.jobs.as_ref().unwrap();
+        let send = |chain| jobs.send(chain).map_err(|_| io::Error::other("no threads"));
+        let mut chain = vec![];
+        for sqe in self.sqes[..self.nqueued].iter() {
+            let sqe = RecordedSqe::from_bytes(sqe.as_bytes());
+            let linked = sqe.flags & (IOSQE_IO_LINK | IOSQE_IO_HARDLINK) != 0;
+            chain.push(sqe);
+            if !linked {
+                send(std::mem::take(&mut chain))?;
+            }
+        }
+        // NB: like the kernel, treat a link flag on the last request as the end of the chain
+        if !chain.is_empty() {
+            send(chain)?;
+        }
+        let ret = self.nqueued as u32;
+        self.nqueued = 0;
+        Ok(ret)
+    }
+
+    /// Submit the queued requests, and wait until at least wait_nr cqes are available. Returns
+    /// the number of submitted requests.
+    pub fn submit_and_wait(&mut self, wait_nr: u32) -> io::Result<u32> {
+        let ret = self.submit()?;
+        let mut cqes = self.completions.cqes.lock().unwrap();
+        while cqes.len() < wait_nr as usize {
+            cqes = self.completions.cond.wait(cqes).unwrap();
+        }
+        Ok(ret)
+    }
+
+    /// See [`IoUring::pop_cqe`]
+    pub fn pop_cqe(&mut self) -> Option<io_uring_cqe> {
+        self.completions.cqes.lock().unwrap().pop_front()
+    }
+
+    /// Number of cqes available to pop
+    pub fn cq_ready(&self) -> u32 {
+        self.completions.cqes.lock().unwrap().len() as u32
+    }
+}
+
+impl Drop for FallbackRing {
+    fn drop(&mut self) {
+        // the threads exit once they have executed the submitted requests
+        self.jobs = None;
+        for t in self.threads.drain(..) {
+            let _ = t.join();
+        }
+    }
+}
+
+/// An IoUring if io_uring is available, or a FallbackRing otherwise
+///
+/// The methods dispatch to the ring, so applications can use the same code for both. Features that
+/// only IoUring has are available via matching on the variants.
+// NB: IoUring is the common case, so it is not boxed
+#[allow(clippy::large_enum_variant)]
+pub enum AutoRing {
+    IoUring(IoUring),
+    Fallback(FallbackRing),
+}
+
+impl AutoRing {
+
+    /// An IoUring with nentries entries, or a FallbackRing if io_uring is not supported (i.e.,
+    /// [`IoUring::init`] fails with `io::ErrorKind::Unsupported`)
+    pub fn init(nentries: libc::c_uint) -> io::Result<AutoRing> {
+        match IoUring::init(nentries) {
+            Ok(ring) => Ok(AutoRing::IoUring(ring)),
+            Err(err) if err.kind() == io::ErrorKind::Unsupported => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(error = %err, "io_uring is not supported, using the fallback ring");
+                Ok(AutoRing::Fallback(FallbackRing::init(nentries)?))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Whether this is a FallbackRing
+    pub fn is_fallback(&self) -> bool {
+        matches!(self, AutoRing::Fallback(_))
+    }
+
+    pub fn get_sqe(&mut self) -> Option<SQEntry<'_>> {
+        match self {
+            AutoRing::IoUring(r) => r.get_sqe(),
+            AutoRing::Fallback(r) => r.get_sqe(),
+        }
+    }
+
+    pub fn submit(&mut self) -> io::Result<u32> {
+        match self {
+            AutoRing::IoUring(r) => r.submit(),
+            AutoRing::Fallback(r) => r.submit(),
+        }
+    }
+
+    pub fn submit_and_wait(&mut self, wait_nr: u32) -> io::Result<u32> {
+        match self {
+            AutoRing::IoUring(r) => r.submit_and_wait(wait_nr),
+            AutoRing::Fallback(r) => r.submit_and_wait(wait_nr),
+        }
+    }
+
+    pub fn pop_cqe(&mut self) -> Option<io_uring_cqe> {
+        match self {
+            AutoRing::IoUring(r) => r.pop_cqe(),
+            AutoRing::Fallback(r) => r.pop_cqe(),
+        }
+    }
+
+    pub fn cq_ready(&self) -> u32 {
+        match self {
+            AutoRing::IoUring(r) => r.cq_ready(),
+            AutoRing::Fallback(r) => r.cq_ready(),
+        }
+    }
+}
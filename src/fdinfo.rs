@@ -0,0 +1,129 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// The kernel's view of a ring, from /proc/self/fdinfo
+//
+// The fdinfo file of a ring fd has the kernel's copy of the ring indices, the SQ poll thread, the
+// number of registered files and buffers, and the requests on the poll and the CQ overflow lists.
+// Comparing it with the application's view (e.g., a kernel SqHead that does not move) helps with
+// debugging hangs, and it can be exported by health checks.
+//
+// The format is not a stable interface: fields have been added over time, so the ones that older
+// kernels do not have are left at their defaults.
+//
+// Reference: io_uring_show_fdinfo() in io_uring/fdinfo.c
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+/// Contents of the fdinfo file of a ring (see [`read`])
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FdInfo {
+    pub sq_mask: u32,
+    pub sq_head: u32,
+    pub sq_tail: u32,
+    /// The kernel's private copy of the SQ head
+    pub cached_sq_head: u32,
+    pub cq_mask: u32,
+    pub cq_head: u32,
+    pub cq_tail: u32,
+    /// The kernel's private copy of the CQ tail
+    pub cached_cq_tail: u32,
+    /// sqes submitted by the application, but not consumed by the kernel yet
+    pub sqes: u32,
+    /// cqes posted by the kernel, but not reaped by the application yet
+    pub cqes: u32,
+    /// Pid of the SQ poll thread, if any
+    pub sq_thread: Option<i32>,
+    /// CPU of the SQ poll thread, if any
+    pub sq_thread_cpu: Option<i32>,
+    /// Time (usecs) the SQ poll thread has been running, and doing work
+    pub sq_total_time: u64,
+    pub sq_work_time: u64,
+    /// Number of registered files and buffers
+    pub user_files: u32,
+    pub user_bufs: u32,
+    /// Number of requests waiting on poll, and of cqes on the CQ overflow list
+    pub poll_list: usize,
+    pub cq_overflow_list: usize,
+}
+
+fn parse_num<T: std::str::FromStr>(key: &str, val: &str) -> io::Result<T> {
+    val.parse().map_err(|_| {
+        let msg = format!("invalid value for {}: {}", key, val);
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    })
+}
+
+fn parse_hex(key: &str, val: &str) -> io::Result<u32> {
+    let hex = val.strip_prefix("0x").unwrap_or(val);
+    u32::from_str_radix(hex, 16).map_err(|_| {
+        let msg = format!("invalid value for {}: {}", key, val);
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    })
+}
+
+// -1 means no SQ poll thread
+fn parse_thread(key: &str, val: &str) -> io::Result<Option<i32>> {
+    let x: i32 = parse_num(key, val)?;
+    Ok(if x < 0 { None } else { Some(x) })
+}
+
+/// Parse the contents of the fdinfo file of a ring
+///
+/// Fails with `InvalidData` if the ring indices are missing (e.g., if this is not the fdinfo of a
+/// ring), or if a value cannot be parsed.
+pub fn parse(s: &str) -> io::Result<FdInfo> {
+    let mut ret = FdInfo::default();
+    let mut found = 0;
+    // the list whose entries (indented lines) follow
+    let mut list: Option<&mut usize> = None;
+    for line in s.lines() {
+        if line.starts_with(char::is_whitespace) {
+            if let Some(n) = list.as_mut() {
+                **n += 1;
+            }
+            continue;
+        }
+        list = None;
+        let (key, val) = match line.split_once(':') {
+            Some((k, v)) => (k, v.trim()),
+            None => continue,
+        };
+        match key {
+            "SqMask" => ret.sq_mask = parse_hex(key, val)?,
+            "SqHead" => { ret.sq_head = parse_num(key, val)?; found += 1 },
+            "SqTail" => { ret.sq_tail = parse_num(key, val)?; found += 1 },
+            "CachedSqHead" => ret.cached_sq_head = parse_num(key, val)?,
+            "CqMask" => ret.cq_mask = parse_hex(key, val)?,
+            "CqHead" => { ret.cq_head = parse_num(key, val)?; found += 1 },
+            "CqTail" => { ret.cq_tail = parse_num(key, val)?; found += 1 },
+            "CachedCqTail" => ret.cached_cq_tail = parse_num(key, val)?,
+            "SQEs" => ret.sqes = parse_num(key, val)?,
+            "CQEs" => ret.cqes = parse_num(key, val)?,
+            "SqThread" => ret.sq_thread = parse_thread(key, val)?,
+            "SqThreadCpu" => ret.sq_thread_cpu = parse_thread(key, val)?,
+            "SqTotalTime" => ret.sq_total_time = parse_num(key, val)?,
+            "SqWorkTime" => ret.sq_work_time = parse_num(key, val)?,
+            "UserFiles" => ret.user_files = parse_num(key, val)?,
+            "UserBufs" => ret.user_bufs = parse_num(key, val)?,
+            "PollList" => list = Some(&mut ret.poll_list),
+            "CqOverflowList" => list = Some(&mut ret.cq_overflow_list),
+            _ => (),
+        }
+    }
+    if found != 4 {
+        let msg = "no ring indices in fdinfo";
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    Ok(ret)
+}
+
+/// Read and parse /proc/self/fdinfo of a ring (see [`parse`])
+pub fn read<R: AsRawFd>(ring: &R) -> io::Result<FdInfo> {
+    let path = format!("/proc/self/fdinfo/{}", ring.as_raw_fd());
+    parse(&std::fs::read_to_string(path)?)
+}
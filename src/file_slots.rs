@@ -0,0 +1,98 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Allocating slots of the registered file table
+//
+// Servers that only use registered files (direct descriptors) open files and accept connections
+// directly into slots of the registered file table, and never get a regular fd. A slot is either
+// picked by the application, or by the kernel (IORING_FILE_INDEX_ALLOC). The kernel picks slots
+// from its allocation range (the whole table, by default), and the slots it picks are not known
+// until the request completes, so the application and the kernel should not pick from the same
+// slots.
+//
+// FileSlots hands out the slots of a range to the application. FileSlots::register() sets up a
+// sparse table whose first slots are handed out by FileSlots, and whose remaining slots are left to
+// the kernel. Slots need to be freed once their file is closed (e.g., via
+// SQEntry::prep_close_fixed()).
+
+use std::io;
+use std::ops::Range;
+
+#[cfg(feature = "linux-6_0")]
+use crate::io_uring::IoUring;
+
+/// An allocator of registered file slots, within a range
+#[derive(Debug, Clone)]
+pub struct FileSlots {
+    range: Range<u32>,
+    // slots that were freed, reused first
+    free: Vec<u32>,
+    // slots in [next, range.end) have never been allocated
+    next: u32,
+}
+
+impl FileSlots {
+
+    /// An allocator for the slots in range
+    pub fn new(range: Range<u32>) -> FileSlots {
+        FileSlots {
+            next: range.start,
+            range,
+            free: vec![],
+        }
+    }
+
+    /// Register a sparse table of nr slots on ring, and return an allocator for its first
+    /// app_slots slots. The kernel allocates the rest (Linux 6.0).
+    #[cfg(feature = "linux-6_0")]
+    pub fn register(ring: &mut IoUring, nr: u32, app_slots: u32) -> io::Result<FileSlots> {
+        if app_slots > nr {
+            let msg = format!("{} application slots in a table of {}", app_slots, nr);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        ring.register_files_sparse(nr)?;
+        if let Err(e) = ring.register_file_alloc_range(app_slots, nr - app_slots) {
+            // NB: best effort
+            let _ = ring.unregister_files();
+            return Err(e);
+        }
+        Ok(FileSlots::new(0..app_slots))
+    }
+
+    /// The slots of the allocator
+    pub fn range(&self) -> Range<u32> {
+        self.range.clone()
+    }
+
+    /// Allocate a slot, if there is one available
+    pub fn alloc(&mut self) -> Option<u32> {
+        if let Some(slot) = self.free.pop() {
+            return Some(slot);
+        }
+        if self.next < self.range.end {
+            self.next += 1;
+            return Some(self.next - 1);
+        }
+        None
+    }
+
+    /// Free a slot allocated via [`Self::alloc`]
+    ///
+    /// NB: freeing a slot twice is not detected, and leads to it being allocated twice.
+    pub fn free(&mut self, slot: u32) -> io::Result<()> {
+        if slot < self.range.start || slot >= self.next {
+            let msg = format!("slot {} is not allocated", slot);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        self.free.push(slot);
+        Ok(())
+    }
+
+    /// Number of slots available for allocation
+    pub fn available(&self) -> usize {
+        (self.range.end - self.next) as usize + self.free.len()
+    }
+}
@@ -0,0 +1,162 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Forwarding completions to other rings, via MSG_RING requests (Linux 5.18)
+//
+// With a ring per thread, a thread that issues a request on behalf of another (e.g., an acceptor
+// thread, or any thread that needs to submit on the ring it owns) can have the result delivered to
+// the ring of the other thread: when the request completes, a MSG_RING request posts a cqe with
+// the same result and the user data chosen by the application to the target ring. The consumer
+// of the target ring handles it as if it had issued the request itself.
+//
+// The user data of forwarded requests is the tag (top 8 bits) and an id, and that of the MSG_RING
+// requests also has the control bit set. With linux-6_4, the cqe flags (e.g., the buffer id, or
+// IORING_CQE_F_MORE for multishot requests) are forwarded too.
+//
+// Reference: io_uring_prep_msg_ring(3)
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use crate::io_uring::{io_uring_cqe, IoUring, SQEntry};
+
+const TAG_SHIFT: u32 = 56;
+const CTL_BIT: u64 = 1 << 55;
+
+struct Target {
+    ring_fd: RawFd,
+    user_data: u64,
+}
+
+/// Requests whose cqes are forwarded to a target ring
+///
+/// ```no_run
+/// # use iouring::io_uring::IoUring;
+/// # use iouring::forward::Forwarder;
+/// # use std::os::unix::io::AsRawFd;
+/// let mut ring = IoUring::init(32).unwrap();
+/// let owner = IoUring::init(32).unwrap();
+/// let mut fwd = Forwarder::new(0xfb);
+/// fwd.queue(&mut ring, owner.as_raw_fd(), 42, |sqe| sqe.prep_fsync(1, 0)).unwrap();
+/// ring.submit_and_wait(1).unwrap();
+/// while let Some(cqe) = ring.pop_cqe() {
+///     if fwd.owns(&cqe) {
+///         fwd.forward(&mut ring, &cqe).unwrap();
+///     }
+/// }
+/// // the thread of owner gets a cqe with user data 42, and the result of the fsync
+/// ```
+///
+/// NB: the target rings need to stay open until the forwarded cqes are posted, and to have room
+/// in their completion queue (MSG_RING fails with EOVERFLOW otherwise).
+pub struct Forwarder {
+    tag: u64,
+    targets: HashMap<u32, Target>,
+    next_id: u32,
+}
+
+impl Forwarder {
+
+    /// Forwarder whose requests use the given tag in the top 8 bits of their user data
+    ///
+    /// No other requests on the ring should have user data with this tag.
+    pub fn new(tag: u8) -> Forwarder {
+        Forwarder {
+            tag: (tag as u64) << TAG_SHIFT,
+            targets: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    // Prepare a request via f, submitting queued requests if the submission queue is full
+    fn prep<F: FnOnce(&mut SQEntry)>(ring: &mut IoUring, f: F) -> io::Result<()> {
+        loop {
+            if let Some(mut sqe) = ring.get_sqe() {
+                f(&mut sqe);
+                return Ok(());
+            }
+            ring.submit()?;
+        }
+    }
+
+    /// Prepare a request via prep, whose cqes are forwarded to the ring of ring_fd with the given
+    /// user data, and return its id
+    ///
+    /// prep does not need to set the user data: it is overwritten.
+    pub fn queue<F>(&mut self, ring: &mut IoUring, ring_fd: RawFd, user_data: u64, prep: F)
+    -> io::Result<u32>
+    where
+        F: FnOnce(&mut SQEntry),
+    {
+        let mut id = self.next_id;
+        while self.targets.contains_key(&id) {
+            id = id.wrapping_add(1);
+        }
+        self.next_id = id.wrapping_add(1);
+        let ud = self.tag | id as u64;
+        Forwarder::prep(ring, |sqe| {
+            prep(sqe);
+            sqe.set_data(ud);
+        })?;
+        self.targets.insert(id, Target { ring_fd, user_data });
+        Ok(id)
+    }
+
+    /// Whether the cqe is for a request of this forwarder
+    pub fn owns(&self, cqe: &io_uring_cqe) -> bool {
+        cqe.user_data() >> TAG_SHIFT == self.tag >> TAG_SHIFT
+    }
+
+    /// Handle a cqe: forward it, if it is the cqe of a forwarded request, or check the result of
+    /// forwarding, if it is the cqe of a MSG_RING request
+    ///
+    /// The MSG_RING requests are queued (and submitted with the next submission). Fails if
+    /// queueing fails, or if a cqe could not be forwarded. Cqes that are not for this forwarder
+    /// (see [`Self::owns`]) are ignored.
+    pub fn forward(&mut self, ring: &mut IoUring, cqe: &io_uring_cqe) -> io::Result<()> {
+        if !self.owns(cqe) {
+            return Ok(());
+        }
+        let ud = cqe.user_data();
+        let id = ud as u32;
+        if ud & CTL_BIT != 0 {
+            if cqe.res() < 0 {
+                let err = io::Error::from_raw_os_error(-cqe.res());
+                let msg = format!("failed to forward cqe of request {}: {}", id, err);
+                return Err(io::Error::new(err.kind(), msg));
+            }
+            return Ok(());
+        }
+
+        let t = match self.targets.get(&id) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let (ring_fd, data, res) = (t.ring_fd, t.user_data, cqe.res() as u32);
+        Forwarder::prep(ring, |sqe| {
+            #[cfg(feature = "linux-6_4")]
+            sqe.prep_msg_ring_cqe_flags(ring_fd, res, data, cqe.flags());
+            #[cfg(not(feature = "linux-6_4"))]
+            sqe.prep_msg_ring(ring_fd, res, data);
+            sqe.set_data(ud | CTL_BIT);
+        })?;
+        if !cqe.has_more() {
+            self.targets.remove(&id);
+        }
+        Ok(())
+    }
+
+    /// Number of requests whose cqes have not been forwarded yet
+    pub fn len(&self) -> usize {
+        self.targets.len()
+    }
+
+    /// Whether the cqes of all requests have been forwarded
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+}
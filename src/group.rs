@@ -0,0 +1,349 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Groups of requests that complete together
+//
+// Fanning out requests (e.g., reading N extents of a file) and waiting for all of them is common
+// enough to deserve a helper. Groups assigns the user data of the requests of each group:
+//   tag (8 bits) | group id (24 bits) | index of the request in the group (32 bits)
+// and collects their cqes, so that the application gets the results of a group at once, in
+// request order.
+//
+// "Any" groups are for racing requests (e.g., hedged reads): they complete with their first cqe.
+// The rest of their requests can be cancelled, and their cqes are dropped as they arrive. The
+// cancel requests use an index of CANCEL_IDX.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+
+use crate::io_uring::{io_uring_cqe, IoUring, SQEntry};
+
+const TAG_SHIFT: u32 = 56;
+const ID_SHIFT: u32 = 32;
+const ID_MASK: u32 = (1 << 24) - 1;
+const CANCEL_IDX: u64 = u32::MAX as u64;
+
+struct Group {
+    cqes: Vec<Option<io_uring_cqe>>,
+    pending: usize,
+    // for any groups: the index of the first request to complete, and whether it was taken
+    any: bool,
+    first: Option<usize>,
+    first_taken: bool,
+}
+
+/// Error for when submitting a group fails after some of its requests were queued
+///
+/// It is returned by [`Groups::submit`] and [`Groups::submit_any`] wrapped in an `io::Error` of
+/// the same kind as the submit error, and it can be recovered via `io::Error::get_ref()` and
+/// `downcast_ref()`. The queued requests are issued (if they were not submitted, on the next
+/// submit), so the group still exists, and consists of them.
+#[derive(Debug)]
+pub struct PartialGroup {
+    id: u32,
+    queued: usize,
+    err: io::Error,
+}
+
+impl PartialGroup {
+    /// The id of the group
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The number of requests that were queued, i.e., the first queued requests of the group
+    pub fn queued(&self) -> usize {
+        self.queued
+    }
+}
+
+impl std::fmt::Display for PartialGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "submitting group {} failed after queueing {} requests: {}",
+               self.id, self.queued, self.err)
+    }
+}
+
+impl std::error::Error for PartialGroup {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.err)
+    }
+}
+
+/// Groups of requests, whose results are collected per group (join semantics)
+///
+/// ```no_run
+/// # use iouring::io_uring::IoUring;
+/// # use iouring::group::Groups;
+/// # let fd = 0;
+/// # let mut bufs = vec![vec![0u8; 4096]; 4];
+/// let iovs: Vec<_> = bufs.iter_mut()
+///     .map(|b| libc::iovec { iov_base: b.as_mut_ptr() as _, iov_len: b.len() })
+///     .collect();
+/// let mut ring = IoUring::init(32).unwrap();
+/// let mut groups = Groups::new(0xfd);
+/// let id = groups.submit(&mut ring, iovs.len(), |i, sqe| {
+///     sqe.prep_readv(fd, &iovs[i], 1, (i * 4096) as u64);
+/// }).unwrap();
+/// let cqes = groups.wait(&mut ring, id, |_other| ()).unwrap();
+/// ```
+///
+/// The requests need to post a single cqe each (i.e., no multishot requests, and no
+/// IOSQE_CQE_SKIP_SUCCESS).
+///
+/// Groups submitted with [`Self::submit_any`] complete when their first request completes (select
+/// semantics), see [`Self::wait_any`].
+pub struct Groups {
+    tag: u64,
+    groups: HashMap<u32, Group>,
+    next_id: u32,
+}
+
+impl Groups {
+
+    /// Groups whose requests use the given tag in the top 8 bits of their user data
+    ///
+    /// No other requests on the ring should have user data with this tag.
+    pub fn new(tag: u8) -> Groups {
+        Groups {
+            tag: (tag as u64) << TAG_SHIFT,
+            groups: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn alloc_id(&mut self) -> io::Result<u32> {
+        for _ in 0..=ID_MASK {
+            let id = self.next_id;
+            self.next_id = (id + 1) & ID_MASK;
+            if !self.groups.contains_key(&id) {
+                return Ok(id);
+            }
+        }
+        Err(io::Error::other("no free group ids"))
+    }
+
+    /// Prepare n requests via prep (which gets the index of the request), and submit them as a
+    /// group. Returns the id of the group.
+    ///
+    /// prep does not need to set the user data: it is overwritten. If the submission queue
+    /// fills up, the queued requests are submitted to make room. If submitting fails after some of
+    /// the requests were queued, the error is a [`PartialGroup`], and the group consists of the
+    /// queued requests.
+    pub fn submit<F>(&mut self, ring: &mut IoUring, n: usize, prep: F) -> io::Result<u32>
+    where
+        F: FnMut(usize, &mut SQEntry),
+    {
+        self.do_submit(ring, n, false, prep)
+    }
+
+    /// Like [`Self::submit`], but the group completes when its first request completes
+    ///
+    /// The other requests keep running, unless cancelled via [`Self::cancel`].
+    pub fn submit_any<F>(&mut self, ring: &mut IoUring, n: usize, prep: F) -> io::Result<u32>
+    where
+        F: FnMut(usize, &mut SQEntry),
+    {
+        self.do_submit(ring, n, true, prep)
+    }
+
+    fn do_submit<F>(&mut self, ring: &mut IoUring, n: usize, any: bool, mut prep: F)
+    -> io::Result<u32>
+    where
+        F: FnMut(usize, &mut SQEntry),
+    {
+        if n == 0 || u32::try_from(n).is_err() {
+            let msg = format!("invalid number of requests in group: {}", n);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let id = self.alloc_id()?;
+        let base = self.tag | ((id as u64) << ID_SHIFT);
+        // NB: the group exists before its requests are queued, so that their cqes are collected
+        // even if submitting fails midway
+        let group = Group { cqes: vec![None; n], pending: n, any, first: None, first_taken: false };
+        self.groups.insert(id, group);
+        for i in 0..n {
+            loop {
+                if let Some(mut sqe) = ring.get_sqe() {
+                    prep(i, &mut sqe);
+                    sqe.set_data(base | i as u64);
+                    break;
+                }
+                if let Err(err) = ring.submit() {
+                    return Err(self.partial(id, i, err));
+                }
+            }
+        }
+        if let Err(err) = ring.submit() {
+            return Err(self.partial(id, n, err));
+        }
+        Ok(id)
+    }
+
+    // Shrink group id to its first queued requests, after submitting failed with err
+    fn partial(&mut self, id: u32, queued: usize, err: io::Error) -> io::Error {
+        if queued == 0 {
+            self.groups.remove(&id);
+            return err;
+        }
+        let group = self.groups.get_mut(&id).unwrap();
+        group.cqes.truncate(queued);
+        group.pending = queued;
+        io::Error::new(err.kind(), PartialGroup { id, queued, err })
+    }
+
+    /// Whether the cqe is for a request of these groups
+    pub fn owns(&self, cqe: &io_uring_cqe) -> bool {
+        cqe.user_data() >> TAG_SHIFT == self.tag >> TAG_SHIFT
+    }
+
+    /// Handle a cqe, and return the id of its group if this was the last pending request of the
+    /// group (see [`Self::take`]), or the first request of an any group (see [`Self::first`])
+    pub fn complete(&mut self, cqe: &io_uring_cqe) -> Option<u32> {
+        if !self.owns(cqe) {
+            return None;
+        }
+        let id = ((cqe.user_data() >> ID_SHIFT) as u32) & ID_MASK;
+        let idx = cqe.user_data() as u32 as usize;
+        let group = self.groups.get_mut(&id)?;
+        let slot = group.cqes.get_mut(idx)?;
+        if slot.is_none() {
+            group.pending -= 1;
+        }
+        *slot = Some(*cqe);
+        if group.any {
+            if group.first.is_none() {
+                group.first = Some(idx);
+                return Some(id);
+            }
+            if group.pending == 0 && group.first_taken {
+                self.groups.remove(&id);
+            }
+            return None;
+        }
+        if group.pending == 0 {
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    /// Number of requests of group id that have not completed yet, if the group exists
+    pub fn pending(&self, id: u32) -> Option<usize> {
+        self.groups.get(&id).map(|g| g.pending)
+    }
+
+    /// Take the cqes of the requests of a completed group, in request order
+    ///
+    /// Returns None if the group does not exist, or has not completed yet.
+    pub fn take(&mut self, id: u32) -> Option<Vec<io_uring_cqe>> {
+        if self.pending(id)? > 0 {
+            return None;
+        }
+        let group = self.groups.remove(&id)?;
+        Some(group.cqes.into_iter().map(|x| x.unwrap()).collect())
+    }
+
+    /// The index and the cqe of the first request of any group id to complete
+    ///
+    /// Returns None if the group does not exist, or none of its requests has completed yet. The
+    /// group is dropped once all of its requests complete.
+    pub fn first(&mut self, id: u32) -> Option<(usize, io_uring_cqe)> {
+        let group = self.groups.get_mut(&id)?;
+        let idx = group.first?;
+        let cqe = group.cqes[idx].unwrap();
+        group.first_taken = true;
+        if group.pending == 0 {
+            self.groups.remove(&id);
+        }
+        Some((idx, cqe))
+    }
+
+    /// Cancel the requests of group id that have not completed yet
+    ///
+    /// Cancelled requests complete with -ECANCELED, unless they complete before the cancellation
+    /// takes effect.
+    pub fn cancel(&mut self, ring: &mut IoUring, id: u32) -> io::Result<()> {
+        let base = self.tag | ((id as u64) << ID_SHIFT);
+        let pending: Vec<usize> = match self.groups.get(&id) {
+            Some(g) => (0..g.cqes.len()).filter(|i| g.cqes[*i].is_none()).collect(),
+            None => return Ok(()),
+        };
+        for i in pending {
+            loop {
+                if let Some(mut sqe) = ring.get_sqe() {
+                    sqe.prep_cancel(base | i as u64);
+                    sqe.set_data(base | CANCEL_IDX);
+                    break;
+                }
+                ring.submit()?;
+            }
+        }
+        ring.submit()?;
+        Ok(())
+    }
+
+    /// Wait until the first request of any group id completes, and return its index and cqe (see
+    /// [`Self::first`])
+    ///
+    /// Other cqes are handled as in [`Self::wait`].
+    pub fn wait_any<F>(&mut self, ring: &mut IoUring, id: u32, mut other: F)
+    -> io::Result<(usize, io_uring_cqe)>
+    where
+        F: FnMut(io_uring_cqe),
+    {
+        let group_ud = self.tag | ((id as u64) << ID_SHIFT);
+        loop {
+            match self.groups.get(&id) {
+                Some(g) if g.any && g.first.is_some() => return Ok(self.first(id).unwrap()),
+                Some(g) if g.any => (),
+                _ => {
+                    let msg = format!("no any group with id {}", id);
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+                }
+            }
+            ring.submit_and_wait(1)?;
+            while let Some(cqe) = ring.pop_cqe() {
+                if cqe.user_data() >> ID_SHIFT == group_ud >> ID_SHIFT {
+                    self.complete(&cqe);
+                } else {
+                    other(cqe);
+                }
+            }
+        }
+    }
+
+    /// Wait until group id completes, and take its cqes
+    ///
+    /// Cqes that are not for the group are passed to other, including the cqes of other groups
+    /// (which can be handled via [`Self::complete`] after this returns).
+    pub fn wait<F>(&mut self, ring: &mut IoUring, id: u32, mut other: F)
+    -> io::Result<Vec<io_uring_cqe>>
+    where
+        F: FnMut(io_uring_cqe),
+    {
+        let group_ud = self.tag | ((id as u64) << ID_SHIFT);
+        loop {
+            match self.pending(id) {
+                None => {
+                    let msg = format!("no group with id {}", id);
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+                }
+                Some(0) => return Ok(self.take(id).unwrap()),
+                Some(_) => (),
+            }
+            ring.submit_and_wait(1)?;
+            while let Some(cqe) = ring.pop_cqe() {
+                if cqe.user_data() >> ID_SHIFT == group_ud >> ID_SHIFT {
+                    self.complete(&cqe);
+                } else {
+                    other(cqe);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Registered buffers backed by huge pages
+//
+// When registering buffers, the kernel builds a bvec (a page, offset, and length) for each
+// physically contiguous segment of a buffer, and fixed requests walk these segments. With regular
+// pages, a 2MiB buffer is 512 segments. With huge pages, the kernel coalesces each huge page into
+// a single segment, which makes large fixed reads and writes cheaper, and uses fewer TLB entries
+// when the CPU touches the data.
+//
+// HugeBufs allocates the buffers in a single mapping. It first tries hugetlbfs pages
+// (MAP_HUGETLB), which need to be reserved by the administrator (vm.nr_hugepages). If there are
+// none, it falls back to transparent huge pages: a regular mapping, aligned to the huge page size,
+// and advised with MADV_HUGEPAGE, which the kernel backs with huge pages if it can.
+//
+// Reference: Documentation/admin-guide/mm/{hugetlbpage,transhuge}.rst
+
+use std::io;
+
+use crate::io_uring::IoUring;
+
+// used if /proc/meminfo does not say
+const DEFAULT_HUGE_PAGE_SZ: usize = 2 << 20;
+
+/// The size of huge pages (the Hugepagesize of /proc/meminfo)
+pub fn huge_page_size() -> usize {
+    let meminfo = match std::fs::read_to_string("/proc/meminfo") {
+        Ok(x) => x,
+        Err(_) => return DEFAULT_HUGE_PAGE_SZ,
+    };
+    meminfo
+        .lines()
+        .find_map(|l| l.strip_prefix("Hugepagesize:"))
+        .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<usize>().ok())
+        .map_or(DEFAULT_HUGE_PAGE_SZ, |kb| kb * 1024)
+}
+
+/// How the memory of [`HugeBufs`] is backed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugePages {
+    /// Reserved hugetlbfs pages (MAP_HUGETLB)
+    HugeTlb,
+    /// Transparent huge pages (MADV_HUGEPAGE), if the kernel can allocate them
+    Thp,
+}
+
+/// Equally-sized buffers, in memory backed by huge pages, for registering with a ring
+pub struct HugeBufs {
+    ptr: *mut u8,
+    mem_sz: usize,
+    buf_sz: usize,
+    nbufs: usize,
+    backing: HugePages,
+}
+
+impl HugeBufs {
+
+    /// Allocate nbufs buffers of buf_sz bytes each
+    ///
+    /// The memory is aligned to the huge page size, and its size is rounded up to it.
+    pub fn alloc(nbufs: usize, buf_sz: usize) -> io::Result<HugeBufs> {
+        let huge_sz = huge_page_size();
+        let mem_sz = match nbufs.checked_mul(buf_sz) {
+            Some(0) | None => {
+                let msg = format!("invalid buffer sizes: {} buffers of {} bytes", nbufs, buf_sz);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+            Some(x) => x.next_multiple_of(huge_sz),
+        };
+
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), mem_sz, prot, flags | libc::MAP_HUGETLB, -1, 0)
+        };
+        let (ptr, backing) = if ptr != libc::MAP_FAILED {
+            (ptr as *mut u8, HugePages::HugeTlb)
+        } else {
+            (HugeBufs::alloc_thp(mem_sz, huge_sz)?, HugePages::Thp)
+        };
+
+        let ret = HugeBufs { ptr, mem_sz, buf_sz, nbufs, backing };
+        if !(ptr as usize).is_multiple_of(huge_sz) {
+            // NB: ret is dropped here, which unmaps the memory
+            let msg = format!("buffer memory {:p} is not aligned to {} bytes", ptr, huge_sz);
+            return Err(io::Error::other(msg));
+        }
+        Ok(ret)
+    }
+
+    // Map mem_sz bytes aligned to huge_sz, and advise the kernel to use huge pages for them
+    fn alloc_thp(mem_sz: usize, huge_sz: usize) -> io::Result<*mut u8> {
+        // NB: mmap() only guarantees page alignment, so map an extra huge page, and trim the
+        // unaligned parts
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+        let map_sz = mem_sz + huge_sz;
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), map_sz, prot, flags, -1, 0) };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let start = ptr as usize;
+        let aligned = start.next_multiple_of(huge_sz);
+        unsafe {
+            if aligned > start {
+                libc::munmap(ptr, aligned - start);
+            }
+            let end = aligned + mem_sz;
+            if start + map_sz > end {
+                libc::munmap(end as *mut libc::c_void, start + map_sz - end);
+            }
+            let err = libc::madvise(aligned as *mut libc::c_void, mem_sz, libc::MADV_HUGEPAGE);
+            if err != 0 {
+                // NB: e.g., the kernel was built without THP: the buffers still work
+                let error = io::Error::last_os_error();
+                eprintln!("WARNING: madvise(MADV_HUGEPAGE) failed: {}", error);
+            }
+        }
+        Ok(aligned as *mut u8)
+    }
+
+    /// How the memory is backed
+    pub fn backing(&self) -> HugePages {
+        self.backing
+    }
+
+    /// Number of buffers
+    pub fn nbufs(&self) -> usize {
+        self.nbufs
+    }
+
+    /// Size of each buffer
+    pub fn buf_size(&self) -> usize {
+        self.buf_sz
+    }
+
+    /// Pointer to the start of buffer i, e.g., for [`crate::io_uring::SQEntry::prep_read_fixed`]
+    pub fn buf_ptr(&self, i: usize) -> *mut u8 {
+        assert!(i < self.nbufs, "buffer index {} out of range", i);
+        unsafe { self.ptr.add(i * self.buf_sz) }
+    }
+
+    /// Buffer i
+    pub fn buf(&self, i: usize) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.buf_ptr(i), self.buf_sz) }
+    }
+
+    /// Buffer i, mutably
+    pub fn buf_mut(&mut self, i: usize) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.buf_ptr(i), self.buf_sz) }
+    }
+
+    /// Register the buffers with ring, so that buffer i is registered buffer i (see
+    /// [`IoUring::register_buffers`])
+    ///
+    /// The buffers need to stay alive until they are unregistered (or the ring is dropped).
+    pub fn register(&mut self, ring: &mut IoUring) -> io::Result<()> {
+        let bufs: Vec<std::io::IoSliceMut> = (0..self.nbufs)
+            .map(|i| unsafe {
+                let buf = std::slice::from_raw_parts_mut(self.buf_ptr(i), self.buf_sz);
+                std::io::IoSliceMut::new(buf)
+            })
+            .collect();
+        ring.register_buffers(&bufs)
+    }
+}
+
+impl Drop for HugeBufs {
+    fn drop(&mut self) {
+        let err = unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.mem_sz) };
+        if err != 0 {
+            let error = io::Error::last_os_error();
+            eprintln!("WARNING: munmap() of huge page buffers failed: {}", error);
+        }
+    }
+}
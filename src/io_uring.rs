@@ -9,40 +9,106 @@
 // git://git.kernel.dk/liburing
 //
 // TODO:
-//  - do the cp example
-//  - port all io_uring_prep functions from liburing.h
-//  - a configuration to pass to init()
+//  - port the remaining io_uring_prep functions from liburing.h
 //
 
 use libc;
 use std::mem;
 use std::io;
-use std::convert::{TryFrom,TryInto};
+use std::convert::TryFrom;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 
 // use std::os::unix::io::{RawFd};
 
 use backtrace::Backtrace;
 
-/**
+use crate::latency::Latencies;
+use crate::record::{Record, RecordedCqe, RecordedSqe, Recorder};
+use crate::notifier::Notifier;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/*
  * io_uring ABI
  */
 
 /*
  * Syscall numbers for io_uring
+ *
+ * The numbers differ across architectures (e.g., mips uses its own numbering), so use
+ * the libc definitions where we know they exist, and fall back to the generic numbers otherwise.
  */
-#[allow(non_upper_case_globals)]
-pub const SYS_io_uring_register: libc::c_long = 427;
-#[allow(non_upper_case_globals)]
-pub const SYS_io_uring_enter: libc::c_long = 426;
-#[allow(non_upper_case_globals)]
-pub const SYS_io_uring_setup: libc::c_long = 425;
+mod syscall_nums {
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "riscv32",
+        target_arch = "riscv64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "s390x",
+        target_arch = "mips",
+        target_arch = "mips32r6",
+        target_arch = "mips64",
+        target_arch = "mips64r6",
+        target_arch = "sparc",
+        target_arch = "sparc64",
+        target_arch = "m68k",
+        target_arch = "csky",
+        target_arch = "hexagon",
+        target_arch = "loongarch64",
+    ))]
+    pub use libc::{SYS_io_uring_setup, SYS_io_uring_enter, SYS_io_uring_register};
+
+    // Architectures not listed above use the unified numbering of asm-generic/unistd.h for new
+    // syscalls. This does not hold for mips, whose ABIs offset the numbers (4425 for o32, 5425
+    // for n64, and 6425 for n32), so all mips variants need to be listed above.
+    #[cfg(not(any(target_arch = "mips", target_arch = "mips32r6",
+                  target_arch = "mips64", target_arch = "mips64r6")))]
+    mod fallback {
+        #[allow(non_upper_case_globals)]
+        pub const SYS_io_uring_setup: libc::c_long = 425;
+        #[allow(non_upper_case_globals)]
+        pub const SYS_io_uring_enter: libc::c_long = 426;
+        #[allow(non_upper_case_globals)]
+        pub const SYS_io_uring_register: libc::c_long = 427;
+    }
+
+    #[cfg(not(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "riscv32",
+        target_arch = "riscv64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "s390x",
+        target_arch = "mips",
+        target_arch = "mips32r6",
+        target_arch = "mips64",
+        target_arch = "mips64r6",
+        target_arch = "sparc",
+        target_arch = "sparc64",
+        target_arch = "m68k",
+        target_arch = "csky",
+        target_arch = "hexagon",
+        target_arch = "loongarch64",
+    )))]
+    pub use fallback::{SYS_io_uring_setup, SYS_io_uring_enter, SYS_io_uring_register};
+}
+
+pub use syscall_nums::{SYS_io_uring_setup, SYS_io_uring_enter, SYS_io_uring_register};
 
 /*
  * Magic offsets for the application to mmap the data it needs
+ *
+ * NB: these are passed to mmap() as off_t, which is 32 bits on 32-bit targets. They all fit.
  */
-const IORING_OFF_SQ_RING: i64 = 0;
-const IORING_OFF_CQ_RING: i64 = 0x08000000;
-const IORING_OFF_SQES:    i64 = 0x10000000;
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x08000000;
+const IORING_OFF_SQES:    libc::off_t = 0x10000000;
 
 
 type KernelRwf = libc::c_int;
@@ -57,60 +123,575 @@ union io_uring_sqe_args {
     rw_flags: KernelRwf,
     fsync_flags: u32,
     poll_events: u16,
+    poll32_events: u32,
     sync_range_flags: u32,
+    msg_flags: u32,
+    accept_flags: u32,
+    cancel_flags: u32,
+    splice_flags: u32,
+    statx_flags: u32,
+    open_flags: u32,
+    msg_ring_flags: u32,
+    timeout_flags: u32,
+    waitid_flags: u32,
 }
 
-#[repr(C)]
-union io_uring_sqe_idx {
-    buf_index: u16,
-    __pad2: [u64; 3],
-}
-
-const IORING_OP_NOP             : u8 = 0;
-const IORING_OP_READV           : u8 = 1;
-const IORING_OP_WRITEV          : u8 = 2;
-const IORING_OP_FSYNC           : u8 = 3;
-const IORING_OP_READ_FIXED      : u8 = 4;
-const IORING_OP_WRITE_FIXED     : u8 = 5;
-const IORING_OP_POLL_ADD        : u8 = 6;
-const IORING_OP_POLL_REMOVE     : u8 = 7;
-const IORING_OP_SYNC_FILE_RANGE : u8 = 8;
-const IORING_OP_SENDMSG         : u8 = 9;
-const IORING_OP_RECVMSG         : u8 = 10;
-const IORING_OP_INVALID         : u8 = 250; // Not part of the ABI, used internally
+// Define OpCode, mapping each variant to its value and kernel name
+macro_rules! opcodes {
+    ($($variant:ident = $val:literal => $name:literal,)*) => {
+        /// Request opcodes (IORING_OP_*)
+        ///
+        /// Display prints the kernel name of the opcode (e.g., READ_FIXED).
+        #[repr(u8)]
+        #[non_exhaustive]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub enum OpCode {
+            $($variant = $val,)*
+        }
+
+        impl OpCode {
+            /// The opcode with the given value, if it is known
+            pub fn from_u8(val: u8) -> Option<OpCode> {
+                match val {
+                    $($val => Some(OpCode::$variant),)*
+                    _ => None,
+                }
+            }
+
+            /// The kernel name of the opcode, without the IORING_OP_ prefix
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(OpCode::$variant => $name,)*
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
+    Nop            =  0 => "NOP",
+    Readv          =  1 => "READV",
+    Writev         =  2 => "WRITEV",
+    Fsync          =  3 => "FSYNC",
+    ReadFixed      =  4 => "READ_FIXED",
+    WriteFixed     =  5 => "WRITE_FIXED",
+    PollAdd        =  6 => "POLL_ADD",
+    PollRemove     =  7 => "POLL_REMOVE",
+    SyncFileRange  =  8 => "SYNC_FILE_RANGE",
+    Sendmsg        =  9 => "SENDMSG",
+    Recvmsg        = 10 => "RECVMSG",
+    Timeout        = 11 => "TIMEOUT",
+    TimeoutRemove  = 12 => "TIMEOUT_REMOVE",
+    Accept         = 13 => "ACCEPT",
+    AsyncCancel    = 14 => "ASYNC_CANCEL",
+    LinkTimeout    = 15 => "LINK_TIMEOUT",
+    Connect        = 16 => "CONNECT",
+    Fallocate      = 17 => "FALLOCATE",
+    Openat         = 18 => "OPENAT",
+    Close          = 19 => "CLOSE",
+    FilesUpdate    = 20 => "FILES_UPDATE",
+    Statx          = 21 => "STATX",
+    Read           = 22 => "READ",
+    Write          = 23 => "WRITE",
+    Fadvise        = 24 => "FADVISE",
+    Madvise        = 25 => "MADVISE",
+    Send           = 26 => "SEND",
+    Recv           = 27 => "RECV",
+    Openat2        = 28 => "OPENAT2",
+    EpollCtl       = 29 => "EPOLL_CTL",
+    Splice         = 30 => "SPLICE",
+    ProvideBuffers = 31 => "PROVIDE_BUFFERS",
+    RemoveBuffers  = 32 => "REMOVE_BUFFERS",
+    Tee            = 33 => "TEE",
+    Shutdown       = 34 => "SHUTDOWN",
+    Renameat       = 35 => "RENAMEAT",
+    Unlinkat       = 36 => "UNLINKAT",
+    Mkdirat        = 37 => "MKDIRAT",
+    Symlinkat      = 38 => "SYMLINKAT",
+    Linkat         = 39 => "LINKAT",
+    MsgRing        = 40 => "MSG_RING",
+    Fsetxattr      = 41 => "FSETXATTR",
+    Setxattr       = 42 => "SETXATTR",
+    Fgetxattr      = 43 => "FGETXATTR",
+    Getxattr       = 44 => "GETXATTR",
+    Socket         = 45 => "SOCKET",
+    UringCmd       = 46 => "URING_CMD",
+    SendZc         = 47 => "SEND_ZC",
+    SendmsgZc      = 48 => "SENDMSG_ZC",
+    ReadMultishot  = 49 => "READ_MULTISHOT",
+    Waitid         = 50 => "WAITID",
+    FutexWait      = 51 => "FUTEX_WAIT",
+    FutexWake      = 52 => "FUTEX_WAKE",
+    FutexWaitv     = 53 => "FUTEX_WAITV",
+    FixedFdInstall = 54 => "FIXED_FD_INSTALL",
+    Ftruncate      = 55 => "FTRUNCATE",
+    Bind           = 56 => "BIND",
+    Listen         = 57 => "LISTEN",
+    RecvZc         = 58 => "RECV_ZC",
+    EpollWait      = 59 => "EPOLL_WAIT",
+    ReadvFixed     = 60 => "READV_FIXED",
+    WritevFixed    = 61 => "WRITEV_FIXED",
+}
+
+impl OpCode {
+    /// The raw value of the opcode
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+}
+
+impl std::fmt::Display for OpCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
 
 bitflags::bitflags!{
-    struct SqeFlags: u8 {
-        const FIXED_FILE    = 1 << 0; // use fixed fileset
-        const IO_DRAIN      = 1 << 1; // issue after inflight IO
-        const IO_LINK       = 1 << 2; // links next sqe
+    /// IOSQE_* flags for submission queue entries (see [`SQEntry::set_flags`])
+    pub struct SqeFlags: u8 {
+        const FIXED_FILE       = 1 << 0; // use fixed fileset
+        const IO_DRAIN         = 1 << 1; // issue after inflight IO
+        const IO_LINK          = 1 << 2; // links next sqe
+        const IO_HARDLINK      = 1 << 3; // like LINK, but stronger
+        #[cfg(feature = "linux-5_6")]
+        const ASYNC            = 1 << 4; // always go async
+        #[cfg(feature = "linux-5_15")]
+        const BUFFER_SELECT    = 1 << 5; // select buffer from sqe->buf_group
+        #[cfg(feature = "linux-5_19")]
+        const CQE_SKIP_SUCCESS = 1 << 6; // don't post CQE if request succeeded
     }
 }
 
+// NB: the ring handles these even if the flags are not available (see queue_mmap())
+const IORING_SETUP_SQE128: u32 = 1 << 10;
+const IORING_SETUP_CQE32: u32 = 1 << 11;
+
 bitflags::bitflags!{
-    struct SetupFlags: u32 {
+    /// IORING_SETUP_* flags (see [`IoUring::init_with_flags`] and [`IoUringBuilder`])
+    pub struct SetupFlags: u32 {
         const IOPOLL = 1 << 0; // io_context is polled
         const SQPOLL = 1 << 1; // SQ poll thread
         const SQ_AFF = 1 << 2; // sq_thread_cpu is valid
         const CQSIZE = 1 << 3; // app defined CQ size
+        const CLAMP  = 1 << 4; // clamp SQ/CQ ring sizes
+        const ATTACH_WQ = 1 << 5; // attach to existing wq
+        #[cfg(feature = "linux-5_15")]
+        const R_DISABLED = 1 << 6; // start with ring disabled
+        #[cfg(feature = "linux-5_19")]
+        const SQE128 = IORING_SETUP_SQE128; // sqes are 128 bytes
+        #[cfg(feature = "linux-5_19")]
+        const CQE32  = IORING_SETUP_CQE32; // cqes are 32 bytes
     }
 }
 
 bitflags::bitflags!{
-    struct SQFlags: u32 {
+    /// IORING_SQ_* flags, set by the kernel in the SQ ring (see [`IoUring::sq_flags`])
+    pub struct SQFlags: u32 {
         const NEED_WAKEUP = 1 << 0; // needs io_uring_enter wakeup
+        const CQ_OVERFLOW = 1 << 1; // CQ ring is overflown
+        const TASKRUN     = 1 << 2; // task should enter the kernel
+    }
+}
+
+bitflags::bitflags!{
+    // io_uring_params->features flags
+    struct Features: u32 {
+        const SINGLE_MMAP     = 1 << 0;
+        const NODROP          = 1 << 1;
+        const SUBMIT_STABLE   = 1 << 2;
+        const RW_CUR_POS      = 1 << 3;
+        const CUR_PERSONALITY = 1 << 4;
+        const FAST_POLL       = 1 << 5;
+        const POLL_32BITS     = 1 << 6;
+        const SQPOLL_NONFIXED = 1 << 7;
+        const EXT_ARG         = 1 << 8;
+        const NATIVE_WORKERS  = 1 << 9;
+        const RSRC_TAGS       = 1 << 10;
+        const CQE_SKIP        = 1 << 11;
+        const LINKED_FILE     = 1 << 12;
+        const REG_REG_RING    = 1 << 13;
+        const RECVSEND_BUNDLE = 1 << 14;
+        const MIN_TIMEOUT     = 1 << 15;
+        const RW_ATTR         = 1 << 16;
+        const NO_IOWAIT       = 1 << 17;
+    }
+}
+
+bitflags::bitflags!{
+    // cqe->flags
+    struct CqeFlags: u32 {
+        const BUFFER        = 1 << 0; // upper 16 bits are the buffer ID
+        const MORE          = 1 << 1; // parent SQE will generate more CQE entries
+        const SOCK_NONEMPTY = 1 << 2; // more data to read after a socket recv
+        const NOTIF         = 1 << 3; // notification CQE (e.g., for zero-copy sends)
+        const BUF_MORE      = 1 << 4; // provided buffer will be used for more data
+    }
+}
+
+const IORING_CQE_BUFFER_SHIFT: u32 = 16;
+
+// offset of sqe->off, and of the command area (sqe->cmd) of URING_CMD sqes, which starts at addr3
+// and extends into the second half of 128-byte sqes
+const IORING_SQE_OFF_OFF: usize = 8;
+const IORING_SQE_CMD_OFF: usize = 48;
+const IORING_SQE_CMD_LEN: usize = 16;
+
+// poll_add flags stored in sqe->len
+const IORING_POLL_ADD_MULTI: u32 = 1 << 0;
+
+// accept flags stored in sqe->ioprio
+const IORING_ACCEPT_MULTISHOT: u16 = 1 << 0;
+
+// msg_ring commands stored in sqe->addr
+const IORING_MSG_DATA: u64 = 0;
+const IORING_MSG_SEND_FD: u64 = 1;
+
+// msg_ring flags: pass the cqe flags in sqe->file_index
+#[cfg(feature = "linux-6_4")]
+const IORING_MSG_RING_FLAGS_PASS: u32 = 1 << 1;
+
+/// Registered file slot for the kernel to pick a free slot (see [`SQEntry::prep_msg_ring_fd`])
+#[cfg(feature = "linux-5_19")]
+pub const IORING_FILE_INDEX_ALLOC: u32 = !0;
+
+// send/recv flags stored in sqe->ioprio
+pub const IORING_RECVSEND_POLL_FIRST: u16 = 1 << 0;
+pub const IORING_RECV_MULTISHOT: u16 = 1 << 1;
+pub const IORING_RECVSEND_FIXED_BUF: u16 = 1 << 2;
+pub const IORING_SEND_ZC_REPORT_USAGE: u16 = 1 << 3;
+pub const IORING_RECVSEND_BUNDLE: u16 = 1 << 4;
+
+/// Set in the res of a zero-copy send notification if the data were copied after all (needs
+/// [`IORING_SEND_ZC_REPORT_USAGE`])
+pub const IORING_NOTIF_USAGE_ZC_COPIED: i32 = 1 << 31;
+
+/// fsync flag (see [`SQEntry::prep_fsync`]): like fdatasync(2)
+pub const IORING_FSYNC_DATASYNC: u32 = 1 << 0;
+
+/// splice flag (see [`SQEntry::prep_splice`]): the input fd is an index into the registered files
+#[cfg(feature = "linux-5_15")]
+pub const SPLICE_F_FD_IN_FIXED: u32 = 1 << 31;
+
+bitflags::bitflags!{
+    // sqe->cancel_flags
+    struct AsyncCancelFlags: u32 {
+        const ALL      = 1 << 0; // cancel all requests that match the given key
+        const FD       = 1 << 1; // key off fd instead of user_data
+        const ANY      = 1 << 2; // match any request
+        const FD_FIXED = 1 << 3; // fd passed is a fixed descriptor
+    }
+}
+
+bitflags::bitflags!{
+    /// IORING_TIMEOUT_* flags (see [`SQEntry::prep_timeout`])
+    pub struct TimeoutFlags: u32 {
+        const ABS      = 1 << 0; // absolute timeout
+        #[cfg(feature = "linux-5_15")]
+        const BOOTTIME = 1 << 2; // CLOCK_BOOTTIME: keeps counting while suspended
+        #[cfg(feature = "linux-5_15")]
+        const REALTIME = 1 << 3; // CLOCK_REALTIME: wall-clock (e.g., calendar) deadlines
+        #[cfg(feature = "linux-6_4")]
+        const MULTISHOT = 1 << 6; // expire periodically (see SQEntry::prep_timeout_multishot)
+    }
+}
+
+/// Timeout for timeout requests (struct __kernel_timespec)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KernelTimespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl From<std::time::Duration> for KernelTimespec {
+    fn from(d: std::time::Duration) -> Self {
+        KernelTimespec { tv_sec: d.as_secs() as i64, tv_nsec: d.subsec_nanos() as i64 }
+    }
+}
+
+/// Offset of read and write requests (see [`SQEntry::set_offset`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offset {
+    /// At the given offset, without changing the file position (pread/pwrite)
+    At(u64),
+    /// At the file position, which is then advanced (read/write), e.g., for pipes and sockets
+    Current,
+    /// At the end of the file, for writes, which advances the file position as with O_APPEND
+    /// (RWF_APPEND)
+    Append,
+}
+
+impl Offset {
+    /// The value of sqe->off for the offset
+    pub const fn raw(&self) -> u64 {
+        match self {
+            Offset::At(x) => *x,
+            Offset::Current | Offset::Append => u64::MAX,
+        }
+    }
+}
+
+impl From<u64> for Offset {
+    fn from(off: u64) -> Self {
+        if off == u64::MAX {
+            Offset::Current
+        } else {
+            Offset::At(off)
+        }
+    }
+}
+
+// ioprio encoding (see include/uapi/linux/ioprio.h)
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+const IOPRIO_NR_LEVELS: u8 = 8;
+
+/// I/O scheduling class (see ioprio_set(2))
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum IoPrioClass {
+    /// No priority: derived from the CPU nice value of the task
+    None = 0,
+    /// Served before everything else (needs CAP_SYS_ADMIN)
+    RealTime = 1,
+    BestEffort = 2,
+    /// Served only when no other I/O is pending
+    Idle = 3,
+}
+
+/// I/O priority of a request: a class, and a level (0, highest, to 7) within the class
+///
+/// Requests without a priority use the priority of the submitting task. Only some I/O
+/// schedulers (e.g., bfq, mq-deadline) act on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoPriority(u16);
+
+impl IoPriority {
+
+    /// Fails with `InvalidInput` if the level is out of range: 0-7 for the real-time and
+    /// best-effort classes, and 0 for the others.
+    pub fn new(class: IoPrioClass, level: u8) -> io::Result<IoPriority> {
+        let max = match class {
+            IoPrioClass::RealTime | IoPrioClass::BestEffort => IOPRIO_NR_LEVELS - 1,
+            IoPrioClass::None | IoPrioClass::Idle => 0,
+        };
+        if level > max {
+            let msg = format!("invalid level for {:?} I/O priority: {}", class, level);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        Ok(IoPriority(((class as u16) << IOPRIO_CLASS_SHIFT) | level as u16))
+    }
+
+    pub fn realtime(level: u8) -> io::Result<IoPriority> {
+        IoPriority::new(IoPrioClass::RealTime, level)
+    }
+
+    pub fn best_effort(level: u8) -> io::Result<IoPriority> {
+        IoPriority::new(IoPrioClass::BestEffort, level)
+    }
+
+    pub fn idle() -> IoPriority {
+        IoPriority((IoPrioClass::Idle as u16) << IOPRIO_CLASS_SHIFT)
+    }
+
+    pub fn class(&self) -> IoPrioClass {
+        match self.0 >> IOPRIO_CLASS_SHIFT {
+            1 => IoPrioClass::RealTime,
+            2 => IoPrioClass::BestEffort,
+            3 => IoPrioClass::Idle,
+            _ => IoPrioClass::None,
+        }
+    }
+
+    pub fn level(&self) -> u8 {
+        (self.0 & ((1 << IOPRIO_CLASS_SHIFT) - 1)) as u8
+    }
+
+    /// The encoded value (as passed to ioprio_set(2))
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+}
+
+const IORING_REG_WAIT_TS: u32 = 1 << 0;
+
+/// Arguments for waiting on cqes, in the registered wait region (struct io_uring_reg_wait, see
+/// [`IoUring::register_wait_region`])
+#[repr(C)]
+pub struct RegWait {
+    ts: KernelTimespec,
+    min_wait_usec: u32,
+    flags: u32,
+    sigmask: u64,
+    sigmask_sz: u32,
+    pad: [u32; 3],
+    pad2: [u64; 2],
+}
+
+const _: () = assert!(mem::size_of::<RegWait>() == 64);
+
+// The extended argument of io_uring_enter() (IORING_ENTER_EXT_ARG)
+#[repr(C)]
+struct io_uring_getevents_arg {
+    sigmask: u64,
+    sigmask_sz: u32,
+    min_wait_usec: u32,
+    ts: u64,
+}
+
+// The extended argument of a wait
+enum WaitArg<'a> {
+    // offset of the arguments in the registered wait region
+    Reg(usize),
+    Timeout(&'a KernelTimespec),
+    // not an extended argument: the signal mask to wait with
+    Sigmask(&'a libc::sigset_t),
+}
+
+impl RegWait {
+    /// Stop waiting after timeout, even if fewer cqes than requested are available
+    pub fn set_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        match timeout {
+            Some(x) => {
+                self.ts = x.into();
+                self.flags |= IORING_REG_WAIT_TS;
+            }
+            None => self.flags &= !IORING_REG_WAIT_TS,
+        }
+    }
+
+    /// Once a cqe is available, keep waiting for up to min_wait for more, to batch completions
+    pub fn set_min_wait(&mut self, min_wait: std::time::Duration) {
+        self.min_wait_usec = u32::try_from(min_wait.as_micros()).unwrap_or(u32::MAX);
     }
 }
 
+// sqe->attr_type_mask flags
+#[cfg(feature = "linux-6_14")]
+const IORING_RW_ATTR_FLAG_PI: u64 = 1 << 0;
+
+#[cfg(feature = "linux-6_14")]
+bitflags::bitflags!{
+    /// What the device checks in the protection information of a request (IO_INTEGRITY_CHK_*)
+    pub struct IntegrityFlags: u16 {
+        const GUARD  = 1 << 0; // guard tag (checksum of the data)
+        const REFTAG = 1 << 1; // reference tag (usually the LBA, see AttrPi::seed)
+        const APPTAG = 1 << 2; // application tag (see AttrPi::app_tag)
+    }
+}
+
+/// Protection information (PI) for a read or write (struct io_uring_attr_pi, Linux 6.14, see
+/// [`SQEntry::set_attr_pi`])
+///
+/// The metadata buffer holds the integrity metadata of the blocks of the request: it is read for
+/// writes and filled for reads. Its format and size depend on the integrity profile of the device.
+#[cfg(feature = "linux-6_14")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct AttrPi {
+    flags: u16,
+    pub app_tag: u16,
+    len: u32,
+    addr: u64,
+    /// Initial reference tag
+    pub seed: u64,
+    rsvd: u64,
+}
+
+#[cfg(feature = "linux-6_14")]
+const _: () = assert!(mem::size_of::<AttrPi>() == 32);
+
+#[cfg(feature = "linux-6_14")]
+impl AttrPi {
+    /// Protection information with metadata buffer (buf, len), and the given checks
+    pub fn new(buf: *mut libc::c_void, len: u32, flags: IntegrityFlags) -> AttrPi {
+        AttrPi {
+            flags: flags.bits(),
+            app_tag: 0,
+            len,
+            addr: buf as usize as u64,
+            seed: 0,
+            rsvd: 0,
+        }
+    }
+}
+
+// IORING_REGISTER_FILES2 (and BUFFERS2)
+#[repr(C)]
+struct io_uring_rsrc_register {
+    nr: u32,
+    flags: u32,
+    resv2: u64,
+    data: u64,
+    tags: u64,
+}
+
+// io_uring_rsrc_register flags: register empty slots
+const IORING_RSRC_REGISTER_SPARSE: u32 = 1 << 0;
+
+// IORING_REGISTER_RING_FDS (and the other update operations)
+#[repr(C)]
+struct io_uring_rsrc_update {
+    offset: u32,
+    resv: u32,
+    data: u64,
+}
+
+// IORING_REGISTER_FILE_ALLOC_RANGE
+#[repr(C)]
+struct io_uring_file_index_range {
+    off: u32,
+    len: u32,
+    resv: u64,
+}
+
+// IORING_REGISTER_FILES_UPDATE
+#[repr(C)]
+struct io_uring_files_update {
+    offset: u32,
+    resv: u32,
+    fds: u64,
+}
+
+// IORING_REGISTER_MEM_REGION
+const IORING_MEM_REGION_TYPE_USER: u32 = 1;
+const IORING_MEM_REGION_REG_WAIT_ARG: u64 = 1;
+
+#[repr(C)]
+struct io_uring_region_desc {
+    user_addr: u64,
+    size: u64,
+    flags: u32,
+    id: u32,
+    mmap_offset: u64,
+    __resv: [u64; 4],
+}
+
+#[repr(C)]
+struct io_uring_mem_region_reg {
+    region_uptr: u64,
+    flags: u64,
+    __resv: [u64; 2],
+}
+
+const _: () = assert!(mem::size_of::<io_uring_region_desc>() == 64);
+const _: () = assert!(mem::size_of::<io_uring_mem_region_reg>() == 32);
+
 bitflags::bitflags!{
-    struct EnterFlags: libc::c_uint {
+    /// IORING_ENTER_* flags (see [`IoUring::enter`])
+    pub struct EnterFlags: libc::c_uint {
         const GETEVENTS = 1<<0;
         const SQ_WAKEUP = 1<<1;
+        const SQ_WAIT = 1<<2; // wait for room in the SQ ring (SQPOLL)
+        const EXT_ARG = 1<<3;
+        const REGISTERED_RING = 1<<4; // the fd is an index into the registered ring fds
+        const ABS_TIMER = 1<<5; // the EXT_ARG timeout is absolute
+        const EXT_ARG_REG = 1<<6; // the argument is an offset into the registered wait region
+        const NO_IOWAIT = 1<<7; // do not account the wait as iowait
     }
 }
 
 #[repr(C)]
-struct io_uring_sqe {
+pub(crate) struct io_uring_sqe {
     opcode: u8,                /* type of operation for this sqe */
     flags: u8,                 /* IOSQE_ flags */
     ioprio: u16,               /* ioprio for the request */
@@ -120,7 +701,23 @@ struct io_uring_sqe {
     len: u32,                  /* buffer size or number of iovecs */
     args: io_uring_sqe_args,
     user_data: u64,
-    idx: io_uring_sqe_idx,
+    buf_index: u16,            /* index into fixed buffers, or buffer group (buf_group) */
+    personality: u16,          /* personality to use, if used */
+    file_index: u32,           /* splice_fd_in, file_index, optlen, or addr_len */
+    addr3: u64,                /* or attr_ptr: pointer to the attributes of reads/writes */
+    __pad2: [u64; 1],          /* or attr_type_mask: IORING_RW_ATTR_FLAG_* */
+}
+
+impl io_uring_sqe {
+    // An sqe that is not part of a ring (see FallbackRing)
+    pub(crate) fn zeroed() -> io_uring_sqe {
+        unsafe { mem::zeroed() }
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        let p = self as *const io_uring_sqe as *const u8;
+        unsafe { std::slice::from_raw_parts(p, mem::size_of::<io_uring_sqe>()) }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -131,7 +728,275 @@ pub struct io_uring_cqe {
     flags: u32,
 }
 
+/// A completion queue entry
+pub type Cqe = io_uring_cqe;
+
+/// A set of CPUs (see [`IoUring::register_iowq_affinity`])
+#[derive(Clone, Copy)]
+pub struct CpuSet {
+    set: libc::cpu_set_t,
+}
+
+impl CpuSet {
+    /// An empty set
+    pub fn new() -> CpuSet {
+        // NB: cpu_set_t is a plain bitmask, so all zeroes is an empty set
+        CpuSet { set: unsafe { std::mem::zeroed() } }
+    }
+
+    /// A set with the given CPUs
+    pub fn from_cpus<I: IntoIterator<Item = usize>>(cpus: I) -> io::Result<CpuSet> {
+        let mut ret = CpuSet::new();
+        for cpu in cpus {
+            ret.add(cpu)?;
+        }
+        Ok(ret)
+    }
+
+    /// Maximum number of CPUs a set can hold
+    pub fn capacity() -> usize {
+        8 * std::mem::size_of::<libc::cpu_set_t>()
+    }
+
+    /// Add cpu to the set
+    pub fn add(&mut self, cpu: usize) -> io::Result<()> {
+        if cpu >= CpuSet::capacity() {
+            let msg = format!("cpu {} exceeds the maximum of {}", cpu, CpuSet::capacity() - 1);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        unsafe { libc::CPU_SET(cpu, &mut self.set) };
+        Ok(())
+    }
+
+    /// Remove cpu from the set
+    pub fn remove(&mut self, cpu: usize) {
+        if cpu < CpuSet::capacity() {
+            unsafe { libc::CPU_CLR(cpu, &mut self.set) };
+        }
+    }
+
+    /// Is cpu in the set?
+    pub fn contains(&self, cpu: usize) -> bool {
+        cpu < CpuSet::capacity() && unsafe { libc::CPU_ISSET(cpu, &self.set) }
+    }
+
+    /// The CPUs in the set
+    pub fn cpus(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..CpuSet::capacity()).filter(move |cpu| self.contains(*cpu))
+    }
+}
+
+impl Default for CpuSet {
+    fn default() -> CpuSet {
+        CpuSet::new()
+    }
+}
+
+impl std::fmt::Debug for CpuSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.cpus()).finish()
+    }
+}
+
+impl io_uring_cqe {
+    // A cqe that is not posted by the kernel (see FallbackRing)
+    pub(crate) fn new(user_data: u64, res: i32, flags: u32) -> io_uring_cqe {
+        io_uring_cqe { user_data, res, flags }
+    }
+
+    /// The user data of the corresponding sqe (see [`SQEntry::set_data`])
+    pub fn user_data(&self) -> u64 {
+        self.user_data
+    }
+
+    /// The result of the operation: a negated errno value on error
+    pub fn res(&self) -> i32 {
+        self.res
+    }
+
+    /// The IORING_CQE_F_* flags
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// The result of the operation as an io::Result: the error for a negative res, and res
+    /// otherwise (e.g., a number of bytes, or an fd, depending on the operation)
+    pub fn result(&self) -> io::Result<u32> {
+        if self.res < 0 {
+            Err(io::Error::from_raw_os_error(-self.res))
+        } else {
+            Ok(self.res as u32)
+        }
+    }
+
+    /// Whether this is the last cqe for the corresponding sqe
+    fn is_terminal(&self) -> bool {
+        !self.has_more()
+    }
+
+    /// Whether the corresponding sqe will generate more cqes (IORING_CQE_F_MORE)
+    pub fn has_more(&self) -> bool {
+        CqeFlags::from_bits_truncate(self.flags).contains(CqeFlags::MORE)
+    }
+
+    /// Whether this is a notification cqe (IORING_CQE_F_NOTIF), e.g., for a zero-copy send
+    pub fn is_notif(&self) -> bool {
+        CqeFlags::from_bits_truncate(self.flags).contains(CqeFlags::NOTIF)
+    }
+
+    /// Whether the kernel kept the rest of the provided buffer of the operation, for more data
+    /// (IORING_CQE_F_BUF_MORE). This only happens for incrementally consumed buffer rings (see
+    /// BufRing::register_incremental()).
+    pub fn buffer_more(&self) -> bool {
+        CqeFlags::from_bits_truncate(self.flags).contains(CqeFlags::BUF_MORE)
+    }
+
+    /// The id of the provided buffer used by the operation, if any (IORING_CQE_F_BUFFER)
+    pub fn buffer_id(&self) -> Option<u16> {
+        if CqeFlags::from_bits_truncate(self.flags).contains(CqeFlags::BUFFER) {
+            Some((self.flags >> IORING_CQE_BUFFER_SHIFT) as u16)
+        } else {
+            None
+        }
+    }
+}
+
+/// A 32-byte cqe, of a ring with [`SetupFlags::CQE32`] (see [`IoUring::pop_cqe32`])
+///
+/// It dereferences to the first half, which is a regular cqe. The second half (big_cqe) carries
+/// request-specific results, e.g., the result (dword 0) of NVMe passthrough commands.
+#[cfg(feature = "linux-5_19")]
+#[derive(Debug, Clone, Copy)]
+pub struct Cqe32 {
+    cqe: io_uring_cqe,
+    big_cqe: [u64; 2],
+}
+
+#[cfg(feature = "linux-5_19")]
+impl Cqe32 {
+    /// The second half of the cqe, as two u64s (big_cqe[0] and big_cqe[1])
+    pub fn big_cqe(&self) -> [u64; 2] {
+        self.big_cqe
+    }
+
+    /// The first u64 of the second half (e.g., the NVMe command result of passthrough commands)
+    pub fn extra1(&self) -> u64 {
+        self.big_cqe[0]
+    }
+
+    /// The second u64 of the second half
+    pub fn extra2(&self) -> u64 {
+        self.big_cqe[1]
+    }
+
+    /// The second half of the cqe, as bytes
+    pub fn big_cqe_bytes(&self) -> [u8; 16] {
+        let mut ret = [0; 16];
+        ret[..8].copy_from_slice(&self.big_cqe[0].to_ne_bytes());
+        ret[8..].copy_from_slice(&self.big_cqe[1].to_ne_bytes());
+        ret
+    }
+
+    /// The regular (first) half of the cqe
+    pub fn cqe(&self) -> io_uring_cqe {
+        self.cqe
+    }
+}
+
+#[cfg(feature = "linux-5_19")]
+impl std::ops::Deref for Cqe32 {
+    type Target = io_uring_cqe;
+
+    fn deref(&self) -> &io_uring_cqe {
+        &self.cqe
+    }
+}
+
+// The header of a provided buffer filled by a multishot recvmsg
+#[cfg(feature = "linux-6_0")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct io_uring_recvmsg_out {
+    namelen: u32,
+    controllen: u32,
+    payloadlen: u32,
+    flags: u32,
+}
+
+/// A message received by a multishot recvmsg (see [`SQEntry::prep_recvmsg_multishot`])
+///
+/// The kernel fills the provided buffer with a header, followed by msg_namelen bytes for the
+/// source address, msg_controllen bytes for the control data (as set in the msghdr of the
+/// request), and the payload.
+#[cfg(feature = "linux-6_0")]
+#[derive(Debug, Clone, Copy)]
+pub struct RecvMsgOut<'a> {
+    hdr: io_uring_recvmsg_out,
+    name: &'a [u8],
+    control: &'a [u8],
+    payload: &'a [u8],
+}
+
+#[cfg(feature = "linux-6_0")]
+impl<'a> RecvMsgOut<'a> {
+
+    /// Parse buf, the first cqe.res() bytes of the provided buffer of the cqe, given the msghdr
+    /// of the request
+    pub fn parse(buf: &'a [u8], msg: &libc::msghdr) -> io::Result<RecvMsgOut<'a>> {
+        let hdr_sz = std::mem::size_of::<io_uring_recvmsg_out>();
+        let name_sz = msg.msg_namelen as usize;
+        // NB: msg_controllen is not a usize on all targets
+        let control_sz: usize = msg.msg_controllen as _;
+        let payload_off = hdr_sz + name_sz + control_sz;
+        if buf.len() < payload_off {
+            let msg = format!("recvmsg buffer too short: {} < {} bytes", buf.len(), payload_off);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        let hdr = unsafe {
+            std::ptr::read_unaligned(buf.as_ptr() as *const io_uring_recvmsg_out)
+        };
+        // NB: the lengths in the header are those of the message, which may be truncated
+        let name = &buf[hdr_sz..hdr_sz + name_sz.min(hdr.namelen as usize)];
+        let control_off = hdr_sz + name_sz;
+        let control = &buf[control_off..control_off + control_sz.min(hdr.controllen as usize)];
+        let payload_sz = (buf.len() - payload_off).min(hdr.payloadlen as usize);
+        let payload = &buf[payload_off..payload_off + payload_sz];
+        Ok(RecvMsgOut { hdr, name, control, payload })
+    }
+
+    /// The source address (e.g., a sockaddr_in)
+    pub fn name(&self) -> &'a [u8] {
+        self.name
+    }
+
+    /// Length of the source address, which is larger than name() if it was truncated
+    pub fn name_len(&self) -> usize {
+        self.hdr.namelen as usize
+    }
+
+    /// The control data (see cmsg(3))
+    pub fn control(&self) -> &'a [u8] {
+        self.control
+    }
+
+    /// The payload of the message
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+
+    /// Length of the payload, which is larger than payload() if it was truncated
+    pub fn payload_len(&self) -> usize {
+        self.hdr.payloadlen as usize
+    }
+
+    /// The msg_flags of the message (e.g., MSG_TRUNC, MSG_CTRUNC)
+    pub fn flags(&self) -> libc::c_int {
+        self.hdr.flags as libc::c_int
+    }
+}
+
 #[repr(C)]
+#[derive(Clone, Copy)]
 struct io_sqring_offsets {
     head: u32,
     tail: u32,
@@ -145,6 +1010,7 @@ struct io_sqring_offsets {
 }
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 struct io_cqring_offsets {
     head: u32,
     tail: u32,
@@ -157,18 +1023,52 @@ struct io_cqring_offsets {
 
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 struct io_uring_params {
     sq_entries: u32,
     cq_entries: u32,
     flags: u32,
     sq_thread_cpu: u32,
     sq_thread_idle: u32,
-    resv: [u32; 5],
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
     sq_off: io_sqring_offsets,
     cq_off: io_cqring_offsets,
 }
 
-/**
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct io_uring_probe_op {
+    op: u8,
+    resv: u8,
+    flags: u16, /* IO_URING_OP_* flags */
+    resv2: u32,
+}
+
+const IO_URING_OP_SUPPORTED: u16 = 1 << 0;
+
+#[repr(C)]
+struct io_uring_probe {
+    last_op: u8, /* last opcode supported */
+    ops_len: u8, /* length of ops[] array below */
+    resv: u16,
+    resv2: [u32; 3],
+    ops: [io_uring_probe_op; 256],
+}
+
+// The kernel ABI structures have the same layout on 32-bit and 64-bit targets. Check this at
+// compile time, so that building for a 32-bit target (e.g., via scripts/cross_check.sh) is enough
+// to catch layout errors.
+const _: () = assert!(mem::size_of::<io_uring_sqe>() == 64);
+const _: () = assert!(mem::size_of::<io_uring_cqe>() == 16);
+const _: () = assert!(mem::size_of::<io_sqring_offsets>() == 40);
+const _: () = assert!(mem::size_of::<io_cqring_offsets>() == 40);
+const _: () = assert!(mem::size_of::<io_uring_params>() == 120);
+const _: () = assert!(mem::size_of::<io_uring_probe_op>() == 8);
+const _: () = assert!(mem::size_of::<io_uring_probe>() == 16 + 256 * 8);
+
+/*
  * Library structures
  */
 
@@ -185,6 +1085,9 @@ struct SQ {
     array: *mut u32,
 
     sqes: *mut io_uring_sqe,
+    sqes_sz: libc::size_t,
+    // 1 if sqes are 128 bytes (IORING_SETUP_SQE128), 0 otherwise
+    sqe_shift: u32,
     // NB: the ring depends on wrapping behavior for working correctly.
     sqe_head: std::num::Wrapping<u32>,
     sqe_tail: std::num::Wrapping<u32>,
@@ -202,11 +1105,80 @@ struct CQ {
     overflow: *mut u32,
 
     cqes: *mut io_uring_cqe,
+    // 1 if cqes are 32 bytes (IORING_SETUP_CQE32), 0 otherwise
+    cqe_shift: u32,
 
     ring_sz: libc::size_t,
     ring_ptr: *mut libc::c_void,
 }
 
+/// A cqe that still occupies its slot in the CQ ring (see [`IoUring::peek_cqe`])
+///
+/// It dereferences to (a copy of) the cqe.
+pub struct CqeRef<'a> {
+    ring: &'a mut IoUring,
+    cqe: io_uring_cqe,
+}
+
+impl CqeRef<'_> {
+    /// Mark the cqe as seen, releasing its slot to the kernel, and return it
+    ///
+    /// This accounts for the cqe as [`IoUring::pop_cqe`] does.
+    pub fn seen(self) -> io_uring_cqe {
+        // NB: the cqe is at the head, since we hold the only reference to the ring
+        self.ring.pop_cqe().unwrap()
+    }
+}
+
+impl std::ops::Deref for CqeRef<'_> {
+    type Target = io_uring_cqe;
+
+    fn deref(&self) -> &io_uring_cqe {
+        &self.cqe
+    }
+}
+
+/// Iterator over the available cqes, consuming them (see [`IoUring::completions`])
+pub struct Completions<'a> {
+    ring: &'a mut IoUring,
+    head: u32,
+    tail: u32,
+    mask: u32,
+}
+
+impl Iterator for Completions<'_> {
+    type Item = io_uring_cqe;
+
+    fn next(&mut self) -> Option<io_uring_cqe> {
+        loop {
+            if self.head == self.tail {
+                return None;
+            }
+            let cq = &self.ring.cq;
+            let cqe = unsafe { *cq.cqes.add(((self.head & self.mask) << cq.cqe_shift) as usize) };
+            self.head = self.head.wrapping_add(1);
+            self.ring.reaped(&cqe);
+            if !self.ring.is_wait_timeout(&cqe) {
+                return Some(cqe);
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.tail.wrapping_sub(self.head) as usize;
+        // NB: the cqes of wait_cqe_timeout() timeouts are not yielded
+        let min = if self.ring.wait_timeouts == 0 { n } else { 0 };
+        (min, Some(n))
+    }
+}
+
+impl Drop for Completions<'_> {
+    fn drop(&mut self) {
+        // NB: cqes that were not yielded stay in the ring
+        unsafe { store_release(self.ring.cq.khead, self.head) };
+    }
+}
+
 pub struct CqIter<'a> {
     curr: std::num::Wrapping<u32>, // current index for the iterator
     cq: &'a CQ,
@@ -214,22 +1186,481 @@ pub struct CqIter<'a> {
 
 
 /// io uring descriptor
+///
+/// Preparing, submitting, and reaping requests (get_sqe(), submit*(), pop_cqe(), peek_cqe(),
+/// completions(), cq_iter(), and poll_completions()) does not allocate, unless it needs to report
+/// an error or a CQ overflow.
+/// Pending request tracking, latency recording, per-opcode counters, and recorders (see
+/// [`IoUring::track_pending`], [`IoUring::track_latency`], [`IoUring::track_op_stats`], and
+/// [`IoUring::set_recorder`]) may allocate when they are enabled.
 pub struct IoUring {
     fd: libc::c_int,
     sq: SQ,
     cq: CQ,
     flags: SetupFlags,
+    features: Features,
+    // last value of the CQ overflow counter we have seen
+    cq_overflow_seen: u32,
+    // number of sqes consumed by the kernel whose terminal cqe has not been reaped yet
+    inflight: u32,
+    // number of timeouts issued by wait_cqe_timeout() whose cqe has not been reaped yet
+    wait_timeouts: u32,
+    // timespecs of the wait_cqe_timeout() timeouts, with the sequence number of their sqe, kept
+    // until the kernel consumes the sqe
+    wait_ts: VecDeque<(u32, Box<KernelTimespec>)>,
+    // index of the ring fd in the registered ring fds, if registered (see register_ring_fd())
+    ring_fd_index: Option<u32>,
+    drop_policy: ShutdownPolicy,
+    stats: Stats,
+    wait_region: Option<WaitRegion>,
+    // submitted requests by user data, if tracked (see IoUring::track_pending())
+    pending: Option<HashMap<u64, PendingOp>>,
+    // latencies of tracked requests, if recorded (see IoUring::track_latency())
+    latency: Option<Latencies>,
+    // counters per opcode, if kept (see IoUring::track_op_stats())
+    op_stats: Option<OpcodeStats>,
+    // log of sqes and cqes, if any (see IoUring::set_recorder())
+    recorder: Option<Recorder>,
+    // parameters returned by io_uring_setup(), for sharing the ring (see crate::share)
+    params: io_uring_params,
+}
+
+// Memory of a registered wait region (see IoUring::register_wait_region())
+struct WaitRegion {
+    waits: *mut RegWait,
+    nr: usize,
+    sz: usize,
+}
+
+impl Drop for WaitRegion {
+    fn drop(&mut self) {
+        // NB: the kernel pins the region pages, so unmapping them while registered is fine
+        let err = unsafe { libc::munmap(self.waits as *mut libc::c_void, self.sz) };
+        if err != 0 {
+            let error = io::Error::last_os_error();
+            eprintln!("WARNING: munmap() of wait region failed: {}", error);
+        }
+    }
+}
+
+/// How to wait between polls in [`IoUring::poll_completions`]
+///
+/// The first `spins` polls are back to back, the next `yields` polls yield the CPU before polling
+/// again, and the polls after that sleep, starting at 1us and doubling up to `max_sleep`. Without
+/// `max_sleep`, polling never sleeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpinPolicy {
+    pub spins: u32,
+    pub yields: u32,
+    pub max_sleep: Option<std::time::Duration>,
+}
+
+impl Default for SpinPolicy {
+    /// Spin for a while, and then yield between polls, but never sleep
+    fn default() -> Self {
+        SpinPolicy { spins: 1000, yields: u32::MAX, max_sleep: None }
+    }
+}
+
+/// Counters of ring activity (see [`IoUring::stats`])
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// sqes submitted to the kernel
+    pub sqes_submitted: u64,
+    /// cqes reaped via [`IoUring::pop_cqe`] (or the other reaping functions)
+    pub cqes_reaped: u64,
+    /// io_uring_enter system calls
+    pub enter_calls: u64,
+    /// [`IoUring::get_sqe`] calls that failed because the submission queue was full
+    pub sq_full: u64,
+    /// completions lost due to CQ ring overflow (the overflow counter of the CQ ring, see
+    /// [`IoUring::cq_overflow`])
+    pub cq_overflows: u64,
+    /// sqes the kernel dropped because they were invalid (the dropped counter of the SQ ring,
+    /// see [`IoUring::sq_dropped`])
+    pub sq_dropped: u64,
+}
+
+impl Stats {
+    /// The counters that changed since prev (an earlier value of the same ring's counters)
+    pub fn delta(&self, prev: &Stats) -> Stats {
+        Stats {
+            sqes_submitted: self.sqes_submitted.wrapping_sub(prev.sqes_submitted),
+            cqes_reaped: self.cqes_reaped.wrapping_sub(prev.cqes_reaped),
+            enter_calls: self.enter_calls.wrapping_sub(prev.enter_calls),
+            sq_full: self.sq_full.wrapping_sub(prev.sq_full),
+            cq_overflows: self.cq_overflows.wrapping_sub(prev.cq_overflows),
+            sq_dropped: self.sq_dropped.wrapping_sub(prev.sq_dropped),
+        }
+    }
+
+    // (name, value) pairs, for printing
+    fn fields(&self) -> [(&'static str, u64); 6] {
+        [
+            ("sqes", self.sqes_submitted),
+            ("cqes", self.cqes_reaped),
+            ("enters", self.enter_calls),
+            ("sq_full", self.sq_full),
+            ("cq_overflows", self.cq_overflows),
+            ("sq_dropped", self.sq_dropped),
+        ]
+    }
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (name, val)) in self.fields().iter().enumerate() {
+            let sep = if i == 0 { "" } else { " " };
+            write!(f, "{}{}={}", sep, name, val)?;
+        }
+        Ok(())
+    }
+}
+
+/// A submitted request whose (last) cqe has not been reaped yet (see
+/// [`IoUring::track_pending`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingOp {
+    pub user_data: u64,
+    /// The IORING_OP_* opcode (see [`OpCode::from_u8`])
+    pub opcode: u8,
+    pub fd: i32,
+    /// When the request was submitted (the oldest one, if there are several)
+    pub submitted: std::time::Instant,
+    /// Number of pending requests with this user data (usually 1)
+    pub count: u32,
+}
+
+impl PendingOp {
+    /// Time since the request was submitted
+    pub fn age(&self) -> std::time::Duration {
+        self.submitted.elapsed()
+    }
+}
+
+impl std::fmt::Display for PendingOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match OpCode::from_u8(self.opcode) {
+            Some(op) => write!(f, "op={}", op)?,
+            None => write!(f, "op={}", self.opcode)?,
+        }
+        write!(f, " fd={} user_data={:#x} age={:?}", self.fd, self.user_data, self.age())?;
+        if self.count > 1 {
+            write!(f, " count={}", self.count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counters of the requests with an opcode (see [`OpcodeStats`])
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpStats {
+    /// sqes submitted to the kernel
+    pub sqes: u64,
+    /// cqes reaped, including the failed ones
+    pub cqes: u64,
+    /// Bytes transferred, i.e., the sum of the (positive) results of reads, writes, sends, and
+    /// receives
+    pub bytes: u64,
+    /// cqes with a negative result
+    pub errors: u64,
+    /// cqes with a negative result, by errno
+    pub errnos: BTreeMap<i32, u64>,
+}
+
+impl OpStats {
+    /// Fraction of cqes that failed (0 if there are none)
+    pub fn error_rate(&self) -> f64 {
+        if self.cqes == 0 {
+            return 0.0;
+        }
+        self.errors as f64 / self.cqes as f64
+    }
+}
+
+// Whether the result of successful requests with op is a number of bytes
+fn transfers_bytes(op: OpCode) -> bool {
+    matches!(op,
+        OpCode::Readv | OpCode::Writev | OpCode::ReadFixed | OpCode::WriteFixed |
+        OpCode::Sendmsg | OpCode::Recvmsg | OpCode::Read | OpCode::Write | OpCode::Send |
+        OpCode::Recv
+    )
+}
+
+/// Counters of the requests of a ring, per opcode (see [`IoUring::track_op_stats`])
+///
+/// The opcode of a cqe is that of the tracked request with the same user data (see
+/// [`IoUring::track_pending`]), so the cqes of requests in flight with the same user data count
+/// towards the opcode of the oldest one. Cqes that do not match a tracked request (e.g., of
+/// requests submitted before tracking started, or failed requests with IOSQE_CQE_SKIP_SUCCESS)
+/// are only counted in [`Self::untracked_cqes`].
+#[derive(Debug, Clone, Default)]
+pub struct OpcodeStats {
+    // by opcode
+    ops: Vec<OpStats>,
+    untracked_cqes: u64,
+}
+
+impl OpcodeStats {
+
+    fn op_mut(&mut self, opcode: u8) -> &mut OpStats {
+        let idx = opcode as usize;
+        if self.ops.len() <= idx {
+            self.ops.resize(idx + 1, OpStats::default());
+        }
+        &mut self.ops[idx]
+    }
+
+    fn submitted(&mut self, opcode: u8) {
+        self.op_mut(opcode).sqes += 1;
+    }
+
+    fn completed(&mut self, opcode: u8, res: i32) {
+        let bytes = res > 0 && OpCode::from_u8(opcode).map(transfers_bytes).unwrap_or(false);
+        let op = self.op_mut(opcode);
+        op.cqes += 1;
+        if res < 0 {
+            op.errors += 1;
+            *op.errnos.entry(-res).or_insert(0) += 1;
+        } else if bytes {
+            op.bytes += res as u64;
+        }
+    }
+
+    /// The counters of op (all zero, if no request with op was submitted)
+    pub fn get(&self, op: OpCode) -> OpStats {
+        self.ops.get(op.as_u8() as usize).cloned().unwrap_or_default()
+    }
+
+    /// The counters of the opcodes with submitted or completed requests, with their (IORING_OP_*)
+    /// opcode, in opcode order
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &OpStats)> {
+        let ops = self.ops.iter().enumerate();
+        ops.filter(|(_, s)| s.sqes > 0 || s.cqes > 0).map(|(i, s)| (i as u8, s))
+    }
+
+    /// Number of cqes, of all opcodes
+    pub fn total_cqes(&self) -> u64 {
+        self.ops.iter().map(|s| s.cqes).sum()
+    }
+
+    /// Number of cqes that did not match a tracked request
+    pub fn untracked_cqes(&self) -> u64 {
+        self.untracked_cqes
+    }
+
+    /// Reset the counters
+    pub fn reset(&mut self) {
+        self.ops.clear();
+        self.untracked_cqes = 0;
+    }
 }
 
-pub struct SQEntry(*mut io_uring_sqe);
+impl std::fmt::Display for OpcodeStats {
+    /// One line per opcode, e.g., "op=RECV sqes=90 cqes=90 (90.0%) bytes=9000 errors=4 (4.4%)
+    /// errno11=4"
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let total = self.total_cqes().max(1) as f64;
+        for (opcode, s) in self.iter() {
+            match OpCode::from_u8(opcode) {
+                Some(op) => write!(f, "op={}", op)?,
+                None => write!(f, "op={}", opcode)?,
+            }
+            write!(f, " sqes={} cqes={} ({:.1}%)", s.sqes, s.cqes, 100.0 * s.cqes as f64 / total)?;
+            write!(f, " bytes={} errors={} ({:.1}%)", s.bytes, s.errors, 100.0 * s.error_rate())?;
+            for (errno, n) in s.errnos.iter() {
+                write!(f, " errno{}={}", errno, n)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
 
+/// The counters of a ring at a point in time (see [`IoUring::stats_snapshot`])
+///
+/// For periodic logging, keep the last snapshot and print the delta of the next one against it:
+///
+/// ```no_run
+/// # let ring = iouring::io_uring::IoUring::init(4).unwrap();
+/// let mut last = ring.stats_snapshot();
+/// loop {
+///     std::thread::sleep(std::time::Duration::from_secs(10));
+///     let now = ring.stats_snapshot();
+///     // e.g., "10.00s: sqes=1000 (100.0/s) cqes=1000 (100.0/s) enters=10 (1.0/s) ..."
+///     eprintln!("{}", now.delta(&last));
+///     last = now;
+/// }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct StatsSnapshot {
+    pub stats: Stats,
+    pub taken: std::time::Instant,
+}
 
-/**
+impl StatsSnapshot {
+    /// The activity between prev and this snapshot
+    pub fn delta(&self, prev: &StatsSnapshot) -> StatsDelta {
+        StatsDelta {
+            stats: self.stats.delta(&prev.stats),
+            elapsed: self.taken.saturating_duration_since(prev.taken),
+        }
+    }
+}
+
+impl std::fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.stats.fmt(f)
+    }
+}
+
+/// Ring activity over an interval (see [`StatsSnapshot::delta`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatsDelta {
+    pub stats: Stats,
+    pub elapsed: std::time::Duration,
+}
+
+impl StatsDelta {
+    /// Rate of a counter of the delta, per second (0 for an empty interval)
+    pub fn per_sec(&self, count: u64) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        count as f64 / secs
+    }
+}
+
+impl std::fmt::Display for StatsDelta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.2}s:", self.elapsed.as_secs_f64())?;
+        for (name, val) in self.stats.fields().iter() {
+            write!(f, " {}={} ({:.1}/s)", name, val, self.per_sec(*val))?;
+        }
+        Ok(())
+    }
+}
+
+/// What to do with in-flight operations when shutting down the ring
+///
+/// The kernel may access application memory (e.g., I/O buffers) until an operation completes. A
+/// policy other than `Detach` ensures that this is no longer the case once shutdown is done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownPolicy {
+    /// Cancel all in-flight operations, and wait until they complete. Requires
+    /// IORING_ASYNC_CANCEL_ANY (Linux 5.19). On older kernels, this falls back to `Wait`.
+    CancelAndWait,
+    /// Wait until all in-flight operations complete. Note that this may block forever (e.g., for
+    /// a recv on a socket that never receives any data).
+    Wait,
+    /// Do not wait. The kernel cancels pending operations when the ring is closed, but this
+    /// happens asynchronously, so memory used by in-flight operations should not be reused.
+    Detach,
+}
+
+/// user_data of the cancel request issued by [`IoUring::shutdown`]
+const SHUTDOWN_CANCEL_UDATA: u64 = u64::MAX;
+
+/// user_data of the timeouts issued by [`IoUring::wait_cqe_timeout`] on kernels without EXT_ARG
+const WAIT_TIMEOUT_UDATA: u64 = u64::MAX - 1;
+
+/// A submission queue entry
+///
+/// An entry is obtained via [`IoUring::get_sqe`], and it mutably borrows the ring. This ensures
+/// that entries cannot be modified after they are handed over to the kernel, since all the
+/// functions that do so (e.g., [`IoUring::submit`]) also need to borrow the ring mutably:
+///
+/// ```compile_fail
+/// # use iouring::io_uring::IoUring;
+/// let mut ring = IoUring::init(4).unwrap();
+/// let mut sqe = ring.get_sqe().unwrap();
+/// ring.submit().unwrap();
+/// sqe.set_data(42); // error: ring is still borrowed by sqe
+/// ```
+///
+/// For rings with 128-byte entries (IORING_SETUP_SQE128), the second field is the second half of
+/// the entry.
+pub struct SQEntry<'a>(&'a mut io_uring_sqe, Option<&'a mut [u8; 64]>);
+
+/// Types that can be copied to and from the command area of URING_CMD entries (see
+/// [`SQEntry::set_cmd`] and [`SQEntry::cmd`])
+///
+/// # Safety
+///
+/// The type needs to be #[repr(C)] (or #[repr(transparent)]), without padding bytes, and any bytes
+/// need to be a valid value of it. Structs of integers, and arrays of them, usually are. Types with
+/// bools, enums, references, or padding are not.
+pub unsafe trait CmdPayload: Copy {}
+
+unsafe impl<const N: usize> CmdPayload for [u8; N] {}
+unsafe impl<const N: usize> CmdPayload for [u32; N] {}
+unsafe impl<const N: usize> CmdPayload for [u64; N] {}
+
+fn payload_bytes<T: CmdPayload>(x: &T) -> &[u8] {
+    // NB: CmdPayload types have no padding, so all their bytes are initialized
+    unsafe { std::slice::from_raw_parts(x as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+
+/*
  * Syscall wrappers
  */
 
+// io_uring_register opcodes (see IoUring::register())
+pub const IORING_REGISTER_BUFFERS: libc::c_uint = 0;
+pub const IORING_UNREGISTER_BUFFERS: libc::c_uint = 1;
+pub const IORING_REGISTER_FILES: libc::c_uint = 2;
+pub const IORING_UNREGISTER_FILES: libc::c_uint = 3;
+pub const IORING_REGISTER_EVENTFD: libc::c_uint = 4;
+pub const IORING_UNREGISTER_EVENTFD: libc::c_uint = 5;
+pub const IORING_REGISTER_FILES_UPDATE: libc::c_uint = 6;
+pub const IORING_REGISTER_EVENTFD_ASYNC: libc::c_uint = 7;
+pub const IORING_REGISTER_PROBE: libc::c_uint = 8;
+pub const IORING_REGISTER_PERSONALITY: libc::c_uint = 9;
+pub const IORING_UNREGISTER_PERSONALITY: libc::c_uint = 10;
+pub const IORING_REGISTER_RESTRICTIONS: libc::c_uint = 11;
+pub const IORING_REGISTER_ENABLE_RINGS: libc::c_uint = 12;
+pub const IORING_REGISTER_FILES2: libc::c_uint = 13;
+pub const IORING_REGISTER_FILES_UPDATE2: libc::c_uint = 14;
+pub const IORING_REGISTER_BUFFERS2: libc::c_uint = 15;
+pub const IORING_REGISTER_BUFFERS_UPDATE: libc::c_uint = 16;
+pub const IORING_REGISTER_IOWQ_AFF: libc::c_uint = 17;
+pub const IORING_UNREGISTER_IOWQ_AFF: libc::c_uint = 18;
+pub const IORING_REGISTER_IOWQ_MAX_WORKERS: libc::c_uint = 19;
+pub const IORING_REGISTER_RING_FDS: libc::c_uint = 20;
+pub const IORING_UNREGISTER_RING_FDS: libc::c_uint = 21;
+pub const IORING_REGISTER_PBUF_RING: libc::c_uint = 22;
+pub const IORING_UNREGISTER_PBUF_RING: libc::c_uint = 23;
+pub const IORING_REGISTER_SYNC_CANCEL: libc::c_uint = 24;
+pub const IORING_REGISTER_FILE_ALLOC_RANGE: libc::c_uint = 25;
+pub const IORING_REGISTER_PBUF_STATUS: libc::c_uint = 26;
+pub const IORING_REGISTER_NAPI: libc::c_uint = 27;
+pub const IORING_UNREGISTER_NAPI: libc::c_uint = 28;
+pub const IORING_REGISTER_CLOCK: libc::c_uint = 29;
+pub const IORING_REGISTER_CLONE_BUFFERS: libc::c_uint = 30;
+pub const IORING_REGISTER_SEND_MSG_RING: libc::c_uint = 31;
+pub const IORING_REGISTER_ZCRX_IFQ: libc::c_uint = 32;
+pub const IORING_REGISTER_RESIZE_RINGS: libc::c_uint = 33;
+pub const IORING_REGISTER_MEM_REGION: libc::c_uint = 34;
+/// Flag for the opcode: the fd is an index into the registered ring fds
+pub const IORING_REGISTER_USE_REGISTERED_RING: libc::c_uint = 1 << 31;
+
+// (feature name, whether it is enabled, opcodes of the requests it enables)
+type KernelFeatureOps = (&'static str, bool, &'static [OpCode]);
+
+/// Opcodes of the requests enabled by each linux-* feature, checked by
+/// [`IoUring::check_kernel_features`]
+const KERNEL_FEATURE_OPS: &[KernelFeatureOps] = &[
+    ("linux-5_6", cfg!(feature = "linux-5_6"), &[OpCode::Statx, OpCode::Send, OpCode::Recv]),
+    ("linux-5_15", cfg!(feature = "linux-5_15"), &[OpCode::Splice, OpCode::Tee, OpCode::Close]),
+    ("linux-5_19", cfg!(feature = "linux-5_19"), &[OpCode::MsgRing, OpCode::UringCmd]),
+    ("linux-6_0", cfg!(feature = "linux-6_0"), &[OpCode::SendZc]),
+    ("linux-6_7", cfg!(feature = "linux-6_7"), &[OpCode::Waitid]),
+];
+
 /// io_uring_register syscall wrapper
-unsafe fn io_uring_register(
+pub(crate) unsafe fn io_uring_register(
     fd: libc::c_int,
     opcode: libc::c_uint,
     arg: *mut libc::c_void,
@@ -270,129 +1701,1085 @@ unsafe fn io_uring_enter(
     //    http://www.sourceware.org/git/?p=glibc.git;a=blob;f=sysdeps/unix/sysv/linux/bits/types/__sigset_t.h;h=e2f18acf30f43496567b1511456089dcd1798425;hb=fef7c63cd5a5a3150dc9465687359351afab5010
     //    indeed, sizeof(sigset_t) is 128)
     //
-    const NSIG_: libc::c_uint = 65;
-    let sigset_size: libc::c_uint = NSIG_ / 8;
+    //  32-bit tasks on a 64-bit kernel go through the compat path, which checks against
+    //  sizeof(compat_sigset_t). That is _COMPAT_NSIG / 8, which is the same as _NSIG / 8.
+    //    https://git.kernel.org/pub/scm/linux/kernel/git/torvalds/linux.git/tree/kernel/signal.c?h=v5.1#n2838
+    let sigset_size = KERNEL_SIGSET_SIZE;
     libc::syscall(SYS_io_uring_enter, fd, to_submit, min_complete, flags, sigset, sigset_size)
 }
 
 
-/**
- * Misc helpers
- */
+/// io_uring_enter syscall wrapper, with an extended argument (IORING_ENTER_EXT_ARG) or a sigset
+/// of argsz bytes
+unsafe fn io_uring_enter_arg(
+    fd: libc::c_int,
+    to_submit: libc::c_uint,
+    min_complete: libc::c_uint,
+    flags: libc::c_uint,
+    arg: *mut libc::c_void,
+    argsz: libc::size_t,
+) -> libc::c_long {
+    libc::syscall(SYS_io_uring_enter, fd, to_submit, min_complete, flags, arg, argsz)
+}
+
+/// Size of the kernel's sigset_t (_NSIG / 8)
+///
+/// _NSIG is 64 everywhere, except for mips where it is 128.
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+const KERNEL_SIGSET_SIZE: libc::c_uint = 64 / 8;
+#[cfg(any(target_arch = "mips", target_arch = "mips64"))]
+const KERNEL_SIGSET_SIZE: libc::c_uint = 128 / 8;
+
+/*
+ * Misc helpers
+ */
+
+/// Convert a number of iovecs to the u32 the kernel expects
+fn iovecs_len(len: usize) -> io::Result<u32> {
+    u32::try_from(len).map_err(|_| {
+        let msg = format!("too many buffers ({}) for a single operation", len);
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    })
+}
+
+/// Compute the size of a ring mapping: a header of hdr_sz bytes, followed by nentries entries of
+/// esz bytes
+///
+/// Sizes are reported by the kernel, so they should always fit, but avoid panicking (or, worse,
+/// wrapping around) if they do not.
+fn ring_size(hdr_sz: u32, nentries: u32, esz: usize) -> io::Result<libc::size_t> {
+    let hdr_sz = libc::size_t::try_from(hdr_sz).ok();
+    let nentries = libc::size_t::try_from(nentries).ok();
+    hdr_sz.zip(nentries)
+        .and_then(|(h, n)| n.checked_mul(esz).and_then(|x| x.checked_add(h)))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "ring size overflows size_t"))
+}
+
+/// Load a ring index that is updated by the kernel, with acquire semantics
+///
+/// NB: not sure if there is a better way to do this than the cast here, but AtomicU32
+/// documentation says that: "This type has the same in-memory representation as the underlying
+/// integer type, u32."
+unsafe fn load_acquire(p: *const u32) -> u32 {
+    let ap = p as *const std::sync::atomic::AtomicU32;
+    (*ap).load(std::sync::atomic::Ordering::Acquire)
+}
+
+/// Store a ring index that is read by the kernel, with release semantics
+unsafe fn store_release(p: *mut u32, val: u32) {
+    let ap = p as *const std::sync::atomic::AtomicU32;
+    (*ap).store(val, std::sync::atomic::Ordering::Release)
+}
+
+/// mmap helper, using the default protection and flags
+unsafe fn mmap(len: libc::size_t, fd: libc::c_int, off: libc::off_t) -> *mut libc::c_void {
+    let prot  = libc::PROT_READ | libc::PROT_WRITE;
+    let flags = libc::MAP_SHARED | libc::MAP_POPULATE;
+    libc::mmap(std::ptr::null_mut(), len, prot, flags, fd, off)
+}
+
+/// munmap helper
+///
+/// Prints a message at stder if munmap() returns an error.
+unsafe fn munmap(addr: *mut libc::c_void, len: libc::size_t) -> libc::c_int {
+        let err = libc::munmap(addr, len);
+        if err == 0 {
+            return err;
+        }
+        let bt = Backtrace::new();
+        let errno_ptr = libc::__errno_location();
+        let old_errno = *errno_ptr;
+        let error = io::Error::from_raw_os_error(old_errno as i32);
+        // NB: not sure how to print a backtrace here. There does not seem to be a way using libstd
+        // that does not involve panic!
+        eprintln!("WARNING: munmap() failed: {}\nBacktrace:\n{:?}", error, bt);
+        *errno_ptr = old_errno;
+        err
+}
+
+/// close() helper
+///
+/// Prints a message at stderr if close() returns an error.
+unsafe fn close(fd: libc::c_int) -> libc::c_int {
+        let err = libc::close(fd);
+        if err == 0 {
+            return err;
+        }
+
+        // Not match we can do in case of an error here. Just print a backtrace.
+        let bt = Backtrace::new();
+        let errno_ptr = libc::__errno_location();
+        let old_errno = *errno_ptr;
+        let error = io::Error::from_raw_os_error(old_errno as i32);
+        eprintln!("WARNING: close() failed: {}\nBacktrace:\n{:?}", error, bt);
+        *errno_ptr = old_errno;
+        err
+}
+
+/*
+ * Main implementation
+ */
+
+impl<'a> SQEntry<'a> {
+    // An entry for an sqe that is not part of a ring (see FallbackRing)
+    pub(crate) fn new(sqe: &'a mut io_uring_sqe) -> SQEntry<'a> {
+        SQEntry(sqe, None)
+    }
+}
+
+impl SQEntry<'_> {
+    fn reset(&mut self) {
+        *self.0 = unsafe { mem::zeroed() };
+        if let Some(ref mut x) = self.1 {
+            **x = [0; 64];
+        }
+    }
+
+    // Overwrite the sqe with a raw one (64, or 128 bytes for SQE128 rings)
+    pub(crate) fn copy_from_bytes(&mut self, b: &[u8]) -> io::Result<()> {
+        match (b.len(), self.1.as_mut()) {
+            (64, _) => self.reset(),
+            (128, Some(ext)) => ext.copy_from_slice(&b[64..]),
+            (len, _) => {
+                let msg = format!("cannot copy a {}-byte sqe", len);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        }
+        unsafe {
+            std::ptr::copy_nonoverlapping(b.as_ptr(), &mut *self.0 as *mut _ as *mut u8, 64);
+        }
+        Ok(())
+    }
+
+    fn prep_rw(
+        &mut self,
+        op: OpCode,
+        fd: libc::c_int,
+        addr: *const libc::c_void,
+        len: u32,
+        off: u64,
+    ) {
+        self.reset();
+        let sqe = &mut *self.0;
+        sqe.opcode = op as u8;
+        sqe.fd = fd;
+        sqe.off = off;
+        // NB: go through usize so that pointers are zero-extended on 32-bit targets
+        sqe.addr = addr as usize as u64;
+        sqe.len = len;
+    }
+
+    pub fn set_data(&mut self, data: u64) {
+        self.0.user_data = data
+    }
+
+    /// Issue the request with the credentials of a registered personality (see
+    /// [`IoUring::register_personality`], and crate::sandbox::SandboxBuilder::personality())
+    #[cfg(feature = "linux-5_15")]
+    pub fn set_personality(&mut self, id: u16) {
+        self.0.personality = id
+    }
+
+    /// Set the I/O priority of a read or write request
+    ///
+    /// The request fails with EPERM if the priority is real-time and the task is not allowed to
+    /// use it. Other requests (e.g., accept, send, recv) use the field for flags, so this must
+    /// not be used with them.
+    ///
+    /// NB: prep_* functions reset the priority, so this needs to be called after them.
+    pub fn set_ioprio(&mut self, prio: IoPriority) {
+        self.0.ioprio = prio.bits()
+    }
+
+    /// Attach protection information to a read or write request (Linux 6.14)
+    ///
+    /// Needs a block device that supports integrity metadata, opened with O_DIRECT. The kernel
+    /// copies pi when the request is submitted, but the metadata buffer it points to needs to stay
+    /// valid until the request completes.
+    ///
+    /// NB: prep_* functions reset the attributes, so this needs to be called after them.
+    #[cfg(feature = "linux-6_14")]
+    pub fn set_attr_pi(&mut self, pi: *const AttrPi) {
+        self.0.addr3 = pi as usize as u64;
+        self.0.__pad2[0] = IORING_RW_ATTR_FLAG_PI;
+    }
+
+    /// The IOSQE_* flags of the entry
+    pub fn flags(&self) -> SqeFlags {
+        SqeFlags::from_bits_truncate(self.0.flags)
+    }
+
+    /// Set the IOSQE_* flags of the entry
+    ///
+    /// NB: prep_* functions reset the flags, so this needs to be called after them.
+    pub fn set_flags(&mut self, flags: SqeFlags) {
+        self.0.flags = flags.bits()
+    }
+
+    pub fn prep_readv(&mut self, fd: libc::c_int, iovecs: *const libc::iovec, nr_vecs: u32, off: u64) {
+        let ptr = iovecs as *const libc::c_void;
+        self.prep_rw(OpCode::Readv, fd, ptr, nr_vecs, off)
+    }
+
+    pub fn prep_writev(&mut self, fd: libc::c_int, iovecs: *const libc::iovec, nr_vecs: u32, off: u64) {
+        let ptr = iovecs as *const libc::c_void;
+        self.prep_rw(OpCode::Writev, fd, ptr, nr_vecs, off)
+    }
+
+    /// Set the RWF_* flags (see preadv2(2)) of a read or write request, e.g., RWF_DSYNC
+    ///
+    /// NB: prep_* functions reset the flags, so this needs to be called after them.
+    pub fn set_rw_flags(&mut self, rw_flags: libc::c_int) {
+        self.0.args.rw_flags = rw_flags;
+    }
+
+    /// Set the offset of a read or write request, which replaces the offset passed to the prep_*
+    /// function
+    ///
+    /// [`Offset::Append`] also adds RWF_APPEND to the RWF_* flags, so for requests that set other
+    /// flags, this needs to be called after [`Self::set_rw_flags`].
+    pub fn set_offset(&mut self, off: Offset) {
+        self.0.off = off.raw();
+        if off == Offset::Append {
+            unsafe { self.0.args.rw_flags |= libc::RWF_APPEND };
+        }
+    }
+
+    /// Sync the file (or only its data, with [`IORING_FSYNC_DATASYNC`])
+    ///
+    /// NB: the fsync is not ordered with respect to other requests; use IO_LINK or IO_DRAIN (see
+    /// [`SqeFlags`]) to have it start after the writes it is supposed to cover.
+    pub fn prep_fsync(&mut self, fd: libc::c_int, fsync_flags: u32) {
+        self.prep_rw(OpCode::Fsync, fd, std::ptr::null(), 0, 0);
+        self.0.args.fsync_flags = fsync_flags;
+    }
+
+    /// statx(2) path, relative to dirfd (Linux 5.6)
+    ///
+    /// path and statxbuf need to stay valid until the request completes.
+    #[cfg(feature = "linux-5_6")]
+    pub fn prep_statx(
+        &mut self,
+        dirfd: libc::c_int,
+        path: *const libc::c_char,
+        flags: libc::c_int,
+        mask: libc::c_uint,
+        statxbuf: *mut libc::statx,
+    ) {
+        self.prep_rw(OpCode::Statx, dirfd, path as *const libc::c_void, mask, 0);
+        self.0.off = statxbuf as usize as u64;
+        self.0.args.statx_flags = flags as u32;
+    }
+
+    /// openat(2) path, relative to dirfd (Linux 5.6). The result is the new fd.
+    ///
+    /// path needs to stay valid until the request is submitted.
+    #[cfg(feature = "linux-5_6")]
+    pub fn prep_openat(
+        &mut self,
+        dirfd: libc::c_int,
+        path: *const libc::c_char,
+        flags: libc::c_int,
+        mode: libc::mode_t,
+    ) {
+        self.prep_rw(OpCode::Openat, dirfd, path as *const libc::c_void, mode, 0);
+        self.0.args.open_flags = flags as u32;
+    }
+
+    /// Like [`Self::prep_openat`], but the file is installed at the given slot of the registered
+    /// files, instead of a new fd (Linux 5.15). The result is 0 on success, or the slot that the
+    /// kernel picked if slot is [`IORING_FILE_INDEX_ALLOC`] (Linux 5.19). flags cannot include
+    /// O_CLOEXEC.
+    #[cfg(feature = "linux-5_15")]
+    pub fn prep_openat_direct(
+        &mut self,
+        dirfd: libc::c_int,
+        path: *const libc::c_char,
+        flags: libc::c_int,
+        mode: libc::mode_t,
+        slot: u32,
+    ) {
+        self.prep_openat(dirfd, path, flags, mode);
+        self.set_target_slot(slot);
+    }
+
+    /// Issue a command (cmd_op) to the driver of fd, e.g., an NVMe passthrough command (Linux 5.19)
+    ///
+    /// cmd is copied into the command area of the entry, which is 16 bytes, or 80 bytes for rings
+    /// with 128-byte entries (IORING_SETUP_SQE128).
+    #[cfg(feature = "linux-5_19")]
+    pub fn prep_uring_cmd(&mut self, fd: libc::c_int, cmd_op: u32, cmd: &[u8]) -> io::Result<()> {
+        if cmd.len() > self.cmd_len() {
+            let msg = format!("command too large: {} bytes", cmd.len());
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+
+        self.prep_rw(OpCode::UringCmd, fd, std::ptr::null(), 0, 0);
+        unsafe {
+            let sqe_p = &mut *self.0 as *mut io_uring_sqe as *mut u8;
+            // NB: cmd_op is a u32 at the start of off
+            (sqe_p.add(IORING_SQE_OFF_OFF) as *mut u32).write_unaligned(cmd_op);
+        }
+        self.set_cmd_bytes(0, cmd)
+    }
+
+    /// Like prep_uring_cmd(), for a command that is a (#[repr(C)]) struct
+    #[cfg(feature = "linux-5_19")]
+    pub fn prep_uring_cmd_typed<T: CmdPayload>(
+        &mut self,
+        fd: libc::c_int,
+        cmd_op: u32,
+        cmd: &T,
+    ) -> io::Result<()> {
+        self.prep_uring_cmd(fd, cmd_op, payload_bytes(cmd))
+    }
+
+    /// Size of the command area of the entry: 16 bytes, or 80 bytes for rings with 128-byte
+    /// entries (IORING_SETUP_SQE128)
+    pub fn cmd_len(&self) -> usize {
+        IORING_SQE_CMD_LEN + self.1.as_ref().map_or(0, |x| x.len())
+    }
+
+    // The command area, as its part in the first half of the entry, and the second half, if any
+    fn cmd_area(&mut self) -> (&mut [u8], Option<&mut [u8; 64]>) {
+        let sqe_p = &mut *self.0 as *mut io_uring_sqe as *mut u8;
+        // NB: the first part is the last IORING_SQE_CMD_LEN bytes of the sqe
+        let cmd0 = unsafe {
+            std::slice::from_raw_parts_mut(sqe_p.add(IORING_SQE_CMD_OFF), IORING_SQE_CMD_LEN)
+        };
+        (cmd0, self.1.as_deref_mut())
+    }
+
+    /// A copy of the command area of the entry, and its size (see [`Self::cmd_len`])
+    pub fn cmd_bytes(&self) -> ([u8; IORING_SQE_CMD_LEN + 64], usize) {
+        let mut ret = [0; IORING_SQE_CMD_LEN + 64];
+        let sqe_p = &*self.0 as *const io_uring_sqe as *const u8;
+        unsafe {
+            let cmd0 = sqe_p.add(IORING_SQE_CMD_OFF);
+            std::ptr::copy_nonoverlapping(cmd0, ret.as_mut_ptr(), IORING_SQE_CMD_LEN);
+        }
+        if let Some(ref cmd1) = self.1 {
+            ret[IORING_SQE_CMD_LEN..].copy_from_slice(&cmd1[..]);
+        }
+        (ret, self.cmd_len())
+    }
+
+    /// Write b at offset off of the command area of the entry
+    ///
+    /// Fails if b does not fit, i.e., if off + b.len() is more than [`Self::cmd_len`].
+    pub fn set_cmd_bytes(&mut self, off: usize, b: &[u8]) -> io::Result<()> {
+        if off > self.cmd_len() || b.len() > self.cmd_len() - off {
+            let msg = format!("{} bytes at offset {} do not fit in the command area", b.len(), off);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let (cmd0, cmd1) = self.cmd_area();
+        let mut b = b;
+        if off < IORING_SQE_CMD_LEN {
+            let n = std::cmp::min(b.len(), IORING_SQE_CMD_LEN - off);
+            cmd0[off..off + n].copy_from_slice(&b[..n]);
+            b = &b[n..];
+        }
+        if let Some(cmd1) = cmd1 {
+            let off1 = off.saturating_sub(IORING_SQE_CMD_LEN);
+            cmd1[off1..off1 + b.len()].copy_from_slice(b);
+        }
+        Ok(())
+    }
+
+    /// Copy cmd to the start of the command area of the entry
+    ///
+    /// Fails if cmd does not fit (see [`Self::cmd_len`]).
+    pub fn set_cmd<T: CmdPayload>(&mut self, cmd: &T) -> io::Result<()> {
+        self.set_cmd_bytes(0, payload_bytes(cmd))
+    }
+
+    /// Read a T from the start of the command area of the entry
+    ///
+    /// Fails if a T does not fit (see [`Self::cmd_len`]).
+    pub fn cmd<T: CmdPayload>(&self) -> io::Result<T> {
+        let size = mem::size_of::<T>();
+        let (bytes, len) = self.cmd_bytes();
+        if size > len {
+            let msg = format!("{} bytes do not fit in the command area", size);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        // NB: CmdPayload guarantees that any bytes are a valid T
+        Ok(unsafe { (bytes.as_ptr() as *const T).read_unaligned() })
+    }
+
+    /// Wait until fd is ready for any of the events in poll_mask (POLLIN, POLLOUT, ...). The result
+    /// is the mask of the ready events.
+    pub fn prep_poll_add(&mut self, fd: libc::c_int, poll_mask: u32) {
+        self.prep_rw(OpCode::PollAdd, fd, std::ptr::null(), 0, 0);
+        // NB: the kernel swaps the 16-bit halves of poll32_events on big-endian targets
+        let poll_mask = if cfg!(target_endian = "big") {
+            poll_mask.rotate_left(16)
+        } else {
+            poll_mask
+        };
+        self.0.args.poll32_events = poll_mask;
+    }
+
+    /// Like prep_poll_add(), but posts a cqe (with IORING_CQE_F_MORE set) every time fd becomes
+    /// ready, until the request is cancelled or terminated by the kernel (Linux 5.13)
+    #[cfg(feature = "linux-5_15")]
+    pub fn prep_poll_multishot(&mut self, fd: libc::c_int, poll_mask: u32) {
+        self.prep_poll_add(fd, poll_mask);
+        self.0.len = IORING_POLL_ADD_MULTI;
+    }
+
+    /// Complete after ts elapses (res is -ETIME), or after count other requests complete (res is
+    /// 0), whichever comes first. A count of 0 means a pure timer.
+    ///
+    /// The timeout is relative, unless flags include [`TimeoutFlags::ABS`]. It is measured with
+    /// CLOCK_MONOTONIC, unless flags select another clock. ts needs to stay valid until the
+    /// request is submitted.
+    pub fn prep_timeout(&mut self, ts: *const KernelTimespec, count: u32, flags: TimeoutFlags) {
+        let ts = ts as *const libc::c_void;
+        self.prep_rw(OpCode::Timeout, -1, ts, 1, count as u64);
+        self.0.args.timeout_flags = flags.bits();
+    }
+
+    /// A periodic timer: post a cqe (with res -ETIME) every ts, nr times, or until cancelled if nr
+    /// is 0 (Linux 6.4)
+    ///
+    /// All cqes but the last have IORING_CQE_F_MORE set (see [`io_uring_cqe::has_more`]). flags
+    /// select the clock, as in [`Self::prep_timeout`].
+    #[cfg(feature = "linux-6_4")]
+    pub fn prep_timeout_multishot(
+        &mut self,
+        ts: *const KernelTimespec,
+        nr: u32,
+        flags: TimeoutFlags,
+    ) {
+        self.prep_timeout(ts, nr, flags | TimeoutFlags::MULTISHOT);
+    }
+
+    /// Cancel the (first) in-flight request with the given user data (Linux 5.5)
+    ///
+    /// The result is 0 if the request was cancelled (and it completes with -ECANCELED), -ENOENT
+    /// if there is no such request, or -EALREADY if the request is already running and could not
+    /// be cancelled.
+    pub fn prep_cancel(&mut self, user_data: u64) {
+        self.prep_rw(OpCode::AsyncCancel, -1, std::ptr::null(), 0, 0);
+        self.0.addr = user_data;
+    }
+
+    /// Cancel the timeout request with the given user data. The timeout completes with
+    /// -ECANCELED.
+    pub fn prep_timeout_remove(&mut self, user_data: u64) {
+        self.prep_rw(OpCode::TimeoutRemove, -1, std::ptr::null(), 0, 0);
+        self.0.addr = user_data;
+    }
+
+    /// Cancel the previous request (which needs to set [`SqeFlags::IO_LINK`]) if it does not
+    /// complete before the timeout (Linux 5.5)
+    ///
+    /// Clocks and ts are as in [`Self::prep_timeout`].
+    pub fn prep_link_timeout(&mut self, ts: *const KernelTimespec, flags: TimeoutFlags) {
+        let ts = ts as *const libc::c_void;
+        self.prep_rw(OpCode::LinkTimeout, -1, ts, 1, 0);
+        self.0.args.timeout_flags = flags.bits();
+    }
+
+    /// Read into a registered buffer (see [`IoUring::register_buffers`])
+    ///
+    /// [buf, buf + len) needs to be within the buffer registered at buf_index.
+    pub fn prep_read_fixed(
+        &mut self,
+        fd: libc::c_int,
+        buf: *mut u8,
+        len: u32,
+        off: u64,
+        buf_index: u16,
+    ) {
+        self.prep_rw(OpCode::ReadFixed, fd, buf as *const libc::c_void, len, off);
+        self.0.buf_index = buf_index;
+    }
+
+    /// Write from a registered buffer (see [`IoUring::register_buffers`])
+    ///
+    /// [buf, buf + len) needs to be within the buffer registered at buf_index.
+    pub fn prep_write_fixed(
+        &mut self,
+        fd: libc::c_int,
+        buf: *const u8,
+        len: u32,
+        off: u64,
+        buf_index: u16,
+    ) {
+        self.prep_rw(OpCode::WriteFixed, fd, buf as *const libc::c_void, len, off);
+        self.0.buf_index = buf_index;
+    }
+
+    /// This uses IoSlice, which is the buffer type used in Write::write_vectored, and "is
+    /// guaranteed to be ABI compatible with the iovec type on Unix platforms"
+    ///
+    /// Returns an error of kind `InvalidInput` (and leaves the entry untouched) if the number of
+    /// buffers does not fit in a u32.
+    // NB: https://github.com/rust-lang/rust/blob/7bf377f289a4f79829309ed69dccfe33f20b089c/src/libstd/sys/unix/fd.rs#L103
+    pub fn prep_write_slice(&mut self, fd: libc::c_int, bufs: &[std::io::IoSlice], off: u64)
+    -> io::Result<()> {
+        let nr_vecs = iovecs_len(bufs.len())?;
+        self.prep_writev(fd, bufs.as_ptr() as *const libc::iovec, nr_vecs, off);
+        Ok(())
+    }
+
+    /// This uses IoSliceMut, which is the buffer type used in Read::read_vectored, and "is
+    /// guaranteed to be ABI compatible with the iovec type on Unix platforms"
+    ///
+    /// Returns an error of kind `InvalidInput` (and leaves the entry untouched) if the number of
+    /// buffers does not fit in a u32.
+    // NB: https://github.com/rust-lang/rust/blob/7bf377f289a4f79829309ed69dccfe33f20b089c/src/libstd/sys/unix/fd.rs#L56
+    pub fn prep_read_slice(&mut self, fd: libc::c_int, bufs: &[std::io::IoSliceMut], off: u64)
+    -> io::Result<()> {
+        let nr_vecs = iovecs_len(bufs.len())?;
+        self.prep_readv(fd, bufs.as_ptr() as *const libc::iovec, nr_vecs, off);
+        Ok(())
+    }
+
+    /// Set the buffer group to select a buffer from (see [`SqeFlags::BUFFER_SELECT`])
+    #[cfg(feature = "linux-5_15")]
+    pub fn set_buf_group(&mut self, bgid: u16) {
+        self.0.buf_index = bgid
+    }
+
+    pub fn prep_accept(
+        &mut self,
+        fd: libc::c_int,
+        addr: *mut libc::sockaddr,
+        addrlen: *mut libc::socklen_t,
+        flags: libc::c_int,
+    ) {
+        self.prep_rw(OpCode::Accept, fd, addr as *const libc::c_void, 0, addrlen as usize as u64);
+        self.0.args.accept_flags = flags as u32;
+    }
+
+    /// Accept connections until cancelled. Each accepted connection posts a cqe with
+    /// IORING_CQE_F_MORE set, as long as the request remains armed (Linux 5.19).
+    #[cfg(feature = "linux-5_19")]
+    pub fn prep_multishot_accept(
+        &mut self,
+        fd: libc::c_int,
+        addr: *mut libc::sockaddr,
+        addrlen: *mut libc::socklen_t,
+        flags: libc::c_int,
+    ) {
+        self.prep_accept(fd, addr, addrlen, flags);
+        self.0.ioprio |= IORING_ACCEPT_MULTISHOT;
+    }
+
+    /// Receive into buf. Use a null buf, a len of 0, and [`SqeFlags::BUFFER_SELECT`] to receive
+    /// into a provided buffer.
+    #[cfg(feature = "linux-5_6")]
+    pub fn prep_recv(
+        &mut self,
+        fd: libc::c_int,
+        buf: *mut libc::c_void,
+        len: u32,
+        flags: libc::c_int,
+    ) {
+        self.prep_rw(OpCode::Recv, fd, buf, len, 0);
+        self.0.args.msg_flags = flags as u32;
+    }
+
+    #[cfg(feature = "linux-5_6")]
+    pub fn prep_send(
+        &mut self,
+        fd: libc::c_int,
+        buf: *const libc::c_void,
+        len: u32,
+        flags: libc::c_int,
+    ) {
+        self.prep_rw(OpCode::Send, fd, buf, len, 0);
+        self.0.args.msg_flags = flags as u32;
+    }
+
+    /// Receive a message into msg, as recvmsg(2). The msghdr (and the buffers it points to) need
+    /// to stay alive until the request completes.
+    #[cfg(feature = "linux-5_6")]
+    pub fn prep_recvmsg(&mut self, fd: libc::c_int, msg: *mut libc::msghdr, flags: libc::c_int) {
+        self.prep_rw(OpCode::Recvmsg, fd, msg as *const libc::c_void, 1, 0);
+        self.0.args.msg_flags = flags as u32;
+    }
+
+    /// Receive messages into provided buffers until cancelled (Linux 6.0)
+    ///
+    /// Each message posts a cqe with IORING_CQE_F_MORE set, as long as the request remains armed.
+    /// The request needs [`SqeFlags::BUFFER_SELECT`] and a buffer group (see
+    /// [`Self::set_buf_group`]). Only the msg_namelen and msg_controllen fields of msg are used:
+    /// they are the space reserved in each buffer for the source address and the control data.
+    /// Use [`RecvMsgOut`] to parse the buffers.
+    #[cfg(feature = "linux-6_0")]
+    pub fn prep_recvmsg_multishot(
+        &mut self,
+        fd: libc::c_int,
+        msg: *mut libc::msghdr,
+        flags: libc::c_int,
+    ) {
+        self.prep_recvmsg(fd, msg, flags);
+        self.0.ioprio |= IORING_RECV_MULTISHOT;
+    }
+
+    /// Send the message msg, as sendmsg(2). The msghdr (and the buffers it points to) need to stay
+    /// alive until the request completes.
+    #[cfg(feature = "linux-5_6")]
+    pub fn prep_sendmsg(&mut self, fd: libc::c_int, msg: *const libc::msghdr, flags: libc::c_int) {
+        self.prep_rw(OpCode::Sendmsg, fd, msg as *const libc::c_void, 1, 0);
+        self.0.args.msg_flags = flags as u32;
+    }
+
+    /// Zero-copy send (Linux 6.0)
+    ///
+    /// This posts two cqes: one with the result of the send (with IORING_CQE_F_MORE set), and a
+    /// notification one (see [`io_uring_cqe::is_notif`]) once the kernel no longer uses buf.
+    /// zc_flags are IORING_RECVSEND_* flags.
+    #[cfg(feature = "linux-6_0")]
+    pub fn prep_send_zc(
+        &mut self,
+        fd: libc::c_int,
+        buf: *const libc::c_void,
+        len: u32,
+        flags: libc::c_int,
+        zc_flags: u16,
+    ) {
+        self.prep_rw(OpCode::SendZc, fd, buf, len, 0);
+        self.0.args.msg_flags = flags as u32;
+        self.0.ioprio = zc_flags;
+    }
+
+    /// Move up to nbytes from fd_in to fd_out, where one of them needs to be a pipe (Linux 5.7)
+    ///
+    /// An offset of -1 means that the file position is used (and updated), and it needs to be -1
+    /// for pipes. splice_flags are SPLICE_F_* flags, including [`SPLICE_F_FD_IN_FIXED`] if fd_in
+    /// is a registered file. For a registered fd_out, use [`SqeFlags::FIXED_FILE`].
+    #[cfg(feature = "linux-5_15")]
+    pub fn prep_splice(
+        &mut self,
+        fd_in: libc::c_int,
+        off_in: i64,
+        fd_out: libc::c_int,
+        off_out: i64,
+        nbytes: u32,
+        splice_flags: u32,
+    ) {
+        self.prep_rw(OpCode::Splice, fd_out, std::ptr::null(), nbytes, off_out as u64);
+        self.0.addr = off_in as u64;
+        self.0.file_index = fd_in as u32;
+        self.0.args.splice_flags = splice_flags;
+    }
+
+    /// Duplicate up to nbytes from pipe fd_in to pipe fd_out, without consuming them (Linux 5.8)
+    ///
+    /// Registered files are handled as in [`Self::prep_splice`].
+    #[cfg(feature = "linux-5_15")]
+    pub fn prep_tee(
+        &mut self,
+        fd_in: libc::c_int,
+        fd_out: libc::c_int,
+        nbytes: u32,
+        splice_flags: u32,
+    ) {
+        self.prep_rw(OpCode::Tee, fd_out, std::ptr::null(), nbytes, 0);
+        self.0.file_index = fd_in as u32;
+        self.0.args.splice_flags = splice_flags;
+    }
+
+    // NB: file_index is 1-based for requests that install a registered file, with 0 meaning a
+    // regular fd
+    #[cfg(feature = "linux-5_15")]
+    fn set_target_slot(&mut self, slot: u32) {
+        // NB: IORING_FILE_INDEX_ALLOC (u32::MAX) is passed as is
+        self.0.file_index = if slot == u32::MAX { slot } else { slot + 1 };
+    }
+
+    /// Like prep_accept(), but the accepted socket is installed at the given slot of the
+    /// registered files (which needs to exist), instead of a new fd (Linux 5.15). The result is 0
+    /// on success, or the slot that the kernel picked if slot is [`IORING_FILE_INDEX_ALLOC`]
+    /// (Linux 5.19). flags cannot include SOCK_CLOEXEC.
+    #[cfg(feature = "linux-5_15")]
+    pub fn prep_accept_direct(
+        &mut self,
+        fd: libc::c_int,
+        addr: *mut libc::sockaddr,
+        addrlen: *mut libc::socklen_t,
+        flags: libc::c_int,
+        slot: u32,
+    ) {
+        self.prep_accept(fd, addr, addrlen, flags);
+        self.set_target_slot(slot);
+    }
+
+    /// Close the registered file at slot (Linux 5.15)
+    #[cfg(feature = "linux-5_15")]
+    pub fn prep_close_fixed(&mut self, slot: u32) {
+        self.prep_rw(OpCode::Close, 0, std::ptr::null(), 0, 0);
+        self.set_target_slot(slot);
+    }
+
+    /// Post a cqe with the given res and user data to the ring of ring_fd (Linux 5.18)
+    ///
+    /// The result of this request is 0 on success, or, e.g., -EOVERFLOW if the cq of the target
+    /// ring is full.
+    #[cfg(feature = "linux-5_19")]
+    pub fn prep_msg_ring(&mut self, ring_fd: libc::c_int, res: u32, data: u64) {
+        self.prep_rw(OpCode::MsgRing, ring_fd, std::ptr::null(), res, data);
+        self.0.addr = IORING_MSG_DATA;
+        self.0.args.msg_ring_flags = 0;
+    }
+
+    /// Like [`Self::prep_msg_ring`], but the posted cqe also gets the given flags (Linux 6.3)
+    #[cfg(feature = "linux-6_4")]
+    pub fn prep_msg_ring_cqe_flags(
+        &mut self,
+        ring_fd: libc::c_int,
+        res: u32,
+        data: u64,
+        flags: u32,
+    ) {
+        self.prep_msg_ring(ring_fd, res, data);
+        self.0.args.msg_ring_flags = IORING_MSG_RING_FLAGS_PASS;
+        self.0.file_index = flags;
+    }
+
+    /// Wait for a child process to change state, like waitid(2) (Linux 6.7)
+    ///
+    /// idtype and id select the child(ren) as in waitid(2) (e.g., P_PID and a pid), and options
+    /// are its W* options (e.g., WEXITED). infop needs to stay valid until the request completes.
+    /// flags are reserved, and need to be 0.
+    #[cfg(feature = "linux-6_7")]
+    pub fn prep_waitid(
+        &mut self,
+        idtype: libc::idtype_t,
+        id: libc::id_t,
+        infop: *mut libc::siginfo_t,
+        options: libc::c_int,
+        flags: u32,
+    ) {
+        let infop = infop as usize as u64;
+        self.prep_rw(OpCode::Waitid, id as libc::c_int, std::ptr::null(), idtype, infop);
+        self.0.file_index = options as u32;
+        self.0.args.waitid_flags = flags;
+    }
+
+    /// Install the registered file at src_slot into dst_slot of the registered files of the ring
+    /// of ring_fd (Linux 6.0). The file remains registered in this ring.
+    ///
+    /// The target ring gets a cqe with the given user data, and a res of 0, or of the slot the
+    /// kernel picked if dst_slot is [`IORING_FILE_INDEX_ALLOC`].
+    #[cfg(feature = "linux-6_0")]
+    pub fn prep_msg_ring_fd(
+        &mut self,
+        ring_fd: libc::c_int,
+        src_slot: u32,
+        dst_slot: u32,
+        data: u64,
+    ) {
+        self.prep_rw(OpCode::MsgRing, ring_fd, std::ptr::null(), 0, data);
+        self.0.addr = IORING_MSG_SEND_FD;
+        self.0.addr3 = src_slot as u64;
+        self.set_target_slot(dst_slot);
+    }
+
+}
+
+/// The opcodes that the kernel supports (see [`IoUring::probe`])
+#[derive(Clone)]
+pub struct Probe {
+    last_op: u8,
+    supported: [bool; 256],
+}
+
+impl Probe {
+    /// Whether the kernel supports op
+    pub fn supports(&self, op: OpCode) -> bool {
+        self.supports_u8(op as u8)
+    }
+
+    /// Whether the kernel supports the opcode with the given value, including opcodes that are
+    /// newer than this crate
+    pub fn supports_u8(&self, op: u8) -> bool {
+        self.supported[op as usize]
+    }
+
+    /// The last opcode that the kernel knows of
+    pub fn last_op(&self) -> u8 {
+        self.last_op
+    }
+
+    /// The supported opcodes that this crate knows of
+    pub fn ops(&self) -> impl Iterator<Item = OpCode> + '_ {
+        (0..=u8::MAX).filter(move |op| self.supports_u8(*op)).filter_map(OpCode::from_u8)
+    }
+}
+
+impl std::fmt::Debug for Probe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Probe")
+            .field("last_op", &self.last_op)
+            .field("ops", &self.ops().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// Error for when io_uring is not available
+///
+/// It is returned by [`IoUring::init`] wrapped in an `io::Error` of kind
+/// `io::ErrorKind::Unsupported` when the io_uring_setup() syscall fails with `ENOSYS` (kernel
+/// older than 5.1, or built without io_uring) or `EPERM` (io_uring disabled via the
+/// kernel.io_uring_disabled sysctl, or blocked by a seccomp filter). It can be recovered via
+/// `io::Error::get_ref()` and `downcast_ref()`.
+#[derive(Debug)]
+pub struct Unsupported {
+    errno: i32,
+}
+
+impl Unsupported {
+    /// The errno returned by io_uring_setup()
+    pub fn raw_os_error(&self) -> i32 {
+        self.errno
+    }
+}
+
+impl std::fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let err = io::Error::from_raw_os_error(self.errno);
+        write!(f, "io_uring is not supported: {}", err)
+    }
+}
+
+impl std::error::Error for Unsupported {}
 
-/// mmap helper, using the default protection and flags
-unsafe fn mmap(len: libc::size_t, fd: libc::c_int, off: libc::off_t) -> *mut libc::c_void {
-    let prot  = libc::PROT_READ | libc::PROT_WRITE;
-    let flags = libc::MAP_SHARED | libc::MAP_POPULATE;
-    let null = 0 as *mut libc::c_void;
-    libc::mmap(null, len, prot, flags, fd, off)
+/// Map an io_uring_setup() error to an Unsupported error if it indicates that io_uring is
+/// unavailable
+fn setup_error(err: io::Error) -> io::Error {
+    match err.raw_os_error() {
+        Some(errno) if errno == libc::ENOSYS || errno == libc::EPERM => {
+            io::Error::new(io::ErrorKind::Unsupported, Unsupported { errno })
+        },
+        _ => err,
+    }
 }
 
-/// munmap helper
+/// Check whether io_uring is available
 ///
-/// Prints a message at stder if munmap() returns an error.
-unsafe fn munmap(addr: *mut libc::c_void, len: libc::size_t) -> libc::c_int {
-        let err = libc::munmap(addr, len);
-        if err == 0 {
-            return err;
+/// This sets up (and immediately tears down) a tiny ring the first time it is called. The result
+/// is cached for subsequent calls. io_uring is unsupported only if setting up the ring fails with
+/// ENOSYS or EPERM (see [`Unsupported`]): other errors (e.g., EMFILE or ENOMEM) are transient,
+/// so io_uring is reported as supported without caching the result, and the check is repeated
+/// on the next call.
+pub fn is_supported() -> bool {
+    static SUPPORTED: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    if let Some(x) = SUPPORTED.get() {
+        return *x;
+    }
+    let mut params: io_uring_params = unsafe { std::mem::zeroed() };
+    let fd = unsafe { io_uring_setup(1, &mut params) };
+    let supported = if fd < 0 {
+        let err = setup_error(io::Error::last_os_error());
+        if err.kind() != io::ErrorKind::Unsupported {
+            return true;
         }
-        let bt = Backtrace::new();
-        let errno_ptr = libc::__errno_location();
-        let old_errno = *errno_ptr;
-        let error = io::Error::from_raw_os_error(old_errno as i32);
-        // NB: not sure how to print a backtrace here. There does not seem to be a way using libstd
-        // that does not involve panic!
-        eprintln!("WARNING: munmap() failed: {}\nBacktrace:\n{:?}", error, bt);
-        *errno_ptr = old_errno;
-        err
+        false
+    } else {
+        unsafe { close(fd) };
+        true
+    };
+    *SUPPORTED.get_or_init(|| supported)
 }
 
-/// close() helper
+/// Maximum number of SQ ring entries (IORING_MAX_ENTRIES)
+pub const MAX_ENTRIES: u32 = 32768;
+
+/// How to handle a number of ring entries that the kernel would not use as is (see
+/// [`normalize_entries`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntriesPolicy {
+    /// Fail unless the number is a power of two, and at most [`MAX_ENTRIES`]
+    Exact,
+    /// Round up to a power of two (what the kernel does), and fail if that is more than
+    /// [`MAX_ENTRIES`]
+    RoundUp,
+    /// Like RoundUp, but use [`MAX_ENTRIES`] instead of failing (what the kernel does with
+    /// [`SetupFlags::CLAMP`])
+    Clamp,
+}
+
+/// The number of SQ ring entries that the kernel would set up for nentries, under the given
+/// policy
 ///
-/// Prints a message at stderr if close() returns an error.
-unsafe fn close(fd: libc::c_int) -> libc::c_int {
-        let err = libc::close(fd);
-        if err == 0 {
-            return err;
-        }
+/// Fails with `InvalidInput` if nentries is 0, or if the policy does not allow it.
+pub fn normalize_entries(nentries: u32, policy: EntriesPolicy) -> io::Result<u32> {
+    let invalid = |why: &str| {
+        let msg = format!("invalid number of ring entries {}: {}", nentries, why);
+        Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+    };
+    if nentries == 0 {
+        return invalid("needs to be at least 1");
+    }
+    match policy {
+        EntriesPolicy::Exact if !nentries.is_power_of_two() => invalid("not a power of two"),
+        EntriesPolicy::Clamp if nentries > MAX_ENTRIES => Ok(MAX_ENTRIES),
+        _ if nentries > MAX_ENTRIES => invalid("larger than the maximum (32768)"),
+        _ => Ok(nentries.next_power_of_two()),
+    }
+}
 
-        // Not match we can do in case of an error here. Just print a backtrace.
-        let bt = Backtrace::new();
-        let errno_ptr = libc::__errno_location();
-        let old_errno = *errno_ptr;
-        let error = io::Error::from_raw_os_error(old_errno as i32);
-        eprintln!("WARNING: close() failed: {}\nBacktrace:\n{:?}", error, bt);
-        *errno_ptr = old_errno;
-        err
+/// A builder for an IoUring with setup parameters beyond the number of entries and the flags
+///
+/// ```no_run
+/// use iouring::io_uring::{IoUringBuilder, SetupFlags};
+///
+/// let ring = IoUringBuilder::new(64)
+///     .setup_flags(SetupFlags::SQPOLL)
+///     .sq_thread_cpu(1)
+///     .sq_thread_idle(100)
+///     .cq_entries(256)
+///     .build()?;
+/// println!("{:?}", ring.params());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct IoUringBuilder {
+    entries: u32,
+    policy: EntriesPolicy,
+    flags: SetupFlags,
+    sq_thread_cpu: Option<u32>,
+    sq_thread_idle: Option<u32>,
+    cq_entries: Option<u32>,
+    wq_fd: Option<RawFd>,
 }
 
-/**
- * Main implementation
- */
+impl IoUringBuilder {
 
-impl SQEntry {
-    fn reset(&mut self) {
-        let ptr = self.0;
-        unsafe { *ptr =  mem::zeroed() };
-    }
-
-    fn prep_rw(&mut self, op: u8, fd: libc::c_int, addr: *const libc::c_void, len: u32, off: u64) {
-        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
-        *sqe = io_uring_sqe {
-            opcode: op,
-            flags: 0,
-            ioprio: 0,
-            fd: fd,
-            off: off,
-            addr: addr as u64,
-            args: io_uring_sqe_args { rw_flags: 0 },
-            user_data: 0,
-            len: len,
-            idx: io_uring_sqe_idx { __pad2: [0; 3] },
-        };
+    /// A builder for a ring of (at least) `entries` SQ entries
+    pub fn new(entries: u32) -> IoUringBuilder {
+        IoUringBuilder {
+            entries,
+            policy: EntriesPolicy::RoundUp,
+            flags: SetupFlags::empty(),
+            sq_thread_cpu: None,
+            sq_thread_idle: None,
+            cq_entries: None,
+            wq_fd: None,
+        }
     }
 
-    pub fn set_data(&mut self, data: u64) {
-        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
-        sqe.user_data = data
+    /// How to handle an `entries` that the kernel would not use as is (default: RoundUp, or
+    /// Clamp with [`SetupFlags::CLAMP`])
+    pub fn entries_policy(mut self, policy: EntriesPolicy) -> Self {
+        self.policy = policy;
+        self
     }
 
-    pub fn prep_readv(&mut self, fd: libc::c_int, iovecs: *const libc::iovec, nr_vecs: u32, off: u64) {
-        let ptr = iovecs as *const libc::c_void;
-        self.prep_rw(IORING_OP_READV, fd, ptr, nr_vecs, off)
+    /// Setup flags for the ring
+    ///
+    /// SQ_AFF, CQSIZE, and ATTACH_WQ are set by [`Self::sq_thread_cpu`], [`Self::cq_entries`],
+    /// and [`Self::attach_wq`], and passing them here without calling these is an error.
+    pub fn setup_flags(mut self, flags: SetupFlags) -> Self {
+        self.flags = flags;
+        self
     }
 
-    pub fn prep_writev(&mut self, fd: libc::c_int, iovecs: *const libc::iovec, nr_vecs: u32, off: u64) {
-        let ptr = iovecs as *const libc::c_void;
-        self.prep_rw(IORING_OP_READV, fd, ptr, nr_vecs, off)
+    /// Pin the SQ polling thread to cpu (SQ_AFF). Requires SQPOLL.
+    pub fn sq_thread_cpu(mut self, cpu: u32) -> Self {
+        self.sq_thread_cpu = Some(cpu);
+        self
     }
 
-    /// This uses IoSlice, which is the buffer type ised in Write::write_vectored, and "is
-    /// guaranteed to be ABI compatible with the iovec type on Unix platforms"
-    //
-    // NB: https://github.com/rust-lang/rust/blob/7bf377f289a4f79829309ed69dccfe33f20b089c/src/libstd/sys/unix/fd.rs#L103
-    pub fn prep_write_slice(&mut self, fd: libc::c_int, bufs: &[std::io::IoSlice], off: u64) {
-        self.prep_writev(
-            fd,
-            bufs.as_ptr() as *const libc::iovec,
-            // NB: len() is usize, arg is u32. This will panic if a conversion cannot be made.
-            bufs.len().try_into().unwrap(),
-            off);
+    /// Milliseconds of idleness after which the SQ polling thread sleeps. Requires SQPOLL.
+    pub fn sq_thread_idle(mut self, ms: u32) -> Self {
+        self.sq_thread_idle = Some(ms);
+        self
     }
 
-    /// This uses IoSliceMut, which is the buffer type ised in Write::read_vectored, and "is
-    /// guaranteed to be ABI compatible with the iovec type on Unix platforms"
-    //
-    // NB: https://github.com/rust-lang/rust/blob/7bf377f289a4f79829309ed69dccfe33f20b089c/src/libstd/sys/unix/fd.rs#L56
-    pub fn prep_read_slice(&mut self, fd: libc::c_int, bufs: &[std::io::IoSliceMut], off: u64) {
-        self.prep_readv(
-            fd,
-            bufs.as_ptr() as *const libc::iovec,
-            // NB: len() is usize, arg is u32. This will panic if a conversion cannot be made.
-            bufs.len().try_into().unwrap(),
-            off);
+    /// Number of CQ entries (CQSIZE), instead of twice the SQ entries. The kernel rounds it up
+    /// to a power of two, and it needs to be at least the number of SQ entries. With
+    /// [`SetupFlags::CLAMP`], it is limited to twice [`MAX_ENTRIES`] instead of failing.
+    pub fn cq_entries(mut self, entries: u32) -> Self {
+        self.cq_entries = Some(entries);
+        self
+    }
+
+    /// Share the io-wq (the kernel workers that execute requests asynchronously) of the ring of
+    /// ring_fd (e.g., [`IoUring::as_raw_fd`]) instead of creating a new one (ATTACH_WQ). With
+    /// SQPOLL, the SQ polling thread is shared as well.
+    pub fn attach_wq(mut self, ring_fd: RawFd) -> Self {
+        self.wq_fd = Some(ring_fd);
+        self
+    }
+
+    /// Set up the ring. The resulting parameters, including the number of entries the kernel
+    /// actually set up, are available via [`IoUring::params`].
+    pub fn build(self) -> io::Result<IoUring> {
+        let invalid = |msg: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        let mut flags = self.flags;
+        let mut params: io_uring_params = unsafe { std::mem::zeroed() };
+        match self.sq_thread_cpu {
+            Some(cpu) => {
+                flags |= SetupFlags::SQ_AFF;
+                params.sq_thread_cpu = cpu;
+            }
+            None if flags.contains(SetupFlags::SQ_AFF) => return invalid("SQ_AFF without a cpu"),
+            None => (),
+        }
+        match self.cq_entries {
+            Some(nr) => {
+                flags |= SetupFlags::CQSIZE;
+                params.cq_entries = nr;
+            }
+            None if flags.contains(SetupFlags::CQSIZE) => {
+                return invalid("CQSIZE without a number of entries")
+            }
+            None => (),
+        }
+        match self.wq_fd {
+            Some(fd) => {
+                flags |= SetupFlags::ATTACH_WQ;
+                params.wq_fd = fd as u32;
+            }
+            None if flags.contains(SetupFlags::ATTACH_WQ) => {
+                return invalid("ATTACH_WQ without a ring fd")
+            }
+            None => (),
+        }
+        if (self.sq_thread_cpu.is_some() || self.sq_thread_idle.is_some())
+            && !flags.contains(SetupFlags::SQPOLL)
+        {
+            return invalid("SQ thread parameters without SQPOLL");
+        }
+        params.sq_thread_idle = self.sq_thread_idle.unwrap_or(0);
+        params.flags = flags.bits();
+        let policy = match self.policy {
+            EntriesPolicy::RoundUp if flags.contains(SetupFlags::CLAMP) => EntriesPolicy::Clamp,
+            x => x,
+        };
+        let entries = normalize_entries(self.entries, policy)?;
+        IoUring::setup(entries, params)
     }
+}
+
+/// The parameters of a ring, as set up by the kernel (see [`IoUring::params`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    pub sq_entries: u32,
+    pub cq_entries: u32,
+    pub flags: SetupFlags,
+    pub sq_thread_cpu: u32,
+    /// In milliseconds
+    pub sq_thread_idle: u32,
+    /// IORING_FEAT_* flags
+    pub features: u32,
+}
 
+/// Sizes of the rings of an IoUring (see [`IoUring::geometry`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub sq_entries: u32,
+    pub cq_entries: u32,
+    /// Size of sqes (64, or 128 with SQE128) and cqes (16, or 32 with CQE32), in bytes
+    pub sqe_size: usize,
+    pub cqe_size: usize,
 }
 
 /// setup functions
@@ -400,79 +2787,165 @@ impl IoUring {
 
     /// initialize an io uring
     pub fn init(nentries: libc::c_uint) -> io::Result<IoUring> {
-        let mut params: io_uring_params = unsafe { std::mem::zeroed() };
+        Self::init_with_flags(nentries, SetupFlags::empty())
+    }
+
+    /// initialize an io uring with the given setup flags
+    ///
+    /// nentries is rounded up to a power of two (see [`EntriesPolicy::RoundUp`]), or clamped with
+    /// [`SetupFlags::CLAMP`]. For other policies, and for the flags that need additional
+    /// parameters (SQ_AFF, CQSIZE, ATTACH_WQ), use [`IoUringBuilder`].
+    pub fn init_with_flags(nentries: libc::c_uint, flags: SetupFlags) -> io::Result<IoUring> {
+        IoUringBuilder::new(nentries).setup_flags(flags).build()
+    }
+
+    // Set up a ring of nentries SQ entries with the given parameters
+    fn setup(nentries: u32, mut params: io_uring_params) -> io::Result<IoUring> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            "io_uring_setup",
+            nentries,
+            flags = ?SetupFlags::from_bits_truncate(params.flags),
+        ).entered();
+
         let params_p = &mut params as *mut io_uring_params;
         let fd = unsafe { io_uring_setup(nentries, params_p) };
         if fd < 0 {
-            return Err(io::Error::last_os_error())
+            let err = io::Error::last_os_error();
+            #[cfg(feature = "tracing")]
+            tracing::debug!(error = %err, "io_uring_setup failed");
+            return Err(setup_error(err))
         }
 
-        let mut ret : IoUring = IoUring {
-            fd: fd,
-            sq: unsafe { std::mem::zeroed() },
-            cq: unsafe { std::mem::zeroed() },
-            // NB: SetupFlags should be given by the user as an argument
-            flags: SetupFlags::from_bits(params.flags).unwrap(),
+        Self::from_params(fd, &params)
+    }
+
+    // Map the queues of the ring of fd, and build an IoUring for it. Closes fd on failure.
+    fn from_params(fd: libc::c_int, params: &io_uring_params) -> io::Result<IoUring> {
+        let (sq, cq) = match Self::queue_mmap(fd, params) {
+            Ok(x) => x,
+            Err(e) => {
+                unsafe { close(fd); }
+                return Err(e);
+            }
         };
 
-        let err = ret.queue_mmap(&mut params);
-        if err.is_err() {
-            unsafe { close(ret.fd); }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            fd,
+            sq_entries = params.sq_entries,
+            cq_entries = params.cq_entries,
+            features = params.features,
+            "ring created"
+        );
+
+        Ok(IoUring {
+            fd,
+            sq,
+            cq,
+            // NB: the kernel leaves params.flags as given by the user
+            flags: SetupFlags::from_bits_truncate(params.flags),
+            // NB: keep unknown features around, there is no harm in it
+            features: unsafe { Features::from_bits_unchecked(params.features) },
+            cq_overflow_seen: 0,
+            inflight: 0,
+            wait_timeouts: 0,
+            wait_ts: VecDeque::new(),
+            ring_fd_index: None,
+            drop_policy: ShutdownPolicy::Detach,
+            stats: Stats::default(),
+            wait_region: None,
+            pending: None,
+            latency: None,
+            op_stats: None,
+            recorder: None,
+            params: *params,
+        })
+    }
+
+    /// The parameters of the ring, as returned by io_uring_setup()
+    pub fn params(&self) -> Params {
+        Params {
+            sq_entries: self.params.sq_entries,
+            cq_entries: self.params.cq_entries,
+            flags: SetupFlags::from_bits_truncate(self.params.flags),
+            sq_thread_cpu: self.params.sq_thread_cpu,
+            sq_thread_idle: self.params.sq_thread_idle,
+            features: self.params.features,
         }
-        Ok(ret)
     }
 
-    fn queue_mmap(&mut self, p: &mut io_uring_params) -> io::Result<()> {
+    /// The parameters of the ring, as returned by io_uring_setup() (struct io_uring_params)
+    pub(crate) fn params_bytes(&self) -> [u8; mem::size_of::<io_uring_params>()] {
+        // NB: io_uring_params is all integers, without padding
+        unsafe { mem::transmute(self.params) }
+    }
+
+    /// An IoUring for fd, a ring created by io_uring_setup() with the given parameters (see
+    /// [`Self::params_bytes`]), e.g., in another process. Takes ownership of fd.
+    ///
+    /// # Safety
+    ///
+    /// fd needs to be an io_uring fd, and params its parameters.
+    pub(crate) unsafe fn from_raw_parts(fd: libc::c_int, params: &[u8]) -> io::Result<IoUring> {
+        if params.len() != mem::size_of::<io_uring_params>() {
+            close(fd);
+            let msg = format!("invalid io_uring_params size: {}", params.len());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        let params = (params.as_ptr() as *const io_uring_params).read_unaligned();
+        Self::from_params(fd, &params)
+    }
+
+    fn queue_mmap(fd: libc::c_int, p: &io_uring_params) -> io::Result<(SQ, CQ)> {
 
         // convinience function for computing pointer offsets
+        //
+        // NB: offsets are within the mapped ring, whose size fits in a size_t, so they also fit
+        // in a uintptr_t.
         let ptr_off = |p: *const libc::c_void, off: u32| -> *mut libc::c_uint {
-            let mut ptr = p as libc::uintptr_t;
-            ptr += libc::uintptr_t::try_from(off).unwrap();
+            let ptr = p as libc::uintptr_t + off as libc::uintptr_t;
             ptr as *mut libc::c_uint
         };
 
         /*
          * mmap submission queue
          */
-        let sq = &mut self.sq;
 
         // From io_uring_setup(2):
         // The addition of sq_off.array to the length of the region accounts for the fact that the
         // ring located at the end of the data structure.
-        let sq_ring_sz  = {
-            let s1 = libc::size_t::try_from(p.sq_off.array).unwrap();
-            let s2 = libc::size_t::try_from(p.sq_entries).unwrap() * mem::size_of::<u32>();
-            s1 + s2
-        };
+        let sq_ring_sz = ring_size(p.sq_off.array, p.sq_entries, mem::size_of::<u32>())?;
+        let sqe_shift = (p.flags & IORING_SETUP_SQE128 != 0) as u32;
+        let cqe_shift = (p.flags & IORING_SETUP_CQE32 != 0) as u32;
+        let sqe_sz = mem::size_of::<io_uring_sqe>() << sqe_shift;
+        let cqe_sz = mem::size_of::<io_uring_cqe>() << cqe_shift;
+        let sqes_sz = ring_size(0, p.sq_entries, sqe_sz)?;
+        let cq_ring_sz = ring_size(p.cq_off.cqes, p.cq_entries, cqe_sz)?;
 
         // mmap the submission queue structure
         let sq_ring_ptr = {
-            let ptr = unsafe { mmap(sq_ring_sz, self.fd, IORING_OFF_SQ_RING) };
+            let ptr = unsafe { mmap(sq_ring_sz, fd, IORING_OFF_SQ_RING) };
             if ptr == libc::MAP_FAILED {
                 return Err(io::Error::last_os_error())
             }
             ptr
         };
 
-        let sqes_size = {
-            let nentries = libc::size_t::try_from(p.sq_entries).unwrap();
-            let esz = libc::size_t::try_from(mem::size_of::<io_uring_sqe>()).unwrap();
-            nentries*esz
-        };
-
         // mmap the submission queue entries array
         let sqes_ptr = {
-            let sqp = unsafe { mmap(sqes_size, self.fd, IORING_OFF_SQES) };
+            let sqp = unsafe { mmap(sqes_sz, fd, IORING_OFF_SQES) };
             if sqp == libc::MAP_FAILED {
+                let err = io::Error::last_os_error();
                 unsafe { munmap(sq_ring_ptr, sq_ring_sz) };
-                return Err(io::Error::last_os_error());
+                return Err(err);
             }
             sqp as *mut io_uring_sqe
         };
 
         // initialize the SQ structure
         // setup pointers to submission queue structure using the sq offsets
-        *sq = {
+        let sq = {
             let ptr = sq_ring_ptr;
             let off : &io_sqring_offsets = &p.sq_off;
             SQ {
@@ -484,40 +2957,33 @@ impl IoUring {
                 kdropped      : ptr_off(ptr, off.dropped),
                 array         : ptr_off(ptr, off.array),
                 sqes          : sqes_ptr,
-                sqe_head      : std::num::Wrapping(0),
-                sqe_tail      : std::num::Wrapping(0),
+                sqes_sz,
+                sqe_shift,
+                // NB: the ring may have been used before (see from_raw_parts())
+                sqe_head      : std::num::Wrapping(unsafe { *ptr_off(ptr, off.tail) }),
+                sqe_tail      : std::num::Wrapping(unsafe { *ptr_off(ptr, off.tail) }),
                 ring_sz       : sq_ring_sz,
                 ring_ptr      : ptr,
             }
         };
 
-        // these two have to be the same so that the unmap when closing io_uring works properly
-        assert_eq!(p.sq_entries, unsafe { *sq.kring_entries });
-
         /*
          * mmap completion queue
          */
-        let cq = &mut self.cq;
-
-        let cq_ring_sz = {
-            let s1 = libc::size_t::try_from(p.cq_off.cqes).unwrap();
-            let s2 = libc::size_t::try_from(p.cq_entries).unwrap() * mem::size_of::<io_uring_cqe>();
-            s1 + s2
-        };
-
         let cq_ring_ptr  = {
-            let ptr = unsafe { mmap(cq_ring_sz, self.fd, IORING_OFF_CQ_RING) };
+            let ptr = unsafe { mmap(cq_ring_sz, fd, IORING_OFF_CQ_RING) };
             if ptr == libc::MAP_FAILED {
+                let err = io::Error::last_os_error();
                 unsafe {
                     munmap(sq_ring_ptr, sq_ring_sz);
-                    munmap(sqes_ptr as *mut libc::c_void, sqes_size);
+                    munmap(sqes_ptr as *mut libc::c_void, sqes_sz);
                 }
-                return Err(io::Error::last_os_error())
+                return Err(err)
             }
             ptr
         };
 
-        *cq = {
+        let cq = {
             let ptr = cq_ring_ptr;
             let off : &io_cqring_offsets = &p.cq_off;
             CQ {
@@ -527,33 +2993,466 @@ impl IoUring {
                 kring_entries: ptr_off(ptr, off.ring_entries),
                 overflow: ptr_off(ptr, off.overflow),
                 cqes: ptr_off(ptr, off.cqes) as *mut io_uring_cqe,
+                cqe_shift,
                 ring_sz: cq_ring_sz,
                 ring_ptr: ptr
             }
         };
 
-        Ok(())
+        Ok((sq, cq))
     }
 
     fn queue_unmap(&mut self) {
-        let sqes_size = {
-            let nentries_ = unsafe { *self.sq.kring_entries };
-            let nentries = libc::size_t::try_from(nentries_).unwrap();
-            let esz = libc::size_t::try_from(mem::size_of::<io_uring_sqe>()).unwrap();
-            nentries*esz
-        };
         unsafe {
             munmap(self.sq.ring_ptr, self.sq.ring_sz);
-            munmap(self.sq.sqes as *mut libc::c_void, sqes_size);
+            munmap(self.sq.sqes as *mut libc::c_void, self.sq.sqes_sz);
             munmap(self.cq.ring_ptr, self.cq.ring_sz);
         }
     }
+}
+
+// registration functions
+impl IoUring {
+
+    /// Register buffers, so that READ_FIXED and WRITE_FIXED requests can refer to them by their
+    /// index in bufs (see [`SQEntry::prep_read_fixed`])
+    ///
+    /// The kernel pins the buffer pages once here, instead of on every request. The buffers need
+    /// to stay alive until they are unregistered (or the ring is dropped): the kernel keeps
+    /// writing to the pinned pages even if the memory is freed.
+    pub fn register_buffers(&mut self, bufs: &[std::io::IoSliceMut]) -> io::Result<()> {
+        let nr = match libc::c_uint::try_from(bufs.len()) {
+            Ok(x) => x,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many buffers")),
+        };
+        // NB: IoSliceMut is ABI compatible with iovec
+        let arg = bufs.as_ptr() as *mut libc::c_void;
+        let err = unsafe { io_uring_register(self.fd, IORING_REGISTER_BUFFERS, arg, nr) };
+        if err < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Unregister the buffers registered via [`Self::register_buffers`]
+    pub fn unregister_buffers(&mut self) -> io::Result<()> {
+        let arg = std::ptr::null_mut();
+        let err = unsafe { io_uring_register(self.fd, IORING_UNREGISTER_BUFFERS, arg, 0) };
+        if err < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Register a table of files, so that requests can refer to them by their index in fds
+    /// (see [`SqeFlags::FIXED_FILE`])
+    ///
+    /// This avoids looking up and reference counting the files on every request.
+    pub fn register_files(&mut self, fds: &[std::os::unix::io::RawFd]) -> io::Result<()> {
+        let nr = match libc::c_uint::try_from(fds.len()) {
+            Ok(x) => x,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many files")),
+        };
+        let arg = fds.as_ptr() as *mut libc::c_void;
+        let err = unsafe { io_uring_register(self.fd, IORING_REGISTER_FILES, arg, nr) };
+        if err < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Unregister the files registered via [`Self::register_files`]
+    pub fn unregister_files(&mut self) -> io::Result<()> {
+        let arg = std::ptr::null_mut();
+        let err = unsafe { io_uring_register(self.fd, IORING_UNREGISTER_FILES, arg, 0) };
+        if err < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Register the ring fd, so that the submit and wait functions pass its index in the
+    /// registered ring fds to io_uring_enter() (IORING_ENTER_REGISTERED_RING), instead of the fd
+    /// (Linux 5.18). Returns the index.
+    ///
+    /// This saves looking up the file of the ring on every io_uring_enter() call. Registered ring
+    /// fds belong to the registering thread, which is fine since an IoUring does not move between
+    /// threads. [`Self::enter`] still uses the fd, unless given the index and REGISTERED_RING.
+    #[cfg(feature = "linux-5_19")]
+    pub fn register_ring_fd(&mut self) -> io::Result<u32> {
+        if let Some(idx) = self.ring_fd_index {
+            return Ok(idx);
+        }
+        // NB: an offset of -1 lets the kernel pick the index
+        let mut up = io_uring_rsrc_update { offset: u32::MAX, resv: 0, data: self.fd as u64 };
+        let arg = &mut up as *mut io_uring_rsrc_update as *mut libc::c_void;
+        let ret = unsafe { io_uring_register(self.fd, IORING_REGISTER_RING_FDS, arg, 1) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if ret != 1 {
+            return Err(io::Error::other(format!("registered {} ring fds instead of 1", ret)));
+        }
+        self.ring_fd_index = Some(up.offset);
+        Ok(up.offset)
+    }
+
+    /// Unregister the ring fd registered via [`Self::register_ring_fd`], if it is registered
+    #[cfg(feature = "linux-5_19")]
+    pub fn unregister_ring_fd(&mut self) -> io::Result<()> {
+        let idx = match self.ring_fd_index {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let mut up = io_uring_rsrc_update { offset: idx, resv: 0, data: 0 };
+        let arg = &mut up as *mut io_uring_rsrc_update as *mut libc::c_void;
+        let ret = unsafe { io_uring_register(self.fd, IORING_UNREGISTER_RING_FDS, arg, 1) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.ring_fd_index = None;
+        Ok(())
+    }
+
+    /// The index of the ring fd in the registered ring fds, if it is registered (see
+    /// [`Self::register_ring_fd`])
+    pub fn ring_fd_index(&self) -> Option<u32> {
+        self.ring_fd_index
+    }
+
+    /// Register the credentials of the current task as a personality, and return its id (Linux
+    /// 5.6)
+    ///
+    /// Requests that set the personality (see [`SQEntry::set_personality`]) are issued with
+    /// these credentials, even if the task changes its credentials later, e.g., to drop
+    /// privileges.
+    pub fn register_personality(&mut self) -> io::Result<u16> {
+        let arg = std::ptr::null_mut();
+        let id = unsafe { io_uring_register(self.fd, IORING_REGISTER_PERSONALITY, arg, 0) };
+        if id < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(id as u16)
+    }
+
+    /// Unregister a personality registered via [`Self::register_personality`]
+    pub fn unregister_personality(&mut self, id: u16) -> io::Result<()> {
+        let arg = std::ptr::null_mut();
+        let err = unsafe {
+            io_uring_register(self.fd, IORING_UNREGISTER_PERSONALITY, arg, id as libc::c_uint)
+        };
+        if err < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Restrict the io-wq workers of the ring, which execute requests asynchronously, to the
+    /// CPUs in cpus (Linux 5.14)
+    #[cfg(feature = "linux-5_15")]
+    pub fn register_iowq_affinity(&mut self, cpus: &CpuSet) -> io::Result<()> {
+        let arg = &cpus.set as *const libc::cpu_set_t as *mut libc::c_void;
+        let sz = std::mem::size_of::<libc::cpu_set_t>() as libc::c_uint;
+        let err = unsafe { io_uring_register(self.fd, IORING_REGISTER_IOWQ_AFF, arg, sz) };
+        if err < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Undo [`Self::register_iowq_affinity`]: io-wq workers inherit the affinity of the task that
+    /// created the ring (Linux 5.14)
+    #[cfg(feature = "linux-5_15")]
+    pub fn unregister_iowq_affinity(&mut self) -> io::Result<()> {
+        let arg = std::ptr::null_mut();
+        let err = unsafe { io_uring_register(self.fd, IORING_UNREGISTER_IOWQ_AFF, arg, 0) };
+        if err < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Set the maximum number of io-wq workers for bounded (e.g., regular file) and unbounded
+    /// (e.g., socket) work, per NUMA node, and return the previous limits (Linux 5.15)
+    ///
+    /// A limit of zero leaves the corresponding limit unchanged, so
+    /// `set_iowq_max_workers(0, 0)` queries the current limits.
+    #[cfg(feature = "linux-5_15")]
+    pub fn set_iowq_max_workers(&mut self, bounded: u32, unbounded: u32) -> io::Result<(u32, u32)> {
+        let mut vals: [u32; 2] = [bounded, unbounded];
+        let arg = vals.as_mut_ptr() as *mut libc::c_void;
+        let err = unsafe { io_uring_register(self.fd, IORING_REGISTER_IOWQ_MAX_WORKERS, arg, 2) };
+        if err < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((vals[0], vals[1]))
+    }
+
+    /// Register a table of nr empty slots for files (Linux 5.19)
+    ///
+    /// Files are installed in the slots by requests that open files directly into registered
+    /// slots (e.g., [`SQEntry::prep_openat_direct`]), or via [`Self::update_registered_files`].
+    #[cfg(feature = "linux-5_19")]
+    pub fn register_files_sparse(&mut self, nr: u32) -> io::Result<()> {
+        let mut rr = io_uring_rsrc_register {
+            nr,
+            flags: IORING_RSRC_REGISTER_SPARSE,
+            resv2: 0,
+            data: 0,
+            tags: 0,
+        };
+        let arg = &mut rr as *mut io_uring_rsrc_register as *mut libc::c_void;
+        let sz = mem::size_of::<io_uring_rsrc_register>() as libc::c_uint;
+        let err = unsafe { io_uring_register(self.fd, IORING_REGISTER_FILES2, arg, sz) };
+        if err < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Set the range of registered file slots [off, off + len) that the kernel picks slots from
+    /// for IORING_FILE_INDEX_ALLOC (Linux 6.0). By default, it is the whole table.
+    ///
+    /// This leaves the slots outside the range to be managed by the application (see
+    /// crate::file_slots).
+    #[cfg(feature = "linux-6_0")]
+    pub fn register_file_alloc_range(&mut self, off: u32, len: u32) -> io::Result<()> {
+        let mut range = io_uring_file_index_range { off, len, resv: 0 };
+        let arg = &mut range as *mut io_uring_file_index_range as *mut libc::c_void;
+        let err = unsafe { io_uring_register(self.fd, IORING_REGISTER_FILE_ALLOC_RANGE, arg, 0) };
+        if err < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Replace the registered files starting at index offset with fds, and return the number of
+    /// files replaced
+    ///
+    /// An fd of -1 empties its slot, and the table can be registered with -1 entries to reserve
+    /// slots for later updates. Requests in flight keep using the files they were issued with.
+    pub fn update_registered_files(&mut self, offset: u32, fds: &[std::os::unix::io::RawFd])
+    -> io::Result<u32> {
+        let nr = match libc::c_uint::try_from(fds.len()) {
+            Ok(x) => x,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many files")),
+        };
+        let mut update = io_uring_files_update {
+            offset,
+            resv: 0,
+            fds: fds.as_ptr() as u64,
+        };
+        let arg = &mut update as *mut io_uring_files_update as *mut libc::c_void;
+        let ret = unsafe { io_uring_register(self.fd, IORING_REGISTER_FILES_UPDATE, arg, nr) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as u32)
+    }
+
+    /// Register an eventfd that the kernel signals whenever it posts a cqe, and return it as a
+    /// [`Notifier`] that can be added to a readiness-based event loop
+    ///
+    /// A ring can only have one registered eventfd. Use [`Self::unregister_notifier`] to remove
+    /// it: dropping the notifier does not unregister it.
+    pub fn notifier(&self) -> io::Result<Notifier> {
+        let notifier = Notifier::new()?;
+        let mut fd = notifier.as_raw_fd();
+        let arg = &mut fd as *mut libc::c_int as *mut libc::c_void;
+        let err = unsafe { io_uring_register(self.fd, IORING_REGISTER_EVENTFD, arg, 1) };
+        if err < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(notifier)
+    }
+
+    /// Unregister the eventfd registered via [`Self::notifier`]
+    pub fn unregister_notifier(&self) -> io::Result<()> {
+        let arg = std::ptr::null_mut();
+        let err = unsafe { io_uring_register(self.fd, IORING_UNREGISTER_EVENTFD, arg, 0) };
+        if err < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Call io_uring_register() on the ring with the given arguments, for registrations that the
+    /// other methods do not cover (e.g., newer ones), and return its (non-negative) result
+    ///
+    /// opcode is one of the IORING_REGISTER_* constants (see [`IORING_REGISTER_BUFFERS`]).
+    ///
+    /// # Safety
+    ///
+    /// arg and nr_args need to be valid for the opcode, e.g., pointing to an array of nr_args
+    /// elements of the type it expects. Some registrations also need memory to stay valid for as
+    /// long as they are in place (e.g., registered buffers), or conflict with state the ring keeps
+    /// (e.g., resizing the rings invalidates the mappings of the IoUring).
+    pub unsafe fn register(
+        &self,
+        opcode: libc::c_uint,
+        arg: *mut libc::c_void,
+        nr_args: libc::c_uint,
+    ) -> io::Result<i32> {
+        let ret = io_uring_register(self.fd, opcode, arg, nr_args);
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as i32)
+    }
+
+    /// Enable a ring created with [`SetupFlags::R_DISABLED`] (Linux 5.10)
+    #[cfg(feature = "linux-5_15")]
+    pub fn enable(&self) -> io::Result<()> {
+        let arg = std::ptr::null_mut();
+        let err = unsafe { io_uring_register(self.fd, IORING_REGISTER_ENABLE_RINGS, arg, 0) };
+        if err < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Register a region of nr wait arguments (Linux 6.13), for [`Self::submit_and_wait_reg`]
+    ///
+    /// Waiting with a timeout (or a minimum wait time) normally passes the arguments to every
+    /// io_uring_enter() call, which copies them. With a registered region, the kernel reads them
+    /// from memory it has already mapped. The ring needs to be created disabled
+    /// ([`SetupFlags::R_DISABLED`]), and enabled (see [`Self::enable`]) after this.
+    #[cfg(feature = "linux-6_13")]
+    pub fn register_wait_region(&mut self, nr: usize) -> io::Result<()> {
+        let page_sz = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let sz = match nr.checked_mul(mem::size_of::<RegWait>()) {
+            Some(x) if x > 0 => x.div_ceil(page_sz) * page_sz,
+            _ => {
+                let msg = format!("invalid number of wait arguments: {}", nr);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        };
+        let ptr = unsafe {
+            let prot = libc::PROT_READ | libc::PROT_WRITE;
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+            libc::mmap(std::ptr::null_mut(), sz, prot, flags, -1, 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // NB: the mapping is zeroed, which is a valid RegWait (no timeout)
+        let region = WaitRegion { waits: ptr as *mut RegWait, nr, sz };
+
+        let mut desc: io_uring_region_desc = unsafe { mem::zeroed() };
+        desc.user_addr = ptr as usize as u64;
+        desc.size = sz as u64;
+        desc.flags = IORING_MEM_REGION_TYPE_USER;
+        let mut reg: io_uring_mem_region_reg = unsafe { mem::zeroed() };
+        reg.region_uptr = &mut desc as *mut io_uring_region_desc as usize as u64;
+        reg.flags = IORING_MEM_REGION_REG_WAIT_ARG;
+        let arg = &mut reg as *mut io_uring_mem_region_reg as *mut libc::c_void;
+        let err = unsafe { io_uring_register(self.fd, IORING_REGISTER_MEM_REGION, arg, 1) };
+        if err < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.wait_region = Some(region);
+        Ok(())
+    }
+
+    /// The wait argument at index idx of the registered wait region
+    #[cfg(feature = "linux-6_13")]
+    pub fn wait_arg(&mut self, idx: usize) -> Option<&mut RegWait> {
+        let region = self.wait_region.as_mut()?;
+        if idx >= region.nr {
+            return None;
+        }
+        Some(unsafe { &mut *region.waits.add(idx) })
+    }
+
+    /// Probe the opcodes that the kernel supports (Linux 5.6)
+    ///
+    /// Unlike [`Self::supports`], the result can be kept around, e.g., to pick between
+    /// alternative opcodes for each request without probing again.
+    pub fn probe(&self) -> io::Result<Probe> {
+        let mut probe: Box<io_uring_probe> = Box::new(unsafe { mem::zeroed() });
+        let arg = &mut *probe as *mut io_uring_probe as *mut libc::c_void;
+        let nops = probe.ops.len() as libc::c_uint;
+        let err = unsafe { io_uring_register(self.fd, IORING_REGISTER_PROBE, arg, nops) };
+        if err < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut supported = [false; 256];
+        for op in &probe.ops[..probe.ops_len as usize] {
+            supported[op.op as usize] = op.flags & IO_URING_OP_SUPPORTED != 0;
+        }
+        Ok(Probe { last_op: probe.last_op, supported })
+    }
+
+    // Returns a table of the opcodes that the kernel supports (Linux 5.6)
+    fn probe_ops(&self) -> io::Result<[bool; 256]> {
+        Ok(self.probe()?.supported)
+    }
+
+    /// The opcodes that the kernel supports (Linux 5.6)
+    ///
+    /// Opcodes that are newer than this crate are not included.
+    pub fn supported_ops(&self) -> io::Result<HashSet<OpCode>> {
+        Ok(self.probe()?.ops().collect())
+    }
+
+    /// Whether the kernel supports the given opcode (Linux 5.6)
+    pub fn supports(&self, op: OpCode) -> io::Result<bool> {
+        Ok(self.probe_ops()?[op as usize])
+    }
 
+    /// Check that the kernel supports the requests enabled by the linux-* features of the crate
+    ///
+    /// The features only determine what is available at compile time, so applications that
+    /// cannot be sure about the kernel they run on can call this after creating the ring. Returns
+    /// an error of kind `Unsupported` that names the first missing operation.
+    pub fn check_kernel_features(&self) -> io::Result<()> {
+        let enabled = KERNEL_FEATURE_OPS.iter().filter(|(_, on, _)| *on);
+        if enabled.clone().next().is_none() {
+            return Ok(());
+        }
+
+        // NB: probing was added in 5.6, so failing to probe means that nothing is supported
+        let supported = self.probe_ops().unwrap_or([false; 256]);
+        for (feature, _, ops) in enabled {
+            if let Some(op) = ops.iter().find(|op| !supported[**op as usize]) {
+                let msg = format!("kernel does not support {} (feature {})", op, feature);
+                return Err(io::Error::new(io::ErrorKind::Unsupported, msg));
+            }
+        }
+        // NB: read/write attributes are not a request of their own
+        if cfg!(feature = "linux-6_14") && !self.features.contains(Features::RW_ATTR) {
+            let msg = "kernel does not support read/write attributes (feature linux-6_14)";
+            return Err(io::Error::new(io::ErrorKind::Unsupported, msg));
+        }
+        Ok(())
+    }
+}
 
+impl std::os::unix::io::AsRawFd for IoUring {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.fd
+    }
 }
 
 impl Drop for IoUring {
     fn drop(&mut self) {
+        if self.drop_policy != ShutdownPolicy::Detach {
+            let policy = self.drop_policy;
+            if let Err(e) = self.shutdown(policy) {
+                let bt = Backtrace::new();
+                eprintln!("WARNING: shutdown() failed: {}\nBacktrace:\n{:?}", e, bt);
+            }
+        }
+        // NB: the registered ring fd holds a reference to the ring file in the task's
+        // registered rings, so unregister it or the ring stays alive (and uses up a slot) until
+        // the thread exits.
+        #[cfg(feature = "linux-5_19")]
+        if self.ring_fd_index.is_some() {
+            if let Err(e) = self.unregister_ring_fd() {
+                eprintln!("WARNING: unregister_ring_fd() failed: {}", e);
+            }
+        }
         self.queue_unmap();
         unsafe { close(self.fd) };
     }
@@ -565,24 +3464,47 @@ impl IoUring {
 
     /// Get a new submission queue entry (sqe)
     ///
-    /// If queue is full, return None
-    pub fn get_sqe(&mut self) -> Option<SQEntry> {
+    /// If queue is full, return None. The entry borrows the ring, so it needs to be dropped before
+    /// submitting.
+    pub fn get_sqe(&mut self) -> Option<SQEntry<'_>> {
+        let nentries: u32 = unsafe { *self.sq.kring_entries };
+        let mask = unsafe { *self.sq.kring_mask };
+        self.get_sqe_geometry(nentries, mask)
+    }
+
+    // get_sqe(), with the number of entries and the mask of the SQ ring given by the caller
+    #[inline(always)]
+    fn get_sqe_geometry(&mut self, nentries: u32, mask: u32) -> Option<SQEntry<'_>> {
         let sq = &mut self.sq;
         let next = sq.sqe_tail + std::num::Wrapping(1);
-        let nentries: u32 = unsafe { *sq.kring_entries };
-        if (next - sq.sqe_head).0 > nentries {
+        // NB: Entries are only free once the kernel has consumed them (i.e., moved its head past
+        // them), which happens asynchronously if there is an SQ poll thread. The acquire pairs
+        // with the kernel's release store of the head, so that the kernel is done reading the
+        // entry before we reuse it.
+        let khead = std::num::Wrapping(unsafe { load_acquire(sq.khead) });
+        if (next - khead).0 > nentries {
+            self.stats.sq_full += 1;
             return None
         }
 
-        let mask = unsafe { *sq.kring_mask };
         let idx = sq.sqe_tail.0 & mask;
-        let sqe_p = unsafe { sq.sqes.offset(idx as isize) };
+        let sqe_p = unsafe { sq.sqes.add((idx << sq.sqe_shift) as usize) };
+        let ext = if sq.sqe_shift == 1 {
+            Some(unsafe { &mut *(sqe_p.add(1) as *mut [u8; 64]) })
+        } else {
+            None
+        };
 
         sq.sqe_tail = next;
-        Some(SQEntry(sqe_p))
+        // NB: the entry is not accessed by the kernel until it is flushed, which cannot happen
+        // while the returned SQEntry borrows the ring.
+        Some(SQEntry(unsafe { &mut *sqe_p }, ext))
     }
 
-    /// Returns: sqes submited
+    /// Returns: sqes in the SQ ring that the kernel has not consumed yet
+    ///
+    /// NB: these include sqes flushed earlier, e.g., if entering the kernel failed, so that they
+    /// are submitted next time.
     // liburing: __io_uring_flush_sq()
     fn flush_sq(&mut self) -> u32 {
         let sq = &mut self.sq;
@@ -590,17 +3512,57 @@ impl IoUring {
         // NB: This works even if there is an overflow on sqe_{tail,head}
         let to_submit = (sq.sqe_tail - sq.sqe_head).0;
         if to_submit == 0 {
-            return 0
+            return self.sq_unconsumed();
         }
 
         let mask = unsafe { *sq.kring_mask };
-        let mut ktail = std::num::Wrapping( {unsafe { *sq.ktail }} );
+        let mut ktail = std::num::Wrapping(unsafe { *sq.ktail });
         let mut submitted = 0;
+        let now = self.pending.as_ref().map(|_| std::time::Instant::now());
         loop  {
-            // I don't see how this can overflow isize, so skip the runtime test
-            let aoff = (ktail.0 & mask) as isize;
+            // NB: indexes are bound by the ring size, so they always fit in a usize
+            let aoff = (ktail.0 & mask) as usize;
             unsafe {
-                *sq.array.offset(aoff) = sq.sqe_head.0 & mask;
+                *sq.array.add(aoff) = sq.sqe_head.0 & mask;
+            }
+            if let (Some(pending), Some(submitted)) = (self.pending.as_mut(), now) {
+                let idx = (sq.sqe_head.0 & mask) << sq.sqe_shift;
+                let sqe = unsafe { &*sq.sqes.add(idx as usize) };
+                // NB: requests that succeed with CQE_SKIP_SUCCESS post no cqe
+                #[cfg(feature = "linux-5_19")]
+                let skip = sqe.flags & SqeFlags::CQE_SKIP_SUCCESS.bits() != 0;
+                #[cfg(not(feature = "linux-5_19"))]
+                let skip = false;
+                if !skip {
+                    let (user_data, opcode, fd) = (sqe.user_data, sqe.opcode, sqe.fd);
+                    let op = PendingOp { user_data, opcode, fd, submitted, count: 0 };
+                    // NB: requests with the same user data share an entry
+                    pending.entry(user_data).or_insert(op).count += 1;
+                }
+            }
+            if let Some(op_stats) = self.op_stats.as_mut() {
+                let idx = (sq.sqe_head.0 & mask) << sq.sqe_shift;
+                op_stats.submitted(unsafe { (*sq.sqes.add(idx as usize)).opcode });
+            }
+            if let Some(recorder) = self.recorder.as_mut() {
+                let idx = (sq.sqe_head.0 & mask) << sq.sqe_shift;
+                let len = mem::size_of::<io_uring_sqe>() << sq.sqe_shift;
+                let sqe_p = unsafe { sq.sqes.add(idx as usize) } as *const u8;
+                let bytes = unsafe { std::slice::from_raw_parts(sqe_p, len) };
+                recorder.record(Record::Sqe(RecordedSqe::from_bytes(bytes)));
+            }
+            #[cfg(feature = "usdt")]
+            {
+                let idx = (sq.sqe_head.0 & mask) << sq.sqe_shift;
+                let sqe = unsafe { &*sq.sqes.add(idx as usize) };
+                crate::usdt::submit(self.fd, sqe.opcode, sqe.fd, sqe.user_data);
+            }
+            #[cfg(feature = "tracing")]
+            {
+                let idx = (sq.sqe_head.0 & mask) << sq.sqe_shift;
+                let sqe = unsafe { &*sq.sqes.add(idx as usize) };
+                let (opcode, flags, user_data) = (sqe.opcode, sqe.flags, sqe.user_data);
+                tracing::trace!(opcode, flags, user_data, "sqe");
             }
             sq.sqe_head += std::num::Wrapping(1);
             ktail += std::num::Wrapping(1);
@@ -613,96 +3575,726 @@ impl IoUring {
 
         // Ensure that the queue consumer (kernel) to see the updated sqe entries before any
         // updates to the tail.
-        //
-        // NB: not sure if there is a better way to do this than the cast here, but AtomicU32
-        // documentation says that: "This type has the same in-memory representation as the
-        // underlying integer type, u32."
-        let ktail_p = sq.ktail as *mut std::sync::atomic::AtomicU32;
-        unsafe {
-            (&*ktail_p).store(ktail.0, std::sync::atomic::Ordering::Release);
+        unsafe { store_release(sq.ktail, ktail.0) };
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record(Record::Submit(submitted));
         }
 
-        submitted
+        self.sq_unconsumed()
+    }
+
+    // Number of sqes in the SQ ring (i.e., flushed) that the kernel has not consumed yet
+    fn sq_unconsumed(&self) -> u32 {
+        let ktail = unsafe { *self.sq.ktail };
+        let khead = unsafe { load_acquire(self.sq.khead) };
+        ktail.wrapping_sub(khead)
     }
 
     // Returns:
-    // None -> No need to enter for the SQ (this will happen when SQPOLL is defined)
+    // None -> No need to enter for the SQ (this will happen when SQPOLL is defined, or when there
+    //         is nothing to submit)
     // Some(flags) -> you need to enter for the SQ, please use the following flags
     //
-    fn sq_ring_needs_enter(&mut self) -> Option<EnterFlags> {
+    fn sq_ring_needs_enter(&mut self, submitted: u32) -> Option<EnterFlags> {
+
+        if submitted == 0 {
+            return None
+        }
 
         if !self.flags.contains(SetupFlags::SQPOLL) {
             return Some(EnterFlags::empty())
         }
 
-        let need_wakeup = unsafe {
-            let flags = std::ptr::read_volatile(self.sq.kflags);
-            SQFlags::from_bits_unchecked(flags).contains(SQFlags::NEED_WAKEUP)
-        };
-        if need_wakeup {
+        if self.sq_flags().contains(SQFlags::NEED_WAKEUP) {
             return Some(EnterFlags::SQ_WAKEUP);
         }
 
         None
     }
 
+    /// Sizes of the rings, as set up by the kernel
+    pub fn geometry(&self) -> Geometry {
+        let (sq_entries, cq_entries) = unsafe { (*self.sq.kring_entries, *self.cq.kring_entries) };
+        Geometry {
+            sq_entries,
+            cq_entries,
+            sqe_size: mem::size_of::<io_uring_sqe>() << self.sq.sqe_shift,
+            cqe_size: mem::size_of::<io_uring_cqe>() << self.cq.cqe_shift,
+        }
+    }
+
+    /// Number of sqes that can be acquired via get_sqe() before the submission queue is full
+    pub fn sq_space_left(&self) -> u32 {
+        let nentries: u32 = unsafe { *self.sq.kring_entries };
+        let khead = std::num::Wrapping(unsafe { load_acquire(self.sq.khead) });
+        nentries - (self.sq.sqe_tail - khead).0
+    }
+
+    /// The flags that the kernel sets in the SQ ring
+    ///
+    ///  - NEED_WAKEUP: the SQPOLL thread is asleep, and submitting needs to wake it up (which
+    ///    [`Self::submit`] does).
+    ///  - CQ_OVERFLOW: cqes did not fit in the CQ ring, and are queued in the kernel until the ring
+    ///    is entered to flush them (see [`Stats::cq_overflows`]).
+    ///  - TASKRUN: the kernel has completions to post, and needs the ring to be entered.
+    pub fn sq_flags(&self) -> SQFlags {
+        unsafe {
+            let flags = std::ptr::read_volatile(self.sq.kflags);
+            SQFlags::from_bits_unchecked(flags)
+        }
+    }
+
+    /// The CQ overflow counter of the kernel: the number of cqes that were dropped because they
+    /// did not fit in the CQ ring
+    ///
+    /// On FEAT_NODROP kernels (Linux 5.5), cqes that do not fit are queued in the kernel instead
+    /// (see [`SQFlags::CQ_OVERFLOW`] and [`Self::flush_overflow`]), and are only dropped if the
+    /// kernel fails to allocate memory for queuing them.
+    pub fn cq_overflow(&self) -> u32 {
+        unsafe { std::ptr::read_volatile(self.cq.overflow) }
+    }
+
+    /// Have the kernel move the cqes queued due to a CQ overflow to the CQ ring, and return the
+    /// number of cqes available
+    ///
+    /// The kernel only moves as many cqes as there is space for in the CQ ring, so this may need
+    /// to be called again after reaping, until [`SQFlags::CQ_OVERFLOW`] is cleared. The submit
+    /// and wait functions also flush the queued cqes, but an application that only polls the CQ
+    /// ring would otherwise stall.
+    pub fn flush_overflow(&mut self) -> io::Result<u32> {
+        if self.cq_ring_needs_flush() {
+            self.enter_getevents(0)?;
+        }
+        self.check_cq_overflow();
+        Ok(self.cq_ready())
+    }
+
+    // Returns true if we need to enter the kernel with GETEVENTS so that it flushes CQEs to the
+    // ring. On FEAT_NODROP kernels, CQEs that did not fit in the CQ ring are queued in the kernel
+    // and the CQ_OVERFLOW flag is set until they are flushed.
+    //
+    // liburing: cq_ring_needs_flush()
+    fn cq_ring_needs_flush(&self) -> bool {
+        self.sq_flags().intersects(SQFlags::CQ_OVERFLOW | SQFlags::TASKRUN)
+    }
+
+    // Check the CQ overflow counter, and warn if it was increased.
+    //
+    // On kernels without FEAT_NODROP, the kernel drops CQEs if the CQ ring is full and increases
+    // the counter. On FEAT_NODROP kernels, CQEs are only dropped (and the counter increased) if
+    // the kernel fails to allocate memory for queuing the overflown CQEs. Either way, completions
+    // were lost, so make some noise.
+    fn check_cq_overflow(&mut self) {
+        let overflow = self.cq_overflow();
+        let dropped = overflow.wrapping_sub(self.cq_overflow_seen);
+        if dropped == 0 {
+            return;
+        }
+        self.cq_overflow_seen = overflow;
+        self.stats.cq_overflows += dropped as u64;
+        let bt = Backtrace::new();
+        let nodrop = if self.features.contains(Features::NODROP) { "" } else { "non-" };
+        eprintln!(
+            "WARNING: {} completion(s) dropped due to CQ ring overflow ({}NODROP kernel)\n\
+             Backtrace:\n{:?}",
+            dropped, nodrop, bt);
+    }
+
     // liburing: __io_uring_submit()
-    fn do_submit(&mut self, submitted: u32, mut wait_nr: u32) -> std::io::Result<u32> {
+    fn do_submit(&mut self, submitted: u32, wait_nr: u32, wait_arg: Option<WaitArg>)
+    -> std::io::Result<u32> {
 
-        let flags = match (wait_nr, self.sq_ring_needs_enter()) {
-            (0, None) => {
+        let cq_needs_flush = self.cq_ring_needs_flush();
+        let mut flags = match (wait_nr, self.sq_ring_needs_enter(submitted), cq_needs_flush) {
+            (0, None, false) if wait_arg.is_none() => {
                 // No need to issue system call, just return
+                self.check_cq_overflow();
                 return Ok(submitted);
             },
-            (0, Some(x)) => x,
-            (_, None) => EnterFlags::GETEVENTS,
-            (_, Some(mut x)) => {
-                x.set(EnterFlags::GETEVENTS, true);
-                x
+            (_, None, _) => EnterFlags::empty(),
+            (_, Some(x), _) => x,
+        };
+        if wait_nr > 0 || cq_needs_flush {
+            flags.insert(EnterFlags::GETEVENTS);
+        }
+        let getevents_arg;
+        let (arg, argsz) = match wait_arg {
+            Some(WaitArg::Reg(off)) => {
+                flags.insert(EnterFlags::GETEVENTS | EnterFlags::EXT_ARG | EnterFlags::EXT_ARG_REG);
+                (off as *mut libc::c_void, mem::size_of::<RegWait>())
+            }
+            Some(WaitArg::Timeout(ts)) => {
+                flags.insert(EnterFlags::GETEVENTS | EnterFlags::EXT_ARG);
+                getevents_arg = io_uring_getevents_arg {
+                    sigmask: 0,
+                    sigmask_sz: KERNEL_SIGSET_SIZE,
+                    min_wait_usec: 0,
+                    ts: ts as *const KernelTimespec as u64,
+                };
+                let arg = &getevents_arg as *const io_uring_getevents_arg as *mut libc::c_void;
+                (arg, mem::size_of::<io_uring_getevents_arg>())
             }
+            Some(WaitArg::Sigmask(sigset)) => {
+                let arg = sigset as *const libc::sigset_t as *mut libc::c_void;
+                (arg, KERNEL_SIGSET_SIZE as usize)
+            }
+            None => (std::ptr::null_mut(), KERNEL_SIGSET_SIZE as usize),
         };
 
-        // NB: I guess liburing truncates wait_nr to submitted to avoid the case of sleeping
-        // forever, even though waiting for more than you submit might be valid if you previously
-        // submitted without waiting.
-        if wait_nr > submitted {
-            wait_nr = submitted;
-        }
+        // NB: Older liburing versions truncated wait_nr to submitted, but waiting for more than
+        // we submit is valid if we previously submitted without waiting (and it is the only way to
+        // wait for completions), so don't.
 
-        let null = 0 as *mut libc::sigset_t;
+        self.stats.enter_calls += 1;
         let ret = unsafe {
-            io_uring_enter(self.fd, submitted, wait_nr, flags.bits(), null)
+            let (fd, reg_flag) = self.enter_fd();
+            io_uring_enter_arg(fd, submitted, wait_nr, (flags | reg_flag).bits(), arg, argsz)
         };
-
-        if ret < 0 {
+        let ret = if ret < 0 {
             // wrap errno
             Err(std::io::Error::last_os_error())
         } else {
             Ok(ret as u32)
-        }
+        };
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            to_submit = submitted,
+            min_complete = wait_nr,
+            flags = ?flags,
+            ret = ?ret,
+            "io_uring_enter"
+        );
+
+        self.check_cq_overflow();
+        ret
     }
 
     // liburing: __io_uring_submit_and_wait
-    fn do_submit_and_wait(&mut self, wait_nr: u32) -> std::io::Result<u32> {
+    fn do_submit_and_wait(&mut self, wait_nr: u32, wait_arg: Option<WaitArg>)
+    -> std::io::Result<u32> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("submit", fd = self.fd, wait_nr).entered();
         let submitted = self.flush_sq();
-        if submitted > 0 {
-            return self.do_submit(submitted, wait_nr)
-        }
-        Ok(0)
+        let ret = self.do_submit(submitted, wait_nr, wait_arg)?;
+        self.inflight += ret;
+        self.stats.sqes_submitted += ret as u64;
+        Ok(ret)
     }
 
     /// Submit sqes acquired via get_sqe() to the kernel.
     ///
     /// Returns number of sqes submitted, or error if io_uring_enter() failed.
     pub fn submit(&mut self) -> std::io::Result<u32> {
-        self.do_submit_and_wait(0)
+        self.do_submit_and_wait(0, None)
+    }
+
+    /// Submit sqes acquired via get_sqe() to the kernel, and wait until at least wait_nr cqes are
+    /// available.
+    ///
+    /// There is no need for any sqes to be pending, so this can also be used just for waiting.
+    /// Returns number of sqes submitted, or error if io_uring_enter() failed.
+    pub fn submit_and_wait(&mut self, wait_nr: u32) -> std::io::Result<u32> {
+        self.do_submit_and_wait(wait_nr, None)
+    }
+
+    /// Like [`Self::submit_and_wait`], but wait with the signal mask set to sigmask, as
+    /// pselect(2) does
+    ///
+    /// The mask is set (and restored) atomically with the wait, so that signals that are blocked
+    /// otherwise can interrupt the wait (with an EINTR error) without racing with it.
+    pub fn submit_and_wait_with_sigmask(&mut self, wait_nr: u32, sigmask: &libc::sigset_t)
+    -> std::io::Result<u32> {
+        self.do_submit_and_wait(wait_nr, Some(WaitArg::Sigmask(sigmask)))
+    }
+
+    /// Like [`Self::submit_and_wait`], but wait with the arguments (e.g., a timeout) at index idx
+    /// of the registered wait region (see [`Self::register_wait_region`])
+    ///
+    /// If the timeout expires before wait_nr cqes are available, and no sqes were submitted, this
+    /// returns an ETIME error.
+    #[cfg(feature = "linux-6_13")]
+    pub fn submit_and_wait_reg(&mut self, wait_nr: u32, idx: usize) -> std::io::Result<u32> {
+        match self.wait_region {
+            Some(ref x) if idx < x.nr => (),
+            _ => {
+                let msg = format!("no registered wait argument at index {}", idx);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        }
+        self.do_submit_and_wait(wait_nr, Some(WaitArg::Reg(idx * mem::size_of::<RegWait>())))
+    }
+
+    /// Call io_uring_enter() with the given arguments, for flag combinations that the other
+    /// methods do not cover
+    ///
+    /// The sqes acquired via get_sqe() are added to the SQ ring first, so that to_submit can
+    /// include them. Returns the result of io_uring_enter(): the number of sqes submitted. The
+    /// call is not retried if it is interrupted.
+    ///
+    /// # Safety
+    ///
+    /// The flags need to be valid for the arguments. E.g., with EXT_ARG the kernel reads a struct
+    /// io_uring_getevents_arg where sigset points, and with REGISTERED_RING it takes the fd of the
+    /// ring to be an index into the registered ring fds.
+    pub unsafe fn enter(
+        &mut self,
+        to_submit: u32,
+        min_complete: u32,
+        flags: EnterFlags,
+        sigset: Option<&libc::sigset_t>,
+    ) -> io::Result<u32> {
+        self.flush_sq();
+        let sigset = sigset.map_or(std::ptr::null_mut(), |x| x as *const _ as *mut _);
+        self.stats.enter_calls += 1;
+        let ret = io_uring_enter(self.fd, to_submit, min_complete, flags.bits(), sigset);
+        #[cfg(feature = "tracing")]
+        tracing::trace!(to_submit, min_complete, flags = ?flags, ret, "io_uring_enter (raw)");
+        let err = io::Error::last_os_error();
+        self.check_cq_overflow();
+        if ret < 0 {
+            return Err(err);
+        }
+        self.inflight += ret as u32;
+        self.stats.sqes_submitted += ret as u64;
+        Ok(ret as u32)
     }
 }
 
 // queue functions: CQ
 impl IoUring {
-    pub fn cq_iter(&self) -> CqIter {
-        let cq_head = unsafe { *self.sq.khead };
+    /// Pop the next cqe, if one is available, releasing its slot to the kernel
+    pub fn pop_cqe(&mut self) -> Option<io_uring_cqe> {
+        let mask = unsafe { *self.cq.kring_mask };
+        self.pop_cqe_mask(mask)
+    }
+
+    // pop_cqe(), with the mask of the CQ ring given by the caller
+    #[inline(always)]
+    fn pop_cqe_mask(&mut self, mask: u32) -> Option<io_uring_cqe> {
+        self.pop_cqe_big(mask, None)
+    }
+
+    // pop_cqe_mask(), also copying the second half of 32-byte cqes to big, if given
+    #[inline(always)]
+    fn pop_cqe_big(&mut self, mask: u32, mut big: Option<&mut [u64; 2]>)
+    -> Option<io_uring_cqe> {
+        loop {
+            let cq = &self.cq;
+            // NB: we are the only ones updating the head
+            let head = unsafe { *cq.khead };
+            let tail = unsafe { load_acquire(cq.ktail) };
+            if head == tail {
+                return None;
+            }
+
+            let cqe_p = unsafe { cq.cqes.add(((head & mask) << cq.cqe_shift) as usize) };
+            let cqe = unsafe { *cqe_p };
+            if let Some(big) = big.as_deref_mut() {
+                if cq.cqe_shift == 1 {
+                    *big = unsafe { *(cqe_p.add(1) as *const [u64; 2]) };
+                }
+            }
+            // The release ensures that we are done reading the cqe before the kernel reuses its
+            // slot
+            unsafe { store_release(cq.khead, head.wrapping_add(1)) };
+            self.reaped(&cqe);
+            if !self.is_wait_timeout(&cqe) {
+                return Some(cqe);
+            }
+        }
+    }
+
+    // Whether a reaped cqe is of a timeout issued by wait_cqe_timeout(), which are not returned
+    #[inline(always)]
+    fn is_wait_timeout(&mut self, cqe: &io_uring_cqe) -> bool {
+        if self.wait_timeouts == 0 || cqe.user_data != WAIT_TIMEOUT_UDATA {
+            return false;
+        }
+        self.wait_timeouts -= 1;
+        true
+    }
+
+    // Account for a cqe that was reaped
+    #[inline(always)]
+    fn reaped(&mut self, cqe: &io_uring_cqe) {
+        if cqe.is_terminal() {
+            self.inflight = self.inflight.saturating_sub(1);
+        }
+        if let Some(pending) = self.pending.as_mut() {
+            match pending.get_mut(&cqe.user_data) {
+                Some(op) => {
+                    if let Some(op_stats) = self.op_stats.as_mut() {
+                        op_stats.completed(op.opcode, cqe.res);
+                    }
+                    if cqe.is_terminal() {
+                        if let Some(latency) = self.latency.as_mut() {
+                            latency.record(op.opcode, op.submitted.elapsed());
+                        }
+                        op.count -= 1;
+                        if op.count == 0 {
+                            pending.remove(&cqe.user_data);
+                        }
+                    }
+                }
+                None => {
+                    if let Some(op_stats) = self.op_stats.as_mut() {
+                        op_stats.untracked_cqes += 1;
+                    }
+                }
+            }
+        }
+        self.stats.cqes_reaped += 1;
+        if let Some(recorder) = self.recorder.as_mut() {
+            let (user_data, res, flags) = (cqe.user_data, cqe.res, cqe.flags);
+            recorder.record(Record::Cqe(RecordedCqe { user_data, res, flags }));
+        }
+        #[cfg(feature = "tracing")]
+        tracing::trace!(user_data = cqe.user_data, res = cqe.res, flags = cqe.flags, "cqe");
+        #[cfg(feature = "usdt")]
+        crate::usdt::complete(self.fd, cqe.user_data, cqe.res, cqe.flags);
+    }
+
+    /// An iterator over the cqes that are available, which releases their slots to the kernel
+    /// when it is dropped
+    ///
+    /// Unlike [`Self::cq_iter`], the yielded cqes are consumed, as with [`Self::pop_cqe`], but
+    /// the CQ head is only updated once, which makes draining the CQ ring cheaper. cqes posted
+    /// after the iterator was created are not yielded.
+    ///
+    /// ```no_run
+    /// # let mut ring = iouring::io_uring::IoUring::init(32).unwrap();
+    /// ring.submit_and_wait(1).unwrap();
+    /// for cqe in ring.completions() {
+    ///     println!("{}: {}", cqe.user_data(), cqe.res());
+    /// }
+    /// ```
+    pub fn completions(&mut self) -> Completions<'_> {
+        let head = unsafe { *self.cq.khead };
+        let tail = unsafe { load_acquire(self.cq.ktail) };
+        let mask = unsafe { *self.cq.kring_mask };
+        Completions { ring: self, head, tail, mask }
+    }
+
+    /// Like [`Self::pop_cqe`], but also return the second half of 32-byte cqes (see
+    /// [`SetupFlags::CQE32`])
+    ///
+    /// On rings with 16-byte cqes, the second half is all zeros.
+    #[cfg(feature = "linux-5_19")]
+    pub fn pop_cqe32(&mut self) -> Option<Cqe32> {
+        let mask = unsafe { *self.cq.kring_mask };
+        let mut big_cqe = [0; 2];
+        let cqe = self.pop_cqe_big(mask, Some(&mut big_cqe))?;
+        Some(Cqe32 { cqe, big_cqe })
+    }
+
+    /// The next cqe, if one is available, without releasing its slot to the kernel
+    ///
+    /// The slot is released when the cqe is marked as seen (see [`CqeRef::seen`]). Until then,
+    /// peeking returns the same cqe.
+    pub fn peek_cqe(&mut self) -> Option<CqeRef<'_>> {
+        loop {
+            let cq = &self.cq;
+            let head = unsafe { *cq.khead };
+            let tail = unsafe { load_acquire(cq.ktail) };
+            if head == tail {
+                return None;
+            }
+            let mask = unsafe { *cq.kring_mask };
+            let cqe = unsafe { *cq.cqes.add(((head & mask) << cq.cqe_shift) as usize) };
+            if self.wait_timeouts == 0 || cqe.user_data != WAIT_TIMEOUT_UDATA {
+                return Some(CqeRef { ring: self, cqe });
+            }
+            // consume the timeout cqe
+            unsafe { store_release(cq.khead, head.wrapping_add(1)) };
+            self.reaped(&cqe);
+            self.is_wait_timeout(&cqe);
+        }
+    }
+
+    /// Wait for the next cqe, without submitting, and return it without releasing its slot (see
+    /// [`Self::peek_cqe`])
+    pub fn wait_cqe(&mut self) -> io::Result<CqeRef<'_>> {
+        while self.peek_cqe().is_none() {
+            self.get_events(1)?;
+        }
+        Ok(self.peek_cqe().unwrap())
+    }
+
+    /// Wait until at least nr cqes are available, without submitting, and return an iterator that
+    /// consumes the available cqes (see [`Self::completions`])
+    ///
+    /// This enters the kernel at most once (unless interrupted by a signal), so a batch of nr
+    /// requests can be waited for with a single wakeup. nr is capped to the size of the CQ ring.
+    pub fn wait_cqe_nr(&mut self, nr: u32) -> io::Result<Completions<'_>> {
+        let nr = std::cmp::min(nr, unsafe { *self.cq.kring_entries });
+        if self.cq_ready() < nr {
+            self.get_events(nr)?;
+        }
+        Ok(self.completions())
+    }
+
+    /// Wait for up to timeout for the next cqe, and return it without releasing its slot (see
+    /// [`Self::peek_cqe`]). Returns None if the timeout expired.
+    ///
+    /// Unlike [`Self::wait_cqe`], this submits the queued sqes. On kernels that support it (Linux
+    /// 5.11), the timeout is passed to io_uring_enter() (IORING_ENTER_EXT_ARG). Otherwise, it is
+    /// a timeout request, which takes an sqe (submitting the queued sqes if there is none), and
+    /// whose cqe is consumed internally.
+    pub fn wait_cqe_timeout(&mut self, timeout: std::time::Duration)
+    -> io::Result<Option<CqeRef<'_>>> {
+        if self.peek_cqe().is_none() {
+            let ts = KernelTimespec::from(timeout);
+            if self.features.contains(Features::EXT_ARG) {
+                match self.do_submit_and_wait(1, Some(WaitArg::Timeout(&ts))) {
+                    Err(e) if e.raw_os_error() == Some(libc::ETIME) => (),
+                    x => {
+                        x?;
+                    }
+                }
+            } else {
+                // NB: the kernel reads the timespec when it consumes the sqe, which may be after
+                // we return (with SQPOLL, or if the submit is partial), so it lives in the ring
+                // until then
+                let khead = unsafe { load_acquire(self.sq.khead) };
+                while let Some((seq, _)) = self.wait_ts.front() {
+                    if (khead.wrapping_sub(*seq) as i32) <= 0 {
+                        break;
+                    }
+                    self.wait_ts.pop_front();
+                }
+                let ts = Box::new(ts);
+                loop {
+                    if let Some(mut sqe) = self.get_sqe() {
+                        sqe.prep_timeout(&*ts, 1, TimeoutFlags::empty());
+                        sqe.set_data(WAIT_TIMEOUT_UDATA);
+                        break;
+                    }
+                    self.submit()?;
+                }
+                self.wait_ts.push_back((self.sq.sqe_tail.0.wrapping_sub(1), ts));
+                self.wait_timeouts += 1;
+                self.submit_and_wait(1)?;
+            }
+        }
+        Ok(self.peek_cqe())
+    }
+
+    /// Number of cqes available to pop
+    pub fn cq_ready(&self) -> u32 {
+        let head = unsafe { *self.cq.khead };
+        let tail = unsafe { load_acquire(self.cq.ktail) };
+        tail.wrapping_sub(head)
+    }
+
+    /// Enter the kernel to wait for at least min_complete cqes, without submitting, and return
+    /// the number of cqes available
+    ///
+    /// Unlike [`Self::submit_and_wait`], this does not touch the SQ ring, so it suits threads that
+    /// only consume completions. With a min_complete of 0, it only flushes completions to the CQ
+    /// ring, e.g., from the CQ overflow list, or from task work that has not run yet.
+    pub fn get_events(&mut self, min_complete: u32) -> io::Result<u32> {
+        self.enter_getevents(min_complete)?;
+        self.check_cq_overflow();
+        Ok(self.cq_ready())
+    }
+
+    /// Submit pending sqes, and poll for completions until at least min_complete cqes are
+    /// available, without ever sleeping in the kernel. Returns the number of available cqes.
+    ///
+    /// This is meant for IOPOLL rings (see [`SetupFlags::IOPOLL`]), where entering the kernel
+    /// with GETEVENTS polls the device for completions. Between polls, it spins, yields, or
+    /// sleeps in userspace according to policy.
+    pub fn poll_completions(&mut self, min_complete: u32, policy: &SpinPolicy) -> io::Result<u32> {
+        self.submit()?;
+        let mut polls = 0u32;
+        let mut sleep = std::time::Duration::from_micros(1);
+        loop {
+            let ready = self.cq_ready();
+            if ready >= min_complete {
+                return Ok(ready);
+            }
+            self.enter_getevents(0)?;
+            polls = polls.saturating_add(1);
+            if polls <= policy.spins {
+                std::hint::spin_loop();
+            } else if polls - policy.spins <= policy.yields || policy.max_sleep.is_none() {
+                std::thread::yield_now();
+            } else if let Some(max) = policy.max_sleep {
+                std::thread::sleep(sleep);
+                sleep = std::cmp::min(sleep * 2, max);
+            }
+        }
+    }
+
+    /// Counters of ring activity since the ring was created
+    pub fn stats(&self) -> Stats {
+        // NB: the ring counters are read now, so that they are current even if the ring was not
+        // entered since they changed
+        let cq_overflows = self.cq_overflow().wrapping_sub(self.cq_overflow_seen) as u64;
+        Stats {
+            sq_dropped: self.sq_dropped() as u64,
+            cq_overflows: self.stats.cq_overflows + cq_overflows,
+            ..self.stats
+        }
+    }
+
+    /// The current counters, timestamped, for computing rates (see [`StatsSnapshot::delta`])
+    pub fn stats_snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot { stats: self.stats(), taken: std::time::Instant::now() }
+    }
+
+    /// Start (or stop) tracking submitted requests until their last cqe is popped (see
+    /// [`Self::pending_ops`])
+    ///
+    /// This costs a hash table update per sqe and cqe, so it is off by default. Only requests
+    /// submitted while tracking is on are tracked, and stopping it forgets the tracked requests.
+    /// The table has room for as many requests as the CQ ring has entries (see
+    /// [`Self::track_pending_with_capacity`]).
+    pub fn track_pending(&mut self, enable: bool) {
+        if !enable {
+            self.pending = None;
+        } else if self.pending.is_none() {
+            let cq_entries = unsafe { *self.cq.kring_entries };
+            self.track_pending_with_capacity(cq_entries as usize);
+        }
+    }
+
+    /// Start tracking submitted requests, in a table with room for capacity requests (with
+    /// distinct user data)
+    ///
+    /// The table is allocated here, so tracking does not allocate unless there are more requests
+    /// in flight than capacity.
+    pub fn track_pending_with_capacity(&mut self, capacity: usize) {
+        self.pending = Some(HashMap::with_capacity(capacity));
+    }
+
+    /// Start (or stop) recording the latency of tracked requests, per opcode (see
+    /// [`Self::latency`])
+    ///
+    /// Starting enables request tracking (see [`Self::track_pending`]), which timestamps requests
+    /// when they are submitted. The latency of a request is recorded when its last cqe is popped,
+    /// which costs a clock read per cqe, and allocates the histogram of an opcode the first time
+    /// one of its requests completes. Stopping forgets the recorded latencies.
+    pub fn track_latency(&mut self, enable: bool) {
+        if !enable {
+            self.latency = None;
+            return;
+        }
+        self.track_pending(true);
+        if self.latency.is_none() {
+            self.latency = Some(Latencies::new());
+        }
+    }
+
+    /// The latency histograms of requests, if they are recorded (see [`Self::track_latency`])
+    ///
+    /// ```no_run
+    /// # use iouring::io_uring::{IoUring, OpCode};
+    /// let mut ring = IoUring::init(32).unwrap();
+    /// ring.track_latency(true);
+    /// // ... submit and reap requests ...
+    /// if let Some(h) = ring.latency().and_then(|l| l.get(OpCode::Read)) {
+    ///     println!("read p99: {:?}", h.percentile(99.0));
+    /// }
+    /// // e.g., "op=READ n=100 min=2.1µs mean=... p99=... max=..." for each opcode
+    /// eprint!("{}", ring.latency().unwrap());
+    /// ```
+    pub fn latency(&self) -> Option<&Latencies> {
+        self.latency.as_ref()
+    }
+
+    /// Start (or stop) keeping counters of requests per opcode (see [`Self::op_stats`])
+    ///
+    /// Starting enables request tracking (see [`Self::track_pending`]), which maps cqes to the
+    /// opcode of their request. Stopping forgets the counters.
+    pub fn track_op_stats(&mut self, enable: bool) {
+        if !enable {
+            self.op_stats = None;
+            return;
+        }
+        self.track_pending(true);
+        if self.op_stats.is_none() {
+            self.op_stats = Some(OpcodeStats::default());
+        }
+    }
+
+    /// The counters of requests per opcode, if they are kept (see [`Self::track_op_stats`])
+    ///
+    /// ```no_run
+    /// # use iouring::io_uring::{IoUring, OpCode};
+    /// let mut ring = IoUring::init(32).unwrap();
+    /// ring.track_op_stats(true);
+    /// // ... submit and reap requests ...
+    /// let stats = ring.op_stats().unwrap();
+    /// let recv = stats.get(OpCode::Recv);
+    /// println!("recv: {} cqes, {:.1}% errors", recv.cqes, 100.0 * recv.error_rate());
+    /// // e.g., "op=RECV sqes=90 cqes=90 (90.0%) bytes=9000 errors=4 (4.4%) errno11=4"
+    /// eprint!("{}", stats);
+    /// ```
+    pub fn op_stats(&self) -> Option<&OpcodeStats> {
+        self.op_stats.as_ref()
+    }
+
+    /// Reset the counters of requests per opcode, e.g., to report them per interval
+    pub fn reset_op_stats(&mut self) {
+        if let Some(op_stats) = self.op_stats.as_mut() {
+            op_stats.reset();
+        }
+    }
+
+    /// Forget the recorded latencies, e.g., to report them per interval
+    pub fn reset_latency(&mut self) {
+        if let Some(latency) = self.latency.as_mut() {
+            latency.reset();
+        }
+    }
+
+    /// Start recording the submitted sqes, the submissions, and the reaped cqes to recorder (or
+    /// stop recording, with None), and return the previous recorder, if any
+    ///
+    /// Taking the recorder back, e.g., flushes its file, or gives access to its records after
+    /// recording is done. See [`crate::record::replay`] for re-issuing the recorded sqes.
+    pub fn set_recorder(&mut self, recorder: Option<Recorder>) -> Option<Recorder> {
+        std::mem::replace(&mut self.recorder, recorder)
+    }
+
+    /// The recorder of the ring, if any (see [`Self::set_recorder`])
+    pub fn recorder(&self) -> Option<&Recorder> {
+        self.recorder.as_ref()
+    }
+
+    /// The tracked requests that have not completed, oldest first (see [`Self::track_pending`])
+    pub fn pending_ops(&self) -> Vec<PendingOp> {
+        let mut ret: Vec<PendingOp> = match self.pending.as_ref() {
+            Some(pending) => pending.values().copied().collect(),
+            None => vec![],
+        };
+        ret.sort_by_key(|op| op.submitted);
+        ret
+    }
+
+    /// A listing of the tracked requests that have not completed, oldest first, one per line
+    ///
+    /// Useful for finding out why a ring hangs, e.g., from a watchdog, or when a shutdown takes
+    /// too long.
+    pub fn dump_pending(&self) -> String {
+        let mut ret = String::new();
+        for op in self.pending_ops() {
+            ret += &format!("{}\n", op);
+        }
+        ret
+    }
+
+    /// Number of invalid sqes that the kernel dropped without posting a cqe
+    ///
+    /// The kernel drops entries of the SQ array that are not valid sqe indices. A non-zero count
+    /// means that the submission queue was corrupted.
+    pub fn sq_dropped(&self) -> u32 {
+        unsafe { std::ptr::read_volatile(self.sq.kdropped) }
+    }
+
+    pub fn cq_iter(&self) -> CqIter<'_> {
+        let cq_head = unsafe { *self.cq.khead };
         CqIter {
             curr: std::num::Wrapping(cq_head),
             cq: &self.cq,
@@ -714,9 +4306,7 @@ impl<'a> Iterator for CqIter<'a> {
     type Item = io_uring_cqe;
 
     fn next(&mut self) -> Option<io_uring_cqe> {
-        let ktail_p = self.cq.ktail as *mut std::sync::atomic::AtomicU32;
-        let tail_ = unsafe { (&*ktail_p).load(std::sync::atomic::Ordering::Acquire) };
-        let tail = std::num::Wrapping(tail_);
+        let tail = std::num::Wrapping(unsafe { load_acquire(self.cq.ktail) });
         if self.curr == tail {
             return None
         }
@@ -724,7 +4314,7 @@ impl<'a> Iterator for CqIter<'a> {
         let mask = unsafe { *self.cq.kring_mask };
         let idx = self.curr.0 & mask;
         let cqe: io_uring_cqe = unsafe {
-            *self.cq.cqes.offset(idx as isize)
+            *self.cq.cqes.add((idx << self.cq.cqe_shift) as usize)
         };
         self.curr += std::num::Wrapping(1);
         Some(cqe)
@@ -732,6 +4322,185 @@ impl<'a> Iterator for CqIter<'a> {
     }
 }
 
+/// A ring whose size is a compile-time constant
+///
+/// The SQ ring has ENTRIES entries, and the CQ ring twice as many. get_sqe() and pop_cqe() use
+/// constant masks instead of loading them from the shared ring memory, and ENTRIES can size
+/// arrays for tracking requests in flight, e.g., `[Option<T>; FixedIoUring::<64>::ENTRIES]`.
+/// Everything else goes through the IoUring it dereferences to.
+///
+/// ENTRIES needs to be a power of two, up to 32768 (IORING_MAX_ENTRIES), which is checked at
+/// compile time.
+pub struct FixedIoUring<const ENTRIES: usize> {
+    ring: IoUring,
+}
+
+impl<const ENTRIES: usize> FixedIoUring<ENTRIES> {
+    /// Number of SQ ring entries
+    pub const ENTRIES: usize = ENTRIES;
+    /// Number of CQ ring entries
+    pub const CQ_ENTRIES: usize = 2 * ENTRIES;
+
+    const SQ_MASK: u32 = {
+        assert!(ENTRIES.is_power_of_two() && ENTRIES <= 32768, "invalid number of entries");
+        (ENTRIES - 1) as u32
+    };
+    const CQ_MASK: u32 = (2 * ENTRIES - 1) as u32;
+
+    pub fn init() -> io::Result<Self> {
+        Self::init_with_flags(SetupFlags::empty())
+    }
+
+    /// See [`IoUring::init_with_flags`]
+    pub fn init_with_flags(flags: SetupFlags) -> io::Result<Self> {
+        let ring = IoUring::init_with_flags(Self::SQ_MASK + 1, flags)?;
+        // NB: the kernel would only pick different sizes if it changed how it sizes rings
+        let (sq_entries, cq_entries) = unsafe { (*ring.sq.kring_entries, *ring.cq.kring_entries) };
+        if sq_entries != Self::SQ_MASK + 1 || cq_entries != Self::CQ_MASK + 1 {
+            let msg = format!("unexpected ring sizes: sq:{} cq:{}", sq_entries, cq_entries);
+            return Err(io::Error::other(msg));
+        }
+        Ok(FixedIoUring { ring })
+    }
+
+    /// See [`IoUring::get_sqe`]
+    #[inline]
+    pub fn get_sqe(&mut self) -> Option<SQEntry<'_>> {
+        self.ring.get_sqe_geometry(Self::SQ_MASK + 1, Self::SQ_MASK)
+    }
+
+    /// See [`IoUring::pop_cqe`]
+    #[inline]
+    pub fn pop_cqe(&mut self) -> Option<io_uring_cqe> {
+        self.ring.pop_cqe_mask(Self::CQ_MASK)
+    }
+
+    pub fn into_inner(self) -> IoUring {
+        self.ring
+    }
+}
+
+impl<const ENTRIES: usize> std::ops::Deref for FixedIoUring<ENTRIES> {
+    type Target = IoUring;
+
+    fn deref(&self) -> &IoUring {
+        &self.ring
+    }
+}
+
+impl<const ENTRIES: usize> std::ops::DerefMut for FixedIoUring<ENTRIES> {
+    fn deref_mut(&mut self) -> &mut IoUring {
+        &mut self.ring
+    }
+}
+
+// shutdown
+impl IoUring {
+
+    /// Set the policy used when the ring is dropped (default: [`ShutdownPolicy::Detach`])
+    pub fn set_drop_policy(&mut self, policy: ShutdownPolicy) {
+        self.drop_policy = policy;
+    }
+
+    /// Shut down the ring according to the given policy
+    ///
+    /// Entries acquired via get_sqe() but not yet submitted are discarded. For `CancelAndWait` and
+    /// `Wait`, this returns once every submitted operation has posted its terminal cqe, at which
+    /// point the kernel no longer accesses any memory they reference. The cqes reaped while
+    /// waiting are returned, so that the caller can release per-operation resources.
+    ///
+    /// NB: operations submitted with IOSQE_CQE_SKIP_SUCCESS are not supported: their successful
+    /// completion is not visible, so shutdown would wait for them forever.
+    pub fn shutdown(&mut self, policy: ShutdownPolicy) -> io::Result<Vec<io_uring_cqe>> {
+        // discard unsubmitted entries
+        self.sq.sqe_tail = self.sq.sqe_head;
+
+        let mut cqes = vec![];
+        match policy {
+            ShutdownPolicy::Detach => return Ok(cqes),
+            ShutdownPolicy::Wait => (),
+            ShutdownPolicy::CancelAndWait => {
+                if self.inflight > 0 {
+                    self.cancel_all(&mut cqes)?;
+                }
+            },
+        }
+
+        self.drain(&mut cqes)?;
+        Ok(cqes)
+    }
+
+    // Issue an ASYNC_CANCEL for all in-flight requests
+    fn cancel_all(&mut self, cqes: &mut Vec<io_uring_cqe>) -> io::Result<()> {
+        let mut sqe_cancel = loop {
+            if let Some(x) = self.get_sqe() {
+                break x;
+            }
+            // NB: we discarded all unsubmitted entries, so we need to wait for the kernel to
+            // consume the submitted ones.
+            self.enter_getevents(0)?;
+            self.reap_into(cqes);
+        };
+
+        sqe_cancel.prep_rw(OpCode::AsyncCancel, -1, std::ptr::null(), 0, 0);
+        sqe_cancel.0.args.cancel_flags = AsyncCancelFlags::ANY.bits();
+        sqe_cancel.set_data(SHUTDOWN_CANCEL_UDATA);
+        self.submit()?;
+        // NB: The cancel cqe is filtered out in drain(). If the kernel does not support
+        // ASYNC_CANCEL_ANY, the cancel fails with EINVAL and we end up just waiting.
+        Ok(())
+    }
+
+    // Wait until all inflight operations are done
+    fn drain(&mut self, cqes: &mut Vec<io_uring_cqe>) -> io::Result<()> {
+        loop {
+            self.reap_into(cqes);
+            if self.inflight == 0 {
+                break;
+            }
+            self.enter_getevents(1)?;
+        }
+        cqes.retain(|cqe| cqe.user_data != SHUTDOWN_CANCEL_UDATA);
+        Ok(())
+    }
+
+    // The fd to pass to io_uring_enter(), and the flag to pass along with it
+    fn enter_fd(&self) -> (libc::c_int, EnterFlags) {
+        match self.ring_fd_index {
+            Some(idx) => (idx as libc::c_int, EnterFlags::REGISTERED_RING),
+            None => (self.fd, EnterFlags::empty()),
+        }
+    }
+
+    // Enter the kernel to wait for min_complete cqes, retrying on EINTR
+    fn enter_getevents(&mut self, min_complete: u32) -> io::Result<()> {
+        let (fd, reg_flag) = self.enter_fd();
+        let flags = (EnterFlags::GETEVENTS | reg_flag).bits();
+        loop {
+            self.stats.enter_calls += 1;
+            let ret = unsafe {
+                io_uring_enter(fd, 0, min_complete, flags, std::ptr::null_mut())
+            };
+            #[cfg(feature = "tracing")]
+            tracing::trace!(to_submit = 0, min_complete, ret, "io_uring_enter (getevents)");
+            if ret >= 0 {
+                return Ok(());
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    // Pop all available cqes
+    fn reap_into(&mut self, cqes: &mut Vec<io_uring_cqe>) {
+        while let Some(cqe) = self.pop_cqe() {
+            cqes.push(cqe);
+        }
+    }
+}
+
 impl IoUring {
     // /// Fill the next SQEntry in the queue via the provided function.
     // ///
@@ -751,7 +4520,7 @@ impl IoUring {
     //     let mut sqe = {
     //         let mask = unsafe { *sq.kring_mask };
     //         let idx = sq.sqe_tail & mask;
-    //         let sqe_p = unsafe { sq.sqes.offset(idx as isize) };
+    //         let sqe_p = unsafe { sq.sqes.add(idx as usize) };
     //         SQEntry(sqe_p)
     //     };
     //     sqe.reset();
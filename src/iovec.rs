@@ -0,0 +1,176 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Owned buffers for vectored I/O
+//
+// readv/writev requests point to an array of iovecs, which point to the buffers. IoVecs owns both,
+// so that the array always matches the buffers, and they are kept alive (and dropped) together.
+// The array is rebuilt every time a request is prepared, which also allows continuing after a
+// partial transfer (see IoVecs::advance).
+//
+// Reference: readv(2), io_uring_prep_readv(3)
+
+#[cfg(feature = "bytes")]
+use std::io;
+use std::os::unix::io::RawFd;
+
+#[cfg(feature = "bytes")]
+use crate::io_uring::io_uring_cqe;
+use crate::io_uring::SQEntry;
+
+/// Buffers whose data stays at the same address when the buffer itself is moved
+///
+/// # Safety
+///
+/// as_ref() (and as_mut(), if implemented) need to return the same memory for as long as the
+/// buffer is alive and not mutated via other means, even if the buffer is moved. This holds for
+/// heap allocations, but not, e.g., for arrays.
+pub unsafe trait StableBuf: AsRef<[u8]> {}
+
+unsafe impl StableBuf for Vec<u8> {}
+unsafe impl StableBuf for Box<[u8]> {}
+unsafe impl StableBuf for &'static [u8] {}
+#[cfg(feature = "bytes")]
+unsafe impl StableBuf for bytes::Bytes {}
+#[cfg(feature = "bytes")]
+unsafe impl StableBuf for bytes::BytesMut {}
+
+/// A list of owned buffers, and the iovec array of readv/writev requests for them
+///
+/// NB: the IoVecs need to stay alive (they can be moved) until the request completes.
+pub struct IoVecs<B> {
+    bufs: Vec<B>,
+    iovecs: Vec<libc::iovec>,
+    // bytes consumed from the start of bufs (see advance())
+    skip: usize,
+}
+
+impl<B: StableBuf> IoVecs<B> {
+
+    /// IoVecs for the given buffers, in order
+    pub fn new(bufs: Vec<B>) -> IoVecs<B> {
+        IoVecs { bufs, iovecs: vec![], skip: 0 }
+    }
+
+    /// Remaining bytes (total, unless advanced)
+    pub fn len(&self) -> usize {
+        self.bufs.iter().map(|b| b.as_ref().len()).sum::<usize>() - self.skip
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Skip the first n of the remaining bytes in subsequent requests, e.g., after a short
+    /// transfer
+    pub fn advance(&mut self, n: usize) {
+        assert!(n <= self.len(), "advance past the end of the buffers");
+        self.skip += n;
+    }
+
+    pub fn bufs(&self) -> &[B] {
+        &self.bufs
+    }
+
+    /// Take the buffers back, e.g., once the request has completed
+    pub fn into_bufs(self) -> Vec<B> {
+        self.bufs
+    }
+
+    /// Prepare a writev request of the remaining bytes
+    pub fn prep_writev(&mut self, sqe: &mut SQEntry, fd: RawFd, off: u64) {
+        let bufs = self.bufs.iter().map(|b| (b.as_ref().as_ptr() as *mut u8, b.as_ref().len()));
+        let nr = build_iovecs(&mut self.iovecs, self.skip, bufs);
+        sqe.prep_writev(fd, self.iovecs.as_ptr(), nr, off)
+    }
+}
+
+impl<B: StableBuf + AsMut<[u8]>> IoVecs<B> {
+
+    /// Prepare a readv request into the remaining bytes
+    pub fn prep_readv(&mut self, sqe: &mut SQEntry, fd: RawFd, off: u64) {
+        let bufs = self.bufs.iter_mut().map(|b| b.as_mut()).map(|b| (b.as_mut_ptr(), b.len()));
+        let nr = build_iovecs(&mut self.iovecs, self.skip, bufs);
+        sqe.prep_readv(fd, self.iovecs.as_ptr(), nr, off)
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl IoVecs<bytes::BytesMut> {
+
+    /// Make sure that each buffer has at least additional bytes of spare capacity, for
+    /// [`Self::prep_readv_spare`]
+    ///
+    /// This may reallocate the buffers, so it cannot be called while a request is in flight.
+    pub fn reserve(&mut self, additional: usize) {
+        for b in self.bufs.iter_mut() {
+            b.reserve(additional);
+        }
+    }
+
+    /// Prepare a readv request into the spare capacity of the buffers (see [`Self::reserve`], and
+    /// [`Self::complete_readv_spare`])
+    ///
+    /// This does not allocate: buffers without spare capacity are not read into. This ignores
+    /// [`Self::advance`]: reads always go after the initialized bytes.
+    pub fn prep_readv_spare(&mut self, sqe: &mut SQEntry, fd: RawFd, off: u64) {
+        // NB: unlike BufMut::chunk_mut(), spare_capacity_mut() never reallocates, so the iovecs
+        // stay valid until the buffers are extended or reserved into
+        let bufs = self.bufs.iter_mut().map(|b| {
+            let spare = b.spare_capacity_mut();
+            (spare.as_mut_ptr() as *mut u8, spare.len())
+        });
+        let nr = build_iovecs(&mut self.iovecs, 0, bufs);
+        sqe.prep_readv(fd, self.iovecs.as_ptr(), nr, off)
+    }
+
+    /// Extend the buffers by the bytes that a request prepared by [`Self::prep_readv_spare`] read,
+    /// given its cqe, and return their number (or the error of the request)
+    ///
+    /// # Safety
+    ///
+    /// cqe needs to be the cqe of the request.
+    pub unsafe fn complete_readv_spare(&mut self, cqe: &io_uring_cqe) -> io::Result<usize> {
+        let n = cqe.result()? as usize;
+        self.set_filled(n);
+        Ok(n)
+    }
+
+    /// Extend the buffers by the n bytes that a request prepared by [`Self::prep_readv_spare`]
+    /// read (i.e., the result of its cqe)
+    ///
+    /// # Safety
+    ///
+    /// The request needs to have completed, and to have read at least n bytes.
+    pub unsafe fn set_filled(&mut self, mut n: usize) {
+        for b in self.bufs.iter_mut() {
+            let k = std::cmp::min(n, b.capacity() - b.len());
+            b.set_len(b.len() + k);
+            n -= k;
+        }
+        assert_eq!(n, 0, "filled past the capacity of the buffers");
+    }
+}
+
+// Build iovecs for the (address, length) buffers, skipping the first skip bytes
+fn build_iovecs<I>(iovecs: &mut Vec<libc::iovec>, mut skip: usize, bufs: I) -> u32
+where
+    I: Iterator<Item = (*mut u8, usize)>,
+{
+    iovecs.clear();
+    for (ptr, len) in bufs {
+        if skip >= len {
+            skip -= len;
+            continue;
+        }
+        let iov_base = unsafe { ptr.add(skip) } as *mut libc::c_void;
+        iovecs.push(libc::iovec { iov_base, iov_len: len - skip });
+        skip = 0;
+    }
+    // NB: the kernel limits the number of iovecs to UIO_MAXIOV (1024), and fails the request with
+    // EINVAL otherwise
+    iovecs.len() as u32
+}
@@ -0,0 +1,225 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Latency histograms, per opcode (see IoUring::track_latency())
+//
+// The histograms are log-linear, as in HdrHistogram: values are in nanoseconds, and each power of
+// two range is split into 2^SUB_BITS buckets, so a bucket is at most 1/2^SUB_BITS (6.25%) wider
+// than its lower bound. Values below 2^(SUB_BITS + 1) have a bucket each. This covers the full
+// u64 range in under a thousand buckets, with a bounded relative error, and recording a value is
+// a few arithmetic operations.
+//
+// Reference: http://hdrhistogram.org/
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use crate::io_uring::OpCode;
+
+const SUB_BITS: u32 = 4;
+const SUB_COUNT: usize = 1 << SUB_BITS;
+const NBUCKETS: usize = (65 - SUB_BITS as usize) * SUB_COUNT;
+
+fn bucket(nanos: u64) -> usize {
+    if nanos < (2 * SUB_COUNT) as u64 {
+        return nanos as usize;
+    }
+    let shift = 63 - nanos.leading_zeros() - SUB_BITS;
+    let sub = (nanos >> shift) as usize - SUB_COUNT;
+    (shift as usize + 1) * SUB_COUNT + sub
+}
+
+// The largest value of bucket idx
+fn bucket_high(idx: usize) -> u64 {
+    if idx < 2 * SUB_COUNT {
+        return idx as u64;
+    }
+    let shift = (idx / SUB_COUNT - 1) as u32;
+    let m = (SUB_COUNT + idx % SUB_COUNT) as u64;
+    // NB: the last bucket ends at u64::MAX, and (m + 1) << shift overflows to 0
+    ((m + 1) << shift).wrapping_sub(1)
+}
+
+/// A histogram of latencies, with a relative error of at most 6.25%
+#[derive(Clone)]
+pub struct Histogram {
+    counts: Box<[u64; NBUCKETS]>,
+    count: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+impl Histogram {
+
+    pub fn new() -> Histogram {
+        Histogram {
+            counts: Box::new([0; NBUCKETS]),
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    /// Record a latency
+    pub fn record(&mut self, d: Duration) {
+        let nanos = u64::try_from(d.as_nanos()).unwrap_or(u64::MAX);
+        self.counts[bucket(nanos)] += 1;
+        self.count += 1;
+        self.sum += nanos as u128;
+        self.min = self.min.min(nanos);
+        self.max = self.max.max(nanos);
+    }
+
+    /// Number of recorded latencies
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Smallest recorded latency (0 if there are none)
+    pub fn min(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos(self.min)
+    }
+
+    /// Largest recorded latency
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max)
+    }
+
+    /// Mean of the recorded latencies (0 if there are none)
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos((self.sum / self.count as u128) as u64)
+    }
+
+    /// The latency that p percent (0 to 100) of the recorded latencies do not exceed
+    ///
+    /// This is the upper bound of the bucket of the latency, capped to [`Self::max`], so it is at
+    /// most 6.25% more than the actual value. Returns 0 if there are no recorded latencies.
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        let p = p.clamp(0.0, 100.0);
+        let rank = ((p / 100.0 * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (idx, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Duration::from_nanos(bucket_high(idx).min(self.max));
+            }
+        }
+        self.max()
+    }
+
+    /// Add the latencies recorded in other
+    pub fn merge(&mut self, other: &Histogram) {
+        for (n, m) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *n += m;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Forget the recorded latencies
+    pub fn reset(&mut self) {
+        *self = Histogram::new();
+    }
+}
+
+impl std::fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Histogram {{ {} }}", self)
+    }
+}
+
+impl std::fmt::Display for Histogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "n={} min={:?} mean={:?}", self.count, self.min(), self.mean())?;
+        for (name, p) in [("p50", 50.0), ("p90", 90.0), ("p99", 99.0), ("p99.9", 99.9)].iter() {
+            write!(f, " {}={:?}", name, self.percentile(*p))?;
+        }
+        write!(f, " max={:?}", self.max())
+    }
+}
+
+/// Latency histograms of the requests of a ring, per opcode (see
+/// [`crate::io_uring::IoUring::track_latency`])
+///
+/// The latency of a request is the time from its submission to the reaping of its (last) cqe, so
+/// it includes the time the cqe waited in the CQ ring.
+#[derive(Debug, Clone, Default)]
+pub struct Latencies {
+    // by opcode, allocated when the first request with the opcode completes
+    hists: Vec<Option<Histogram>>,
+}
+
+impl Latencies {
+
+    pub fn new() -> Latencies {
+        Latencies::default()
+    }
+
+    /// Record the latency of a request with the given (IORING_OP_*) opcode
+    pub fn record(&mut self, opcode: u8, d: Duration) {
+        let idx = opcode as usize;
+        if self.hists.len() <= idx {
+            self.hists.resize(idx + 1, None);
+        }
+        self.hists[idx].get_or_insert_with(Histogram::new).record(d);
+    }
+
+    /// The histogram of op, if any request with op has completed
+    pub fn get(&self, op: OpCode) -> Option<&Histogram> {
+        self.hists.get(op.as_u8() as usize)?.as_ref()
+    }
+
+    /// The histograms, with their (IORING_OP_*) opcode, in opcode order
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &Histogram)> {
+        self.hists.iter().enumerate().filter_map(|(i, h)| Some((i as u8, h.as_ref()?)))
+    }
+
+    /// All latencies, regardless of opcode
+    pub fn total(&self) -> Histogram {
+        let mut ret = Histogram::new();
+        for (_, h) in self.iter() {
+            ret.merge(h);
+        }
+        ret
+    }
+
+    /// Forget the recorded latencies
+    pub fn reset(&mut self) {
+        self.hists.clear();
+    }
+}
+
+impl std::fmt::Display for Latencies {
+    /// One line per opcode, e.g., "op=READV n=100 min=2.1µs ... max=80µs"
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (opcode, h) in self.iter() {
+            match OpCode::from_u8(opcode) {
+                Some(op) => writeln!(f, "op={} {}", op, h)?,
+                None => writeln!(f, "op={} {}", opcode, h)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,9 +1,54 @@
 #![allow(dead_code)]
 
 pub mod io_uring;
+pub mod batch;
+#[cfg(feature = "linux-5_19")]
+pub mod buf_ring;
+pub mod compat;
+pub mod deadline;
+pub mod fallback;
+pub mod fdinfo;
+pub mod file_slots;
+#[cfg(feature = "linux-5_19")]
+pub mod forward;
+pub mod group;
+pub mod hugebuf;
+pub mod iovec;
+pub mod latency;
+pub mod notifier;
+#[cfg(feature = "linux-5_15")]
+pub mod pipe;
+pub mod pool;
+#[cfg(feature = "linux-6_7")]
+pub mod process;
+pub mod ratelimit;
+pub mod record;
+#[cfg(feature = "linux-5_15")]
+pub mod sandbox;
+pub mod scope;
+pub mod share;
+#[cfg(feature = "linux-5_15")]
+pub mod signals;
+pub mod timers;
+pub mod transfer;
+pub mod util;
+#[cfg(feature = "usdt")]
+mod usdt;
+
+pub use crate::io_uring::is_supported;
 
 #[cfg(test)]
 mod tests {
+    // Whether to skip a test because io_uring is not available. Other setup errors are bugs, so
+    // tests unwrap() them.
+    fn skip() -> bool {
+        if crate::is_supported() {
+            return false;
+        }
+        eprintln!("io_uring is not available, skipping");
+        true
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
@@ -11,8 +56,3122 @@ mod tests {
 
     #[test]
     fn hello() {
+        let _res = crate::io_uring::IoUring::init(4);
+    }
+
+    #[test]
+    fn supported() {
         let res = crate::io_uring::IoUring::init(4);
+        match res {
+            Ok(_) => assert!(crate::is_supported()),
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::Unsupported {
+                    assert!(!crate::is_supported());
+                    let inner = e.get_ref().unwrap();
+                    assert!(inner.downcast_ref::<crate::io_uring::Unsupported>().is_some());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn shutdown_cancel() {
+        use crate::io_uring::{IoUring, ShutdownPolicy};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+
+        // a read from an empty pipe that nobody writes to never completes
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut buf = [0u8; 16];
+        let bufs = [std::io::IoSliceMut::new(&mut buf)];
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_read_slice(fds[0], &bufs, 0).unwrap();
+            sqe.set_data(42);
+        }
+        assert_eq!(ring.submit().unwrap(), 1);
+
+        let cqes = ring.shutdown(ShutdownPolicy::CancelAndWait).unwrap();
+        assert_eq!(cqes.len(), 1);
+        assert_eq!(cqes[0].user_data(), 42);
+        assert_eq!(cqes[0].res(), -libc::ECANCELED);
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[cfg(feature = "linux-5_15")]
+    #[test]
+    fn splice_fixed_files() {
+        use crate::io_uring::{IoUring, SqeFlags, SPLICE_F_FD_IN_FIXED};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+
+        // splice from one pipe to another, referring to both via the registered file table
+        let mut p1 = [0 as libc::c_int; 2];
+        let mut p2 = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(p1.as_mut_ptr()) }, 0);
+        assert_eq!(unsafe { libc::pipe(p2.as_mut_ptr()) }, 0);
+        let msg = b"hello";
+        let n = unsafe { libc::write(p1[1], msg.as_ptr() as *const libc::c_void, msg.len()) };
+        assert_eq!(n, msg.len() as isize);
+
+        ring.register_files(&[p1[0], p2[1]]).unwrap();
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_splice(0, -1, 1, -1, msg.len() as u32, SPLICE_F_FD_IN_FIXED);
+            sqe.set_flags(SqeFlags::FIXED_FILE);
+            sqe.set_data(7);
+        }
+        ring.submit_and_wait(1).unwrap();
+        let cqe = ring.pop_cqe().unwrap();
+        assert_eq!(cqe.user_data(), 7);
+        assert_eq!(cqe.res(), msg.len() as i32);
+        ring.unregister_files().unwrap();
+
+        let mut buf = [0u8; 16];
+        let n = unsafe { libc::read(p2[0], buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        assert_eq!(&buf[..n as usize], msg);
+
+        for fd in p1.iter().chain(p2.iter()) {
+            unsafe { libc::close(*fd) };
+        }
+    }
+
+    #[test]
+    fn update_registered_files() {
+        use crate::io_uring::{IoUring, SqeFlags};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut p = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(p.as_mut_ptr()) }, 0);
+        let n = unsafe { libc::write(p[1], b"hi".as_ptr() as *const libc::c_void, 2) };
+        assert_eq!(n, 2);
+
+        // reserve a slot, fill it, and read via it
+        ring.register_files(&[-1, -1]).unwrap();
+        assert_eq!(ring.update_registered_files(1, &[p[0]]).unwrap(), 1);
+        let mut buf = [0u8; 8];
+        let iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: 8 };
+        let read_slot1 = |ring: &mut IoUring| {
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                sqe.prep_readv(1, &iov, 1, 0);
+                sqe.set_flags(SqeFlags::FIXED_FILE);
+            }
+            ring.submit_and_wait(1).unwrap();
+            ring.pop_cqe().unwrap().res()
+        };
+        assert_eq!(read_slot1(&mut ring), 2);
+        assert_eq!(&buf[..2], b"hi");
+
+        // empty the slot
+        assert_eq!(ring.update_registered_files(1, &[-1]).unwrap(), 1);
+        assert_eq!(read_slot1(&mut ring), -libc::EBADF);
+        assert!(ring.update_registered_files(2, &[p[0]]).is_err());
+
+        ring.unregister_files().unwrap();
+        unsafe {
+            libc::close(p[0]);
+            libc::close(p[1]);
+        }
+    }
+
+    #[cfg(feature = "linux-6_0")]
+    #[test]
+    fn file_slots() {
+        use crate::file_slots::FileSlots;
+        use crate::io_uring::{IoUring, IORING_FILE_INDEX_ALLOC};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut slots = FileSlots::register(&mut ring, 4, 2).unwrap();
+        assert_eq!((slots.alloc(), slots.alloc(), slots.alloc()), (Some(0), Some(1), None));
+        slots.free(1).unwrap();
+        assert!(slots.free(2).is_err());
+        assert_eq!(slots.available(), 1);
+        assert_eq!(slots.alloc(), Some(1));
+
+        // the kernel picks slots outside of the application range
+        let path = std::ffi::CString::new("/dev/null").unwrap();
+        let mut kslots = vec![];
+        for _ in 0..3 {
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                let slot = IORING_FILE_INDEX_ALLOC;
+                sqe.prep_openat_direct(libc::AT_FDCWD, path.as_ptr(), libc::O_RDONLY, 0, slot);
+            }
+            ring.submit_and_wait(1).unwrap();
+            kslots.push(ring.pop_cqe().unwrap().res());
+        }
+        assert_eq!(kslots, vec![2, 3, -libc::ENFILE]);
+
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_close_fixed(2);
+        }
+        ring.submit_and_wait(1).unwrap();
+        assert_eq!(ring.pop_cqe().unwrap().res(), 0);
+        ring.unregister_files().unwrap();
+    }
+
+    #[test]
+    fn personality() {
+        use crate::io_uring::IoUring;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let id = ring.register_personality().unwrap();
+        #[cfg(feature = "linux-5_15")]
+        {
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                sqe.prep_fsync(-1, 0);
+                sqe.set_personality(id);
+            }
+            ring.submit_and_wait(1).unwrap();
+            assert_eq!(ring.pop_cqe().unwrap().res(), -libc::EBADF);
+        }
+        ring.unregister_personality(id).unwrap();
+        let err = ring.unregister_personality(id).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    #[cfg(feature = "linux-5_19")]
+    fn register_ring_fd() {
+        use crate::io_uring::IoUring;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let idx = ring.register_ring_fd().unwrap();
+        assert_eq!(ring.ring_fd_index(), Some(idx));
+        assert_eq!(ring.register_ring_fd().unwrap(), idx);
+        for _ in 0..2 {
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                sqe.prep_fsync(-1, 0);
+            }
+            ring.submit_and_wait(1).unwrap();
+            assert_eq!(ring.pop_cqe().unwrap().res(), -libc::EBADF);
+            ring.unregister_ring_fd().unwrap();
+            assert_eq!(ring.ring_fd_index(), None);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "linux-5_19")]
+    fn register_ring_fd_drop() {
+        use crate::io_uring::IoUring;
+
+        // there are 16 registered ring slots per task, so this fails if drop leaks them
+        for _ in 0..40 {
+            if skip() {
+                return;
+            }
+            let mut ring = IoUring::init(4).unwrap();
+            ring.register_ring_fd().unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "linux-5_15")]
+    fn iowq_affinity() {
+        use crate::io_uring::{CpuSet, IoUring};
+
+        let cpus = CpuSet::from_cpus(vec![0, 3]).unwrap();
+        assert!(cpus.contains(0) && cpus.contains(3) && !cpus.contains(1));
+        assert_eq!(cpus.cpus().collect::<Vec<_>>(), vec![0, 3]);
+        assert!(CpuSet::new().add(CpuSet::capacity()).is_err());
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let cpus = CpuSet::from_cpus(vec![0]).unwrap();
+        ring.register_iowq_affinity(&cpus).unwrap();
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+        }
+        ring.submit_and_wait(1).unwrap();
+        assert_eq!(ring.pop_cqe().unwrap().res(), -libc::EBADF);
+        ring.unregister_iowq_affinity().unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "linux-5_15")]
+    fn iowq_max_workers() {
+        use crate::io_uring::IoUring;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let (bounded, unbounded) = ring.set_iowq_max_workers(0, 0).unwrap();
+        assert_eq!(ring.set_iowq_max_workers(2, 3).unwrap(), (bounded, unbounded));
+        assert_eq!(ring.set_iowq_max_workers(0, 0).unwrap(), (2, 3));
+        assert_eq!(ring.set_iowq_max_workers(0, 5).unwrap(), (2, 3));
+        assert_eq!(ring.set_iowq_max_workers(0, 0).unwrap(), (2, 5));
+    }
+
+    #[test]
+    fn builder() {
+        use crate::io_uring::{IoUring, IoUringBuilder, SetupFlags};
+
+        if skip() {
+            return;
+        }
+        let ring = IoUringBuilder::new(5).cq_entries(100).build().unwrap();
+        let params = ring.params();
+        assert_eq!((params.sq_entries, params.cq_entries), (8, 128));
+        assert!(params.flags.contains(SetupFlags::CQSIZE));
+        assert_eq!(ring.geometry().cq_entries, 128);
+
+        let err = IoUringBuilder::new(4).setup_flags(SetupFlags::CQSIZE).build().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let err = IoUringBuilder::new(4).sq_thread_idle(10).build().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let err = IoUring::init_with_flags(4, SetupFlags::SQ_AFF).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        let ring = IoUringBuilder::new(4)
+            .setup_flags(SetupFlags::SQPOLL)
+            .sq_thread_cpu(0)
+            .sq_thread_idle(50)
+            .build();
+        // NB: SQPOLL might need privileges
+        if let Ok(ring) = ring {
+            let params = ring.params();
+            assert!(params.flags.contains(SetupFlags::SQPOLL | SetupFlags::SQ_AFF));
+            assert_eq!((params.sq_thread_cpu, params.sq_thread_idle), (0, 50));
+        }
+    }
+
+    #[test]
+    fn attach_wq() {
+        use crate::io_uring::{IoUring, IoUringBuilder, SetupFlags};
+        use std::os::unix::io::AsRawFd;
+
+        if skip() {
+            return;
+        }
+        let ring0 = IoUring::init(4).unwrap();
+        let mut ring = IoUringBuilder::new(4).attach_wq(ring0.as_raw_fd()).build().unwrap();
+        assert!(ring.params().flags.contains(SetupFlags::ATTACH_WQ));
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+        }
+        ring.submit_and_wait(1).unwrap();
+        assert_eq!(ring.pop_cqe().unwrap().res(), -libc::EBADF);
+
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let err = IoUringBuilder::new(4).attach_wq(file.as_raw_fd()).build().err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        let err = IoUring::init_with_flags(4, SetupFlags::ATTACH_WQ).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn clamp() {
+        use crate::io_uring::{IoUringBuilder, SetupFlags, MAX_ENTRIES};
+
+        let err = IoUringBuilder::new(MAX_ENTRIES + 1).build().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        let ring = IoUringBuilder::new(MAX_ENTRIES + 1)
+            .setup_flags(SetupFlags::CLAMP)
+            .cq_entries(u32::MAX)
+            .build();
+        if skip() {
+            return;
+        }
+        let ring = ring.unwrap();
+        let params = ring.params();
+        assert_eq!((params.sq_entries, params.cq_entries), (MAX_ENTRIES, 2 * MAX_ENTRIES));
+        assert_eq!(ring.geometry().cq_entries, 2 * MAX_ENTRIES);
+    }
+
+    #[test]
+    fn read_fixed() {
+        use crate::io_uring::IoUring;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let msg = b"hello";
+        let n = unsafe { libc::write(fds[1], msg.as_ptr() as *const libc::c_void, msg.len()) };
+        assert_eq!(n, msg.len() as isize);
+
+        let mut buf = vec![0u8; 4096];
+        ring.register_buffers(&[std::io::IoSliceMut::new(&mut buf)]).unwrap();
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            // read into the middle of the registered buffer
+            let ptr = unsafe { buf.as_mut_ptr().add(100) };
+            sqe.prep_read_fixed(fds[0], ptr, 16, 0, 0);
+            sqe.set_data(3);
+        }
+        ring.submit_and_wait(1).unwrap();
+        let cqe = ring.pop_cqe().unwrap();
+        assert_eq!(cqe.user_data(), 3);
+        assert_eq!(cqe.res(), msg.len() as i32);
+        ring.unregister_buffers().unwrap();
+        assert_eq!(&buf[100..100 + msg.len()], msg);
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[cfg(feature = "linux-5_15")]
+    #[test]
+    fn poll_multishot() {
+        use crate::io_uring::IoUring;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_poll_multishot(fds[0], libc::POLLIN as u32);
+            sqe.set_data(5);
+        }
+        ring.submit().unwrap();
+
+        // every write to the pipe triggers the (still armed) poll request
+        for _ in 0..2 {
+            let n = unsafe { libc::write(fds[1], b"x".as_ptr() as *const libc::c_void, 1) };
+            assert_eq!(n, 1);
+            ring.submit_and_wait(1).unwrap();
+            let cqe = ring.pop_cqe().unwrap();
+            assert_eq!(cqe.user_data(), 5);
+            assert!(cqe.res() & libc::POLLIN as i32 != 0);
+            assert!(cqe.has_more());
+            let mut buf = [0u8; 1];
+            unsafe { libc::read(fds[0], buf.as_mut_ptr() as *mut libc::c_void, 1) };
+        }
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[cfg(feature = "linux-5_19")]
+    #[test]
+    fn big_entries() {
+        use crate::io_uring::{IoUring, SetupFlags};
+
+        let flags = SetupFlags::SQE128 | SetupFlags::CQE32;
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init_with_flags(4, flags).unwrap();
+
+        // go around the rings a few times, to check the entry stride
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        for i in 0..10 {
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                // NB: pipes do not support commands, so this fails
+                sqe.prep_uring_cmd(fds[0], 0, &[0xaa; 80]).unwrap();
+                sqe.set_data(100 + i);
+            }
+            ring.submit_and_wait(1).unwrap();
+            let cqe = ring.pop_cqe().unwrap();
+            assert_eq!(cqe.user_data(), 100 + i);
+            assert!(cqe.res() < 0);
+        }
+
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            assert!(sqe.prep_uring_cmd(fds[0], 0, &[0; 81]).is_err());
+
+            // the command area spans both halves of the entry
+            assert_eq!(sqe.cmd_len(), 80);
+            sqe.set_cmd(&[1u32, 2, 3, 4, 5]).unwrap();
+            assert_eq!(sqe.cmd::<[u32; 5]>().unwrap(), [1, 2, 3, 4, 5]);
+            sqe.set_cmd_bytes(78, &[7, 8]).unwrap();
+            assert_eq!(sqe.cmd_bytes().0[78..], [7, 8]);
+            assert!(sqe.set_cmd_bytes(79, &[0; 2]).is_err());
+            assert!(sqe.cmd::<[u8; 81]>().is_err());
+        }
+
+        // the second half of the cqes of NOPs with IORING_NOP_CQE32 (Linux 6.17) carries their off
+        // and addr
+        let nop = crate::record::RecordedSqe {
+            opcode: 0,
+            off: 0x1111,
+            addr: 0x2222,
+            op_flags: 1 << 5,
+            user_data: 42,
+            ..crate::record::RecordedSqe::from_bytes(&[0; 128])
+        };
+        let (bytes, len) = nop.to_bytes();
+        ring.get_sqe().unwrap().copy_from_bytes(&bytes[..len]).unwrap();
+        // NB: the failed prep_uring_cmd() above left its (stale) sqe queued
+        ring.submit_and_wait(2).unwrap();
+        let mut cqe = ring.pop_cqe32().unwrap();
+        if cqe.user_data() != 42 {
+            cqe = ring.pop_cqe32().unwrap();
+        }
+        assert_eq!(cqe.user_data(), 42);
+        // NB: older kernels reject the flag
+        if cqe.res() == 0 {
+            assert_eq!(cqe.big_cqe(), [0x1111, 0x2222]);
+            assert_eq!((cqe.extra1(), cqe.extra2()), (0x1111, 0x2222));
+            assert_eq!(&cqe.big_cqe_bytes()[..8], &0x1111u64.to_ne_bytes());
+        } else {
+            assert_eq!(cqe.res(), -libc::EINVAL);
+        }
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn stats() {
+        use crate::io_uring::IoUring;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        assert_eq!(ring.stats(), Default::default());
+
+        for i in 0..4 {
+            let mut sqe = ring.get_sqe().unwrap();
+            // NB: fails with EBADF
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(i);
+        }
+        assert!(ring.get_sqe().is_none());
+        ring.submit_and_wait(4).unwrap();
+        while ring.pop_cqe().is_some() {}
+
+        let stats = ring.stats();
+        assert_eq!(stats.sqes_submitted, 4);
+        assert_eq!(stats.cqes_reaped, 4);
+        assert_eq!(stats.enter_calls, 1);
+        assert_eq!(stats.sq_full, 1);
+        assert_eq!(stats.cq_overflows, 0);
+        assert_eq!(stats.sq_dropped, 0);
+    }
+
+    #[test]
+    fn stats_snapshot() {
+        use crate::io_uring::{IoUring, Stats, StatsSnapshot};
+        use std::time::Duration;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let first = ring.stats_snapshot();
+        for _ in 0..2 {
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                sqe.prep_fsync(-1, 0);
+            }
+            ring.submit_and_wait(1).unwrap();
+            ring.pop_cqe().unwrap();
+        }
+        let second = ring.stats_snapshot();
+
+        let delta = second.delta(&first);
+        assert_eq!(delta.stats.sqes_submitted, 2);
+        assert_eq!(delta.stats.cqes_reaped, 2);
+        assert_eq!(delta.stats, second.stats.delta(&first.stats));
+        assert_eq!(second.delta(&second).stats, Stats::default());
+
+        // fixed timestamps, for predictable rates
+        let prev = StatsSnapshot { stats: Stats::default(), taken: first.taken };
+        let next = StatsSnapshot {
+            stats: Stats { sqes_submitted: 30, cqes_reaped: 15, ..Default::default() },
+            taken: first.taken + Duration::from_secs(3),
+        };
+        let delta = next.delta(&prev);
+        assert_eq!(delta.per_sec(delta.stats.sqes_submitted), 10.0);
+        assert_eq!(
+            delta.to_string(),
+            "3.00s: sqes=30 (10.0/s) cqes=15 (5.0/s) enters=0 (0.0/s) sq_full=0 (0.0/s) \
+             cq_overflows=0 (0.0/s) sq_dropped=0 (0.0/s)"
+        );
+        assert_eq!(
+            next.to_string(),
+            "sqes=30 cqes=15 enters=0 sq_full=0 cq_overflows=0 sq_dropped=0"
+        );
+    }
+
+    #[test]
+    fn poll_completions() {
+        use crate::io_uring::{IoUring, SpinPolicy};
+        use std::time::Duration;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        assert_eq!(ring.cq_ready(), 0);
+        for i in 0..2 {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(i);
+        }
+        // spin briefly, then sleep between polls
+        let max_sleep = Some(Duration::from_millis(1));
+        let policy = SpinPolicy { spins: 10, yields: 10, max_sleep };
+        assert_eq!(ring.poll_completions(2, &policy).unwrap(), 2);
+        assert_eq!(ring.cq_ready(), 2);
+        while let Some(cqe) = ring.pop_cqe() {
+            assert_eq!(cqe.res(), -libc::EBADF);
+        }
+        assert_eq!(ring.cq_ready(), 0);
+    }
+
+    #[test]
+    fn peek_cqe() {
+        use crate::io_uring::IoUring;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        assert!(ring.peek_cqe().is_none());
+        for i in 0..2 {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(i);
+        }
+        ring.submit().unwrap();
+
+        // the cqe stays in the ring until it is seen
+        let ud = ring.wait_cqe().unwrap().user_data();
+        let cqe = ring.peek_cqe().unwrap();
+        assert_eq!((cqe.user_data(), cqe.res()), (ud, -libc::EBADF));
+        let cqe = cqe.seen();
+        assert_eq!(cqe.user_data(), ud);
+
+        let cqe = ring.wait_cqe().unwrap().seen();
+        assert_eq!(cqe.user_data(), 1 - ud);
+        assert!(ring.peek_cqe().is_none());
+        assert_eq!(ring.stats().cqes_reaped, 2);
+    }
+
+    #[test]
+    fn wait_cqe_timeout() {
+        use crate::io_uring::IoUring;
+        use std::time::{Duration, Instant};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let t = Instant::now();
+        assert!(ring.wait_cqe_timeout(Duration::from_millis(10)).unwrap().is_none());
+        assert!(t.elapsed() >= Duration::from_millis(10));
+
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(7);
+        }
+        let cqe = ring.wait_cqe_timeout(Duration::from_secs(10)).unwrap().unwrap().seen();
+        assert_eq!((cqe.user_data(), cqe.res()), (7, -libc::EBADF));
+        // NB: without EXT_ARG, the cqes of the internal timeouts are not returned
+        assert!(ring.wait_cqe_timeout(Duration::from_millis(1)).unwrap().is_none());
+        assert!(ring.pop_cqe().is_none());
+    }
+
+    #[test]
+    fn wait_with_sigmask() {
+        use crate::io_uring::IoUring;
+
+        extern "C" fn handler(_: libc::c_int) {}
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut set: libc::sigset_t = unsafe { std::mem::zeroed() };
+        let mut old_set: libc::sigset_t = unsafe { std::mem::zeroed() };
+        let mut old_act: libc::sigaction = unsafe { std::mem::zeroed() };
+        unsafe {
+            let mut act: libc::sigaction = std::mem::zeroed();
+            act.sa_sigaction = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            assert_eq!(libc::sigaction(libc::SIGUSR1, &act, &mut old_act), 0);
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, libc::SIGUSR1);
+            assert_eq!(libc::pthread_sigmask(libc::SIG_BLOCK, &set, &mut old_set), 0);
+            // the signal stays pending while it is blocked
+            assert_eq!(libc::pthread_kill(libc::pthread_self(), libc::SIGUSR1), 0);
+        }
+
+        // it interrupts a wait that unblocks it, but not one that does not
+        let mut wait_set = old_set;
+        unsafe { libc::sigdelset(&mut wait_set, libc::SIGUSR1) };
+        let err = ring.submit_and_wait_with_sigmask(1, &wait_set).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+        }
+        assert_eq!(ring.submit_and_wait_with_sigmask(1, &set).unwrap(), 1);
+        assert_eq!(ring.pop_cqe().unwrap().res(), -libc::EBADF);
+
+        unsafe {
+            libc::pthread_sigmask(libc::SIG_SETMASK, &old_set, std::ptr::null_mut());
+            libc::sigaction(libc::SIGUSR1, &old_act, std::ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn flush_overflow() {
+        use crate::io_uring::{IoUring, SQFlags};
+
+        // 1 sq entry, and 2 cq entries
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(1).unwrap();
+        for i in 0..4 {
+            // NB: removing a timeout that does not exist completes inline, with -ENOENT
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                sqe.prep_timeout_remove(1000);
+                sqe.set_data(i);
+            }
+            ring.submit().unwrap();
+        }
+        assert_eq!(ring.cq_ready(), 2);
+        assert!(ring.sq_flags().contains(SQFlags::CQ_OVERFLOW));
+
+        let mut uds = vec![];
+        while uds.len() < 4 {
+            while let Some(cqe) = ring.pop_cqe() {
+                uds.push(cqe.user_data());
+            }
+            ring.flush_overflow().unwrap();
+        }
+        assert_eq!(uds, vec![0, 1, 2, 3]);
+        assert!(!ring.sq_flags().contains(SQFlags::CQ_OVERFLOW));
+        assert_eq!(ring.cq_overflow(), 0);
+        assert_eq!(ring.stats().cq_overflows, 0);
+    }
+
+    #[test]
+    fn wait_cqe_nr() {
+        use crate::io_uring::IoUring;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(8).unwrap();
+        assert_eq!(ring.wait_cqe_nr(0).unwrap().count(), 0);
+        for i in 0..4 {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(i);
+        }
+        ring.submit().unwrap();
+        let mut uds: Vec<u64> = ring.wait_cqe_nr(4).unwrap().map(|cqe| cqe.user_data()).collect();
+        uds.sort();
+        assert_eq!(uds, vec![0, 1, 2, 3]);
+        assert_eq!(ring.cq_ready(), 0);
+    }
+
+    #[test]
+    fn completions() {
+        use crate::io_uring::IoUring;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        assert_eq!(ring.completions().count(), 0);
+        for i in 0..3 {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(i);
+        }
+        ring.submit_and_wait(3).unwrap();
+
+        // cqes that are not yielded stay in the ring
+        let mut cqes = ring.completions();
+        assert_eq!(cqes.size_hint(), (3, Some(3)));
+        let first = cqes.next().unwrap();
+        drop(cqes);
+        assert_eq!(ring.cq_ready(), 2);
+
+        let mut uds = vec![first.user_data()];
+        for cqe in ring.completions() {
+            assert_eq!(cqe.res(), -libc::EBADF);
+            uds.push(cqe.user_data());
+        }
+        uds.sort();
+        assert_eq!(uds, vec![0, 1, 2]);
+        assert_eq!(ring.cq_ready(), 0);
+        assert_eq!(ring.stats().cqes_reaped, 3);
+    }
+
+    #[test]
+    fn cqe_result() {
+        use crate::io_uring::{Cqe, IoUring};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut fds = [0 as libc::c_int; 2];
+        let err = unsafe {
+            libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr())
+        };
+        assert_eq!(err, 0);
+        let iov = libc::iovec { iov_base: b"abc".as_ptr() as *mut libc::c_void, iov_len: 3 };
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_writev(fds[0], &iov, 1, 0);
+            sqe.set_data(0);
+        }
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(1);
+        }
+        ring.submit_and_wait(2).unwrap();
+        let mut cqes: Vec<Cqe> = ring.cq_iter().collect();
+        while ring.pop_cqe().is_some() {}
+        cqes.sort_by_key(|cqe| cqe.user_data());
+        assert_eq!(cqes[0].result().unwrap(), 3);
+        assert_eq!(cqes[1].result().unwrap_err().raw_os_error(), Some(libc::EBADF));
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn sq_ring_state() {
+        use crate::io_uring::{IoUring, SQFlags};
+
+        // NB: the CQ ring has 2 entries
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(1).unwrap();
+        assert_eq!(ring.sq_dropped(), 0);
+        assert!(!ring.sq_flags().contains(SQFlags::CQ_OVERFLOW));
+
+        // writes to a pipe with room complete inline, so the third one overflows the CQ ring
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let bufs = [std::io::IoSlice::new(b"x")];
+        for i in 0..3 {
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                sqe.prep_write_slice(fds[1], &bufs, 0).unwrap();
+                sqe.set_data(i);
+            }
+            ring.submit().unwrap();
+        }
+        assert!(ring.sq_flags().contains(SQFlags::CQ_OVERFLOW));
+        assert_eq!(ring.sq_dropped(), 0);
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn check_kernel_features() {
+        use crate::io_uring::IoUring;
+
+        if skip() {
+            return;
+        }
+        let ring = IoUring::init(4).unwrap();
+        // NB: the result depends on the kernel, but it should be a supported error if anything
+        if let Err(e) = ring.check_kernel_features() {
+            assert_eq!(e.kind(), std::io::ErrorKind::Unsupported);
+        }
+    }
+
+    #[test]
+    fn opcodes() {
+        use crate::io_uring::{IoUring, OpCode};
+
+        assert_eq!(OpCode::ReadFixed.as_u8(), 4);
+        assert_eq!(OpCode::from_u8(4), Some(OpCode::ReadFixed));
+        assert_eq!(OpCode::from_u8(250), None);
+        assert_eq!(OpCode::UringCmd.to_string(), "URING_CMD");
+        for op in 0..=u8::MAX {
+            if let Some(x) = OpCode::from_u8(op) {
+                assert_eq!(x.as_u8(), op);
+            }
+        }
+
+        if skip() {
+            return;
+        }
+        let ring = IoUring::init(4).unwrap();
+        // NB: probing needs Linux 5.6
+        let ops = match ring.supported_ops() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        assert!(ops.contains(&OpCode::Nop));
+        assert!(ops.contains(&OpCode::Readv));
+        assert!(ring.supports(OpCode::Readv).unwrap());
+        for op in [OpCode::SendZc, OpCode::EpollWait] {
+            assert_eq!(ring.supports(op).unwrap(), ops.contains(&op));
+        }
+
+        let probe = ring.probe().unwrap();
+        assert_eq!(probe.ops().collect::<std::collections::HashSet<_>>(), ops);
+        assert!(probe.supports(OpCode::Readv));
+        assert!(probe.last_op() >= OpCode::Readv as u8);
+        assert!(!probe.supports_u8(u8::MAX));
+    }
+
+    #[test]
+    fn timeouts() {
+        use crate::io_uring::{IoUring, KernelTimespec, SqeFlags, TimeoutFlags};
+        use std::time::Duration;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let wait = |ring: &mut IoUring, n| {
+            ring.submit_and_wait(n).unwrap();
+            let mut ret = vec![];
+            while let Some(cqe) = ring.pop_cqe() {
+                ret.push((cqe.user_data(), cqe.res()));
+            }
+            ret.sort();
+            ret
+        };
+
+        let short = KernelTimespec::from(Duration::from_millis(1));
+        let clocks = [
+            TimeoutFlags::empty(),
+            #[cfg(feature = "linux-5_15")]
+            TimeoutFlags::BOOTTIME,
+            #[cfg(feature = "linux-5_15")]
+            TimeoutFlags::REALTIME,
+        ];
+        for flags in clocks {
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                sqe.prep_timeout(&short, 0, flags);
+                sqe.set_data(1);
+            }
+            assert_eq!(wait(&mut ring, 1), [(1, -libc::ETIME)]);
+        }
+
+        // an absolute deadline in the past expires immediately
+        let past = KernelTimespec { tv_sec: 1, tv_nsec: 0 };
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_timeout(&past, 0, TimeoutFlags::ABS);
+            sqe.set_data(2);
+        }
+        assert_eq!(wait(&mut ring, 1), [(2, -libc::ETIME)]);
+
+        let long = KernelTimespec::from(Duration::from_secs(60));
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_timeout(&long, 0, TimeoutFlags::empty());
+            sqe.set_data(3);
+        }
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_timeout_remove(3);
+            sqe.set_data(4);
+        }
+        assert_eq!(wait(&mut ring, 2), [(3, -libc::ECANCELED), (4, 0)]);
+
+        // a read from an empty pipe is cancelled by its linked timeout
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut buf = [0u8; 16];
+        let bufs = [std::io::IoSliceMut::new(&mut buf)];
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_read_slice(fds[0], &bufs, 0).unwrap();
+            sqe.set_flags(SqeFlags::IO_LINK);
+            sqe.set_data(5);
+        }
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_link_timeout(&short, TimeoutFlags::empty());
+            sqe.set_data(6);
+        }
+        assert_eq!(wait(&mut ring, 2), [(5, -libc::ECANCELED), (6, -libc::ETIME)]);
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[cfg(feature = "linux-6_4")]
+    #[test]
+    fn timeout_multishot() {
+        use crate::io_uring::{IoUring, KernelTimespec, TimeoutFlags};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let ts = KernelTimespec::from(std::time::Duration::from_millis(1));
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_timeout_multishot(&ts, 3, TimeoutFlags::empty());
+            sqe.set_data(1);
+        }
+        ring.submit().unwrap();
+
+        let mut more = vec![];
+        while more.len() < 3 {
+            ring.submit_and_wait(1).unwrap();
+            while let Some(cqe) = ring.pop_cqe() {
+                assert_eq!((cqe.user_data(), cqe.res()), (1, -libc::ETIME));
+                more.push(cqe.has_more());
+            }
+        }
+        assert_eq!(more, [true, true, false]);
+    }
+
+    #[cfg(feature = "linux-6_13")]
+    #[test]
+    fn wait_region() {
+        use crate::io_uring::{IoUring, SetupFlags};
+        use std::time::{Duration, Instant};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init_with_flags(4, SetupFlags::R_DISABLED).unwrap();
+        if ring.register_wait_region(2).is_err() {
+            // NB: needs Linux 6.13
+            return;
+        }
+        ring.enable().unwrap();
+        assert!(ring.wait_arg(2).is_none());
+        ring.wait_arg(1).unwrap().set_timeout(Some(Duration::from_millis(10)));
+
+        // nothing to wait for: times out
+        let t = Instant::now();
+        let err = ring.submit_and_wait_reg(1, 1).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ETIME));
+        assert!(t.elapsed() >= Duration::from_millis(10));
+
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(7);
+        }
+        // index 0 has no timeout
+        assert_eq!(ring.submit_and_wait_reg(1, 0).unwrap(), 1);
+        assert_eq!(ring.pop_cqe().unwrap().user_data(), 7);
+
+        let err = ring.submit_and_wait_reg(1, 2).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(feature = "linux-6_14")]
+    #[test]
+    fn attr_pi() {
+        use crate::io_uring::{AttrPi, IntegrityFlags, IoUring};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        if ring.check_kernel_features().is_err() {
+            // NB: needs Linux 6.14
+            return;
+        }
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        let mut meta = [0u8; 8];
+        let pi = AttrPi::new(meta.as_mut_ptr() as _, 8, IntegrityFlags::GUARD);
+        let data = [1u8; 16];
+        let iov = libc::iovec { iov_base: data.as_ptr() as *mut _, iov_len: data.len() };
+        for (i, with_pi) in [false, true].iter().enumerate() {
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                sqe.prep_writev(fds[1], &iov, 1, 0);
+                if *with_pi {
+                    sqe.set_attr_pi(&pi);
+                }
+                sqe.set_data(i as u64);
+            }
+            ring.submit_and_wait(1).unwrap();
+            let cqe = ring.pop_cqe().unwrap();
+            assert_eq!(cqe.user_data(), i as u64);
+            if *with_pi {
+                // NB: a pipe has no integrity metadata
+                assert!(cqe.res() < 0);
+            } else {
+                assert_eq!(cqe.res(), 16);
+            }
+        }
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn compat() {
+        use crate::compat::*;
+        use crate::io_uring::SetupFlags;
+
+        if skip() {
+            return;
+        }
+        let mut ring = io_uring_queue_init(4, SetupFlags::empty()).unwrap();
+
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let msg = b"hello";
+        let iov = libc::iovec { iov_base: msg.as_ptr() as *mut libc::c_void, iov_len: msg.len() };
+        {
+            let mut sqe = io_uring_get_sqe(&mut ring).unwrap();
+            io_uring_prep_writev(&mut sqe, fds[1], &iov, 1, 0);
+            io_uring_sqe_set_data(&mut sqe, 7);
+        }
+        assert_eq!(io_uring_submit(&mut ring).unwrap(), 1);
+        let cqe = io_uring_wait_cqe(&mut ring).unwrap();
+        assert_eq!(io_uring_cqe_get_data(&cqe), 7);
+        assert_eq!(cqe.res(), msg.len() as i32);
+        io_uring_cqe_seen(&mut ring, &cqe);
+        assert!(io_uring_peek_cqe(&mut ring).is_none());
+
+        let mut buf = [0u8; 16];
+        let iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: 16 };
+        {
+            let mut sqe = io_uring_get_sqe(&mut ring).unwrap();
+            io_uring_prep_readv(&mut sqe, fds[0], &iov, 1, 0);
+            io_uring_sqe_set_data(&mut sqe, 8);
+        }
+        // NB: wait_cqe submits the pending read
+        let cqe = io_uring_wait_cqe(&mut ring).unwrap();
+        assert_eq!(io_uring_cqe_get_data(&cqe), 8);
+        assert_eq!(cqe.res(), msg.len() as i32);
+        assert_eq!(&buf[..msg.len()], msg);
+        io_uring_queue_exit(ring);
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn batch() {
+        use crate::batch::Batch;
+        use crate::io_uring::{IoUring, SQEntry};
+        use std::cell::Cell;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        assert_eq!(Batch::new(0xfa).window(&ring), 4);
+        assert_eq!(Batch::with_limit(0xfa, 100).window(&ring), 4);
+        assert_eq!(Batch::with_limit(0xfa, 0).window(&ring), 1);
+
+        // a request that is not part of the batch
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(42);
+        }
+        // fsync fails with EINVAL for pipes, and EBADF for -1
+        let fsync_fds = [fds[1], -1];
+        let ops = (0..10).map(|i| move |sqe: &mut SQEntry| sqe.prep_fsync(fsync_fds[i % 2], 0));
+        let mut others = vec![];
+        let cqes = Batch::new(0xfa).join_all_ops(&mut ring, ops, |c| others.push(c)).unwrap();
+        assert_eq!(cqes.len(), 10);
+        for (i, cqe) in cqes.iter().enumerate() {
+            let res = if i % 2 == 0 { -libc::EINVAL } else { -libc::EBADF };
+            assert_eq!(cqe.res(), res);
+        }
+        assert_eq!(others.len(), 1);
+        assert_eq!(others[0].user_data(), 42);
+
+        // no more than limit requests in flight
+        let (issued, completed, max) = (Cell::new(0), Cell::new(0), Cell::new(0));
+        let ops = (0..10).map(|_| |sqe: &mut SQEntry| {
+            issued.set(issued.get() + 1);
+            max.set(max.get().max(issued.get() - completed.get()));
+            sqe.prep_fsync(-1, 0);
+        });
+        let batch = Batch::with_limit(0xfa, 2);
+        batch.for_each_op(&mut ring, ops, |_, cqe| {
+            assert_eq!(cqe.res(), -libc::EBADF);
+            completed.set(completed.get() + 1);
+        }, |_| panic!("unexpected cqe")).unwrap();
+        assert_eq!((issued.get(), completed.get(), max.get()), (10, 10, 2));
+
+        // sink() consumes an unbounded sequence of requests lazily
+        issued.set(0);
+        let ops = (0..).map(|_| |sqe: &mut SQEntry| {
+            issued.set(issued.get() + 1);
+            sqe.prep_fsync(-1, 0);
+        });
+        let mut sink = batch.sink(&mut ring, ops, |_| panic!("unexpected cqe"));
+        let (_, cqe) = sink.next().unwrap().unwrap();
+        assert_eq!(cqe.res(), -libc::EBADF);
+        assert_eq!(issued.get(), 2);
+        let mut idxs: Vec<usize> = sink.by_ref().take(5).map(|x| x.unwrap().0).collect();
+        idxs.sort_unstable();
+        assert_eq!(idxs.len(), 5);
+        assert!(idxs.iter().all(|i| *i < issued.get()));
+        assert!(issued.get() <= 6 + 2);
+        // drain the requests in flight
+        let inflight = sink.inflight();
+        drop(sink);
+        ring.submit_and_wait(inflight as u32).unwrap();
+        while ring.pop_cqe().is_some() {}
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn groups() {
+        use crate::group::Groups;
+        use crate::io_uring::IoUring;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut groups = Groups::new(0xfd);
+
+        // a request that is not part of a group
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(42);
+        }
+        // more requests than sqes: fsync fails with EINVAL for pipes, and EBADF for -1
+        let fsync_fds = [fds[1], -1];
+        let g1 = groups.submit(&mut ring, 6, |i, sqe| sqe.prep_fsync(fsync_fds[i % 2], 0)).unwrap();
+        let g2 = groups.submit(&mut ring, 1, |_, sqe| sqe.prep_fsync(-1, 0)).unwrap();
+        assert_ne!(g1, g2);
+
+        let mut others = vec![];
+        let cqes = groups.wait(&mut ring, g1, |cqe| others.push(cqe)).unwrap();
+        assert_eq!(cqes.len(), 6);
+        for (i, cqe) in cqes.iter().enumerate() {
+            let res = if i % 2 == 0 { -libc::EINVAL } else { -libc::EBADF };
+            assert_eq!(cqe.res(), res);
+        }
+        assert!(groups.pending(g1).is_none());
+        assert!(groups.take(g1).is_none());
+
+        // the cqes of other groups that arrived while waiting
+        for cqe in others.iter() {
+            if groups.owns(cqe) {
+                assert_eq!(groups.complete(cqe), Some(g2));
+            }
+        }
+        let cqes = groups.wait(&mut ring, g2, |cqe| others.push(cqe)).unwrap();
+        assert_eq!(cqes[0].res(), -libc::EBADF);
+        assert!(others.iter().any(|c| c.user_data() == 42));
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "linux-5_15")]
+    fn groups_partial() {
+        use crate::group::{Groups, PartialGroup};
+        use crate::io_uring::{IoUring, SetupFlags};
+
+        if skip() {
+            return;
+        }
+        // submitting to a disabled ring fails (EBADFD), so only the requests that fit in the SQ
+        // are queued
+        let mut ring = IoUring::init_with_flags(2, SetupFlags::R_DISABLED).unwrap();
+        let mut groups = Groups::new(0xfd);
+        let err = groups.submit(&mut ring, 4, |_, sqe| sqe.prep_fsync(-1, 0)).unwrap_err();
+        let partial = err.get_ref().unwrap().downcast_ref::<PartialGroup>().unwrap();
+        assert_eq!(partial.queued(), 2);
+        let id = partial.id();
+        assert_eq!(groups.pending(id), Some(2));
+
+        ring.enable().unwrap();
+        let cqes = groups.wait(&mut ring, id, |_| panic!("unexpected cqe")).unwrap();
+        assert_eq!(cqes.len(), 2);
+        assert!(cqes.iter().all(|cqe| cqe.res() == -libc::EBADF));
+        assert!(ring.pop_cqe().is_none());
+    }
+
+    #[test]
+    fn groups_any() {
+        use crate::group::Groups;
+        use crate::io_uring::IoUring;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut groups = Groups::new(0xfd);
+
+        // a poll that never completes, and an fsync that fails right away
+        let g = groups.submit_any(&mut ring, 2, |i, sqe| match i {
+            0 => sqe.prep_poll_add(fds[0], libc::POLLIN as u32),
+            _ => sqe.prep_fsync(-1, 0),
+        }).unwrap();
+        let (idx, cqe) = groups.wait_any(&mut ring, g, |_| panic!("unexpected cqe")).unwrap();
+        assert_eq!((idx, cqe.res()), (1, -libc::EBADF));
+        assert_eq!(groups.pending(g), Some(1));
+
+        // the group is dropped once the cancelled poll completes
+        groups.cancel(&mut ring, g).unwrap();
+        let mut res = vec![];
+        while groups.pending(g).is_some() {
+            ring.submit_and_wait(1).unwrap();
+            while let Some(cqe) = ring.pop_cqe() {
+                assert!(groups.owns(&cqe));
+                assert_eq!(groups.complete(&cqe), None);
+                res.push(cqe.res());
+            }
+        }
+        assert!(res.contains(&-libc::ECANCELED));
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn iovecs() {
+        use crate::io_uring::IoUring;
+        use crate::iovec::IoVecs;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        let mut wr = IoVecs::new(vec![b"hello".to_vec(), vec![], b" world".to_vec()]);
+        assert_eq!(wr.len(), 11);
+        // pretend that "hel" was already written
+        wr.advance(3);
+        let mut rd = IoVecs::new(vec![vec![0u8; 2], vec![0u8; 6]]);
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            wr.prep_writev(&mut sqe, fds[1], 0);
+            sqe.set_data(1);
+        }
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            rd.prep_readv(&mut sqe, fds[0], 0);
+            sqe.set_data(2);
+        }
+        ring.submit_and_wait(2).unwrap();
+        let mut res = [0; 2];
+        for _ in 0..2 {
+            let cqe = ring.pop_cqe().unwrap();
+            res[cqe.user_data() as usize - 1] = cqe.res();
+        }
+        assert_eq!(res, [8, 8]);
+        assert_eq!(rd.into_bufs().concat(), b"lo world");
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn iovecs_bytes() {
+        use crate::io_uring::IoUring;
+        use crate::iovec::IoVecs;
+        use bytes::{Bytes, BytesMut};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        let hello = Bytes::from_static(b"hello world");
+        let mut wr = IoVecs::new(vec![hello.slice(..6), hello.slice(6..)]);
+        let mut rd = IoVecs::new(vec![BytesMut::with_capacity(4), BytesMut::with_capacity(16)]);
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            wr.prep_writev(&mut sqe, fds[1], 0);
+            sqe.set_data(1);
+        }
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            rd.prep_readv_spare(&mut sqe, fds[0], 0);
+            sqe.set_data(2);
+        }
+        ring.submit_and_wait(2).unwrap();
+        for _ in 0..2 {
+            let cqe = ring.pop_cqe().unwrap();
+            assert_eq!(cqe.res(), 11);
+            if cqe.user_data() == 2 {
+                assert_eq!(unsafe { rd.complete_readv_spare(&cqe) }.unwrap(), 11);
+            }
+        }
+        assert_eq!(rd.bufs()[0].len(), rd.bufs()[0].capacity());
+        assert_eq!(rd.bufs().concat(), b"hello world");
+
+        // reserving makes room in the full buffer, which is read into first
+        rd.reserve(8);
+        assert_eq!(unsafe { libc::write(fds[1], b"abc".as_ptr() as _, 3) }, 3);
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            rd.prep_readv_spare(&mut sqe, fds[0], 0);
+        }
+        ring.submit_and_wait(1).unwrap();
+        let cqe = ring.pop_cqe().unwrap();
+        assert_eq!(unsafe { rd.complete_readv_spare(&cqe) }.unwrap(), 3);
+        assert_eq!(rd.bufs().concat(), b"hellabco world");
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn notifier() {
+        use crate::io_uring::IoUring;
+        use std::os::unix::io::AsRawFd;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let notifier = ring.notifier().unwrap();
+        let readable = || {
+            let fd = notifier.as_raw_fd();
+            let mut pfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+            unsafe { libc::poll(&mut pfd, 1, 0) == 1 }
+        };
+        assert!(!readable());
+
+        for i in 0..2 {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(i);
+        }
+        ring.submit_and_wait(2).unwrap();
+        assert!(readable());
+        // NB: the kernel might signal the eventfd once for both cqes
+        assert!(notifier.reset().unwrap() > 0);
+        assert!(!readable());
+        assert_eq!(notifier.reset().unwrap(), 0);
+        while ring.pop_cqe().is_some() {}
+
+        ring.unregister_notifier().unwrap();
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+        }
+        ring.submit_and_wait(1).unwrap();
+        assert!(!readable());
+    }
+
+    #[cfg(feature = "mio")]
+    #[test]
+    fn notifier_mio() {
+        use crate::io_uring::IoUring;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut notifier = ring.notifier().unwrap();
+        let mut poll = mio::Poll::new().unwrap();
+        poll.registry().register(&mut notifier, mio::Token(1), mio::Interest::READABLE).unwrap();
+
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(5);
+        }
+        ring.submit().unwrap();
+        let mut events = mio::Events::with_capacity(4);
+        let timeout = Some(std::time::Duration::from_secs(5));
+        loop {
+            match poll.poll(&mut events, timeout) {
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                x => break x.unwrap(),
+            }
+        }
+        assert!(events.iter().any(|e| e.token() == mio::Token(1) && e.is_readable()));
+        notifier.reset().unwrap();
+        assert_eq!(ring.pop_cqe().unwrap().user_data(), 5);
     }
 
+    #[cfg(feature = "linux-5_15")]
+    #[test]
+    fn sandbox() {
+        use crate::io_uring::{OpCode, SqeFlags};
+        use crate::sandbox::SandboxBuilder;
+
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let msg = b"hello";
+        let n = unsafe { libc::write(fds[1], msg.as_ptr() as *const libc::c_void, msg.len()) };
+        assert_eq!(n, msg.len() as isize);
+
+        let mut buf = vec![0u8; 4096];
+        let bufs = [std::io::IoSliceMut::new(&mut buf)];
+        let files = [fds[0]];
+        let res = SandboxBuilder::new(4)
+            .allow_op(OpCode::ReadFixed)
+            .require_sqe_flags(SqeFlags::FIXED_FILE)
+            .files(&files)
+            .buffers(&bufs)
+            .personality()
+            .build();
+        if skip() {
+            return;
+        }
+        let mut sandbox = res.unwrap();
+        let ring = &mut sandbox.ring;
+        assert!(sandbox.personality.is_some());
+
+        // NB: the kernel stops submitting at the first request it rejects, so submit them one
+        // by one
+        let ptr = bufs[0].as_ptr() as *mut u8;
+        let mut run = |fd, flags, personality| {
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                if fd < 0 {
+                    sqe.prep_fsync(0, 0);
+                } else {
+                    sqe.prep_read_fixed(fd, ptr, 16, 0, 0);
+                }
+                sqe.set_flags(flags);
+                if let Some(id) = personality {
+                    sqe.set_personality(id);
+                }
+            }
+            ring.submit_and_wait(1).unwrap();
+            ring.pop_cqe().unwrap().res()
+        };
+        // not a registered file
+        assert_eq!(run(fds[0], SqeFlags::empty(), None), -libc::EACCES);
+        // not an allowed opcode
+        assert_eq!(run(-1, SqeFlags::FIXED_FILE, None), -libc::EACCES);
+        assert_eq!(run(0, SqeFlags::FIXED_FILE, sandbox.personality), msg.len() as i32);
+
+        // register operations are not allowed either
+        let err = ring.unregister_files().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+
+        drop(sandbox);
+        assert_eq!(&buf[..msg.len()], msg);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[cfg(feature = "linux-5_15")]
+    #[test]
+    fn pipe() {
+        use crate::io_uring::{IoUring, SqeFlags};
+        use crate::pipe::Pipe;
+        use std::io::{Read, Write};
+        use std::os::unix::io::AsRawFd;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let pipe = Pipe::with_size(1 << 16).unwrap();
+        assert!(pipe.size().unwrap() >= 1 << 16);
+
+        // file -> pipe -> socket
+        let path = std::env::temp_dir().join(format!("iouring-pipe-{}", std::process::id()));
+        let mut f = std::fs::File::create(&path).unwrap();
+        f.write_all(b"0123456789").unwrap();
+        let f = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let (mut s0, s1) = std::os::unix::net::UnixStream::pair().unwrap();
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            pipe.splice_in(&mut sqe, f.as_raw_fd(), Some(4), 4);
+            sqe.set_flags(SqeFlags::IO_LINK);
+            sqe.set_data(1);
+        }
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            pipe.splice_out(&mut sqe, s1.as_raw_fd(), None, 4);
+            sqe.set_data(2);
+        }
+        ring.submit_and_wait(2).unwrap();
+        for ud in 1..=2 {
+            let cqe = ring.pop_cqe().unwrap();
+            assert_eq!((cqe.user_data(), cqe.res()), (ud, 4));
+        }
+        let mut buf = [0u8; 4];
+        s0.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"4567");
+    }
+
+    #[cfg(feature = "linux-6_7")]
+    #[test]
+    // NB: the children are reaped by the waitid requests
+    #[allow(clippy::zombie_processes)]
+    fn process() {
+        use crate::io_uring::IoUring;
+        use crate::process::Children;
+        use std::process::Command;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        if !ring.supports(crate::io_uring::OpCode::Waitid).unwrap_or(false) {
+            // NB: needs Linux 6.7
+            return;
+        }
+        let mut children = Children::new(0xfe);
+        let c1 = children.spawn(&mut ring, Command::new("sh").args(["-c", "exit 3"])).unwrap();
+        let c2 = children.spawn(&mut ring, Command::new("sleep").arg("10")).unwrap();
+        let err = children.adopt(&mut ring, c1.id()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        unsafe { libc::kill(c2.id() as libc::pid_t, libc::SIGKILL) };
+
+        let mut exited = vec![];
+        while !children.is_empty() {
+            ring.submit_and_wait(1).unwrap();
+            while let Some(cqe) = ring.pop_cqe() {
+                let (pid, status) = children.exited(&cqe).unwrap();
+                exited.push((pid, status.unwrap()));
+            }
+        }
+        exited.sort_by_key(|(pid, _)| *pid != c1.id());
+        assert_eq!(exited[0].0, c1.id());
+        assert_eq!(exited[0].1.code(), Some(3));
+        assert_eq!(exited[1].0, c2.id());
+        assert_eq!(std::os::unix::process::ExitStatusExt::signal(&exited[1].1), Some(9));
+    }
+
+    #[cfg(feature = "linux-5_15")]
+    #[test]
+    fn signals() {
+        use crate::io_uring::IoUring;
+        use crate::signals::Signals;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let signals = Signals::new(&[libc::SIGUSR2], 9).unwrap();
+        signals.arm(&mut ring).unwrap();
+        ring.submit().unwrap();
+
+        // NB: the signal is blocked on this thread only, so direct it at this thread
+        for _ in 0..2 {
+            assert_eq!(unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGUSR2) }, 0);
+            ring.submit_and_wait(1).unwrap();
+            let cqe = ring.pop_cqe().unwrap();
+            assert!(signals.owns(&cqe));
+            let infos = signals.received(&mut ring, &cqe).unwrap();
+            assert_eq!(infos.len(), 1);
+            assert_eq!(infos[0].ssi_signo, libc::SIGUSR2 as u32);
+        }
+        assert!(signals.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn timers() {
+        use crate::io_uring::IoUring;
+        use crate::timers::Timers;
+        use std::time::Duration;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut timers = Timers::new(0xff);
+        let ms = Duration::from_millis;
+        timers.add(&mut ring, 1, ms(50)).unwrap();
+        timers.add(&mut ring, 2, ms(100)).unwrap();
+        timers.add(&mut ring, 3, ms(150)).unwrap();
+        let err = timers.add(&mut ring, 1, ms(50)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        // NB: the submission queue has 4 entries, so this submits the adds
+        assert!(timers.cancel(&mut ring, 2).unwrap());
+        assert!(!timers.cancel(&mut ring, 4).unwrap());
+        timers.reset(&mut ring, 3, ms(1)).unwrap();
+        assert_eq!(timers.len(), 2);
+
+        let mut expired = vec![];
+        while !timers.is_empty() {
+            ring.submit_and_wait(1).unwrap();
+            while let Some(cqe) = ring.pop_cqe() {
+                assert!(timers.owns(&cqe));
+                expired.extend(timers.expired(&cqe));
+            }
+        }
+        assert_eq!(expired, vec![3, 1]);
+    }
+
+    #[test]
+    fn transfers() {
+        use crate::io_uring::IoUring;
+        use crate::iovec::IoVecs;
+        use crate::transfer::{Transfers, OFF_CURRENT};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let write = |msg: &[u8]| {
+            let n = unsafe { libc::write(fds[1], msg.as_ptr() as *const libc::c_void, msg.len()) };
+            assert_eq!(n, msg.len() as isize);
+        };
+
+        // reads from a pipe return what is available, so this takes two reads
+        let mut transfers = Transfers::new(0xfc);
+        let bufs = IoVecs::new(vec![vec![0u8; 4], vec![0u8; 4]]);
+        let id = transfers.read(&mut ring, fds[0], OFF_CURRENT, bufs).unwrap();
+        write(b"abc");
+        ring.submit_and_wait(1).unwrap();
+        let cqe = ring.pop_cqe().unwrap();
+        assert_eq!(cqe.res(), 3);
+        assert!(transfers.complete(&mut ring, &cqe).is_none());
+        write(b"defgh");
+        ring.submit_and_wait(1).unwrap();
+        let cqe = ring.pop_cqe().unwrap();
+        let done = transfers.complete(&mut ring, &cqe).unwrap();
+        assert_eq!((done.id, done.bytes), (id, 8));
+        assert!(done.error.is_none());
+        assert_eq!(done.bufs.into_bufs().concat(), b"abcdefgh");
+
+        // EOF
+        let bufs = IoVecs::new(vec![vec![0u8; 8]]);
+        transfers.read(&mut ring, fds[0], OFF_CURRENT, bufs).unwrap();
+        write(b"xy");
+        unsafe { libc::close(fds[1]) };
+        let done = loop {
+            ring.submit_and_wait(1).unwrap();
+            let cqe = ring.pop_cqe().unwrap();
+            if let Some(x) = transfers.complete(&mut ring, &cqe) {
+                break x;
+            }
+        };
+        assert_eq!(done.bytes, 2);
+        assert!(done.error.is_none());
+        assert!(transfers.is_empty());
+
+        unsafe { libc::close(fds[0]) };
+    }
+
+    #[test]
+    fn transfers_nowait() {
+        use crate::io_uring::IoUring;
+        use crate::iovec::IoVecs;
+        use crate::transfer::{Transfers, OFF_CURRENT};
+        use std::io::Write;
+        use std::os::unix::io::AsRawFd;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut transfers = Transfers::new(0xfc);
+        transfers.set_nowait(true);
+        let mut run = |fd, off, write_after: Option<libc::c_int>| {
+            let bufs = IoVecs::new(vec![vec![0u8; 4]]);
+            transfers.read(&mut ring, fd, off, bufs).unwrap();
+            ring.submit().unwrap();
+            if let Some(wfd) = write_after {
+                let n = unsafe { libc::write(wfd, b"abcd".as_ptr() as *const libc::c_void, 4) };
+                assert_eq!(n, 4);
+            }
+            loop {
+                ring.submit_and_wait(1).unwrap();
+                let cqe = ring.pop_cqe().unwrap();
+                if let Some(x) = transfers.complete(&mut ring, &cqe) {
+                    break x;
+                }
+            }
+        };
+
+        // a file that was just written is in the page cache
+        let path = std::env::temp_dir().join(format!("iouring-nowait-{}", std::process::id()));
+        let mut f = std::fs::File::create(&path).unwrap();
+        f.write_all(b"abcd").unwrap();
+        let f = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let done = run(f.as_raw_fd(), 0, None);
+        assert_eq!(done.bytes, 4);
+        assert!(!done.fell_back);
+
+        // an empty pipe is not readable without waiting
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let done = run(fds[0], OFF_CURRENT, Some(fds[1]));
+        assert_eq!(done.bytes, 4);
+        assert!(done.fell_back);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn ioprio() {
+        use crate::io_uring::{IoPrioClass, IoPriority, IoUring};
+
+        let prio = IoPriority::best_effort(4).unwrap();
+        assert_eq!(prio.bits(), (2 << 13) | 4);
+        assert_eq!(prio.class(), IoPrioClass::BestEffort);
+        assert_eq!(prio.level(), 4);
+        assert_eq!(IoPriority::idle().class(), IoPrioClass::Idle);
+        assert_eq!(IoPriority::realtime(7).unwrap().level(), 7);
+        assert!(IoPriority::realtime(8).is_err());
+        assert!(IoPriority::new(IoPrioClass::Idle, 1).is_err());
 
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let bufs = [std::io::IoSlice::new(b"x")];
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_write_slice(fds[1], &bufs, 0).unwrap();
+            sqe.set_ioprio(prio);
+        }
+        ring.submit_and_wait(1).unwrap();
+        assert_eq!(ring.pop_cqe().unwrap().res(), 1);
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn offsets() {
+        use crate::io_uring::{IoUring, Offset};
+        use std::io::{IoSlice, IoSliceMut};
+        use std::os::unix::io::AsRawFd;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let path = std::env::temp_dir().join(format!("iouring-offsets-{}", std::process::id()));
+        let f = std::fs::OpenOptions::new()
+            .read(true).write(true).create(true).truncate(true)
+            .open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let fd = f.as_raw_fd();
+
+        let mut write = |buf: &[u8], off: Offset| {
+            let bufs = [IoSlice::new(buf)];
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                sqe.prep_write_slice(fd, &bufs, 0).unwrap();
+                sqe.set_offset(off);
+            }
+            ring.submit_and_wait(1).unwrap();
+            ring.pop_cqe().unwrap().res()
+        };
+        assert_eq!(write(b"xbcd", Offset::At(0)), 4);
+        assert_eq!(write(b"ef", Offset::Append), 2);
+        assert_eq!(write(b"a", Offset::At(0)), 1);
+        // the append advanced the file position
+        assert_eq!(write(b"g", Offset::Current), 1);
+        assert_eq!(std::fs::read(format!("/proc/self/fd/{}", fd)).unwrap(), b"abcdefg");
+
+        assert_eq!(unsafe { libc::lseek(fd, 0, libc::SEEK_SET) }, 0);
+        let mut buf = [0u8; 3];
+        for expected in [b"abc", b"def"] {
+            let bufs = [IoSliceMut::new(&mut buf)];
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                sqe.prep_read_slice(fd, &bufs, 0).unwrap();
+                sqe.set_offset(Offset::from(u64::MAX));
+            }
+            ring.submit_and_wait(1).unwrap();
+            assert_eq!(ring.pop_cqe().unwrap().res(), 3);
+            assert_eq!(&buf, expected);
+        }
+    }
+
+    #[test]
+    fn pool() {
+        use crate::io_uring::SQEntry;
+        use crate::pool::RingPool;
+
+        assert!(RingPool::new(vec![]).is_err());
+        if skip() {
+            return;
+        }
+        let mut pool = RingPool::init(2, 4).unwrap();
+        let mut pipes = [[0 as libc::c_int; 2]; 3];
+        for p in pipes.iter_mut() {
+            assert_eq!(unsafe { libc::pipe(p.as_mut_ptr()) }, 0);
+        }
+        let poll = |sqe: &mut SQEntry, fd| sqe.prep_poll_add(fd, libc::POLLIN as u32);
+
+        // the pipes are empty, so the polls stay in flight
+        let (a, b, c) = (pipes[0][0], pipes[1][0], pipes[2][0]);
+        assert_eq!(pool.queue(Some(a), 1, |sqe| poll(sqe, a)).unwrap(), 0);
+        assert_eq!(pool.queue(Some(b), 2, |sqe| poll(sqe, b)).unwrap(), 1);
+        assert_eq!(pool.queue(Some(c), 3, |sqe| poll(sqe, c)).unwrap(), 0);
+        // ring 1 is less loaded, but a has requests in flight on ring 0
+        assert_eq!(pool.route(None), 1);
+        assert_eq!(pool.queue(Some(a), 4, |sqe| poll(sqe, a)).unwrap(), 0);
+        assert_eq!(pool.queue(None, 5, |sqe| sqe.prep_fsync(-1, 0)).unwrap(), 1);
+        assert!(pool.queue(None, 5, |sqe| sqe.prep_fsync(-1, 0)).is_err());
+        assert_eq!((pool.depth(0), pool.depth(1)), (3, 2));
+        pool.submit().unwrap();
+
+        for p in pipes.iter() {
+            assert_eq!(unsafe { libc::write(p[1], b"x".as_ptr() as *const libc::c_void, 1) }, 1);
+        }
+        let mut reaped = vec![];
+        for i in 0..pool.nrings() {
+            while pool.depth(i) > 0 {
+                pool.ring(i).submit_and_wait(1).unwrap();
+                pool.reap(|ring_idx, cqe| reaped.push((ring_idx, cqe.user_data())));
+            }
+        }
+        reaped.sort();
+        assert_eq!(reaped, vec![(0, 1), (0, 3), (0, 4), (1, 2), (1, 5)]);
+        assert_eq!(pool.in_flight(), 0);
+
+        for p in pipes.iter() {
+            unsafe {
+                libc::close(p[0]);
+                libc::close(p[1]);
+            }
+        }
+    }
+
+    #[cfg(feature = "linux-5_19")]
+    #[test]
+    fn forward() {
+        use crate::forward::Forwarder;
+        use crate::io_uring::IoUring;
+        use std::os::unix::io::AsRawFd;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut owner = IoUring::init(4).unwrap();
+        let mut fwd = Forwarder::new(0xfb);
+        fwd.queue(&mut ring, owner.as_raw_fd(), 42, |sqe| sqe.prep_fsync(-1, 0)).unwrap();
+        assert_eq!(fwd.len(), 1);
+
+        // the fsync, and then the msg_ring
+        for _ in 0..2 {
+            ring.submit_and_wait(1).unwrap();
+            let cqe = ring.pop_cqe().unwrap();
+            assert!(fwd.owns(&cqe));
+            fwd.forward(&mut ring, &cqe).unwrap();
+        }
+        assert!(fwd.is_empty());
+        assert!(ring.pop_cqe().is_none());
+
+        owner.submit_and_wait(1).unwrap();
+        let cqe = owner.pop_cqe().unwrap();
+        assert_eq!(cqe.user_data(), 42);
+        assert_eq!(cqe.res(), -libc::EBADF);
+
+        // forwarding fails if the target is not a ring
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        fwd.queue(&mut ring, fds[0], 43, |sqe| sqe.prep_fsync(-1, 0)).unwrap();
+        ring.submit_and_wait(1).unwrap();
+        let cqe = ring.pop_cqe().unwrap();
+        fwd.forward(&mut ring, &cqe).unwrap();
+        ring.submit_and_wait(1).unwrap();
+        let cqe = ring.pop_cqe().unwrap();
+        assert!(fwd.forward(&mut ring, &cqe).is_err());
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[cfg(feature = "linux-6_4")]
+    #[test]
+    fn buf_ring_mmap() {
+        use crate::buf_ring::BufRing;
+        use crate::io_uring::{IoUring, SqeFlags};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut bufring = match BufRing::register_mmap(&ring, 5, 2) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        let mut buf = vec![0u8; 16];
+        unsafe { bufring.add(buf.as_mut_ptr(), buf.len() as u32, 1) };
+        bufring.commit();
+
+        let mut fds = [0 as libc::c_int; 2];
+        let err = unsafe {
+            libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr())
+        };
+        assert_eq!(err, 0);
+        unsafe { libc::write(fds[1], b"hello".as_ptr() as *const libc::c_void, 5) };
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_recv(fds[0], std::ptr::null_mut(), 0, 0);
+            sqe.set_flags(SqeFlags::BUFFER_SELECT);
+            sqe.set_buf_group(5);
+        }
+        ring.submit_and_wait(1).unwrap();
+        let cqe = ring.pop_cqe().unwrap();
+        assert_eq!(cqe.res(), 5);
+        assert_eq!(cqe.buffer_id(), Some(1));
+        assert_eq!(&buf[..5], b"hello");
+
+        bufring.unregister(&ring).unwrap();
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[cfg(feature = "linux-6_13")]
+    #[test]
+    fn buf_ring_inc() {
+        use crate::buf_ring::BufRing;
+        use crate::io_uring::{IoUring, SqeFlags};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut bufring = match BufRing::register_incremental(&ring, 7, 1) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        assert!(bufring.is_incremental());
+        let mut buf = vec![0u8; 64];
+        unsafe { bufring.add(buf.as_mut_ptr(), buf.len() as u32, 3) };
+        bufring.commit();
+
+        let mut fds = [0 as libc::c_int; 2];
+        let err = unsafe {
+            libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr())
+        };
+        assert_eq!(err, 0);
+
+        // both receives use the same buffer, one after the other
+        let mut data = Vec::new();
+        for msg in [&b"hello"[..], &b"world!"[..]].iter() {
+            unsafe { libc::write(fds[1], msg.as_ptr() as *const libc::c_void, msg.len()) };
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                sqe.prep_recv(fds[0], std::ptr::null_mut(), 0, 0);
+                sqe.set_flags(SqeFlags::BUFFER_SELECT);
+                sqe.set_buf_group(7);
+            }
+            ring.submit_and_wait(1).unwrap();
+            let cqe = ring.pop_cqe().unwrap();
+            assert_eq!(cqe.res(), msg.len() as i32);
+            assert!(cqe.buffer_more());
+            let (bid, range) = bufring.consume(&cqe).unwrap();
+            assert_eq!(bid, 3);
+            data.extend_from_slice(&buf[range]);
+        }
+        assert_eq!(data, b"helloworld!");
+
+        bufring.unregister(&ring).unwrap();
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[cfg(feature = "linux-6_0")]
+    #[test]
+    fn recvmsg_multishot() {
+        use crate::buf_ring::BufRing;
+        use crate::io_uring::{IoUring, RecvMsgOut, SqeFlags};
+        use std::net::UdpSocket;
+        use std::os::unix::io::AsRawFd;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut bufring = match BufRing::register(&ring, 3, 4) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        let mut bufs = vec![[0u8; 256]; 4];
+        for (i, buf) in bufs.iter_mut().enumerate() {
+            unsafe { bufring.add(buf.as_mut_ptr(), buf.len() as u32, i as u16) };
+        }
+        bufring.commit();
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_namelen = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_recvmsg_multishot(server.as_raw_fd(), &mut msg, 0);
+            sqe.set_flags(SqeFlags::BUFFER_SELECT);
+            sqe.set_buf_group(3);
+            sqe.set_data(1);
+        }
+        ring.submit().unwrap();
+
+        for payload in [&b"ping"[..], &b"pong!"[..]].iter() {
+            client.send_to(payload, server.local_addr().unwrap()).unwrap();
+            ring.submit_and_wait(1).unwrap();
+            let cqe = ring.pop_cqe().unwrap();
+            assert_eq!(cqe.user_data(), 1);
+            assert!(cqe.res() > 0, "recvmsg failed: {}", cqe.res());
+            assert!(cqe.has_more());
+            let bid = cqe.buffer_id().unwrap() as usize;
+            let out = RecvMsgOut::parse(&bufs[bid][..cqe.res() as usize], &msg).unwrap();
+            assert_eq!(out.payload(), *payload);
+            assert_eq!(out.payload_len(), payload.len());
+            assert_eq!(out.name_len(), std::mem::size_of::<libc::sockaddr_in>());
+            let sin_p = out.name().as_ptr() as *const libc::sockaddr_in;
+            let sin = unsafe { std::ptr::read_unaligned(sin_p) };
+            assert_eq!(u16::from_be(sin.sin_port), client.local_addr().unwrap().port());
+        }
+
+        // reply via sendmsg
+        let mut iov = libc::iovec { iov_base: b"ok".as_ptr() as *mut libc::c_void, iov_len: 2 };
+        let mut sin: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+        sin.sin_family = libc::AF_INET as libc::sa_family_t;
+        sin.sin_port = client.local_addr().unwrap().port().to_be();
+        sin.sin_addr.s_addr = u32::from(std::net::Ipv4Addr::LOCALHOST).to_be();
+        let mut reply: libc::msghdr = unsafe { std::mem::zeroed() };
+        reply.msg_name = &mut sin as *mut libc::sockaddr_in as *mut libc::c_void;
+        reply.msg_namelen = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        reply.msg_iov = &mut iov;
+        reply.msg_iovlen = 1;
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_sendmsg(server.as_raw_fd(), &reply, 0);
+            sqe.set_data(2);
+        }
+        ring.submit_and_wait(1).unwrap();
+        let cqe = ring.pop_cqe().unwrap();
+        assert_eq!((cqe.user_data(), cqe.res()), (2, 2));
+        let mut rbuf = [0u8; 8];
+        assert_eq!(client.recv(&mut rbuf).unwrap(), 2);
+        assert_eq!(&rbuf[..2], b"ok");
+
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_cancel(1);
+            sqe.set_data(3);
+        }
+        ring.submit_and_wait(2).unwrap();
+        while ring.pop_cqe().is_some() {}
+        bufring.unregister(&ring).unwrap();
+    }
+
+    #[cfg(feature = "linux-5_19")]
+    #[test]
+    fn multishot_accept() {
+        use crate::io_uring::IoUring;
+        use std::net::{TcpListener, TcpStream};
+        use std::os::unix::io::AsRawFd;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            let null = std::ptr::null_mut();
+            sqe.prep_multishot_accept(listener.as_raw_fd(), null, null as _, 0);
+            sqe.set_data(1);
+        }
+        ring.submit().unwrap();
+
+        let addr = listener.local_addr().unwrap();
+        let _clients = [TcpStream::connect(addr).unwrap(), TcpStream::connect(addr).unwrap()];
+        for _ in 0..2 {
+            ring.submit_and_wait(1).unwrap();
+            let cqe = ring.pop_cqe().unwrap();
+            assert_eq!(cqe.user_data(), 1);
+            assert!(cqe.res() >= 0, "accept failed: {}", cqe.res());
+            assert!(cqe.has_more());
+            unsafe { libc::close(cqe.res()) };
+        }
+
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_cancel(1);
+            sqe.set_data(2);
+        }
+        ring.submit_and_wait(2).unwrap();
+        while ring.pop_cqe().is_some() {}
+    }
+
+    #[cfg(feature = "linux-6_0")]
+    #[test]
+    fn send_zc() {
+        use crate::io_uring::IoUring;
+        use std::io::Read;
+        use std::net::{TcpListener, TcpStream};
+        use std::os::unix::io::AsRawFd;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        let buf = b"hello zero-copy";
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_send_zc(client.as_raw_fd(), buf.as_ptr() as _, buf.len() as u32, 0, 0);
+            sqe.set_data(1);
+        }
+        ring.submit_and_wait(2).unwrap();
+        // the result comes first, and the notification once the kernel is done with buf
+        let cqe = ring.pop_cqe().unwrap();
+        assert_eq!((cqe.user_data(), cqe.res()), (1, buf.len() as i32));
+        assert!(cqe.has_more() && !cqe.is_notif());
+        let cqe = ring.pop_cqe().unwrap();
+        assert_eq!(cqe.user_data(), 1);
+        assert!(cqe.is_notif() && !cqe.has_more());
+
+        let mut rbuf = [0u8; 32];
+        let n = server.read(&mut rbuf).unwrap();
+        assert_eq!(&rbuf[..n], &buf[..]);
+    }
+
+    #[test]
+    fn hugebuf() {
+        use crate::hugebuf::{huge_page_size, HugeBufs};
+        use crate::io_uring::IoUring;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut bufs = HugeBufs::alloc(3, 4096).unwrap();
+        assert_eq!(bufs.buf_ptr(0) as usize % huge_page_size(), 0);
+        assert_eq!(bufs.buf_ptr(2) as usize - bufs.buf_ptr(0) as usize, 2 * 4096);
+        bufs.register(&mut ring).unwrap();
+
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        unsafe { libc::write(fds[1], b"hello".as_ptr() as *const libc::c_void, 5) };
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_read_fixed(fds[0], bufs.buf_ptr(2), 16, 0, 2);
+        }
+        ring.submit_and_wait(1).unwrap();
+        assert_eq!(ring.pop_cqe().unwrap().res(), 5);
+        ring.unregister_buffers().unwrap();
+        assert_eq!(&bufs.buf(2)[..5], b"hello");
+        bufs.buf_mut(1)[0] = 1;
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn scope() {
+        use crate::io_uring::{IoUring, KernelTimespec, TimeoutFlags};
+        use crate::scope::scope;
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        // a request outside the scope, whose cqe the scope reaps
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(7);
+        }
+        ring.submit_and_wait(1).unwrap();
+
+        // the read is never waited for in the closure, so the scope waits for it, and the write
+        // (issued after the read) completes it
+        let mut buf = [0u8; 16];
+        let iov = libc::iovec { iov_base: buf.as_mut_ptr() as _, iov_len: buf.len() };
+        let ((), other) = scope(&mut ring, 0xfc, |s| {
+            let id = s.push(|sqe| sqe.prep_readv(fds[0], &iov, 1, 0)).unwrap();
+            assert_eq!(id, 0);
+            s.submit().unwrap();
+            assert_eq!(s.inflight(), 1);
+            unsafe { libc::write(fds[1], b"hello".as_ptr() as *const libc::c_void, 5) };
+        });
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(other.len(), 1);
+        assert_eq!(other[0].user_data(), 7);
+
+        // a request that never completes is cancelled if the closure panics
+        let ts = KernelTimespec::from(std::time::Duration::from_secs(60));
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            scope(&mut ring, 0xfc, |s| {
+                s.push(|sqe| sqe.prep_timeout(&ts, 0, TimeoutFlags::empty())).unwrap();
+                s.submit().unwrap();
+                panic!("scope closure panicked");
+            })
+        }));
+        assert!(res.is_err());
+        assert!(ring.pop_cqe().is_none());
+
+        // cancel_all() cancels the requests, and wait() reports them
+        let (res, _) = scope(&mut ring, 0xfc, |s| {
+            s.push(|sqe| sqe.prep_timeout(&ts, 0, TimeoutFlags::empty())).unwrap();
+            s.submit().unwrap();
+            s.cancel_all().unwrap();
+            s.wait().unwrap().map(|(id, cqe)| (id, cqe.res()))
+        });
+        assert_eq!(res, Some((0, -libc::ECANCELED)));
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn share() {
+        use crate::io_uring::{IoUring, SqeFlags};
+        use crate::share::{recv_ring, send_ring};
+        use std::os::unix::net::UnixStream;
+
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (s1, s2) = UnixStream::pair().unwrap();
+        // NB: send_ring() may wait for recv_ring(), so it runs in another thread
+        let wfd = fds[1];
+        let sender = std::thread::spawn(move || {
+            if skip() {
+                return false;
+            }
+            let mut ring = IoUring::init(4).unwrap();
+            ring.register_files(&[wfd]).unwrap();
+            // use the ring, so that the receiver does not start from empty queues
+            for _ in 0..3 {
+                ring.get_sqe().unwrap().prep_fsync(-1, 0);
+                ring.submit_and_wait(1).unwrap();
+                ring.pop_cqe().unwrap();
+            }
+            send_ring(&s1, &ring).unwrap();
+            true
+        });
+        let ring = recv_ring(&s2);
+        if !sender.join().unwrap() {
+            return;
+        }
+        let mut ring = ring.unwrap();
+        assert_eq!(ring.geometry().sq_entries, 4);
+
+        // the registered files come with the ring
+        let msg = b"hello";
+        let iov = libc::iovec { iov_base: msg.as_ptr() as _, iov_len: msg.len() };
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_writev(0, &iov, 1, 0);
+            sqe.set_flags(SqeFlags::FIXED_FILE);
+            sqe.set_data(9);
+        }
+        ring.submit_and_wait(1).unwrap();
+        let cqe = ring.pop_cqe().unwrap();
+        assert_eq!((cqe.user_data(), cqe.res()), (9, msg.len() as i32));
+        let mut buf = [0u8; 5];
+        unsafe { libc::read(fds[0], buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        assert_eq!(&buf, msg);
+
+        // the sender is gone
+        assert!(recv_ring(&s2).is_err());
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn ratelimit() {
+        use crate::io_uring::{IoUring, SQEntry};
+        use crate::ratelimit::{Limits, Throttle};
+        use std::time::{Duration, Instant};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(8).unwrap();
+        let fsync = |ud: u64| move |sqe: &mut SQEntry| {
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(ud);
+        };
+        let ms = Duration::from_millis;
+
+        // 10 requests per second, in bursts of up to 2
+        let limits = Limits { ops_per_sec: Some(10), burst: ms(200), ..Limits::default() };
+        let mut throttle = Throttle::new(limits);
+        for ud in 0..4 {
+            throttle.push(-1, 0, fsync(ud));
+        }
+        let t0 = Instant::now();
+        assert_eq!(throttle.submit_ready_at(&mut ring, t0).unwrap(), Some(ms(100)));
+        assert_eq!(throttle.len(), 2);
+        assert_eq!(throttle.submit_ready_at(&mut ring, t0 + ms(50)).unwrap(), Some(ms(50)));
+        assert_eq!(throttle.submit_ready_at(&mut ring, t0 + ms(100)).unwrap(), Some(ms(100)));
+        assert_eq!(throttle.len(), 1);
+        assert_eq!(throttle.submit_ready_at(&mut ring, t0 + ms(200)).unwrap(), None);
+        assert!(throttle.is_empty());
+        ring.submit_and_wait(4).unwrap();
+        let mut uds: Vec<u64> =
+            std::iter::from_fn(|| ring.pop_cqe()).map(|c| c.user_data()).collect();
+        uds.sort_unstable();
+        assert_eq!(uds, vec![0, 1, 2, 3]);
+
+        // 1000 bytes per second, per fd: requests on fd 1 that are out of budget do not hold back
+        // requests on fd 2, but they do hold back later (even if smaller) requests on fd 1
+        let limits = Limits { bytes_per_sec: Some(1000), burst: ms(1000), ..Limits::default() };
+        let mut throttle = Throttle::per_fd(limits);
+        throttle.push(1, 600, fsync(10));
+        throttle.push(1, 600, fsync(11));
+        throttle.push(1, 100, fsync(12));
+        throttle.push(2, 100, fsync(20));
+        let t0 = Instant::now();
+        assert_eq!(throttle.submit_ready_at(&mut ring, t0).unwrap(), Some(ms(200)));
+        assert_eq!(throttle.len(), 2);
+        assert_eq!(throttle.submit_ready_at(&mut ring, t0 + ms(200)).unwrap(), Some(ms(100)));
+        assert_eq!(throttle.submit_ready_at(&mut ring, t0 + ms(300)).unwrap(), None);
+        ring.submit_and_wait(4).unwrap();
+        let uds: Vec<u64> = std::iter::from_fn(|| ring.pop_cqe()).map(|c| c.user_data()).collect();
+        assert_eq!(uds, vec![10, 20, 11, 12]);
+    }
+
+    #[test]
+    fn latency() {
+        use crate::io_uring::{IoUring, OpCode};
+        use crate::latency::Histogram;
+        use std::time::Duration;
+
+        let mut h = Histogram::new();
+        assert_eq!((h.count(), h.percentile(99.0), h.mean()), (0, Duration::ZERO, Duration::ZERO));
+        for i in 1..=1000 {
+            h.record(Duration::from_nanos(i));
+        }
+        assert_eq!((h.count(), h.min()), (1000, Duration::from_nanos(1)));
+        assert_eq!(h.max(), h.percentile(100.0));
+        assert_eq!(h.mean(), Duration::from_nanos(500));
+        for p in [1.0, 50.0, 90.0, 99.0, 99.9].iter() {
+            let exact = (p * 10.0) as u64;
+            let got = h.percentile(*p).as_nanos() as u64;
+            assert!(got >= exact && got <= exact + exact / 16, "p{}: {}", p, got);
+        }
+        let mut big = Histogram::new();
+        big.record(Duration::MAX);
+        big.merge(&h);
+        assert_eq!(big.count(), 1001);
+        assert_eq!(big.percentile(100.0), Duration::from_nanos(u64::MAX));
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        assert!(ring.latency().is_none());
+        ring.track_latency(true);
+        for _ in 0..3 {
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                sqe.prep_fsync(-1, 0);
+            }
+            ring.submit_and_wait(1).unwrap();
+            assert!(ring.pop_cqe().is_some());
+        }
+        let latency = ring.latency().unwrap();
+        assert_eq!(latency.get(OpCode::Fsync).unwrap().count(), 3);
+        assert!(latency.get(OpCode::Readv).is_none());
+        assert_eq!(latency.total().count(), 3);
+        assert!(format!("{}", latency).starts_with("op=FSYNC n=3 "));
+        ring.reset_latency();
+        assert!(ring.latency().unwrap().get(OpCode::Fsync).is_none());
+        ring.track_latency(false);
+        assert!(ring.latency().is_none());
+    }
+
+    #[test]
+    fn op_stats() {
+        use crate::io_uring::{IoUring, OpCode};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        assert!(ring.op_stats().is_none());
+
+        // a request submitted before tracking starts
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(100);
+        }
+        ring.submit().unwrap();
+        ring.track_op_stats(true);
+        let bufs = [std::io::IoSlice::new(b"xyz")];
+        for i in 0..2 {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_write_slice(fds[1], &bufs, 0).unwrap();
+            sqe.set_data(i);
+        }
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(2);
+        }
+        ring.submit_and_wait(4).unwrap();
+        while ring.pop_cqe().is_some() {}
+
+        let stats = ring.op_stats().unwrap();
+        let w = stats.get(OpCode::Writev);
+        assert_eq!((w.sqes, w.cqes, w.bytes, w.errors), (2, 2, 6, 0));
+        let f = stats.get(OpCode::Fsync);
+        assert_eq!((f.sqes, f.cqes, f.bytes, f.errors), (1, 1, 0, 1));
+        assert_eq!(f.errnos.get(&libc::EBADF), Some(&1));
+        assert_eq!(f.error_rate(), 1.0);
+        assert_eq!((stats.total_cqes(), stats.untracked_cqes()), (3, 1));
+        assert_eq!(stats.get(OpCode::Read), Default::default());
+        let out = format!("{}", stats);
+        assert!(out.starts_with("op=WRITEV sqes=2 cqes=2 (66.7%) bytes=6 errors=0 (0.0%)\n"));
+        let fsync = "op=FSYNC sqes=1 cqes=1 (33.3%) bytes=0 errors=1 (100.0%)";
+        assert!(out.contains(&format!("{} errno{}=1\n", fsync, libc::EBADF)));
+
+        ring.reset_op_stats();
+        assert_eq!(ring.op_stats().unwrap().total_cqes(), 0);
+        ring.track_op_stats(false);
+        assert!(ring.op_stats().is_none());
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn pending_ops() {
+        use crate::io_uring::{IoUring, OpCode};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        // not tracked
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(1);
+        }
+        ring.submit_and_wait(1).unwrap();
+        assert!(ring.pop_cqe().is_some());
+
+        ring.track_pending(true);
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_poll_add(fds[0], libc::POLLIN as u32);
+            sqe.set_data(2);
+        }
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(3);
+        }
+        ring.submit_and_wait(1).unwrap();
+        assert_eq!(ring.pop_cqe().unwrap().user_data(), 3);
+
+        let ops = ring.pending_ops();
+        assert_eq!(ops.len(), 1);
+        assert_eq!((ops[0].user_data, ops[0].fd), (2, fds[0]));
+        assert_eq!(OpCode::from_u8(ops[0].opcode), Some(OpCode::PollAdd));
+        let dump = ring.dump_pending();
+        assert!(dump.starts_with("op=POLL_ADD "), "{}", dump);
+        assert_eq!(dump.lines().count(), 1);
+
+        assert_eq!(unsafe { libc::write(fds[1], b"x".as_ptr() as *const libc::c_void, 1) }, 1);
+        ring.submit_and_wait(1).unwrap();
+        assert_eq!(ring.pop_cqe().unwrap().user_data(), 2);
+        assert!(ring.pending_ops().is_empty());
+        assert_eq!(ring.dump_pending(), "");
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn fallback() {
+        use crate::fallback::{AutoRing, FallbackRing};
+        use crate::io_uring::{SqeFlags, IORING_FSYNC_DATASYNC};
+
+        let mut ring = FallbackRing::with_threads(4, 2).unwrap();
+        assert_eq!(ring.nthreads(), 2);
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        // write and read back, with offsets that pipes ignore
+        let (a, b) = (b"hello, ".to_vec(), b"fallback".to_vec());
+        let iovs = [std::io::IoSlice::new(&a), std::io::IoSlice::new(&b)];
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_write_slice(fds[1], &iovs, 0).unwrap();
+            sqe.set_data(1);
+        }
+        ring.submit_and_wait(1).unwrap();
+        let cqe = ring.pop_cqe().unwrap();
+        assert_eq!((cqe.user_data(), cqe.res()), (1, 15));
+        let mut buf = [0u8; 15];
+        {
+            let iovs = [std::io::IoSliceMut::new(&mut buf)];
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                sqe.prep_read_slice(fds[0], &iovs, 0).unwrap();
+                sqe.set_data(2);
+            }
+            ring.submit_and_wait(1).unwrap();
+            assert_eq!(ring.pop_cqe().unwrap().res(), 15);
+        }
+        assert_eq!(&buf, b"hello, fallback");
+
+        // a failed request cancels the rest of its chain; the queue has room for 4 requests
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, IORING_FSYNC_DATASYNC);
+            sqe.set_flags(SqeFlags::IO_LINK);
+            sqe.set_data(3);
+        }
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(fds[1], 0);
+            sqe.set_data(4);
+        }
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(fds[1], 0);
+            sqe.set_data(5);
+        }
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_cancel(1);
+            sqe.set_data(6);
+        }
+        assert!(ring.get_sqe().is_none());
+        assert_eq!(ring.submit_and_wait(4).unwrap(), 4);
+        let mut res = vec![];
+        while let Some(cqe) = ring.pop_cqe() {
+            res.push((cqe.user_data(), cqe.res()));
+        }
+        res.sort();
+        let expected = vec![(3, -libc::EBADF), (4, -libc::ECANCELED), (5, -libc::EINVAL),
+            (6, -libc::EINVAL)];
+        assert_eq!(res, expected);
+        drop(ring);
+
+        let mut ring = AutoRing::init(4).unwrap();
+        assert_eq!(ring.is_fallback(), !crate::io_uring::is_supported());
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+        }
+        ring.submit_and_wait(1).unwrap();
+        assert_eq!(ring.cq_ready(), 1);
+        assert_eq!(ring.pop_cqe().unwrap().res(), -libc::EBADF);
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn fdinfo() {
+        use crate::fdinfo;
+        use crate::io_uring::IoUring;
+
+        let sample = "pos:\t0\nflags:\t02000002\nmnt_id:\t17\nino:\t101685\n\
+            SqMask:\t0x3\nSqHead:\t5\nSqTail:\t6\nCachedSqHead:\t5\n\
+            CqMask:\t0x7\nCqHead:\t4\nCqTail:\t5\nCachedCqTail:\t5\nSQEs:\t1\nCQEs:\t1\n\
+            SqThread:\t-1\nSqThreadCpu:\t-1\nSqTotalTime:\t0\nSqWorkTime:\t0\n\
+            UserFiles:\t1\n    0: pipe:[1234]\nUserBufs:\t0\n\
+            PollList:\n  op=6, task_works=0\n  op=6, task_works=0\n\
+            CqOverflowList:\n  user_data=1, res=0, flags=0\nNAPI:\tdisabled\n";
+        let info = fdinfo::parse(sample).unwrap();
+        assert_eq!((info.sq_mask, info.sq_head, info.sq_tail), (3, 5, 6));
+        assert_eq!((info.cq_mask, info.cq_head, info.cq_tail), (7, 4, 5));
+        assert_eq!((info.sqes, info.cqes), (1, 1));
+        assert_eq!(info.sq_thread, None);
+        assert_eq!((info.user_files, info.user_bufs), (1, 0));
+        assert_eq!((info.poll_list, info.cq_overflow_list), (2, 1));
+        assert!(fdinfo::parse("pos:\t0\n").is_err());
+        assert!(fdinfo::parse("SqHead:\tx\n").is_err());
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        ring.register_files(&fds).unwrap();
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_poll_add(fds[0], libc::POLLIN as u32);
+        }
+        ring.submit().unwrap();
+        let info = fdinfo::read(&ring).unwrap();
+        assert_eq!((info.sq_mask, info.sq_head, info.sq_tail), (3, 1, 1));
+        assert_eq!((info.cq_head, info.cq_tail), (0, 0));
+        assert_eq!(info.user_files, 2);
+        assert_eq!(info.poll_list, 1);
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn fixed_ring() {
+        use crate::io_uring::FixedIoUring;
+
+        type Ring = FixedIoUring<4>;
+        if skip() {
+            return;
+        }
+        let mut ring = Ring::init().unwrap();
+        let mut slots = [None; Ring::ENTRIES];
+        assert_eq!(Ring::CQ_ENTRIES, 8);
+        assert_eq!(ring.sq_space_left(), 4);
+
+        // go around the rings a few times
+        for round in 0..5u64 {
+            for (i, slot) in slots.iter_mut().enumerate() {
+                let mut sqe = ring.get_sqe().unwrap();
+                sqe.prep_fsync(-1, 0);
+                sqe.set_data(i as u64);
+                *slot = Some(round);
+            }
+            assert!(ring.get_sqe().is_none());
+            ring.submit_and_wait(4).unwrap();
+            while let Some(cqe) = ring.pop_cqe() {
+                assert_eq!(cqe.res(), -libc::EBADF);
+                assert_eq!(slots[cqe.user_data() as usize].take(), Some(round));
+            }
+            assert!(slots.iter().all(|x| x.is_none()));
+        }
+        assert_eq!(ring.stats().cqes_reaped, 20);
+    }
+
+    #[test]
+    fn entries() {
+        use crate::io_uring::{normalize_entries, EntriesPolicy, IoUring, IoUringBuilder, MAX_ENTRIES};
+        use std::io::ErrorKind;
+
+        for policy in [EntriesPolicy::Exact, EntriesPolicy::RoundUp, EntriesPolicy::Clamp] {
+            assert_eq!(normalize_entries(0, policy).unwrap_err().kind(), ErrorKind::InvalidInput);
+            assert_eq!(normalize_entries(64, policy).unwrap(), 64);
+        }
+        assert!(normalize_entries(100, EntriesPolicy::Exact).is_err());
+        assert_eq!(normalize_entries(100, EntriesPolicy::RoundUp).unwrap(), 128);
+        assert_eq!(normalize_entries(100, EntriesPolicy::Clamp).unwrap(), 128);
+        assert!(normalize_entries(MAX_ENTRIES + 1, EntriesPolicy::RoundUp).is_err());
+        assert_eq!(normalize_entries(MAX_ENTRIES + 1, EntriesPolicy::Clamp).unwrap(), MAX_ENTRIES);
+
+        if skip() {
+            return;
+        }
+        let ring = IoUring::init(5).unwrap();
+        let geo = ring.geometry();
+        assert_eq!((geo.sq_entries, geo.cq_entries), (8, 16));
+        assert_eq!((geo.sqe_size, geo.cqe_size), (64, 16));
+        let err = IoUring::init(MAX_ENTRIES + 1).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let ring = IoUringBuilder::new(u32::MAX).entries_policy(EntriesPolicy::Clamp).build();
+        assert_eq!(ring.unwrap().geometry().sq_entries, MAX_ENTRIES);
+    }
+
+    #[test]
+    fn enter() {
+        use crate::io_uring::{EnterFlags, IoUring};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        for i in 0..2 {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(i);
+        }
+        let mut mask: libc::sigset_t = unsafe { std::mem::zeroed() };
+        unsafe { libc::sigemptyset(&mut mask) };
+        let ret = unsafe { ring.enter(2, 2, EnterFlags::GETEVENTS, Some(&mask)) };
+        assert_eq!(ret.unwrap(), 2);
+        assert_eq!(ring.cq_ready(), 2);
+        while let Some(cqe) = ring.pop_cqe() {
+            assert_eq!(cqe.res(), -libc::EBADF);
+        }
+        assert_eq!(ring.stats().sqes_submitted, 2);
+
+        // nothing to submit or wait for
+        assert_eq!(unsafe { ring.enter(0, 0, EnterFlags::empty(), None) }.unwrap(), 0);
+        let err = unsafe { ring.enter(0, 0, EnterFlags::from_bits_unchecked(1 << 31), None) };
+        assert_eq!(err.unwrap_err().raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn record() {
+        use crate::io_uring::{IoUring, OpCode};
+        use crate::record::{self, Record, Recorder};
+
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(4).unwrap();
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        assert!(ring.set_recorder(Some(Recorder::buffer(6))).is_none());
+        let bufs = [std::io::IoSlice::new(b"x")];
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_write_slice(fds[1], &bufs, 0).unwrap();
+            sqe.set_data(1);
+        }
+        for i in 2..4 {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+            sqe.set_data(i);
+        }
+        ring.submit_and_wait(3).unwrap();
+        while ring.pop_cqe().is_some() {}
+
+        // the first sqe is evicted from the buffer
+        let records: Vec<Record> = ring.recorder().unwrap().records().copied().collect();
+        assert_eq!(records.len(), 6);
+        match records[0] {
+            Record::Sqe(sqe) => assert_eq!((sqe.opcode, sqe.user_data), (OpCode::Fsync.as_u8(), 2)),
+            ref x => panic!("unexpected record: {}", x),
+        }
+        assert_eq!(records[2], Record::Submit(3));
+        let mut log = String::new();
+        for rec in records.iter() {
+            let line = format!("{}", rec);
+            assert_eq!(line.parse::<Record>().unwrap(), *rec);
+            log += &line;
+            log += "\n";
+        }
+        assert!(log.starts_with("sqe op=FSYNC flags=0x0 ioprio=0 fd=-1 "));
+        assert!("sqe op=NOPE".parse::<Record>().is_err());
+
+        // replay the fsyncs, with a different fd for the second
+        ring.set_recorder(None);
+        let mut n = 0;
+        let replayed = unsafe {
+            record::replay(&mut ring, log.as_bytes(), |sqe| {
+                n += 1;
+                if n == 2 {
+                    sqe.fd = fds[1];
+                }
+                true
+            })
+        };
+        assert_eq!(replayed.unwrap(), 2);
+        ring.submit_and_wait(2).unwrap();
+        let mut res = vec![];
+        while let Some(cqe) = ring.pop_cqe() {
+            res.push((cqe.user_data(), cqe.res()));
+        }
+        res.sort();
+        assert_eq!(res, vec![(2, -libc::EBADF), (3, -libc::EINVAL)]);
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn register() {
+        use crate::io_uring::{IoUring, IORING_REGISTER_PERSONALITY, IORING_UNREGISTER_PERSONALITY};
+
+        if skip() {
+            return;
+        }
+        let ring = IoUring::init(4).unwrap();
+        let null = std::ptr::null_mut();
+        let id = unsafe { ring.register(IORING_REGISTER_PERSONALITY, null, 0) }.unwrap();
+        assert!(id > 0);
+        let ret = unsafe { ring.register(IORING_UNREGISTER_PERSONALITY, null, id as u32) };
+        assert_eq!(ret.unwrap(), 0);
+        let err = unsafe { ring.register(IORING_UNREGISTER_PERSONALITY, null, id as u32) };
+        assert_eq!(err.unwrap_err().raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn get_events() {
+        use crate::io_uring::IoUring;
+
+        // NB: the CQ ring has 2 entries
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(1).unwrap();
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let bufs = [std::io::IoSlice::new(b"x")];
+        for i in 0..3 {
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                sqe.prep_write_slice(fds[1], &bufs, 0).unwrap();
+                sqe.set_data(i);
+            }
+            ring.submit().unwrap();
+        }
+        assert_eq!(ring.cq_ready(), 2);
+        assert!(ring.pop_cqe().is_some() && ring.pop_cqe().is_some());
+        assert_eq!(ring.cq_ready(), 0);
+
+        // the third cqe is on the overflow list; the queued sqe is not submitted
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_fsync(-1, 0);
+        }
+        assert_eq!(ring.get_events(0).unwrap(), 1);
+        assert_eq!(ring.pop_cqe().unwrap().user_data(), 2);
+        assert_eq!(ring.stats().sqes_submitted, 3);
+        ring.submit_and_wait(1).unwrap();
+        assert_eq!(ring.pop_cqe().unwrap().res(), -libc::EBADF);
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn deadlines() {
+        use crate::deadline::{Deadlines, Outcome};
+        use crate::io_uring::IoUring;
+        use std::time::Duration;
+
+        // NB: each request takes two sqes, so the second one is submitted separately
+        if skip() {
+            return;
+        }
+        let mut ring = IoUring::init(2).unwrap();
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut deadlines = Deadlines::new(0xfc);
+        assert_eq!(ring.sq_space_left(), 2);
+
+        // the pipe is empty, so the poll misses its deadline
+        let late = deadlines.submit(&mut ring, Duration::from_millis(10), |sqe| {
+            sqe.prep_poll_add(fds[0], libc::POLLIN as u32);
+        }).unwrap();
+        assert_eq!(ring.sq_space_left(), 0);
+        let done = deadlines.submit(&mut ring, Duration::from_secs(10), |sqe| {
+            sqe.prep_fsync(-1, 0);
+        }).unwrap();
+        assert_eq!(deadlines.len(), 2);
+
+        let mut outcomes = vec![];
+        while !deadlines.is_empty() {
+            ring.submit_and_wait(1).unwrap();
+            while let Some(cqe) = ring.pop_cqe() {
+                assert!(deadlines.owns(&cqe));
+                if let Some(x) = deadlines.complete(&cqe) {
+                    outcomes.push(x);
+                }
+            }
+        }
+        assert_eq!(outcomes.len(), 2);
+        for (id, outcome) in outcomes {
+            match outcome {
+                Outcome::Done(cqe) => {
+                    assert_eq!(id, done);
+                    assert_eq!(cqe.res(), -libc::EBADF);
+                }
+                Outcome::Late => assert_eq!(id, late),
+            }
+        }
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn util() {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join(format!("iouring-util-{}", std::process::id()));
+        let mut f = std::fs::File::create(&path).unwrap();
+        f.write_all(&[0u8; 1000]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(crate::util::file_size(&f).unwrap(), 1000);
+        assert!(!crate::util::is_block_device(&f).unwrap());
+        let lbs = crate::util::logical_block_size(&f).unwrap();
+        assert!(lbs.is_power_of_two());
+        assert_eq!(crate::util::physical_block_size(&f).unwrap() % lbs, 0);
+
+        // neither a regular file nor a block device
+        let null = std::fs::File::open("/dev/null").unwrap();
+        let err = crate::util::file_size(&null).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_events() {
+        use crate::io_uring::IoUring;
+        use std::sync::{Arc, Mutex};
+
+        // collects the messages of all events
+        struct Collector(Arc<Mutex<Vec<String>>>);
+
+        struct MsgVisitor<'a>(&'a mut String);
+
+        impl tracing::field::Visit for MsgVisitor<'_> {
+            fn record_debug(&mut self, field: &tracing::field::Field, v: &dyn std::fmt::Debug) {
+                if field.name() == "message" {
+                    *self.0 = format!("{:?}", v);
+                }
+            }
+        }
+
+        impl tracing::Subscriber for Collector {
+            fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+                tracing::span::Id::from_u64(1)
+            }
+            fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+            fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+            fn event(&self, event: &tracing::Event<'_>) {
+                let mut msg = String::new();
+                event.record(&mut MsgVisitor(&mut msg));
+                self.0.lock().unwrap().push(msg);
+            }
+            fn enter(&self, _: &tracing::span::Id) {}
+            fn exit(&self, _: &tracing::span::Id) {}
+        }
+
+        let msgs = Arc::new(Mutex::new(vec![]));
+        let supported = tracing::subscriber::with_default(Collector(msgs.clone()), || {
+            if skip() {
+                return false;
+            }
+            let mut ring = IoUring::init(4).unwrap();
+            {
+                let mut sqe = ring.get_sqe().unwrap();
+                sqe.prep_fsync(-1, 0);
+                sqe.set_data(1);
+            }
+            ring.submit_and_wait(1).unwrap();
+            ring.pop_cqe().unwrap();
+            true
+        });
+        if !supported {
+            return;
+        }
+
+        let msgs = msgs.lock().unwrap();
+        for m in ["ring created", "sqe", "io_uring_enter", "cqe"] {
+            assert!(msgs.iter().any(|x| x == m), "no {} event in {:?}", m, msgs);
+        }
+    }
 }
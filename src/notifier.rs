@@ -0,0 +1,105 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Completion notifications via an eventfd (IORING_REGISTER_EVENTFD)
+//
+// The kernel signals a registered eventfd every time it posts a cqe. The eventfd can be added to
+// a readiness-based event loop (epoll, mio, polling, ...), so that the ring's completions can be
+// handled together with other event sources, without blocking in io_uring_enter().
+//
+// Reference: io_uring_register_eventfd(3)
+
+use std::io;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+
+/// An eventfd that is signaled when cqes are posted to an io_uring (see [`IoUring::notifier`])
+///
+/// The notifier implements `AsFd` and `AsRawFd`, so it can be registered with `polling`, or with
+/// `epoll` directly. With the "mio" feature, it also implements `mio::event::Source`.
+///
+/// The eventfd stays readable until [`Self::reset`] is called. Reset it before draining the
+/// completion queue, so that cqes that are posted after draining signal it again.
+///
+/// [`IoUring::notifier`]: crate::io_uring::IoUring::notifier
+pub struct Notifier {
+    fd: RawFd,
+}
+
+impl Notifier {
+
+    // Create a non-blocking eventfd
+    pub(crate) fn new() -> io::Result<Notifier> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Notifier { fd })
+    }
+
+    /// Clear the eventfd, and return its counter, which is 0 if it was not signaled
+    ///
+    /// NB: the kernel might signal the eventfd once for multiple cqes, so the counter is not the
+    /// number of cqes posted since the last reset.
+    pub fn reset(&self) -> io::Result<u64> {
+        let mut val = 0u64;
+        let p = &mut val as *mut u64 as *mut libc::c_void;
+        let ret = unsafe { libc::read(self.fd, p, std::mem::size_of::<u64>()) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            // NB: not signaled
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Ok(0);
+            }
+            return Err(err);
+        }
+        Ok(val)
+    }
+}
+
+impl AsRawFd for Notifier {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl AsFd for Notifier {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+}
+
+#[cfg(feature = "mio")]
+impl mio::event::Source for Notifier {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        mio::unix::SourceFd(&self.fd).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        mio::unix::SourceFd(&self.fd).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        mio::unix::SourceFd(&self.fd).deregister(registry)
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        // NB: the kernel holds its own reference to the eventfd, so closing it while it is still
+        // registered is fine: the ring keeps signaling an eventfd that nobody reads.
+        unsafe { libc::close(self.fd) };
+    }
+}
@@ -0,0 +1,139 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Pipes for splice pipelines
+//
+// splice() moves data between a file and a pipe without copying it to userspace, so a zero-copy
+// file -> socket transfer is two splices through an intermediate pipe: file -> pipe -> socket.
+// Linking the two requests (IOSQE_IO_LINK) issues the second one once the first completes.
+//
+// The pipe buffer bounds how much each pair of splices moves, so it is worth growing it
+// (F_SETPIPE_SZ) for bulk transfers.
+//
+// Reference: splice(2), io_uring_prep_splice(3), fcntl(2) (F_SETPIPE_SZ)
+
+use std::convert::TryFrom;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use crate::io_uring::SQEntry;
+
+/// A pipe pair, used as the intermediate buffer of splice requests
+///
+/// ```no_run
+/// # use iouring::io_uring::{IoUring, SqeFlags};
+/// # use iouring::pipe::Pipe;
+/// # use std::os::unix::io::AsRawFd;
+/// # let (file, sock) = (std::fs::File::open("f").unwrap(), std::fs::File::open("s").unwrap());
+/// let mut ring = IoUring::init(4).unwrap();
+/// let pipe = Pipe::with_size(1 << 20).unwrap();
+/// let len = pipe.size().unwrap() as u32;
+/// {
+///     let mut sqe = ring.get_sqe().unwrap();
+///     pipe.splice_in(&mut sqe, file.as_raw_fd(), Some(0), len);
+///     sqe.set_flags(SqeFlags::IO_LINK);
+/// }
+/// {
+///     let mut sqe = ring.get_sqe().unwrap();
+///     pipe.splice_out(&mut sqe, sock.as_raw_fd(), None, len);
+/// }
+/// ring.submit_and_wait(2).unwrap();
+/// ```
+///
+/// NB: splice_out() moves at most what is in the pipe, so a short splice_in() results in a short
+/// splice_out().
+pub struct Pipe {
+    rd: RawFd,
+    wr: RawFd,
+}
+
+impl Pipe {
+
+    /// Create a pipe with the default size
+    pub fn new() -> io::Result<Pipe> {
+        let mut fds = [0 as libc::c_int; 2];
+        let err = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+        if err != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Pipe { rd: fds[0], wr: fds[1] })
+    }
+
+    /// Create a pipe, and set its size (see [`Self::set_size`])
+    pub fn with_size(size: usize) -> io::Result<Pipe> {
+        let pipe = Pipe::new()?;
+        pipe.set_size(size)?;
+        Ok(pipe)
+    }
+
+    /// Set the size of the pipe buffer (F_SETPIPE_SZ), and return the actual size
+    ///
+    /// The kernel rounds the size up to a power-of-two number of pages. Unprivileged processes
+    /// cannot exceed /proc/sys/fs/pipe-max-size (EPERM).
+    pub fn set_size(&self, size: usize) -> io::Result<usize> {
+        let size = match libc::c_int::try_from(size) {
+            Ok(x) => x,
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "pipe size too large"))
+            }
+        };
+        let ret = unsafe { libc::fcntl(self.wr, libc::F_SETPIPE_SZ, size) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+
+    /// Size of the pipe buffer (F_GETPIPE_SZ)
+    pub fn size(&self) -> io::Result<usize> {
+        let ret = unsafe { libc::fcntl(self.wr, libc::F_GETPIPE_SZ) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+
+    /// The read end of the pipe
+    pub fn read_fd(&self) -> RawFd {
+        self.rd
+    }
+
+    /// The write end of the pipe
+    pub fn write_fd(&self) -> RawFd {
+        self.wr
+    }
+
+    /// Prepare a splice of up to len bytes from fd into the pipe
+    ///
+    /// off is the offset to read from, or None to use (and update) the file position, which is
+    /// required if fd is a pipe or a socket.
+    pub fn splice_in(&self, sqe: &mut SQEntry, fd: RawFd, off: Option<u64>, len: u32) {
+        sqe.prep_splice(fd, splice_off(off), self.wr, -1, len, 0)
+    }
+
+    /// Prepare a splice of up to len bytes from the pipe to fd
+    ///
+    /// off is as in [`Self::splice_in`], but for writing.
+    pub fn splice_out(&self, sqe: &mut SQEntry, fd: RawFd, off: Option<u64>, len: u32) {
+        sqe.prep_splice(self.rd, -1, fd, splice_off(off), len, 0)
+    }
+}
+
+fn splice_off(off: Option<u64>) -> i64 {
+    match off {
+        Some(x) => x as i64,
+        None => -1,
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.rd);
+            libc::close(self.wr);
+        }
+    }
+}
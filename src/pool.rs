@@ -0,0 +1,168 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// A pool of rings, with requests routed to the least-loaded ring
+//
+// A single ring serializes submissions and completions, so deployments with many requests in
+// flight spread them over multiple rings (e.g., to spread io-wq workers, or to use multiple SQPOLL
+// threads). RingPool picks the ring of each request: the ring with the fewest requests in flight,
+// unless the request is for an fd that already has requests in flight, in which case it goes to
+// the same ring (so that, e.g., linked requests, and requests on a stream socket, stay in order).
+//
+// The pool counts requests in flight per ring by their user data, which is left to the
+// application, but needs to be unique among the requests in flight on each ring.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use crate::io_uring::{io_uring_cqe, IoUring, SQEntry};
+
+struct Ring {
+    ring: IoUring,
+    // requests in flight: user data -> fd hint
+    inflight: HashMap<u64, Option<RawFd>>,
+}
+
+/// Rings that requests are routed to by load (requests in flight), and by fd affinity
+///
+/// ```no_run
+/// # use iouring::pool::RingPool;
+/// # let fd = 0;
+/// # let mut buf = vec![0u8; 4096];
+/// let iov = libc::iovec { iov_base: buf.as_mut_ptr() as _, iov_len: buf.len() };
+/// let mut pool = RingPool::init(4, 64).unwrap();
+/// pool.queue(Some(fd), 1, |sqe| sqe.prep_readv(fd, &iov, 1, 0)).unwrap();
+/// pool.submit().unwrap();
+/// while pool.in_flight() > 0 {
+///     pool.reap(|ring_idx, cqe| println!("{}: {}", ring_idx, cqe.res()));
+/// }
+/// ```
+///
+/// All cqes need to go through [`Self::reap`], so that the pool knows when requests complete.
+pub struct RingPool {
+    rings: Vec<Ring>,
+    // fds with requests in flight: fd -> (ring index, number of requests)
+    fds: HashMap<RawFd, (usize, usize)>,
+}
+
+impl RingPool {
+
+    /// A pool of the given rings. Fails with `InvalidInput` if there are none.
+    pub fn new(rings: Vec<IoUring>) -> io::Result<RingPool> {
+        if rings.is_empty() {
+            let msg = "pool without rings";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let rings = rings.into_iter().map(|ring| Ring { ring, inflight: HashMap::new() }).collect();
+        Ok(RingPool { rings, fds: HashMap::new() })
+    }
+
+    /// A pool of nrings rings, each initialized with [`IoUring::init`]
+    pub fn init(nrings: usize, nentries: libc::c_uint) -> io::Result<RingPool> {
+        let rings = (0..nrings).map(|_| IoUring::init(nentries)).collect::<io::Result<_>>()?;
+        RingPool::new(rings)
+    }
+
+    /// Number of rings in the pool
+    pub fn nrings(&self) -> usize {
+        self.rings.len()
+    }
+
+    /// Ring i, e.g., to wait for its completions
+    pub fn ring(&mut self, i: usize) -> &mut IoUring {
+        &mut self.rings[i].ring
+    }
+
+    /// Number of requests in flight on ring i
+    pub fn depth(&self, i: usize) -> usize {
+        self.rings[i].inflight.len()
+    }
+
+    /// Number of requests in flight on all rings
+    pub fn in_flight(&self) -> usize {
+        self.rings.iter().map(|r| r.inflight.len()).sum()
+    }
+
+    /// The ring that a request for fd (if any) would be routed to
+    ///
+    /// This is the ring of the requests in flight for fd, if there are any, or the ring with the
+    /// fewest requests in flight (the first one, if there is a tie).
+    pub fn route(&self, fd: Option<RawFd>) -> usize {
+        if let Some((i, _)) = fd.and_then(|fd| self.fds.get(&fd)) {
+            return *i;
+        }
+        (0..self.rings.len()).min_by_key(|i| self.rings[*i].inflight.len()).unwrap()
+    }
+
+    /// Prepare a request via prep on the ring it is routed to (see [`Self::route`]), and return
+    /// the index of the ring
+    ///
+    /// fd is the affinity hint: requests with the same fd go to the same ring while any of them is
+    /// in flight. prep does not need to set the user data: it is set to user_data. If the
+    /// submission queue is full, the queued requests of the ring are submitted to make room. Fails
+    /// with `InvalidInput` if there is a request in flight with the same user data on the ring.
+    pub fn queue<F>(&mut self, fd: Option<RawFd>, user_data: u64, prep: F) -> io::Result<usize>
+    where
+        F: FnOnce(&mut SQEntry),
+    {
+        let i = self.route(fd);
+        let r = &mut self.rings[i];
+        if r.inflight.contains_key(&user_data) {
+            let msg = format!("user data {:#x} is already in flight on ring {}", user_data, i);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        loop {
+            if let Some(mut sqe) = r.ring.get_sqe() {
+                prep(&mut sqe);
+                sqe.set_data(user_data);
+                break;
+            }
+            r.ring.submit()?;
+        }
+        r.inflight.insert(user_data, fd);
+        if let Some(fd) = fd {
+            self.fds.entry(fd).or_insert((i, 0)).1 += 1;
+        }
+        Ok(i)
+    }
+
+    /// Submit the queued requests of all rings
+    pub fn submit(&mut self) -> io::Result<()> {
+        for r in self.rings.iter_mut() {
+            r.ring.submit()?;
+        }
+        Ok(())
+    }
+
+    /// Pass the available cqes of all rings, with the index of their ring, to f, and return
+    /// their number
+    ///
+    /// A request is no longer in flight once its last cqe (i.e., without IORING_CQE_F_MORE) is
+    /// reaped.
+    pub fn reap<F>(&mut self, mut f: F) -> usize
+    where
+        F: FnMut(usize, io_uring_cqe),
+    {
+        let mut n = 0;
+        for (i, r) in self.rings.iter_mut().enumerate() {
+            while let Some(cqe) = r.ring.pop_cqe() {
+                n += 1;
+                if !cqe.has_more() {
+                    if let Some(Some(fd)) = r.inflight.remove(&cqe.user_data()) {
+                        let e = self.fds.get_mut(&fd).unwrap();
+                        e.1 -= 1;
+                        if e.1 == 0 {
+                            self.fds.remove(&fd);
+                        }
+                    }
+                }
+                f(i, cqe);
+            }
+        }
+        n
+    }
+}
@@ -0,0 +1,143 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Child processes, reaped via WAITID requests (Linux 6.7)
+//
+// A WAITID request for a child completes when the child exits, so child exits are delivered
+// through the completion queue, and supervisors do not need SIGCHLD handling or a thread blocked
+// in waitpid().
+//
+// The user data of waitid requests is the tag (top 8 bits) and the pid of the child. The
+// siginfo_t that the kernel fills in is kept in the Children until the request completes.
+//
+// Reference: io_uring_prep_waitid(3), waitid(2)
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, Command, ExitStatus};
+
+use crate::io_uring::{io_uring_cqe, IoUring};
+
+const TAG_SHIFT: u32 = 56;
+
+/// Child processes whose exits are delivered as cqes
+///
+/// The application passes cqes to [`Self::exited`]:
+///
+/// ```no_run
+/// # use iouring::io_uring::IoUring;
+/// # use iouring::process::Children;
+/// let mut ring = IoUring::init(32).unwrap();
+/// let mut children = Children::new(0xfe);
+/// children.spawn(&mut ring, &mut std::process::Command::new("true")).unwrap();
+/// while !children.is_empty() {
+///     ring.submit_and_wait(1).unwrap();
+///     while let Some(cqe) = ring.pop_cqe() {
+///         if let Some((pid, status)) = children.exited(&cqe) {
+///             println!("{} exited: {:?}", pid, status);
+///         }
+///     }
+/// }
+/// ```
+///
+/// NB: the waitid requests reap the children, so they should not be waited for otherwise (e.g.,
+/// via `Child::wait`, or with a SIGCHLD handler that calls waitpid(-1, ...)). The requests need to
+/// complete before the Children is dropped, since the kernel writes to memory it owns.
+pub struct Children {
+    tag: u64,
+    // children with a waitid request in flight: pid -> siginfo
+    waiting: HashMap<u32, Box<libc::siginfo_t>>,
+}
+
+impl Children {
+
+    /// Children whose requests use the given tag in the top 8 bits of their user data
+    ///
+    /// No other requests on the ring should have user data with this tag.
+    pub fn new(tag: u8) -> Children {
+        Children {
+            tag: (tag as u64) << TAG_SHIFT,
+            waiting: HashMap::new(),
+        }
+    }
+
+    /// Spawn a child, and queue a request for its exit
+    ///
+    /// The returned `Child` is useful for its pid and stdio handles, but it must not be waited for.
+    pub fn spawn(&mut self, ring: &mut IoUring, cmd: &mut Command) -> io::Result<Child> {
+        let child = cmd.spawn()?;
+        self.adopt(ring, child.id())?;
+        Ok(child)
+    }
+
+    /// Queue a request for the exit of pid, which needs to be a child of this process
+    ///
+    /// Queued requests are submitted if the submission queue is full. Fails with `InvalidInput`
+    /// if there is already a request for pid.
+    pub fn adopt(&mut self, ring: &mut IoUring, pid: u32) -> io::Result<()> {
+        if self.waiting.contains_key(&pid) {
+            let msg = format!("already waiting for {}", pid);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let mut info: Box<libc::siginfo_t> = Box::new(unsafe { std::mem::zeroed() });
+        let infop: *mut libc::siginfo_t = &mut *info;
+        let ud = self.tag | pid as u64;
+        loop {
+            if let Some(mut sqe) = ring.get_sqe() {
+                sqe.prep_waitid(libc::P_PID, pid, infop, libc::WEXITED, 0);
+                sqe.set_data(ud);
+                break;
+            }
+            ring.submit()?;
+        }
+        self.waiting.insert(pid, info);
+        Ok(())
+    }
+
+    /// Number of children with a request in flight
+    pub fn len(&self) -> usize {
+        self.waiting.len()
+    }
+
+    /// Whether there are no children with a request in flight
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+
+    /// Whether the cqe is for a request of these children
+    pub fn owns(&self, cqe: &io_uring_cqe) -> bool {
+        cqe.user_data() >> TAG_SHIFT == self.tag >> TAG_SHIFT
+    }
+
+    /// Handle a cqe, and return the pid and the exit status of the child it is for
+    ///
+    /// Returns None if the cqe is not for these children (see [`Self::owns`]). The status is an
+    /// error if the request failed, e.g., with ECHILD if pid is not a child of this process.
+    pub fn exited(&mut self, cqe: &io_uring_cqe) -> Option<(u32, io::Result<ExitStatus>)> {
+        if !self.owns(cqe) {
+            return None;
+        }
+        let pid = cqe.user_data() as u32;
+        let info = self.waiting.remove(&pid)?;
+        if cqe.res() < 0 {
+            return Some((pid, Err(io::Error::from_raw_os_error(-cqe.res()))));
+        }
+
+        // NB: ExitStatus wraps a wait status, so build one from the siginfo
+        let status = unsafe { info.si_status() };
+        let raw = match info.si_code {
+            libc::CLD_EXITED => (status & 0xff) << 8,
+            libc::CLD_KILLED => status & 0x7f,
+            libc::CLD_DUMPED => (status & 0x7f) | 0x80,
+            code => {
+                let msg = format!("unexpected si_code: {}", code);
+                return Some((pid, Err(io::Error::other(msg))));
+            }
+        };
+        Some((pid, Ok(ExitStatus::from_raw(raw))))
+    }
+}
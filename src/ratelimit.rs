@@ -0,0 +1,250 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Rate limiting requests, via token buckets
+//
+// Background work (e.g., scrubbing, or backfilling a replica) can issue requests as fast as the
+// device completes them, and starve the latency-sensitive requests that share the ring (and the
+// device) with it. Throttle queues the requests of such work, and issues them only as fast as its
+// budgets allow: requests per second, bytes per second, or both, either for all of its requests,
+// or separately for each fd. Requests that are not issued via a Throttle are not limited.
+//
+// Each budget is a token bucket: it fills at the given rate, up to a burst of `burst` worth of
+// tokens, and each request takes a token (and a token per byte, for the bytes budget). A request
+// that is larger than the burst is issued when the bucket is full, and leaves the bucket in debt,
+// so large requests are delayed, not blocked forever.
+//
+// Throttle never sleeps: Throttle::submit_ready() issues the requests that are within budget, and
+// returns how long until the next one is, so that the caller can wait for that long (e.g., with a
+// timeout on the ring) along with its other work.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+use crate::io_uring::{IoUring, SQEntry};
+
+/// Budgets of a [`Throttle`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub ops_per_sec: Option<u64>,
+    pub bytes_per_sec: Option<u64>,
+    /// How much of the rate can be used at once, after an idle period
+    pub burst: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            ops_per_sec: None,
+            bytes_per_sec: None,
+            burst: Duration::from_millis(100),
+        }
+    }
+}
+
+/// A token bucket, filling at rate tokens per second, up to a capacity
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+
+    /// A full bucket, filling at rate tokens per second, with a capacity of burst worth of tokens
+    /// (and at least one token)
+    pub fn new(rate: u64, burst: Duration, now: Instant) -> TokenBucket {
+        let capacity = (rate as f64 * burst.as_secs_f64()).max(1.0);
+        TokenBucket {
+            rate: rate as f64,
+            capacity,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = std::cmp::max(self.last, now);
+    }
+
+    /// Time until n tokens can be taken (zero if they can be taken now)
+    pub fn wait_time(&mut self, n: u64, now: Instant) -> Duration {
+        self.refill(now);
+        // NB: requests larger than the capacity wait for a full bucket
+        let need = (n as f64).min(self.capacity);
+        if self.tokens >= need {
+            return Duration::ZERO;
+        }
+        if self.rate == 0.0 {
+            return Duration::MAX;
+        }
+        Duration::from_secs_f64((need - self.tokens) / self.rate)
+    }
+
+    /// Whether the bucket is full, i.e., it is as if it was never used
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+
+    /// Take n tokens, which may leave the bucket in debt
+    pub fn take(&mut self, n: u64, now: Instant) {
+        self.refill(now);
+        self.tokens -= n as f64;
+    }
+}
+
+// The buckets for a budget scope (the throttle, or an fd)
+#[derive(Debug, Clone)]
+struct Buckets {
+    ops: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn new(limits: &Limits, now: Instant) -> Buckets {
+        Buckets {
+            ops: limits.ops_per_sec.map(|r| TokenBucket::new(r, limits.burst, now)),
+            bytes: limits.bytes_per_sec.map(|r| TokenBucket::new(r, limits.burst, now)),
+        }
+    }
+
+    fn wait_time(&mut self, bytes: u64, now: Instant) -> Duration {
+        let ops = self.ops.as_mut().map_or(Duration::ZERO, |b| b.wait_time(1, now));
+        let bytes = self.bytes.as_mut().map_or(Duration::ZERO, |b| b.wait_time(bytes, now));
+        std::cmp::max(ops, bytes)
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.ops.as_mut().is_none_or(|b| b.is_full(now))
+            && self.bytes.as_mut().is_none_or(|b| b.is_full(now))
+    }
+
+    fn take(&mut self, bytes: u64, now: Instant) {
+        if let Some(ref mut b) = self.ops {
+            b.take(1, now);
+        }
+        if let Some(ref mut b) = self.bytes {
+            b.take(bytes, now);
+        }
+    }
+}
+
+struct Queued<P> {
+    fd: RawFd,
+    bytes: u64,
+    prep: P,
+}
+
+/// Requests issued within budgets (see [`Limits`])
+///
+/// The requests are prepared by calling their prep function on an sqe, which needs to set their
+/// user data. They are issued in order, except that with per-fd budgets, requests on an fd that
+/// is out of budget do not hold back requests on other fds.
+pub struct Throttle<P> {
+    limits: Limits,
+    // budgets of the throttle, or of each fd (NB: full fd buckets are removed when the fd has no
+    // queued requests, since a new bucket is the same)
+    global: Option<Buckets>,
+    per_fd: HashMap<RawFd, Buckets>,
+    queue: VecDeque<Queued<P>>,
+}
+
+impl<P: FnOnce(&mut SQEntry)> Throttle<P> {
+
+    /// A throttle whose budgets apply to all of its requests
+    pub fn new(limits: Limits) -> Throttle<P> {
+        Throttle {
+            limits,
+            global: Some(Buckets::new(&limits, Instant::now())),
+            per_fd: HashMap::new(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// A throttle whose budgets apply separately to the requests on each fd
+    pub fn per_fd(limits: Limits) -> Throttle<P> {
+        Throttle {
+            global: None,
+            ..Throttle::new(limits)
+        }
+    }
+
+    /// Queue a request on fd that transfers bytes bytes (0, if it does not transfer data)
+    pub fn push(&mut self, fd: RawFd, bytes: u64, prep: P) {
+        self.queue.push_back(Queued { fd, bytes, prep });
+    }
+
+    /// Number of queued requests
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Queue the requests that are within budget to ring, and return the time until the next
+    /// request is, or None if there are no more requests
+    ///
+    /// The requests are queued, not submitted, unless the submission queue fills up.
+    pub fn submit_ready(&mut self, ring: &mut IoUring) -> io::Result<Option<Duration>> {
+        self.submit_ready_at(ring, Instant::now())
+    }
+
+    /// Like [`Self::submit_ready`], with the given current time
+    pub fn submit_ready_at(&mut self, ring: &mut IoUring, now: Instant)
+    -> io::Result<Option<Duration>> {
+        let mut next: Option<Duration> = None;
+        let in_order = self.global.is_some();
+        // fds with a request that is out of budget: their later requests need to wait for it
+        let mut blocked = HashSet::new();
+        let mut i = 0;
+        while i < self.queue.len() {
+            let (fd, bytes) = (self.queue[i].fd, self.queue[i].bytes);
+            if blocked.contains(&fd) {
+                i += 1;
+                continue;
+            }
+            let buckets = match self.global {
+                Some(ref mut x) => x,
+                None => {
+                    let limits = &self.limits;
+                    self.per_fd.entry(fd).or_insert_with(|| Buckets::new(limits, now))
+                }
+            };
+            let wait = buckets.wait_time(bytes, now);
+            if wait > Duration::ZERO {
+                next = Some(next.map_or(wait, |x| x.min(wait)));
+                if in_order {
+                    break;
+                }
+                blocked.insert(fd);
+                i += 1;
+                continue;
+            }
+
+            loop {
+                if let Some(mut sqe) = ring.get_sqe() {
+                    buckets.take(bytes, now);
+                    let q = self.queue.remove(i).unwrap();
+                    (q.prep)(&mut sqe);
+                    break;
+                }
+                ring.submit()?;
+            }
+        }
+        // NB: in per-fd mode, every fd with queued requests is blocked at this point
+        self.per_fd.retain(|fd, b| blocked.contains(fd) || !b.is_full(now));
+        Ok(next)
+    }
+}
@@ -0,0 +1,359 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Recording the submission and completion stream of a ring, and replaying it
+//
+// Bugs that depend on the kernel (e.g., on the order in which requests are submitted, or on how
+// they are batched) are hard to reproduce without the exact submission stream. A Recorder attached
+// to a ring (see IoUring::set_recorder()) logs every submitted sqe, every submission, and every
+// reaped cqe, either to an in-memory buffer of the last records, or to a file. Records are lines
+// of decoded fields, e.g.:
+//
+//   sqe op=READV flags=0x0 ioprio=0 fd=3 off=0 addr=0x7ffc1d2e4a10 len=2 op_flags=0x0 ...
+//   submit n=1
+//   cqe user_data=0x1 res=16 flags=0x0
+//
+// and replay() re-issues the sqes of a log, with the same submission boundaries. The extra 64
+// bytes of 128-byte sqes (SQE128) are recorded, but the extra 16 bytes of 32-byte cqes are not.
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::io::{self, BufRead, Write};
+
+use crate::io_uring::{IoUring, OpCode};
+
+/// The fields of a submitted sqe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordedSqe {
+    pub opcode: u8,
+    pub flags: u8,
+    pub ioprio: u16,
+    pub fd: i32,
+    pub off: u64,
+    pub addr: u64,
+    pub len: u32,
+    /// The opcode-specific flags (e.g., rw_flags, or poll32_events)
+    pub op_flags: u32,
+    pub user_data: u64,
+    pub buf_index: u16,
+    pub personality: u16,
+    pub file_index: u32,
+    pub addr3: u64,
+    pub pad: u64,
+    /// The second half of 128-byte sqes (e.g., the command of URING_CMD requests)
+    pub cmd: Option<[u8; 64]>,
+}
+
+impl RecordedSqe {
+
+    /// Decode a raw sqe (64, or 128 bytes)
+    pub(crate) fn from_bytes(b: &[u8]) -> RecordedSqe {
+        let u16_at = |off: usize| u16::from_ne_bytes(b[off..off + 2].try_into().unwrap());
+        let u32_at = |off: usize| u32::from_ne_bytes(b[off..off + 4].try_into().unwrap());
+        let u64_at = |off: usize| u64::from_ne_bytes(b[off..off + 8].try_into().unwrap());
+        RecordedSqe {
+            opcode: b[0],
+            flags: b[1],
+            ioprio: u16_at(2),
+            fd: u32_at(4) as i32,
+            off: u64_at(8),
+            addr: u64_at(16),
+            len: u32_at(24),
+            op_flags: u32_at(28),
+            user_data: u64_at(32),
+            buf_index: u16_at(40),
+            personality: u16_at(42),
+            file_index: u32_at(44),
+            addr3: u64_at(48),
+            pad: u64_at(56),
+            cmd: b.get(64..128).map(|x| x.try_into().unwrap()),
+        }
+    }
+
+    /// Encode as a raw sqe: 64 bytes, or 128 if there is a cmd
+    pub(crate) fn to_bytes(self) -> ([u8; 128], usize) {
+        let mut b = [0u8; 128];
+        b[0] = self.opcode;
+        b[1] = self.flags;
+        b[2..4].copy_from_slice(&self.ioprio.to_ne_bytes());
+        b[4..8].copy_from_slice(&self.fd.to_ne_bytes());
+        b[8..16].copy_from_slice(&self.off.to_ne_bytes());
+        b[16..24].copy_from_slice(&self.addr.to_ne_bytes());
+        b[24..28].copy_from_slice(&self.len.to_ne_bytes());
+        b[28..32].copy_from_slice(&self.op_flags.to_ne_bytes());
+        b[32..40].copy_from_slice(&self.user_data.to_ne_bytes());
+        b[40..42].copy_from_slice(&self.buf_index.to_ne_bytes());
+        b[42..44].copy_from_slice(&self.personality.to_ne_bytes());
+        b[44..48].copy_from_slice(&self.file_index.to_ne_bytes());
+        b[48..56].copy_from_slice(&self.addr3.to_ne_bytes());
+        b[56..64].copy_from_slice(&self.pad.to_ne_bytes());
+        match self.cmd {
+            Some(cmd) => {
+                b[64..].copy_from_slice(&cmd);
+                (b, 128)
+            }
+            None => (b, 64),
+        }
+    }
+}
+
+/// The fields of a reaped cqe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordedCqe {
+    pub user_data: u64,
+    pub res: i32,
+    pub flags: u32,
+}
+
+/// An entry of a recorded log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Record {
+    /// An sqe was flushed to the submission queue
+    Sqe(RecordedSqe),
+    /// The preceding n sqes were flushed to the kernel, as a batch (e.g., by a submit call)
+    Submit(u32),
+    /// A cqe was reaped
+    Cqe(RecordedCqe),
+}
+
+impl std::fmt::Display for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Record::Sqe(s) => {
+                match OpCode::from_u8(s.opcode) {
+                    Some(op) => write!(f, "sqe op={}", op)?,
+                    None => write!(f, "sqe op={}", s.opcode)?,
+                }
+                write!(f, " flags={:#x} ioprio={} fd={} off={} addr={:#x} len={} op_flags={:#x}",
+                    s.flags, s.ioprio, s.fd, s.off, s.addr, s.len, s.op_flags)?;
+                write!(f, " user_data={:#x} buf_index={} personality={} file_index={}",
+                    s.user_data, s.buf_index, s.personality, s.file_index)?;
+                write!(f, " addr3={:#x} pad={:#x}", s.addr3, s.pad)?;
+                if let Some(cmd) = s.cmd {
+                    write!(f, " cmd=")?;
+                    for x in cmd.iter() {
+                        write!(f, "{:02x}", x)?;
+                    }
+                }
+                Ok(())
+            }
+            Record::Submit(n) => write!(f, "submit n={}", n),
+            Record::Cqe(c) => {
+                write!(f, "cqe user_data={:#x} res={} flags={:#x}", c.user_data, c.res, c.flags)
+            }
+        }
+    }
+}
+
+fn invalid(line: &str, what: &str) -> io::Error {
+    let msg = format!("invalid record ({}): {}", what, line);
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn parse_u64(val: &str) -> Option<u64> {
+    match val.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => val.parse().ok(),
+    }
+}
+
+fn parse_opcode(val: &str) -> Option<u8> {
+    (0..=u8::MAX)
+        .find(|x| OpCode::from_u8(*x).map(|op| op.name() == val).unwrap_or(false))
+        .or_else(|| val.parse().ok())
+}
+
+fn parse_cmd(val: &str) -> Option<[u8; 64]> {
+    if val.len() != 128 {
+        return None;
+    }
+    let mut ret = [0u8; 64];
+    for (i, x) in ret.iter_mut().enumerate() {
+        *x = u8::from_str_radix(val.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(ret)
+}
+
+impl std::str::FromStr for Record {
+    type Err = io::Error;
+
+    /// Parse a record, as printed by Display
+    fn from_str(line: &str) -> io::Result<Record> {
+        let mut words = line.split_whitespace();
+        let kind = words.next().ok_or_else(|| invalid(line, "empty"))?;
+        let mut sqe = RecordedSqe::from_bytes(&[0u8; 64]);
+        let mut cqe = RecordedCqe { user_data: 0, res: 0, flags: 0 };
+        let mut n = 0;
+        for word in words {
+            let (key, val) = word.split_once('=').ok_or_else(|| invalid(line, word))?;
+            let num = || parse_u64(val).ok_or_else(|| invalid(line, word));
+            match (kind, key) {
+                ("sqe", "op") => {
+                    sqe.opcode = parse_opcode(val).ok_or_else(|| invalid(line, word))?
+                }
+                ("sqe", "flags") => sqe.flags = num()? as u8,
+                ("sqe", "ioprio") => sqe.ioprio = num()? as u16,
+                ("sqe", "fd") => sqe.fd = val.parse().map_err(|_| invalid(line, word))?,
+                ("sqe", "off") => sqe.off = num()?,
+                ("sqe", "addr") => sqe.addr = num()?,
+                ("sqe", "len") => sqe.len = num()? as u32,
+                ("sqe", "op_flags") => sqe.op_flags = num()? as u32,
+                ("sqe", "user_data") => sqe.user_data = num()?,
+                ("sqe", "buf_index") => sqe.buf_index = num()? as u16,
+                ("sqe", "personality") => sqe.personality = num()? as u16,
+                ("sqe", "file_index") => sqe.file_index = num()? as u32,
+                ("sqe", "addr3") => sqe.addr3 = num()?,
+                ("sqe", "pad") => sqe.pad = num()?,
+                ("sqe", "cmd") => {
+                    sqe.cmd = Some(parse_cmd(val).ok_or_else(|| invalid(line, word))?)
+                }
+                ("submit", "n") => n = num()? as u32,
+                ("cqe", "user_data") => cqe.user_data = num()?,
+                ("cqe", "res") => cqe.res = val.parse().map_err(|_| invalid(line, word))?,
+                ("cqe", "flags") => cqe.flags = num()? as u32,
+                _ => return Err(invalid(line, word)),
+            }
+        }
+        match kind {
+            "sqe" => Ok(Record::Sqe(sqe)),
+            "submit" => Ok(Record::Submit(n)),
+            "cqe" => Ok(Record::Cqe(cqe)),
+            _ => Err(invalid(line, kind)),
+        }
+    }
+}
+
+enum Sink {
+    Buffer(VecDeque<Record>, usize),
+    Writer(Box<dyn Write + Send>),
+    // the writer failed
+    Failed,
+}
+
+/// A log of the sqes, submissions, and cqes of a ring (see [`IoUring::set_recorder`])
+///
+/// ```no_run
+/// # use iouring::io_uring::IoUring;
+/// # use iouring::record::Recorder;
+/// let mut ring = IoUring::init(32).unwrap();
+/// ring.set_recorder(Some(Recorder::buffer(1024)));
+/// // ... submit and reap requests ...
+/// // the last 1024 records, e.g., to attach to a bug report
+/// for rec in ring.recorder().unwrap().records() {
+///     eprintln!("{}", rec);
+/// }
+/// ```
+///
+/// Recording costs a formatted line (for writers), or a copy of the record (for buffers), per sqe
+/// and cqe. It is meant for debugging, so it is off by default.
+pub struct Recorder {
+    sink: Sink,
+}
+
+impl Recorder {
+
+    /// Recorder that keeps the last capacity records in memory (see [`Self::records`])
+    pub fn buffer(capacity: usize) -> Recorder {
+        Recorder { sink: Sink::Buffer(VecDeque::with_capacity(capacity), capacity) }
+    }
+
+    /// Recorder that writes records to w, one per line
+    ///
+    /// If writing fails, recording stops, and a warning is printed.
+    pub fn writer<W: Write + Send + 'static>(w: W) -> Recorder {
+        Recorder { sink: Sink::Writer(Box::new(w)) }
+    }
+
+    /// Recorder that writes records to the file at path, which is created (or truncated)
+    pub fn file<P: AsRef<std::path::Path>>(path: P) -> io::Result<Recorder> {
+        let f = std::fs::File::create(path)?;
+        Ok(Recorder::writer(io::BufWriter::new(f)))
+    }
+
+    pub(crate) fn record(&mut self, rec: Record) {
+        match &mut self.sink {
+            Sink::Buffer(records, capacity) => {
+                if *capacity == 0 {
+                    return;
+                }
+                if records.len() == *capacity {
+                    records.pop_front();
+                }
+                records.push_back(rec);
+            }
+            Sink::Writer(w) => {
+                if let Err(err) = writeln!(w, "{}", rec) {
+                    eprintln!("WARNING: recording failed, stopping: {}", err);
+                    self.sink = Sink::Failed;
+                }
+            }
+            Sink::Failed => (),
+        }
+    }
+
+    /// The buffered records, oldest first (none for writers)
+    pub fn records(&self) -> impl Iterator<Item = &Record> {
+        let records = match &self.sink {
+            Sink::Buffer(records, _) => Some(records.iter()),
+            _ => None,
+        };
+        records.into_iter().flatten()
+    }
+
+    /// Flush the writer, if any
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::Writer(w) => w.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Re-issue the sqes of a log (as written by a [`Recorder`]), submitting them in the recorded
+/// batches, and return the number of submitted sqes
+///
+/// Each sqe is passed to fixup before it is queued, which can change it (e.g., to point addr to a
+/// buffer of the replaying process, or to map fds), or skip it by returning false. Cqe records are
+/// ignored: the cqes of the replayed requests are left on the ring, to be compared against the log
+/// by the caller.
+///
+/// # Safety
+///
+/// The kernel accesses the memory that the replayed sqes point to (e.g., addr, and addr3), so
+/// these need to be valid for the requests, after fixup.
+pub unsafe fn replay<R, F>(ring: &mut IoUring, log: R, mut fixup: F) -> io::Result<usize>
+where
+    R: BufRead,
+    F: FnMut(&mut RecordedSqe) -> bool,
+{
+    let mut submitted = 0;
+    for line in log.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.parse()? {
+            Record::Sqe(mut sqe) => {
+                if !fixup(&mut sqe) {
+                    continue;
+                }
+                let (bytes, len) = sqe.to_bytes();
+                loop {
+                    if let Some(mut ring_sqe) = ring.get_sqe() {
+                        ring_sqe.copy_from_bytes(&bytes[..len])?;
+                        break;
+                    }
+                    submitted += ring.submit()? as usize;
+                }
+            }
+            Record::Submit(_) => submitted += ring.submit()? as usize,
+            Record::Cqe(_) => (),
+        }
+    }
+    submitted += ring.submit()? as usize;
+    Ok(submitted)
+}
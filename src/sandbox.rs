@@ -0,0 +1,169 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Restricted rings (IORING_REGISTER_RESTRICTIONS, Linux 5.10)
+//
+// A ring can be created disabled (IORING_SETUP_R_DISABLED), so that the application can register
+// resources and a set of restrictions before any request is submitted. Once the ring is enabled,
+// the kernel rejects (with EACCES) requests whose opcode is not allowed, or whose IOSQE_* flags are
+// not allowed, as well as register operations that are not allowed. Restrictions cannot be changed
+// afterwards, so the ring can be handed to less trusted code.
+//
+// SandboxBuilder does all of this in one place, in the order the kernel expects:
+//   setup (disabled) -> register files/buffers/personality -> register restrictions -> enable
+//
+// Reference: io_uring_register_restrictions(3), io_uring_enable_rings(3)
+
+use std::convert::TryFrom;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use crate::io_uring::{io_uring_register, IoUring, OpCode, SetupFlags, SqeFlags};
+use crate::io_uring::IORING_REGISTER_RESTRICTIONS;
+
+// restriction opcodes
+const IORING_RESTRICTION_REGISTER_OP: u16 = 0;
+const IORING_RESTRICTION_SQE_OP: u16 = 1;
+const IORING_RESTRICTION_SQE_FLAGS_ALLOWED: u16 = 2;
+const IORING_RESTRICTION_SQE_FLAGS_REQUIRED: u16 = 3;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct io_uring_restriction {
+    opcode: u16,
+    // register_op, sqe_op, or sqe_flags, depending on opcode
+    arg: u8,
+    resv: u8,
+    resv2: [u32; 3],
+}
+
+const _: () = assert!(std::mem::size_of::<io_uring_restriction>() == 16);
+
+/// A ring created by [`SandboxBuilder`], and the personality registered for it (if any)
+pub struct Sandbox {
+    pub ring: IoUring,
+    /// Use with [`crate::io_uring::SQEntry::set_personality`] to issue requests with the
+    /// credentials of the task that built the sandbox
+    pub personality: Option<u16>,
+}
+
+/// Builder for a restricted ring
+///
+/// Only the allowed requests and register operations are permitted on the resulting ring, so at
+/// least one request opcode needs to be allowed for the ring to be useful.
+///
+/// NB: dropping an IoUring with [`crate::io_uring::ShutdownPolicy::CancelAndWait`] issues
+/// ASYNC_CANCEL requests for in-flight requests, so allow [`OpCode::AsyncCancel`] if relying on it.
+pub struct SandboxBuilder<'a> {
+    entries: u32,
+    flags: SetupFlags,
+    restrictions: Vec<io_uring_restriction>,
+    files: Option<&'a [RawFd]>,
+    buffers: Option<&'a [io::IoSliceMut<'a>]>,
+    personality: bool,
+}
+
+impl<'a> SandboxBuilder<'a> {
+
+    /// A sandbox for a ring of (at least) `entries` entries, with nothing allowed
+    pub fn new(entries: u32) -> SandboxBuilder<'a> {
+        SandboxBuilder {
+            entries,
+            flags: SetupFlags::empty(),
+            restrictions: vec![],
+            files: None,
+            buffers: None,
+            personality: false,
+        }
+    }
+
+    /// Additional setup flags for the ring
+    pub fn setup_flags(mut self, flags: SetupFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    fn restriction(mut self, opcode: u16, arg: u8) -> Self {
+        self.restrictions.push(io_uring_restriction { opcode, arg, resv: 0, resv2: [0; 3] });
+        self
+    }
+
+    /// Allow requests with the given opcode
+    pub fn allow_op(self, op: OpCode) -> Self {
+        self.restriction(IORING_RESTRICTION_SQE_OP, op as u8)
+    }
+
+    /// Allow the given register operation (IORING_REGISTER_*) on the enabled ring
+    pub fn allow_register_op(self, op: u8) -> Self {
+        self.restriction(IORING_RESTRICTION_REGISTER_OP, op)
+    }
+
+    /// Allow requests to set the given flags (in addition to the required ones)
+    pub fn allow_sqe_flags(self, flags: SqeFlags) -> Self {
+        self.restriction(IORING_RESTRICTION_SQE_FLAGS_ALLOWED, flags.bits())
+    }
+
+    /// Require requests to set the given flags, e.g., [`SqeFlags::FIXED_FILE`] to restrict them
+    /// to the registered files
+    pub fn require_sqe_flags(self, flags: SqeFlags) -> Self {
+        self.restriction(IORING_RESTRICTION_SQE_FLAGS_REQUIRED, flags.bits())
+    }
+
+    /// Files to register (see [`IoUring::register_files`])
+    pub fn files(mut self, fds: &'a [RawFd]) -> Self {
+        self.files = Some(fds);
+        self
+    }
+
+    /// Buffers to register (see [`IoUring::register_buffers`])
+    ///
+    /// The buffers need to stay alive for as long as the ring.
+    pub fn buffers(mut self, bufs: &'a [io::IoSliceMut<'a>]) -> Self {
+        self.buffers = Some(bufs);
+        self
+    }
+
+    /// Register the credentials of the current task as a personality (Linux 5.6), e.g., before
+    /// dropping privileges
+    pub fn personality(mut self) -> Self {
+        self.personality = true;
+        self
+    }
+
+    /// Create the ring, register the resources and the restrictions, and enable it
+    pub fn build(self) -> io::Result<Sandbox> {
+        let flags = self.flags | SetupFlags::R_DISABLED;
+        let mut ring = IoUring::init_with_flags(self.entries, flags)?;
+        if let Some(fds) = self.files {
+            ring.register_files(fds)?;
+        }
+        if let Some(bufs) = self.buffers {
+            ring.register_buffers(bufs)?;
+        }
+
+        let personality = if self.personality {
+            Some(ring.register_personality()?)
+        } else {
+            None
+        };
+
+        let nr = match libc::c_uint::try_from(self.restrictions.len()) {
+            Ok(x) => x,
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many restrictions"))
+            }
+        };
+        let arg = self.restrictions.as_ptr() as *mut libc::c_void;
+        let fd = ring.as_raw_fd();
+        let err = unsafe { io_uring_register(fd, IORING_REGISTER_RESTRICTIONS, arg, nr) };
+        if err < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        ring.enable()?;
+        Ok(Sandbox { ring, personality })
+    }
+}
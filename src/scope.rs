@@ -0,0 +1,207 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Scoped requests
+//
+// The kernel accesses the memory of a request (e.g., its buffers) until the request completes, so
+// the memory needs to outlive the request, and freeing it earlier is a use-after-free that the
+// borrow checker cannot see. This is the same problem that std::thread::scope() solves for
+// threads: scope() runs a closure that issues requests via a Scope, and returns only after every
+// request issued in the scope has posted its terminal cqe. Hence, requests in the scope can use
+// memory that outlives the scope, such as buffers on the stack of the caller.
+//
+// If the closure panics, the requests still in flight are cancelled, and waited for, before the
+// panic continues. If waiting fails, the process aborts, since returning would leave the kernel
+// accessing memory that is about to be freed.
+//
+// The user data of scope requests is the tag (top 8 bits) and the id of the request in the scope.
+
+use std::collections::HashSet;
+use std::io;
+
+use crate::io_uring::{io_uring_cqe, IoUring, SQEntry};
+
+const TAG_SHIFT: u32 = 56;
+const ID_MASK: u64 = (1 << TAG_SHIFT) - 1;
+// id of the cancel requests issued by the scope
+const CANCEL_ID: u64 = ID_MASK;
+
+/// Run f with a [`Scope`] for issuing requests on ring, and wait for all of them to complete
+///
+/// ```no_run
+/// # use iouring::io_uring::IoUring;
+/// # let fd = 0;
+/// let mut ring = IoUring::init(32).unwrap();
+/// let mut buf = [0u8; 4096];
+/// let iov = libc::iovec { iov_base: buf.as_mut_ptr() as _, iov_len: buf.len() };
+/// let (res, _other) = iouring::scope::scope(&mut ring, 0xfc, |s| {
+///     s.push(|sqe| sqe.prep_readv(fd, &iov, 1, 0)).unwrap();
+///     s.wait().unwrap().map(|(_, cqe)| cqe.res())
+///     // NB: had we not waited, scope() would wait for the read before returning
+/// });
+/// ```
+///
+/// Everything that a pushed request points to (buffers, iovecs, timespecs, ...) needs to outlive
+/// the call to scope(), i.e., it cannot be a local of f: requests that f did not wait for are
+/// submitted and waited for after f returns, when its locals are gone.
+///
+/// Requests are identified by the tag in the top 8 bits of their user data, and no other requests
+/// on the ring should have user data with this tag. The cqes of other requests that are reaped
+/// within the scope are returned along with the result of f. The requests need to post a terminal
+/// cqe, i.e., they cannot use IOSQE_CQE_SKIP_SUCCESS.
+pub fn scope<F, R>(ring: &mut IoUring, tag: u8, f: F) -> (R, Vec<io_uring_cqe>)
+where
+    F: FnOnce(&mut Scope<'_>) -> R,
+{
+    let mut s = Scope {
+        ring,
+        tag: (tag as u64) << TAG_SHIFT,
+        next_id: 0,
+        inflight: HashSet::new(),
+        other: vec![],
+    };
+    let ret = f(&mut s);
+    // NB: the remaining requests are waited for when s is dropped
+    s.finish(false);
+    let other = std::mem::take(&mut s.other);
+    (ret, other)
+}
+
+/// Requests issued within [`scope`]
+pub struct Scope<'ring> {
+    ring: &'ring mut IoUring,
+    tag: u64,
+    next_id: u64,
+    // ids of the requests that have not posted their terminal cqe
+    inflight: HashSet<u64>,
+    other: Vec<io_uring_cqe>,
+}
+
+impl Scope<'_> {
+
+    /// Whether the cqe is for a request of this scope (including its cancel requests)
+    pub fn owns(&self, cqe: &io_uring_cqe) -> bool {
+        cqe.user_data() >> TAG_SHIFT == self.tag >> TAG_SHIFT
+    }
+
+    /// Queue a request, prepared by calling prep on an sqe, and return its id
+    ///
+    /// prep does not need to set the user data: it is overwritten. If the submission queue is full,
+    /// the queued requests are submitted first.
+    pub fn push<P>(&mut self, prep: P) -> io::Result<u64>
+    where
+        P: FnOnce(&mut SQEntry),
+    {
+        let id = self.next_id;
+        if id == CANCEL_ID {
+            return Err(io::Error::other("out of scope request ids"));
+        }
+        self.queue(id, prep)?;
+        self.next_id += 1;
+        self.inflight.insert(id);
+        Ok(id)
+    }
+
+    fn queue<P>(&mut self, id: u64, prep: P) -> io::Result<()>
+    where
+        P: FnOnce(&mut SQEntry),
+    {
+        loop {
+            if let Some(mut sqe) = self.ring.get_sqe() {
+                prep(&mut sqe);
+                sqe.set_data(self.tag | id);
+                return Ok(());
+            }
+            self.ring.submit()?;
+        }
+    }
+
+    /// Submit the queued requests
+    pub fn submit(&mut self) -> io::Result<u32> {
+        self.ring.submit()
+    }
+
+    /// Number of requests that have not posted their terminal cqe
+    pub fn inflight(&self) -> usize {
+        self.inflight.len()
+    }
+
+    /// Submit the queued requests, and wait for the next cqe of a request of the scope. Returns
+    /// the id of the request and its cqe, or None if there are no requests in flight.
+    pub fn wait(&mut self) -> io::Result<Option<(u64, io_uring_cqe)>> {
+        loop {
+            while let Some(cqe) = self.ring.pop_cqe() {
+                if let Some(x) = self.handle(cqe) {
+                    return Ok(Some(x));
+                }
+            }
+            if self.inflight.is_empty() {
+                return Ok(None);
+            }
+            self.ring.submit_and_wait(1)?;
+        }
+    }
+
+    // Account for cqe, and return it with its id if it is for a request of the scope
+    fn handle(&mut self, cqe: io_uring_cqe) -> Option<(u64, io_uring_cqe)> {
+        if !self.owns(&cqe) {
+            self.other.push(cqe);
+            return None;
+        }
+        let id = cqe.user_data() & ID_MASK;
+        if id == CANCEL_ID {
+            return None;
+        }
+        if !cqe.has_more() {
+            self.inflight.remove(&id);
+        }
+        Some((id, cqe))
+    }
+
+    /// Cancel the requests in flight. Their cqes are still reported by [`Self::wait`].
+    pub fn cancel_all(&mut self) -> io::Result<()> {
+        let ids: Vec<u64> = self.inflight.iter().copied().collect();
+        for id in ids {
+            let ud = self.tag | id;
+            self.queue(CANCEL_ID, |sqe| sqe.prep_cancel(ud))?;
+        }
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    // Wait until all requests are done, cancelling them first if cancel is set
+    //
+    // NB: this is the only thing that makes the scope sound, so if it fails (other than being
+    // interrupted), we abort.
+    fn finish(&mut self, cancel: bool) {
+        if cancel && !self.inflight.is_empty() {
+            if let Err(err) = self.cancel_all() {
+                eprintln!("WARNING: cancelling scope requests failed: {}", err);
+            }
+        }
+        loop {
+            match self.wait() {
+                Ok(Some(_)) => continue,
+                Ok(None) => return,
+                // NB: a signal (e.g., SIGCHLD) interrupting the wait is no reason to abort
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    eprintln!("FATAL: waiting for scope requests failed: {}", err);
+                    std::process::abort();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Scope<'_> {
+    fn drop(&mut self) {
+        // NB: we get here with requests in flight only if the closure of scope() panicked
+        if !self.inflight.is_empty() {
+            self.finish(true);
+        }
+    }
+}
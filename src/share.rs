@@ -0,0 +1,216 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Passing rings to other processes
+//
+// A ring is an fd, and its queues are mapped from it, so another process can use a ring if it has
+// the fd: e.g., a privileged broker sets up a ring (registering files and buffers, restricting it,
+// see crate::sandbox), and hands it to an unprivileged worker. To map the queues, the receiver
+// also needs the parameters that io_uring_setup() returned (the offsets of the queue fields), so
+// send_ring() sends them as the data of the message that carries the fd (SCM_RIGHTS).
+//
+// Since Linux 6.7, the kernel refuses to pass io_uring fds via SCM_RIGHTS (sendmsg() fails with
+// EINVAL), to avoid reference cycles between rings and sockets. In that case, send_ring() sends its
+// pid and the number of the ring fd instead, and the receiver copies the fd via pidfd_getfd()
+// (Linux 5.6). This needs the receiver to be allowed to ptrace the sender (e.g., same user, and,
+// with Yama, the sender allows it via PR_SET_PTRACER, which send_ring() does). send_ring() waits
+// for the receiver to acknowledge that it has copied the fd.
+//
+// Registered files, buffers, and restrictions belong to the ring, so the receiver can use the
+// fixed-file slots and registered buffers of the sender as they are. Their user-space state
+// (e.g., tracked requests or statistics) is not sent, though: the sender should hand over an idle
+// ring, and not use it afterwards, since the queues do not support concurrent users.
+//
+// Reference: unix(7), cmsg(3), pidfd_getfd(2)
+
+use std::convert::TryInto;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use crate::io_uring::IoUring;
+
+// NB: io_uring_params is 120 bytes, which the assertions in io_uring.rs check
+const PARAMS_SZ: usize = 120;
+// The message: the parameters, and the pid and the fd number of the sender
+const MSG_SZ: usize = PARAMS_SZ + 8;
+
+/// Send ring over the Unix socket sock, for [`recv_ring`] on the other end
+///
+/// The peer needs to call [`recv_ring`] for this to return, if the kernel does not allow passing
+/// the ring via SCM_RIGHTS.
+pub fn send_ring<S: AsRawFd>(sock: &S, ring: &IoUring) -> io::Result<()> {
+    let sock = sock.as_raw_fd();
+    let mut data = [0u8; MSG_SZ];
+    data[..PARAMS_SZ].copy_from_slice(&ring.params_bytes());
+    data[PARAMS_SZ..PARAMS_SZ + 4].copy_from_slice(&unsafe { libc::getpid() }.to_ne_bytes());
+    data[PARAMS_SZ + 4..].copy_from_slice(&ring.as_raw_fd().to_ne_bytes());
+    match send_msg(sock, Some(ring.as_raw_fd()), &data) {
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => (),
+        x => return x,
+    }
+
+    // The kernel does not pass rings: let the peer copy the fd
+    let peer = peer_pid(sock)?;
+    // NB: this fails without Yama, where it is not needed
+    unsafe { libc::prctl(libc::PR_SET_PTRACER, peer as libc::c_ulong, 0, 0, 0) };
+    let ret = send_msg(sock, None, &data).and_then(|_| {
+        let mut ack = [0u8; 1];
+        match recv_msg(sock, &mut ack)? {
+            (None, 1) => Ok(()),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "peer did not copy the ring")),
+        }
+    });
+    unsafe { libc::prctl(libc::PR_SET_PTRACER, 0, 0, 0, 0) };
+    ret
+}
+
+/// Receive a ring sent via [`send_ring`] over the Unix socket sock
+pub fn recv_ring<S: AsRawFd>(sock: &S) -> io::Result<IoUring> {
+    let sock = sock.as_raw_fd();
+    let mut data = [0u8; MSG_SZ];
+    let fd = match recv_msg(sock, &mut data)? {
+        (_, 0) => {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "socket closed"));
+        }
+        (Some(fd), len) if len == MSG_SZ => fd,
+        (None, len) if len == MSG_SZ => {
+            let pid = i32::from_ne_bytes(data[PARAMS_SZ..PARAMS_SZ + 4].try_into().unwrap());
+            let fd = i32::from_ne_bytes(data[PARAMS_SZ + 4..].try_into().unwrap());
+            let fd = copy_fd(pid, fd)?;
+            if let Err(e) = send_msg(sock, None, &[1]) {
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+            fd
+        }
+        (fd, len) => {
+            if let Some(fd) = fd {
+                unsafe { libc::close(fd) };
+            }
+            let msg = format!("invalid ring message size: {}", len);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+    };
+    // NB: the sender is trusted to send a ring fd, with its parameters
+    unsafe { IoUring::from_raw_parts(fd, &data[..PARAMS_SZ]) }
+}
+
+// The pid of the peer of a Unix socket
+fn peer_pid(sock: RawFd) -> io::Result<libc::pid_t> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let err = unsafe {
+        let cred_p = &mut cred as *mut libc::ucred as *mut libc::c_void;
+        libc::getsockopt(sock, libc::SOL_SOCKET, libc::SO_PEERCRED, cred_p, &mut len)
+    };
+    if err != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.pid)
+}
+
+// Copy fd of process pid
+fn copy_fd(pid: libc::pid_t, fd: RawFd) -> io::Result<RawFd> {
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if pidfd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ret = unsafe { libc::syscall(libc::SYS_pidfd_getfd, pidfd, fd, 0) };
+    let err = io::Error::last_os_error();
+    unsafe { libc::close(pidfd as RawFd) };
+    if ret < 0 {
+        return Err(err);
+    }
+    Ok(ret as RawFd)
+}
+
+// Space for the control message of a single fd
+fn cmsg_space() -> usize {
+    unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) as usize }
+}
+
+fn send_msg(sock: RawFd, fd: Option<RawFd>, data: &[u8]) -> io::Result<()> {
+    // NB: u64s, so that the buffer is aligned for cmsghdr
+    let mut cbuf = vec![0u64; cmsg_space().div_ceil(8)];
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if let Some(fd) = fd {
+        msg.msg_control = cbuf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = cmsg_space() as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+            (libc::CMSG_DATA(cmsg) as *mut RawFd).write_unaligned(fd);
+        }
+    }
+
+    loop {
+        let ret = unsafe { libc::sendmsg(sock, &msg, libc::MSG_NOSIGNAL) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if ret as usize != data.len() {
+            let msg = format!("short send: {} of {} bytes", ret, data.len());
+            return Err(io::Error::new(io::ErrorKind::WriteZero, msg));
+        }
+        return Ok(());
+    }
+}
+
+// Receive a message, and return its fd, if any, and the length of its data
+fn recv_msg(sock: RawFd, data: &mut [u8]) -> io::Result<(Option<RawFd>, usize)> {
+    let mut cbuf = vec![0u64; cmsg_space().div_ceil(8)];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cbuf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = cmsg_space() as _;
+
+    let len = loop {
+        let ret = unsafe { libc::recvmsg(sock, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        break ret as usize;
+    };
+
+    let mut fd = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                fd = Some((libc::CMSG_DATA(cmsg) as *const RawFd).read_unaligned());
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & (libc::MSG_TRUNC | libc::MSG_CTRUNC) != 0 {
+        if let Some(fd) = fd {
+            unsafe { libc::close(fd) };
+        }
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated message"));
+    }
+    Ok((fd, len))
+}
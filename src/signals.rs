@@ -0,0 +1,143 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Signals as completions, via a signalfd
+//
+// Blocked signals are queued on a signalfd instead of running a handler. A multishot poll request
+// on the signalfd posts a cqe whenever signals are pending, so that they are handled in the
+// completion loop, and the application only ever waits in io_uring_enter().
+//
+// Reference: signalfd(2), sigprocmask(2)
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use crate::io_uring::{io_uring_cqe, IoUring};
+
+/// A signalfd for a set of signals, polled via the ring
+///
+/// ```no_run
+/// # use iouring::io_uring::IoUring;
+/// # use iouring::signals::Signals;
+/// let mut ring = IoUring::init(32).unwrap();
+/// let signals = Signals::new(&[libc::SIGINT, libc::SIGTERM], u64::MAX).unwrap();
+/// signals.arm(&mut ring).unwrap();
+/// loop {
+///     ring.submit_and_wait(1).unwrap();
+///     while let Some(cqe) = ring.pop_cqe() {
+///         if signals.owns(&cqe) {
+///             for info in signals.received(&mut ring, &cqe).unwrap() {
+///                 println!("got signal {}", info.ssi_signo);
+///             }
+///             continue;
+///         }
+///         // other completions
+///     }
+/// }
+/// ```
+///
+/// NB: signals are blocked for the calling thread only, and a process-directed signal is
+/// delivered to any thread that does not block it, so create the Signals before spawning other
+/// threads (which inherit the signal mask). Dropping the Signals does not unblock the signals.
+pub struct Signals {
+    fd: RawFd,
+    user_data: u64,
+}
+
+impl Signals {
+
+    /// Block the given signals for the calling thread, and create a signalfd for them
+    ///
+    /// user_data is the user data of the poll request (see [`Self::arm`]).
+    pub fn new(signals: &[libc::c_int], user_data: u64) -> io::Result<Signals> {
+        let mut mask: libc::sigset_t = unsafe { std::mem::zeroed() };
+        unsafe { libc::sigemptyset(&mut mask) };
+        for sig in signals {
+            if unsafe { libc::sigaddset(&mut mask, *sig) } != 0 {
+                let msg = format!("invalid signal: {}", sig);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        }
+
+        // NB: pthread_sigmask returns the error instead of setting errno
+        let err = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &mask, std::ptr::null_mut()) };
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err));
+        }
+        let fd = unsafe { libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Signals { fd, user_data })
+    }
+
+    /// Queue a multishot poll request on the signalfd, submitting queued requests if the
+    /// submission queue is full
+    ///
+    /// [`Self::received`] re-arms the request if the kernel terminates it (but not if it fails).
+    pub fn arm(&self, ring: &mut IoUring) -> io::Result<()> {
+        loop {
+            if let Some(mut sqe) = ring.get_sqe() {
+                sqe.prep_poll_multishot(self.fd, libc::POLLIN as u32);
+                sqe.set_data(self.user_data);
+                return Ok(());
+            }
+            ring.submit()?;
+        }
+    }
+
+    /// Whether the cqe is for the poll request of the signalfd
+    pub fn owns(&self, cqe: &io_uring_cqe) -> bool {
+        cqe.user_data() == self.user_data
+    }
+
+    /// Handle a cqe of the poll request, and return the pending signals
+    ///
+    /// The list might be empty, e.g., if the signals were already read after a previous cqe.
+    pub fn received(
+        &self,
+        ring: &mut IoUring,
+        cqe: &io_uring_cqe,
+    ) -> io::Result<Vec<libc::signalfd_siginfo>> {
+        cqe.result()?;
+        if !cqe.has_more() {
+            self.arm(ring)?;
+        }
+        self.read()
+    }
+
+    /// Read the pending signals, without blocking
+    pub fn read(&self) -> io::Result<Vec<libc::signalfd_siginfo>> {
+        let mut ret = vec![];
+        loop {
+            let mut info: libc::signalfd_siginfo = unsafe { std::mem::zeroed() };
+            let sz = std::mem::size_of::<libc::signalfd_siginfo>();
+            let p = &mut info as *mut libc::signalfd_siginfo as *mut libc::c_void;
+            let n = unsafe { libc::read(self.fd, p, sz) };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(ret);
+                }
+                return Err(err);
+            }
+            // NB: the kernel only returns whole siginfos
+            ret.push(info);
+        }
+    }
+}
+
+impl AsRawFd for Signals {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
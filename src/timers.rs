@@ -0,0 +1,209 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Logical timers on top of timeout requests
+//
+// Each armed timer is a TIMEOUT request, and cancelling it is a TIMEOUT_REMOVE request, so timers
+// expire through the completion queue together with everything else, and there is no need for a
+// separate timer thread or for computing wait timeouts.
+//
+// The user data of timer requests is:
+//   tag (8 bits) | control (1 bit) | generation (23 bits) | timer id (32 bits)
+// The tag identifies the requests of a Timers instance among the other requests of the ring.
+// Re-arming a timer removes its timeout and adds a new one with the next generation, so that an
+// expiration that raced with the reset (or with a cancel) is recognized as stale and dropped.
+// (TIMEOUT_REMOVE with IORING_TIMEOUT_UPDATE keeps the user data, so it cannot tell the two
+// apart.)
+//
+// Reference: io_uring_prep_timeout(3), io_uring_prep_timeout_remove(3)
+
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+use crate::io_uring::{io_uring_cqe, IoUring, KernelTimespec, SQEntry, TimeoutFlags};
+
+const TAG_SHIFT: u32 = 56;
+const CTL_BIT: u64 = 1 << 55;
+const GEN_SHIFT: u32 = 32;
+const GEN_MASK: u32 = (1 << 23) - 1;
+
+/// Many logical timers, multiplexed onto timeout requests of a ring
+///
+/// Timers are identified by a u32 id chosen by the application. Expirations are delivered as
+/// cqes, which the application passes to [`Self::expired`]:
+///
+/// ```no_run
+/// # use iouring::io_uring::IoUring;
+/// # use iouring::timers::Timers;
+/// # use std::time::Duration;
+/// let mut ring = IoUring::init(32).unwrap();
+/// let mut timers = Timers::new(0xff);
+/// timers.add(&mut ring, 1, Duration::from_millis(100)).unwrap();
+/// loop {
+///     ring.submit_and_wait(1).unwrap();
+///     while let Some(cqe) = ring.pop_cqe() {
+///         if timers.owns(&cqe) {
+///             if let Some(id) = timers.expired(&cqe) {
+///                 println!("timer {} expired", id);
+///             }
+///             continue;
+///         }
+///         // other completions
+///     }
+/// }
+/// ```
+///
+/// NB: the kernel reads the deadline of a timeout when it is submitted, and the deadlines are
+/// kept in the Timers, so requests prepared by it need to be submitted before it is dropped.
+pub struct Timers {
+    tag: u64,
+    flags: TimeoutFlags,
+    // armed timers: id -> generation
+    armed: HashMap<u32, u32>,
+    // deadlines of timeouts without a cqe yet, by user data
+    inflight: HashMap<u64, Box<KernelTimespec>>,
+    next_gen: u32,
+}
+
+impl Timers {
+
+    /// Timers whose requests use the given tag in the top 8 bits of their user data
+    ///
+    /// No other requests on the ring should have user data with this tag.
+    pub fn new(tag: u8) -> Timers {
+        Timers::with_flags(tag, TimeoutFlags::empty())
+    }
+
+    /// Like [`Self::new`], but with flags for the timeouts, e.g., to select the clock
+    ///
+    /// NB: timers are always relative, so [`TimeoutFlags::ABS`] is ignored.
+    pub fn with_flags(tag: u8, flags: TimeoutFlags) -> Timers {
+        Timers {
+            tag: (tag as u64) << TAG_SHIFT,
+            flags: flags - TimeoutFlags::ABS,
+            armed: HashMap::new(),
+            inflight: HashMap::new(),
+            next_gen: 0,
+        }
+    }
+
+    fn user_data(&self, id: u32, gen: u32) -> u64 {
+        self.tag | ((gen as u64) << GEN_SHIFT) | id as u64
+    }
+
+    // Prepare a request via f, submitting queued requests if the submission queue is full
+    fn queue<F: FnOnce(&mut SQEntry)>(ring: &mut IoUring, f: F) -> io::Result<()> {
+        loop {
+            if let Some(mut sqe) = ring.get_sqe() {
+                f(&mut sqe);
+                return Ok(());
+            }
+            ring.submit()?;
+        }
+    }
+
+    fn arm(&mut self, ring: &mut IoUring, id: u32, after: Duration) -> io::Result<()> {
+        let gen = self.next_gen;
+        let ud = self.user_data(id, gen);
+        let ts = Box::new(KernelTimespec::from(after));
+        let ts_ptr: *const KernelTimespec = &*ts;
+        let flags = self.flags;
+        Timers::queue(ring, |sqe| {
+            sqe.prep_timeout(ts_ptr, 0, flags);
+            sqe.set_data(ud);
+        })?;
+        self.inflight.insert(ud, ts);
+        self.armed.insert(id, gen);
+        self.next_gen = (gen + 1) & GEN_MASK;
+        Ok(())
+    }
+
+    fn disarm(&mut self, ring: &mut IoUring, id: u32) -> io::Result<bool> {
+        let gen = match self.armed.get(&id) {
+            Some(x) => *x,
+            None => return Ok(false),
+        };
+        let ud = self.user_data(id, gen);
+        Timers::queue(ring, |sqe| {
+            sqe.prep_timeout_remove(ud);
+            sqe.set_data(ud | CTL_BIT);
+        })?;
+        self.armed.remove(&id);
+        Ok(true)
+    }
+
+    /// Arm timer id to expire after the given duration
+    ///
+    /// Fails with `InvalidInput` if the timer is already armed.
+    pub fn add(&mut self, ring: &mut IoUring, id: u32, after: Duration) -> io::Result<()> {
+        if self.armed.contains_key(&id) {
+            let msg = format!("timer {} is already armed", id);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        self.arm(ring, id, after)
+    }
+
+    /// Disarm timer id. Returns false if it was not armed (e.g., because it already expired).
+    pub fn cancel(&mut self, ring: &mut IoUring, id: u32) -> io::Result<bool> {
+        self.disarm(ring, id)
+    }
+
+    /// Re-arm timer id to expire after the given duration, whether it is armed or not
+    pub fn reset(&mut self, ring: &mut IoUring, id: u32, after: Duration) -> io::Result<()> {
+        self.disarm(ring, id)?;
+        self.arm(ring, id, after)
+    }
+
+    /// Whether timer id is armed
+    pub fn is_armed(&self, id: u32) -> bool {
+        self.armed.contains_key(&id)
+    }
+
+    /// Number of armed timers
+    pub fn len(&self) -> usize {
+        self.armed.len()
+    }
+
+    /// Whether no timers are armed
+    pub fn is_empty(&self) -> bool {
+        self.armed.is_empty()
+    }
+
+    /// Whether the cqe is for a request of these timers
+    pub fn owns(&self, cqe: &io_uring_cqe) -> bool {
+        cqe.user_data() >> TAG_SHIFT == self.tag >> TAG_SHIFT
+    }
+
+    /// Handle a cqe of these timers (see [`Self::owns`]), and return the id of the timer that
+    /// expired, if any
+    ///
+    /// Cqes of cancel requests, and of timeouts that were cancelled or reset, return None.
+    pub fn expired(&mut self, cqe: &io_uring_cqe) -> Option<u32> {
+        let ud = cqe.user_data();
+        if !self.owns(cqe) || ud & CTL_BIT != 0 {
+            // NB: removing a timeout fails if it has already expired (or is expiring), in which
+            // case its cqe is (or will be) stale.
+            return None;
+        }
+
+        // NB: with a count of 0, the only way for a timeout to complete is to expire or to be
+        // cancelled
+        self.inflight.remove(&ud);
+        if cqe.res() != -libc::ETIME {
+            return None;
+        }
+        let id = ud as u32;
+        let gen = ((ud >> GEN_SHIFT) as u32) & GEN_MASK;
+        match self.armed.get(&id) {
+            Some(x) if *x == gen => {
+                self.armed.remove(&id);
+                Some(id)
+            }
+            _ => None,
+        }
+    }
+}
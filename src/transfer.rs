@@ -0,0 +1,209 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Full-length reads and writes
+//
+// Reads and writes can transfer fewer bytes than requested (e.g., reads from pipes and sockets,
+// or writes interrupted by a signal), and almost every caller needs to issue the remainder. A
+// Transfers issues it automatically: on a short transfer it advances the buffers (and the offset)
+// and resubmits, until all bytes are transferred, a read reaches EOF, or a request fails.
+//
+// Optionally, reads are first attempted with RWF_NOWAIT, which only reads what is in the page
+// cache, and fails with EAGAIN otherwise. On EAGAIN, the read is retried without the flag, and
+// io_uring issues it from its worker threads (io-wq). This gives cache hits the lowest latency,
+// and reports misses, without any fallback code in the caller.
+//
+// The user data of transfer requests is the tag (top 8 bits) and the transfer id.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use crate::io_uring::{io_uring_cqe, IoUring, Offset, SQEntry};
+use crate::iovec::{IoVecs, StableBuf};
+
+const TAG_SHIFT: u32 = 56;
+
+/// Offset for files without a position (pipes, sockets), or for using the file position
+pub const OFF_CURRENT: u64 = Offset::Current.raw();
+
+// prep_readv or prep_writev of IoVecs<B>
+type PrepFn<B> = fn(&mut IoVecs<B>, &mut SQEntry, RawFd, u64);
+
+struct Transfer<B> {
+    fd: RawFd,
+    off: u64,
+    bufs: IoVecs<B>,
+    prep: PrepFn<B>,
+    is_write: bool,
+    done: usize,
+    // whether to issue the next request with RWF_NOWAIT, and whether a nowait request failed
+    nowait: bool,
+    fell_back: bool,
+}
+
+/// A transfer that finished (see [`Transfers::complete`])
+pub struct Completed<B> {
+    pub id: u32,
+    /// Bytes transferred, which is less than requested only for reads that reached EOF, or if
+    /// there is an error
+    pub bytes: usize,
+    /// The error of the request that failed, if any
+    pub error: Option<io::Error>,
+    /// The buffers, advanced past the transferred bytes
+    pub bufs: IoVecs<B>,
+    /// Whether a RWF_NOWAIT read failed with EAGAIN (i.e., missed the page cache), and was
+    /// retried without the flag (see [`Transfers::set_nowait`])
+    pub fell_back: bool,
+}
+
+/// Reads and writes that are resubmitted until they transfer all bytes
+///
+/// NB: the buffers are owned by the Transfers while in flight, so it needs to stay alive until
+/// all transfers complete.
+pub struct Transfers<B> {
+    tag: u64,
+    transfers: HashMap<u32, Transfer<B>>,
+    next_id: u32,
+    nowait: bool,
+}
+
+impl<B: StableBuf> Transfers<B> {
+
+    /// Transfers whose requests use the given tag in the top 8 bits of their user data
+    ///
+    /// No other requests on the ring should have user data with this tag.
+    pub fn new(tag: u8) -> Transfers<B> {
+        Transfers {
+            tag: (tag as u64) << TAG_SHIFT,
+            transfers: HashMap::new(),
+            next_id: 0,
+            nowait: false,
+        }
+    }
+
+    /// Attempt reads with RWF_NOWAIT first (Linux 5.9 for io_uring), and retry them without it
+    /// if they fail with EAGAIN (default: off)
+    ///
+    /// This applies to reads that are started afterwards. Writes are not affected.
+    pub fn set_nowait(&mut self, nowait: bool) {
+        self.nowait = nowait;
+    }
+
+    // Queue the request for the remaining bytes of transfer id
+    fn queue(&mut self, ring: &mut IoUring, id: u32) -> io::Result<()> {
+        let ud = self.tag | id as u64;
+        let t = self.transfers.get_mut(&id).unwrap();
+        loop {
+            if let Some(mut sqe) = ring.get_sqe() {
+                (t.prep)(&mut t.bufs, &mut sqe, t.fd, t.off);
+                if t.nowait {
+                    sqe.set_rw_flags(libc::RWF_NOWAIT);
+                }
+                sqe.set_data(ud);
+                return Ok(());
+            }
+            ring.submit()?;
+        }
+    }
+
+    fn start(&mut self, ring: &mut IoUring, t: Transfer<B>) -> io::Result<u32> {
+        let mut id = self.next_id;
+        while self.transfers.contains_key(&id) {
+            id = id.wrapping_add(1);
+        }
+        self.next_id = id.wrapping_add(1);
+        self.transfers.insert(id, t);
+        if let Err(e) = self.queue(ring, id) {
+            self.transfers.remove(&id);
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    /// Queue a write of bufs to fd at off (or [`OFF_CURRENT`]), and return the transfer id
+    pub fn write(&mut self, ring: &mut IoUring, fd: RawFd, off: u64, bufs: IoVecs<B>)
+    -> io::Result<u32> {
+        let prep: PrepFn<B> = IoVecs::prep_writev;
+        let t = Transfer {
+            fd, off, bufs, prep, is_write: true, done: 0, nowait: false, fell_back: false,
+        };
+        self.start(ring, t)
+    }
+
+    /// Whether the cqe is for a request of these transfers
+    pub fn owns(&self, cqe: &io_uring_cqe) -> bool {
+        cqe.user_data() >> TAG_SHIFT == self.tag >> TAG_SHIFT
+    }
+
+    /// Handle a cqe: resubmit the remainder of a short transfer, or return the transfer if it is
+    /// done
+    ///
+    /// Returns None if the cqe is not for these transfers (see [`Self::owns`]), or if the
+    /// transfer continues.
+    pub fn complete(&mut self, ring: &mut IoUring, cqe: &io_uring_cqe) -> Option<Completed<B>> {
+        if !self.owns(cqe) {
+            return None;
+        }
+        let id = cqe.user_data() as u32;
+        let t = self.transfers.get_mut(&id)?;
+        let res = cqe.res();
+        let mut error = None;
+        if res == -libc::EAGAIN && t.nowait {
+            t.nowait = false;
+            t.fell_back = true;
+        } else if res == -libc::EAGAIN || res == -libc::EINTR {
+            // NB: retry as is
+        } else if res < 0 {
+            error = Some(io::Error::from_raw_os_error(-res));
+        } else if res == 0 && !t.bufs.is_empty() {
+            if t.is_write {
+                error = Some(io::Error::new(io::ErrorKind::WriteZero, "write returned 0"));
+            }
+            // NB: otherwise, the read reached EOF
+        } else {
+            let n = res as usize;
+            t.bufs.advance(n);
+            t.done += n;
+            if t.off != OFF_CURRENT {
+                t.off += n as u64;
+            }
+        }
+
+        let finished = error.is_some() || res == 0 || t.bufs.is_empty();
+        if !finished {
+            match self.queue(ring, id) {
+                Ok(()) => return None,
+                Err(e) => error = Some(e),
+            }
+        }
+        let t = self.transfers.remove(&id).unwrap();
+        Some(Completed { id, bytes: t.done, error, bufs: t.bufs, fell_back: t.fell_back })
+    }
+
+    /// Number of transfers in flight
+    pub fn len(&self) -> usize {
+        self.transfers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transfers.is_empty()
+    }
+}
+
+impl<B: StableBuf + AsMut<[u8]>> Transfers<B> {
+
+    /// Queue a read from fd at off (or [`OFF_CURRENT`]) into bufs, and return the transfer id
+    pub fn read(&mut self, ring: &mut IoUring, fd: RawFd, off: u64, bufs: IoVecs<B>)
+    -> io::Result<u32> {
+        let prep: PrepFn<B> = IoVecs::prep_readv;
+        let nowait = self.nowait;
+        let t = Transfer {
+            fd, off, bufs, prep, is_write: false, done: 0, nowait, fell_back: false,
+        };
+        self.start(ring, t)
+    }
+}
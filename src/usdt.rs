@@ -0,0 +1,105 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// USDT (SystemTap SDT) probes for submission and completion (enable the "usdt" feature)
+//
+// The probes let bpftrace and perf correlate the activity of the application on a ring with the
+// io_uring tracepoints of the kernel, e.g.:
+//
+//   bpftrace -e 'usdt:./app:iouring:complete /arg2 < 0/ { @errors[arg2] = count(); }'
+//
+// Probes:
+//   iouring:submit   (ring_fd: i32, opcode: u8, fd: i32, user_data: u64), per sqe flushed to the SQ
+//   iouring:complete (ring_fd: i32, user_data: u64, res: i32, flags: u32), per cqe popped
+//
+// A probe is a nop instruction, and an ELF note (in .note.stapsdt) with its address and the
+// locations of its arguments, which tracers use to attach to it. This is what <sys/sdt.h> does
+// for C. Probes are only emitted on x86_64 and aarch64; on other architectures, they compile to
+// nothing.
+//
+// Reference: https://sourceware.org/systemtap/wiki/UserSpaceProbeImplementation
+
+// The assembly of a probe: a nop, and a note with its address and the SDT argument specs $args
+// (with {0}-{3} for the registers of the arguments)
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+macro_rules! stapsdt {
+    ($provider:literal, $name:literal, $args:literal) => {
+        concat!(
+            "990: nop\n",
+            ".pushsection .note.stapsdt, \"\", \"note\"\n",
+            ".balign 4\n",
+            ".4byte 992f-991f, 994f-993f, 3\n",
+            "991: .asciz \"stapsdt\"\n",
+            "992: .balign 4\n",
+            "993: .8byte 990b\n",
+            ".8byte _.stapsdt.base\n",
+            ".8byte 0\n",
+            ".asciz \"", $provider, "\"\n",
+            ".asciz \"", $name, "\"\n",
+            ".asciz \"", $args, "\"\n",
+            "994: .balign 4\n",
+            ".popsection\n",
+            ".ifndef _.stapsdt.base\n",
+            ".pushsection .stapsdt.base, \"aG\", \"progbits\", .stapsdt.base, comdat\n",
+            ".weak _.stapsdt.base\n",
+            ".hidden _.stapsdt.base\n",
+            "_.stapsdt.base: .space 1\n",
+            ".size _.stapsdt.base, 1\n",
+            ".popsection\n",
+            ".endif\n",
+        )
+    };
+}
+
+// Emit a probe with 4 (64-bit) arguments
+// NB: the argument specs use AT&T syntax on x86_64 (e.g., %rax)
+#[cfg(target_arch = "x86_64")]
+macro_rules! probe4 {
+    ($provider:literal, $name:literal, $args:literal, $a0:expr, $a1:expr, $a2:expr, $a3:expr) => {
+        unsafe {
+            std::arch::asm!(
+                stapsdt!($provider, $name, $args),
+                in(reg) $a0, in(reg) $a1, in(reg) $a2, in(reg) $a3,
+                options(att_syntax, nomem, nostack, preserves_flags),
+            )
+        }
+    };
+}
+
+#[cfg(target_arch = "aarch64")]
+macro_rules! probe4 {
+    ($provider:literal, $name:literal, $args:literal, $a0:expr, $a1:expr, $a2:expr, $a3:expr) => {
+        unsafe {
+            std::arch::asm!(
+                stapsdt!($provider, $name, $args),
+                in(reg) $a0, in(reg) $a1, in(reg) $a2, in(reg) $a3,
+                options(nomem, nostack, preserves_flags),
+            )
+        }
+    };
+}
+
+// NB: the arguments are passed as 64-bit registers, so signed ones are sign-extended
+
+/// Probe iouring:submit
+#[inline(always)]
+pub(crate) fn submit(ring_fd: i32, opcode: u8, fd: i32, user_data: u64) {
+    let (ring_fd, opcode, fd) = (ring_fd as i64, opcode as u64, fd as i64);
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    probe4!("iouring", "submit", "-8@{0} 8@{1} -8@{2} 8@{3}", ring_fd, opcode, fd, user_data);
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    let _ = (ring_fd, opcode, fd, user_data);
+}
+
+/// Probe iouring:complete
+#[inline(always)]
+pub(crate) fn complete(ring_fd: i32, user_data: u64, res: i32, flags: u32) {
+    let (ring_fd, res, flags) = (ring_fd as i64, res as i64, flags as u64);
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    probe4!("iouring", "complete", "-8@{0} 8@{1} -8@{2} 8@{3}", ring_fd, user_data, res, flags);
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    let _ = (ring_fd, user_data, res, flags);
+}
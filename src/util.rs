@@ -0,0 +1,143 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// File and block device queries
+//
+// Sizing and aligning I/O (e.g., for O_DIRECT or registered buffers) needs to know the size of a
+// file and the block size of the device under it. For block devices, these come from ioctls: the
+// sizes that fstat() reports for them are meaningless.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+// ioctl request encoding (_IOC() in asm/ioctl.h): most architectures use the asm-generic one,
+// but powerpc, mips, and sparc use 3 direction bits (with different values), and 13 size bits.
+#[cfg(any(
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "mips",
+    target_arch = "mips32r6",
+    target_arch = "mips64",
+    target_arch = "mips64r6",
+    target_arch = "sparc",
+    target_arch = "sparc64",
+))]
+mod ioc {
+    pub const NONE: libc::c_ulong = 1;
+    pub const READ: libc::c_ulong = 2;
+    pub const SIZEBITS: u32 = 13;
+}
+
+#[cfg(not(any(
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "mips",
+    target_arch = "mips32r6",
+    target_arch = "mips64",
+    target_arch = "mips64r6",
+    target_arch = "sparc",
+    target_arch = "sparc64",
+)))]
+mod ioc {
+    pub const NONE: libc::c_ulong = 0;
+    pub const READ: libc::c_ulong = 2;
+    pub const SIZEBITS: u32 = 14;
+}
+
+const fn ioc(dir: libc::c_ulong, ty: u8, nr: u8, size: usize) -> libc::c_ulong {
+    // from the low bits: number (8 bits), type (8 bits), size (SIZEBITS bits), and direction
+    (dir << (16 + ioc::SIZEBITS))
+        | ((size as libc::c_ulong) << 16)
+        | ((ty as libc::c_ulong) << 8)
+        | nr as libc::c_ulong
+}
+
+// _IOR(0x12, 114, size_t), so its value depends on the size of size_t (and on the architecture)
+const BLKGETSIZE64: libc::c_ulong =
+    ioc(ioc::READ, 0x12, 114, std::mem::size_of::<libc::size_t>());
+// _IO(0x12, 104) and _IO(0x12, 123): both take an int pointer
+const BLKSSZGET: libc::c_ulong = ioc(ioc::NONE, 0x12, 104, 0);
+const BLKPBSZGET: libc::c_ulong = ioc(ioc::NONE, 0x12, 123, 0);
+
+#[cfg(target_arch = "x86_64")]
+const _: () = assert!(BLKGETSIZE64 == 0x80081272 && BLKSSZGET == 0x1268 && BLKPBSZGET == 0x127b);
+
+fn fstat<F: AsRawFd>(f: &F) -> io::Result<libc::stat> {
+    let mut ret: libc::stat = unsafe { std::mem::zeroed() };
+    let err = unsafe { libc::fstat(f.as_raw_fd(), &mut ret) };
+    if err != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+fn s_isreg(st: &libc::stat) -> bool {
+    (st.st_mode & libc::S_IFMT) == libc::S_IFREG
+}
+
+fn s_isblk(st: &libc::stat) -> bool {
+    (st.st_mode & libc::S_IFMT) == libc::S_IFBLK
+}
+
+// Issue a block device ioctl that returns an int
+fn blk_ioctl_int<F: AsRawFd>(f: &F, req: libc::c_ulong) -> io::Result<u32> {
+    let mut val: libc::c_int = 0;
+    let err = unsafe { libc::ioctl(f.as_raw_fd(), req as _, &mut val) };
+    if err != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(val as u32)
+}
+
+/// Whether f is a block device
+pub fn is_block_device<F: AsRawFd>(f: &F) -> io::Result<bool> {
+    Ok(s_isblk(&fstat(f)?))
+}
+
+/// Size of a regular file or a block device, in bytes
+///
+/// (fs::metadata().len() does not work for block devices)
+pub fn file_size<F: AsRawFd>(f: &F) -> io::Result<u64> {
+    let st = fstat(f)?;
+    if s_isreg(&st) {
+        Ok(st.st_size as u64)
+    } else if s_isblk(&st) {
+        let mut bytes: u64 = 0;
+        let err = unsafe { libc::ioctl(f.as_raw_fd(), BLKGETSIZE64 as _, &mut bytes) };
+        if err != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(bytes)
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "not a regular file or a block device"))
+    }
+}
+
+/// Logical block size: the smallest unit the device can address, and hence the alignment that
+/// O_DIRECT needs for offsets, sizes, and buffers
+///
+/// For regular files, this is the block size of the file system (st_blksize), which is a multiple
+/// of the logical block size of the device, so it is a conservative choice.
+pub fn logical_block_size<F: AsRawFd>(f: &F) -> io::Result<u32> {
+    let st = fstat(f)?;
+    if s_isblk(&st) {
+        blk_ioctl_int(f, BLKSSZGET)
+    } else {
+        Ok(st.st_blksize as u32)
+    }
+}
+
+/// Physical block size: the smallest unit the device can write without a read-modify-write
+///
+/// For regular files, this is the block size of the file system (see [`logical_block_size`]).
+pub fn physical_block_size<F: AsRawFd>(f: &F) -> io::Result<u32> {
+    let st = fstat(f)?;
+    if s_isblk(&st) {
+        blk_ioctl_int(f, BLKPBSZGET)
+    } else {
+        Ok(st.st_blksize as u32)
+    }
+}
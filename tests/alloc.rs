@@ -0,0 +1,110 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Check that preparing, submitting, and reaping requests does not allocate.
+//
+// The global allocator of this test binary counts allocations, so it has a single test: other
+// tests running in parallel would allocate.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use iouring::io_uring::{FixedIoUring, IoUring};
+
+struct Counting;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// Allocations done by f
+fn allocs<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCS.load(Ordering::Relaxed);
+    f();
+    ALLOCS.load(Ordering::Relaxed) - before
+}
+
+// Run rounds of requests on fd (writes to a pipe), n at a time
+fn rounds(ring: &mut IoUring, fd: libc::c_int, n: u32) {
+    let buf = [std::io::IoSlice::new(b"x")];
+    for _ in 0..100 {
+        for i in 0..n {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_write_slice(fd, &buf, 0).unwrap();
+            sqe.set_data(i as u64);
+        }
+        ring.submit_and_wait(n).unwrap();
+        assert_eq!(ring.cq_iter().count(), n as usize);
+        let mut reaped = 0;
+        while let Some(cqe) = ring.pop_cqe() {
+            assert_eq!(cqe.res(), 1);
+            reaped += 1;
+        }
+        assert_eq!(reaped, n);
+    }
+}
+
+#[test]
+fn no_allocations() {
+    if !iouring::is_supported() {
+        eprintln!("io_uring is not available, skipping");
+        return;
+    }
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    // NB: 100 rounds of 8 bytes fit in the pipe
+    let (rd, wr) = (fds[0], fds[1]);
+    let mut drain = [0u8; 1024];
+    let mut drain_pipe = || unsafe {
+        libc::read(rd, drain.as_mut_ptr() as *mut libc::c_void, drain.len());
+    };
+
+    let mut ring = IoUring::init(8).unwrap();
+    assert_eq!(allocs(|| rounds(&mut ring, wr, 8)), 0);
+    drain_pipe();
+
+    let mut fixed = FixedIoUring::<8>::init().unwrap();
+    let n = allocs(|| {
+        let buf = [std::io::IoSlice::new(b"x")];
+        for _ in 0..100 {
+            {
+                let mut sqe = fixed.get_sqe().unwrap();
+                sqe.prep_write_slice(wr, &buf, 0).unwrap();
+            }
+            fixed.submit_and_wait(1).unwrap();
+            assert_eq!(fixed.pop_cqe().unwrap().res(), 1);
+        }
+    });
+    assert_eq!(n, 0);
+    drain_pipe();
+
+    // the tracking table is allocated up front
+    ring.track_pending(true);
+    assert_eq!(allocs(|| rounds(&mut ring, wr, 8)), 0);
+    drain_pipe();
+
+    unsafe {
+        libc::close(rd);
+        libc::close(wr);
+    }
+}
@@ -0,0 +1,267 @@
+//
+// Kornilios Kourtis <kkourt@kkourt.io>
+//
+// vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+//
+
+// Tests of the core ring machinery against the kernel, using the public API only.
+//
+// Requests go to memfds, temporary files, pipes, and socket pairs, so that the tests do not depend
+// on the filesystem or the network. If io_uring is not available (e.g., it is disabled via the
+// kernel.io_uring_disabled sysctl, or blocked by seccomp in a container), the tests pass without
+// doing anything.
+
+use std::io::{IoSlice, IoSliceMut};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use iouring::io_uring::{io_uring_cqe, IoUring, SQFlags, SqeFlags};
+
+fn ring(nentries: u32) -> Option<IoUring> {
+    if !iouring::is_supported() {
+        eprintln!("io_uring is not available, skipping");
+        return None;
+    }
+    Some(IoUring::init(nentries).unwrap())
+}
+
+fn memfd() -> RawFd {
+    let name = b"iouring-test\0".as_ptr() as *const libc::c_char;
+    let fd = unsafe { libc::memfd_create(name, libc::MFD_CLOEXEC) };
+    assert!(fd >= 0, "memfd_create: {}", std::io::Error::last_os_error());
+    fd
+}
+
+fn pipe() -> [RawFd; 2] {
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+    fds
+}
+
+fn socketpair() -> [RawFd; 2] {
+    let mut fds = [0; 2];
+    let ty = libc::SOCK_STREAM | libc::SOCK_CLOEXEC;
+    assert_eq!(unsafe { libc::socketpair(libc::AF_UNIX, ty, 0, fds.as_mut_ptr()) }, 0);
+    fds
+}
+
+fn close(fds: &[RawFd]) {
+    for fd in fds {
+        unsafe { libc::close(*fd) };
+    }
+}
+
+// Submit the queued requests, and wait for n cqes
+fn wait(ring: &mut IoUring, n: usize) -> Vec<io_uring_cqe> {
+    let mut ret = vec![];
+    while ret.len() < n {
+        ring.submit_and_wait(1).unwrap();
+        while let Some(cqe) = ring.pop_cqe() {
+            ret.push(cqe);
+        }
+    }
+    assert_eq!(ret.len(), n);
+    ret.sort_by_key(|cqe| cqe.user_data());
+    ret
+}
+
+// Write two buffers to wfd, and read them back from rfd into three, at off
+fn writev_readv(ring: &mut IoUring, wfd: RawFd, rfd: RawFd, off: u64) {
+    let (a, b) = (b"hello, ".to_vec(), b"io_uring!".to_vec());
+    let iovs = [IoSlice::new(&a), IoSlice::new(&b)];
+    {
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.prep_write_slice(wfd, &iovs, off).unwrap();
+        sqe.set_data(1);
+    }
+    let cqes = wait(ring, 1);
+    assert_eq!(cqes[0].res(), 16);
+
+    let (mut x, mut y, mut z) = ([0u8; 4], [0u8; 8], [0u8; 4]);
+    {
+        let iovs = [IoSliceMut::new(&mut x), IoSliceMut::new(&mut y), IoSliceMut::new(&mut z)];
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_read_slice(rfd, &iovs, off).unwrap();
+            sqe.set_data(2);
+        }
+        let cqes = wait(ring, 1);
+        assert_eq!(cqes[0].res(), 16);
+    }
+    let got: Vec<u8> = x.iter().chain(y.iter()).chain(z.iter()).copied().collect();
+    assert_eq!(got, b"hello, io_uring!");
+}
+
+#[test]
+fn rw_memfd() {
+    let mut ring = match ring(4) {
+        Some(x) => x,
+        None => return,
+    };
+    let fd = memfd();
+    writev_readv(&mut ring, fd, fd, 4096);
+    // reads past the end of the file return 0
+    let mut buf = [0u8; 8];
+    let iovs = [IoSliceMut::new(&mut buf)];
+    {
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.prep_read_slice(fd, &iovs, 1 << 20).unwrap();
+    }
+    assert_eq!(wait(&mut ring, 1)[0].res(), 0);
+    close(&[fd]);
+}
+
+#[test]
+fn rw_tempfile() {
+    let mut ring = match ring(4) {
+        Some(x) => x,
+        None => return,
+    };
+    let path = std::env::temp_dir().join(format!("iouring-ring-{}", std::process::id()));
+    let f = std::fs::OpenOptions::new()
+        .read(true).write(true).create(true).truncate(true)
+        .open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    writev_readv(&mut ring, f.as_raw_fd(), f.as_raw_fd(), 0);
+    assert_eq!(f.metadata().unwrap().len(), 16);
+}
+
+#[test]
+fn rw_pipe() {
+    let mut ring = match ring(4) {
+        Some(x) => x,
+        None => return,
+    };
+    let fds = pipe();
+    // NB: pipes have no position, so the offset needs to be 0 or -1
+    writev_readv(&mut ring, fds[1], fds[0], 0);
+    close(&fds);
+}
+
+#[test]
+fn rw_socketpair() {
+    let mut ring = match ring(4) {
+        Some(x) => x,
+        None => return,
+    };
+    let fds = socketpair();
+    writev_readv(&mut ring, fds[0], fds[1], 0);
+    writev_readv(&mut ring, fds[1], fds[0], 0);
+    close(&fds);
+}
+
+#[test]
+fn link() {
+    let mut ring = match ring(8) {
+        Some(x) => x,
+        None => return,
+    };
+    let fd = memfd();
+
+    // the read is issued after the write
+    let buf = b"linked".to_vec();
+    let wiovs = [IoSlice::new(&buf)];
+    let mut rbuf = [0u8; 6];
+    let riovs = [IoSliceMut::new(&mut rbuf)];
+    {
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.prep_write_slice(fd, &wiovs, 0).unwrap();
+        sqe.set_flags(SqeFlags::IO_LINK);
+        sqe.set_data(1);
+    }
+    {
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.prep_read_slice(fd, &riovs, 0).unwrap();
+        sqe.set_data(2);
+    }
+    let cqes = wait(&mut ring, 2);
+    assert_eq!((cqes[0].res(), cqes[1].res()), (6, 6));
+    assert_eq!(&rbuf, b"linked");
+
+    // a failed request cancels the rest of the chain, but not what comes after it
+    {
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.prep_fsync(-1, 0);
+        sqe.set_flags(SqeFlags::IO_LINK);
+        sqe.set_data(3);
+    }
+    {
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.prep_fsync(fd, 0);
+        sqe.set_flags(SqeFlags::IO_LINK);
+        sqe.set_data(4);
+    }
+    {
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.prep_fsync(fd, 0);
+        sqe.set_data(5);
+    }
+    {
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.prep_fsync(fd, 0);
+        sqe.set_data(6);
+    }
+    let res: Vec<i32> = wait(&mut ring, 4).iter().map(|cqe| cqe.res()).collect();
+    assert_eq!(res, vec![-libc::EBADF, -libc::ECANCELED, -libc::ECANCELED, 0]);
+    close(&[fd]);
+}
+
+#[test]
+fn cancel() {
+    let mut ring = match ring(4) {
+        Some(x) => x,
+        None => return,
+    };
+    let fds = socketpair();
+
+    // the socket has no data, so the poll stays in flight until it is cancelled
+    {
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.prep_poll_add(fds[0], libc::POLLIN as u32);
+        sqe.set_data(1);
+    }
+    ring.submit().unwrap();
+    {
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.prep_cancel(1);
+        sqe.set_data(2);
+    }
+    {
+        let mut sqe = ring.get_sqe().unwrap();
+        sqe.prep_cancel(1234);
+        sqe.set_data(3);
+    }
+    let cqes = wait(&mut ring, 3);
+    assert_eq!(cqes[0].res(), -libc::ECANCELED);
+    assert_eq!(cqes[1].res(), 0);
+    assert_eq!(cqes[2].res(), -libc::ENOENT);
+    close(&fds);
+}
+
+#[test]
+fn cq_overflow() {
+    // NB: the CQ ring has 2 entries
+    let mut ring = match ring(1) {
+        Some(x) => x,
+        None => return,
+    };
+    let fds = pipe();
+    let buf = [IoSlice::new(b"x")];
+    for i in 0..4 {
+        {
+            let mut sqe = ring.get_sqe().unwrap();
+            sqe.prep_write_slice(fds[1], &buf, 0).unwrap();
+            sqe.set_data(i);
+        }
+        ring.submit().unwrap();
+    }
+    assert!(ring.sq_flags().contains(SQFlags::CQ_OVERFLOW));
+
+    // the kernel keeps the cqes that did not fit (FEAT_NODROP), and posts them once there is room
+    let cqes = wait(&mut ring, 4);
+    for (i, cqe) in cqes.iter().enumerate() {
+        assert_eq!((cqe.user_data(), cqe.res()), (i as u64, 1));
+    }
+    assert!(!ring.sq_flags().contains(SQFlags::CQ_OVERFLOW));
+    assert_eq!(ring.stats().cq_overflows, 0);
+    close(&fds);
+}